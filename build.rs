@@ -27,7 +27,7 @@ fn main() -> rusqlite::Result<()> {
     let connection = Connection::open(TEST_DATABASE)?;
     connection.execute_batch(include_str!("schema.sql"))?;
 
-    // Add test users for convenience
+    // Add test users for convenience. The test user is also an administrator.
     add_test_data(&connection, "test@test.me", "12345678",
                   "Cooldep1",
                   "Acme",
@@ -67,7 +67,7 @@ fn add_test_data(conn: &Connection, email: &str,password: &str,
 
 
     conn.execute(
-        "INSERT INTO Site_users (sid, email, password_hash) VALUES (?, ?, ?)",
+        "INSERT INTO Site_users (sid, email, password_hash, email_verified, role) VALUES (?, ?, ?, 1, 'admin')",
         params![user_id.as_bytes(), email, password_hash(password)],
     )?;
     conn.execute(
@@ -1,5 +1,5 @@
 import sqlite3
-from datetime import timedelta
+from datetime import timedelta, timezone
 from faker import Faker
 import secrets
 import hashlib
@@ -76,7 +76,7 @@ for i in range(NUMBER_OF_USERS):
     password_hash= hash_password(password)
     print(email,password)
     site_users = [sid, email, password_hash]
-    cur.execute("INSERT INTO Site_users (sid, email, password_hash) VALUES (?,?,?) ",
+    cur.execute("INSERT INTO Site_users (sid, email, password_hash, email_verified) VALUES (?,?,?,1) ",
             site_users)
     # Deparment
     depid = random_id()
@@ -166,7 +166,7 @@ for i in range(NUMBER_OF_USERS):
 
         conn.commit()
         # For_Product_Data_Refres
-        datetime0 =  fake.date_between(start_date='-60d', end_date='today')
+        datetime0 =  fake.date_time_between(start_date='-60d', end_date='now', tzinfo=timezone.utc)
         for day in range(N_DAYS):
             if day == 0:
                datetime = datetime0 
@@ -174,9 +174,10 @@ for i in range(NUMBER_OF_USERS):
                 datetime = datetime0 + timedelta(days = day) 
             #print(f"Date:{datetime}, asin:{asin}")
 
-
-            cur.execute("INSERT INTO For_Product_Data_Refresh(datetime,ASIN) \
-                    VALUES (?,?)", [datetime,asin])
+            # Timestamps are stored in the same RFC 3339 format used by the app
+            refresh_id = random_id()
+            cur.execute("INSERT INTO For_Product_Data_Refresh(RefreshID,ASIN,datetime) \
+                    VALUES (?,?,?)", [refresh_id,asin,datetime.isoformat(timespec='seconds')])
             # Has_Listing_collected
             listing_id = random_id()
             price = round(random.uniform(2,50))
@@ -185,13 +186,13 @@ for i in range(NUMBER_OF_USERS):
                     asin,
                     conditions,
                     price,
-                    datetime,
+                    refresh_id,
                     comid,
                     comid
                     ]
 
             cur.execute("INSERT INTO Has_Listing_collected(ListingID,ASIN,condition,\
-                    Price,datetime,shipped_comID,sold_ComID) VALUES (?,?,?,?,?,?,?)",has_listing_collected)
+                    Price,RefreshID,shipped_comID,sold_ComID) VALUES (?,?,?,?,?,?,?)",has_listing_collected)
             conn.commit()
 
 
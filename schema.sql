@@ -6,14 +6,25 @@ CREATE TABLE Site_users
     sid           BINARY(16),
     email         VARCHAR(100),
     password_hash BINARY(32),
+    email_verified BOOLEAN NOT NULL DEFAULT 0,
+    -- Either 'user' or 'admin'. Administrators can open the pages under /admin.
+    role          CHAR(5)    NOT NULL DEFAULT 'user',
+    -- Disabled users can not sign in
+    disabled      BOOLEAN    NOT NULL DEFAULT 0,
+    -- Set when the user deletes their account. The account is removed for good once the grace
+    -- period has passed, unless the user signs in again before then.
+    deletion_requested_at DATETIME,
     UNIQUE(email),--add UNIQUE(email)
     PRIMARY KEY (sid)
 );
 
+-- Departments are identified by Amazon's browse node ID from the breadcrumb link, since
+-- departments in different branches can share a name such as "Accessories"
 CREATE TABLE Department
 (
     DepID BINARY(16),
-    name  VARCHAR(255) UNIQUE,
+    name  VARCHAR(255),
+    node  INTEGER UNIQUE,
     PRIMARY KEY (DepID)
 );
 
@@ -41,6 +52,8 @@ CREATE TABLE Tracks
 (
     sid BINARY(16),
     PID BINARY(16),
+    -- Optional price the user would like to be notified below
+    target_price REAL,
     PRIMARY KEY (sid, PID),
     FOREIGN KEY (sid) REFERENCES Site_users (sid),
     FOREIGN KEY (PID) REFERENCES Sold_Product_Manufactured (PID)
@@ -79,11 +92,12 @@ CREATE TABLE Subscribes_To
     FOREIGN Key (sid) REFERENCES Site_users (sid)
 );
 
+-- The department each department is listed under, which is replaced when Amazon moves it
 CREATE TABLE Area_within
 (
     sub_DepID      BINARY(16),
     Category_DepID BINARY(16),
-    Primary Key (sub_DepID, Category_DepID),
+    Primary Key (sub_DepID),
     FOREIGN Key (sub_DepID) REFERENCES Department (DepID),
     FOREIGN Key (Category_DepID) REFERENCES Department (DepID)
 );
@@ -108,11 +122,15 @@ CREATE TABLE Ranked_Best_Seller_Rank
     FOREIGN KEY (ASIN) REFERENCES Product_variant_Sold (ASIN) ON DELETE CASCADE
 );
 
+-- Each time the listings of a product were collected. Products may be refreshed several times a day.
 CREATE TABLE For_Product_Data_Refresh
 (
-    datetime date,
-    ASIN     CHAR(10),
-    Primary Key (datetime,ASIN),
+    RefreshID BINARY(16),
+    ASIN      CHAR(10) NOT NULL,
+    -- UTC time the listings were collected
+    datetime  DATETIME NOT NULL,
+    Primary Key (RefreshID),
+    UNIQUE (ASIN, datetime),
     Foreign Key (ASIN) REFERENCES Product_variant_Sold (ASIN) ON DELETE CASCADE
 );
 
@@ -129,12 +147,167 @@ CREATE TABLE Has_Listing_collected
     ASIN          CHAR(10),
     condition     CHAR(20),
     Price         real,
-    datetime      date     NOT NULL,
+    RefreshID     BINARY(16) NOT NULL,
     shipped_comID BINARY(16) NOT NULL,
     sold_ComID    BINARY(16) NOT NULL,
+    -- Either 'scraped' from Amazon or 'imported' from a third-party tracker's price history
+    source        CHAR(10)   NOT NULL DEFAULT 'scraped',
     Primary Key (ListingID, ASIN),
     Foreign Key (ASIN) REFERENCES Product_variant_Sold (ASIN) ON DELETE CASCADE,
-    Foreign Key (ASIN,datetime) REFERENCES For_Product_Data_Refresh (ASIN,datetime) ON DELETE CASCADE,
+    Foreign Key (RefreshID) REFERENCES For_Product_Data_Refresh (RefreshID) ON DELETE CASCADE,
     Foreign Key (shipped_comID) REFERENCES Company (ComID),
     Foreign Key (sold_ComID) REFERENCES Company (ComID)
 );
+
+CREATE INDEX Has_Listing_collected_RefreshID ON Has_Listing_collected (RefreshID);
+
+-- Listings older than the retention period are compacted into the lowest, highest and closing price
+-- of each day, and daily prices older than that into weeks. Prices are the lowest listing of each
+-- refresh, and the close is the last of them in the period.
+CREATE TABLE Price_Daily
+(
+    ASIN        CHAR(10),
+    condition   CHAR(20) NOT NULL DEFAULT '',
+    -- Midnight UTC at the start of the day
+    period      DATETIME NOT NULL,
+    min_price   real     NOT NULL,
+    max_price   real     NOT NULL,
+    close_price real     NOT NULL,
+    closed_at   DATETIME NOT NULL,
+    samples     INTEGER  NOT NULL,
+    Primary Key (ASIN, condition, period),
+    Foreign Key (ASIN) REFERENCES Product_variant_Sold (ASIN) ON DELETE CASCADE
+);
+
+-- As Price_Daily, for the weeks starting on Monday
+CREATE TABLE Price_Weekly
+(
+    ASIN        CHAR(10),
+    condition   CHAR(20) NOT NULL DEFAULT '',
+    period      DATETIME NOT NULL,
+    min_price   real     NOT NULL,
+    max_price   real     NOT NULL,
+    close_price real     NOT NULL,
+    closed_at   DATETIME NOT NULL,
+    samples     INTEGER  NOT NULL,
+    Primary Key (ASIN, condition, period),
+    Foreign Key (ASIN) REFERENCES Product_variant_Sold (ASIN) ON DELETE CASCADE
+);
+
+-- Changes found by comparing each refresh of a product with the one before it. The condition and
+-- seller are empty for changes to the product as a whole, such as going out of stock.
+CREATE TABLE Price_Change_Events
+(
+    EventID     BINARY(16),
+    ASIN        CHAR(10)    NOT NULL,
+    RefreshID   BINARY(16)  NOT NULL,
+    occurred_at DATETIME    NOT NULL,
+    -- One of price_drop, price_rise, new_seller, seller_gone, out_of_stock, back_in_stock or new_lowest
+    kind        CHAR(20)    NOT NULL,
+    condition   CHAR(20)    NOT NULL DEFAULT '',
+    seller      VARCHAR(64) NOT NULL DEFAULT '',
+    old_price   real,
+    new_price   real,
+    Primary Key (EventID),
+    UNIQUE (RefreshID, kind, condition, seller),
+    Foreign Key (ASIN) REFERENCES Product_variant_Sold (ASIN) ON DELETE CASCADE,
+    Foreign Key (RefreshID) REFERENCES For_Product_Data_Refresh (RefreshID) ON DELETE CASCADE
+);
+
+CREATE INDEX Price_Change_Events_ASIN ON Price_Change_Events (ASIN, occurred_at);
+
+-- Index of raw pages fetched from Amazon. The pages themselves are stored compressed on disk under
+-- their content hash so they can be re-parsed when the parsers change.
+CREATE TABLE Page_Archive
+(
+    ArchiveID  BINARY(16),
+    hash       CHAR(64)      NOT NULL,
+    ASIN       CHAR(10),
+    URL        VARCHAR(1024) NOT NULL,
+    fetched_at DATETIME      NOT NULL,
+    status     INTEGER       NOT NULL,
+    Primary Key (ArchiveID)
+);
+
+CREATE INDEX Page_Archive_ASIN ON Page_Archive (ASIN, fetched_at);
+
+-- Daily counts of how often each field could be read from pages fetched from Amazon
+CREATE TABLE Parser_Health
+(
+    day       DATE,
+    field     VARCHAR(64),
+    successes INTEGER NOT NULL DEFAULT 0,
+    failures  INTEGER NOT NULL DEFAULT 0,
+    Primary Key (day, field)
+);
+
+-- Raised at most once per field each day when its failure rate crosses the alert threshold
+CREATE TABLE Parser_Alerts
+(
+    day          DATE,
+    field        VARCHAR(64),
+    failure_rate REAL     NOT NULL,
+    raised_at    DATETIME NOT NULL,
+    Primary Key (day, field)
+);
+
+-- Failed logins counted for each email address and client IP, kept across restarts so the
+-- throttle cannot be reset by restarting the server
+CREATE TABLE Login_Attempts
+(
+    kind         CHAR(5),
+    key          VARCHAR(255),
+    failures     INTEGER  NOT NULL,
+    last_failure DATETIME NOT NULL,
+    Primary Key (kind, key)
+);
+
+-- Single-use tokens emailed to users to verify their address or reset their password
+CREATE TABLE Account_Tokens
+(
+    TokenID    BINARY(16),
+    sid        BINARY(16) NOT NULL,
+    purpose    CHAR(6)    NOT NULL,
+    expires_at DATETIME   NOT NULL,
+    used_at    DATETIME,
+    Primary Key (TokenID),
+    FOREIGN KEY (sid) REFERENCES Site_users (sid) ON DELETE CASCADE
+);
+
+-- Signed in sessions. Deleting a row signs the session out.
+CREATE TABLE User_Sessions
+(
+    SessionID  BINARY(16),
+    sid        BINARY(16) NOT NULL,
+    created_at DATETIME   NOT NULL,
+    last_seen  DATETIME   NOT NULL,
+    user_agent VARCHAR(512),
+    ip         VARCHAR(45),
+    Primary Key (SessionID),
+    FOREIGN KEY (sid) REFERENCES Site_users (sid) ON DELETE CASCADE
+);
+
+CREATE INDEX User_Sessions_sid ON User_Sessions (sid);
+
+-- TOTP secrets of users who have set up two-factor authentication. The secret is only used once
+-- enabled, which happens after the user enters a code from their authenticator app.
+CREATE TABLE Two_Factor
+(
+    sid            BINARY(16),
+    secret         BLOB     NOT NULL,
+    enabled        BOOLEAN  NOT NULL DEFAULT 0,
+    last_used_step INTEGER,
+    created_at     DATETIME NOT NULL,
+    Primary Key (sid),
+    FOREIGN KEY (sid) REFERENCES Site_users (sid) ON DELETE CASCADE
+);
+
+-- Hashes of the single-use codes for signing in without the authenticator app
+CREATE TABLE Recovery_Codes
+(
+    sid       BINARY(16),
+    code_hash BINARY(32),
+    used_at   DATETIME,
+    Primary Key (sid, code_hash),
+    FOREIGN KEY (sid) REFERENCES Site_users (sid) ON DELETE CASCADE
+);
@@ -0,0 +1,112 @@
+use crate::database::Connection;
+use crate::env::var_or;
+use crate::export::HistoryRow;
+use crate::session::UserId;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use sqlx::{FromRow, Sqlite};
+
+/// How long a deleted account is kept before it is removed for good. Signing in during the grace
+/// period cancels the deletion.
+#[derive(Debug, Copy, Clone)]
+pub struct DeletionPolicy {
+    pub grace_period: Duration,
+}
+
+impl DeletionPolicy {
+    pub fn from_env() -> Self {
+        DeletionPolicy {
+            grace_period: Duration::days(var_or("ACCOUNT_DELETION_GRACE_DAYS", 14)),
+        }
+    }
+
+    /// When an account whose deletion was requested at the given time is removed
+    pub fn deletes_at(&self, requested_at: DateTime<Utc>) -> DateTime<Utc> {
+        requested_at + self.grace_period
+    }
+}
+
+impl Default for DeletionPolicy {
+    fn default() -> Self {
+        DeletionPolicy {
+            grace_period: Duration::days(14),
+        }
+    }
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct AccountProfile {
+    pub email: String,
+    pub role: String,
+    pub email_verified: bool,
+    pub two_factor_enabled: bool,
+    pub deletion_requested_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct TrackedProduct {
+    pub asin: Option<String>,
+    pub name: Option<String>,
+    pub target_price: Option<f64>,
+}
+
+/// The conditions a user is subscribed to for a product
+#[derive(Debug, FromRow, Serialize)]
+pub struct Subscription {
+    pub asin: String,
+    pub conditions: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportedSession {
+    pub created_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// Everything stored about a user, as downloaded from the account page. Secrets such as the
+/// password hash and two-factor key are left out.
+#[derive(Debug, Serialize)]
+pub struct AccountExport {
+    pub exported_at: DateTime<Utc>,
+    pub profile: AccountProfile,
+    pub sessions: Vec<ExportedSession>,
+    pub tracked_products: Vec<TrackedProduct>,
+    pub subscriptions: Vec<Subscription>,
+    /// Every listing collected for the products the user has subscribed to
+    pub price_history: Vec<HistoryRow>,
+}
+
+/// Collect the data of a user for export. Returns `None` if the user does not exist.
+pub async fn export_account(
+    database: &mut Connection<Sqlite>,
+    user: UserId,
+    now: DateTime<Utc>,
+) -> sqlx::Result<Option<AccountExport>> {
+    let profile = match database.account_profile(*user).await? {
+        Some(profile) => profile,
+        None => return Ok(None),
+    };
+
+    let sessions = database
+        .user_sessions(user)
+        .await?
+        .into_iter()
+        .map(|session| ExportedSession {
+            created_at: session.created_at,
+            last_seen: session.last_seen,
+            user_agent: session.user_agent,
+            ip: session.ip,
+        })
+        .collect();
+
+    Ok(Some(AccountExport {
+        exported_at: now,
+        profile,
+        sessions,
+        tracked_products: database.tracked_products(*user).await?,
+        subscriptions: database.subscriptions(*user).await?,
+        price_history: database.user_history(user, None, None).await?,
+    }))
+}
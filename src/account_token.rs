@@ -0,0 +1,205 @@
+use crate::database::Connection;
+use crate::env::optional_var;
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use log::warn;
+use sha2::{Digest, Sha256};
+use sqlx::types::Uuid;
+use sqlx::Sqlite;
+use std::fmt::Write;
+
+/// What a token emailed to a user allows them to do. Each token can only be used for its purpose.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TokenPurpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+impl TokenPurpose {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TokenPurpose::VerifyEmail => "verify",
+            TokenPurpose::ResetPassword => "reset",
+        }
+    }
+
+    /// Password resets expire quickly since anyone reading the email can take over the account
+    pub fn lifetime(self) -> Duration {
+        match self {
+            TokenPurpose::VerifyEmail => Duration::days(2),
+            TokenPurpose::ResetPassword => Duration::hours(1),
+        }
+    }
+}
+
+/// Creates and redeems the single-use tokens sent in account emails. The database stores which
+/// user a token belongs to, when it expires and if it was used. The token sent to the user is the
+/// row's ID along with a signature of the ID, purpose and expiry, so a token cannot be guessed
+/// from IDs alone and a row changed in the database no longer matches its token.
+pub struct AccountTokens {
+    key: [u8; 32],
+}
+
+impl AccountTokens {
+    pub fn new(secret: &str) -> Self {
+        AccountTokens {
+            key: Sha256::digest(secret.as_bytes()).into(),
+        }
+    }
+
+    /// Uses the `ACCOUNT_TOKEN_SECRET` key. Without it a random key is used, so tokens sent
+    /// before a restart can no longer be redeemed.
+    pub fn from_env() -> Self {
+        match optional_var("ACCOUNT_TOKEN_SECRET") {
+            Some(secret) => AccountTokens::new(&secret),
+            None => {
+                warn!("ACCOUNT_TOKEN_SECRET is not set, so emailed links will stop working on restart");
+                AccountTokens::new(&format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple()))
+            }
+        }
+    }
+
+    /// Create a token for the user. Unused tokens for the same purpose are replaced, so only the
+    /// most recent email works.
+    pub async fn issue(
+        &self,
+        database: &mut Connection<Sqlite>,
+        user: Uuid,
+        purpose: TokenPurpose,
+        now: DateTime<Utc>,
+    ) -> sqlx::Result<String> {
+        let id = Uuid::new_v4();
+        let expires_at = now + purpose.lifetime();
+
+        database.add_account_token(id, user, purpose.as_str(), expires_at).await?;
+        Ok(self.sign(id, purpose, expires_at))
+    }
+
+    /// Use up a token, returning the user it was issued to. Tokens which are invalid, expired or
+    /// already used are rejected.
+    pub async fn redeem(
+        &self,
+        database: &mut Connection<Sqlite>,
+        token: &str,
+        purpose: TokenPurpose,
+        now: DateTime<Utc>,
+    ) -> sqlx::Result<Option<Uuid>> {
+        let id = match parse_token(token) {
+            Some((id, _)) => id,
+            None => return Ok(None),
+        };
+
+        let (user, expires_at) = match database.account_token(id, purpose.as_str()).await? {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        if !self.verify(token, purpose, expires_at) || now >= expires_at {
+            return Ok(None);
+        }
+
+        // Only one request can mark the token as used
+        if !database.use_account_token(id, now).await? {
+            return Ok(None);
+        }
+
+        Ok(Some(user))
+    }
+
+    /// Check a token is valid without using it, so the form it links to can be shown
+    pub async fn is_valid(
+        &self,
+        database: &mut Connection<Sqlite>,
+        token: &str,
+        purpose: TokenPurpose,
+        now: DateTime<Utc>,
+    ) -> sqlx::Result<bool> {
+        let id = match parse_token(token) {
+            Some((id, _)) => id,
+            None => return Ok(false),
+        };
+
+        Ok(match database.account_token(id, purpose.as_str()).await? {
+            Some((_, expires_at)) => self.verify(token, purpose, expires_at) && now < expires_at,
+            None => false,
+        })
+    }
+
+    fn sign(&self, id: Uuid, purpose: TokenPurpose, expires_at: DateTime<Utc>) -> String {
+        format!("{}.{}", id.simple(), self.signature(id, purpose, expires_at))
+    }
+
+    /// The signature is compared in constant time, so the time taken does not reveal how much of a
+    /// guessed signature was correct
+    fn verify(&self, token: &str, purpose: TokenPurpose, expires_at: DateTime<Utc>) -> bool {
+        match parse_token(token) {
+            Some((id, signature)) => match from_hex(signature) {
+                Some(signature) => self.mac(id, purpose, expires_at).verify_slice(&signature).is_ok(),
+                None => false,
+            },
+            None => false,
+        }
+    }
+
+    fn signature(&self, id: Uuid, purpose: TokenPurpose, expires_at: DateTime<Utc>) -> String {
+        to_hex(&self.mac(id, purpose, expires_at).finalize().into_bytes())
+    }
+
+    fn mac(&self, id: Uuid, purpose: TokenPurpose, expires_at: DateTime<Utc>) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(format!("{}:{}:{}", id.simple(), purpose.as_str(), expires_at.timestamp()).as_bytes());
+        mac
+    }
+}
+
+/// Split a token into its ID and signature
+fn parse_token(token: &str) -> Option<(Uuid, &str)> {
+    let (id, signature) = token.split_once('.')?;
+    Some((Uuid::parse_str(id).ok()?, signature))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    let digit = |byte: u8| (byte as char).to_digit(16);
+
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match *pair {
+            [high, low] => Some((digit(high)? * 16 + digit(low)?) as u8),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    pub fn signed_tokens() {
+        let tokens = AccountTokens::new("secret");
+        let id = Uuid::new_v4();
+        let expires_at = Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap();
+        let token = tokens.sign(id, TokenPurpose::ResetPassword, expires_at);
+
+        assert_eq!(parse_token(&token).map(|(parsed, _)| parsed), Some(id));
+        assert!(tokens.verify(&token, TokenPurpose::ResetPassword, expires_at));
+
+        // The signature covers the purpose and expiry, and depends on the key
+        assert!(!tokens.verify(&token, TokenPurpose::VerifyEmail, expires_at));
+        assert!(!tokens.verify(&token, TokenPurpose::ResetPassword, expires_at + Duration::hours(1)));
+        assert!(!AccountTokens::new("other").verify(&token, TokenPurpose::ResetPassword, expires_at));
+
+        let forged = format!("{}.{}", id.simple(), "0".repeat(64));
+        assert!(!tokens.verify(&forged, TokenPurpose::ResetPassword, expires_at));
+        assert!(!tokens.verify(&format!("{}.zz", id.simple()), TokenPurpose::ResetPassword, expires_at));
+        assert_eq!(parse_token("not-a-token"), None);
+    }
+}
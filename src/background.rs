@@ -0,0 +1,152 @@
+use crate::account::DeletionPolicy;
+use crate::env::{optional_var, var_or};
+use crate::scraper::health::{AlertPolicy, ParserHealth};
+use crate::scraper::rules::RulesWatcher;
+use crate::scraper::AmazonApi;
+use crate::database::Connection;
+use crate::retention::RetentionPolicy;
+use crate::session::SessionPolicy;
+use chrono::Utc;
+use log::{error, info, warn};
+use rocket::fairing::AdHoc;
+use sqlx::{Pool, Sqlite};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Starts the periodic jobs which run for as long as the server is up
+pub fn fairing() -> AdHoc {
+    AdHoc::on_liftoff("Background jobs", |rocket| {
+        Box::pin(async move {
+            let pool = match rocket.state::<Pool<Sqlite>>() {
+                Some(pool) => pool.clone(),
+                None => {
+                    error!("Unable to start background jobs without a database pool");
+                    return;
+                }
+            };
+
+            let policy = rocket.state::<SessionPolicy>().copied().unwrap_or_default();
+            tokio::spawn(purge_expired_sessions(pool.clone(), policy));
+
+            let policy = rocket.state::<DeletionPolicy>().copied().unwrap_or_default();
+            tokio::spawn(purge_deleted_accounts(pool.clone(), policy));
+
+            let policy = rocket.state::<RetentionPolicy>().copied().unwrap_or_default();
+            tokio::spawn(compact_price_history(pool.clone(), policy));
+
+            if let Some(amazon_api) = rocket.state::<AmazonApi>() {
+                tokio::spawn(flush_parser_health(pool, amazon_api.health().clone()));
+            }
+
+            if let Some(path) = optional_var("EXTRACTION_RULES") {
+                tokio::spawn(watch_extraction_rules(path));
+            }
+        })
+    })
+}
+
+/// Periodically write parser health counters to the database and check them for alerts
+async fn flush_parser_health(pool: Pool<Sqlite>, health: Arc<ParserHealth>) {
+    let policy = AlertPolicy {
+        threshold: var_or("PARSER_ALERT_THRESHOLD", 0.25),
+        min_samples: var_or("PARSER_ALERT_MIN_SAMPLES", 20),
+    };
+
+    let period = Duration::from_secs(var_or("PARSER_HEALTH_FLUSH_SECS", 60));
+    let mut interval = tokio::time::interval(period);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = health.flush(&pool, policy).await {
+            warn!("Failed to write parser health counters: {}", e);
+        }
+    }
+}
+
+/// Sessions are removed when they are next used after expiring, but sessions which are never used
+/// again would otherwise stay in the database
+async fn purge_expired_sessions(pool: Pool<Sqlite>, policy: SessionPolicy) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+
+    loop {
+        interval.tick().await;
+
+        let purged = match pool.acquire().await {
+            Ok(connection) => Connection::from(connection).delete_expired_sessions(&policy, Utc::now()).await,
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = purged {
+            warn!("Failed to remove expired sessions: {}", e);
+        }
+    }
+}
+
+/// Permanently remove accounts once the grace period after their deletion was requested has passed
+async fn purge_deleted_accounts(pool: Pool<Sqlite>, policy: DeletionPolicy) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+
+    loop {
+        interval.tick().await;
+
+        let purged = match pool.acquire().await {
+            Ok(connection) => {
+                let requested_before = Utc::now() - policy.grace_period;
+                Connection::from(connection).delete_requested_accounts(requested_before).await
+            }
+            Err(e) => Err(e),
+        };
+
+        match purged {
+            Ok(0) => {}
+            Ok(count) => info!("Permanently deleted {} accounts", count),
+            Err(e) => warn!("Failed to remove deleted accounts: {}", e),
+        }
+    }
+}
+
+/// Compact listings and daily prices once they are older than the retention policy keeps them for
+async fn compact_price_history(pool: Pool<Sqlite>, policy: RetentionPolicy) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+
+    loop {
+        interval.tick().await;
+
+        let mut database = match pool.acquire().await {
+            Ok(connection) => Connection::from(connection),
+            Err(e) => {
+                warn!("Failed to compact price history: {}", e);
+                continue;
+            }
+        };
+
+        let now = Utc::now();
+        match database.compact_listings(policy.daily_cutoff(now)).await {
+            Ok(0) => {}
+            Ok(count) => info!("Compacted {} listings into daily prices", count),
+            Err(e) => warn!("Failed to compact listings: {}", e),
+        }
+
+        match database.compact_daily_prices(policy.weekly_cutoff(now)).await {
+            Ok(0) => {}
+            Ok(count) => info!("Compacted {} daily prices into weekly prices", count),
+            Err(e) => warn!("Failed to compact daily prices: {}", e),
+        }
+    }
+}
+
+/// Reload the extraction rules whenever the file is modified so selectors can be fixed without a
+/// restart
+async fn watch_extraction_rules(path: String) {
+    let period = Duration::from_secs(var_or("EXTRACTION_RULES_POLL_SECS", 30));
+    let mut interval = tokio::time::interval(period);
+    let mut watcher = RulesWatcher::new(path);
+
+    loop {
+        interval.tick().await;
+
+        // Errors are logged by the watcher and the previous rules are kept
+        let _ = watcher.reload_if_changed();
+    }
+}
@@ -0,0 +1,209 @@
+use crate::stats::PriceStats;
+use serde::Serialize;
+use sqlx::FromRow;
+use std::collections::HashSet;
+
+/// A department with the department it is listed under. IDs are written in hex, as in URLs.
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct DepartmentNode {
+    pub id: String,
+    pub name: String,
+    /// Amazon's browse node ID, unknown for departments added before it was stored
+    pub node: Option<i64>,
+    pub parent: Option<String>,
+}
+
+/// A tracked product with the department and manufacturer it is filed under
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct CatalogProduct {
+    pub asin: String,
+    pub name: Option<String>,
+    pub department: String,
+    pub manufacturer_id: String,
+    pub manufacturer: Option<String>,
+}
+
+/// How good the current price of a product is, judged by its cheapest condition
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub struct Deal {
+    pub current: f64,
+    /// Percentage the current price is below the median price, negative when it is above
+    pub discount: f64,
+    pub at_all_time_low: bool,
+}
+
+impl Deal {
+    /// The deal on the condition with the lowest current price, if any prices were collected
+    pub fn from_stats(stats: &[PriceStats]) -> Option<Self> {
+        let cheapest = stats.iter().min_by(|a, b| a.current.total_cmp(&b.current))?;
+        let discount = if cheapest.median > 0.0 {
+            100.0 * (cheapest.median - cheapest.current) / cheapest.median
+        } else {
+            0.0
+        };
+
+        Some(Deal {
+            current: cheapest.current,
+            discount,
+            at_all_time_low: cheapest.current <= cheapest.all_time_low,
+        })
+    }
+}
+
+/// Deals across the products of a department or manufacturer
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct DealSummary {
+    pub products: usize,
+    /// Products with at least one price collected
+    pub priced: usize,
+    pub average_discount: Option<f64>,
+    pub at_all_time_low: usize,
+}
+
+impl DealSummary {
+    /// Summarize the deal of each product, which is `None` for products without prices
+    pub fn new<'a>(deals: impl IntoIterator<Item = Option<&'a Deal>>) -> Self {
+        let mut summary = DealSummary::default();
+        let mut total_discount = 0.0;
+        for deal in deals {
+            summary.products += 1;
+            if let Some(deal) = deal {
+                summary.priced += 1;
+                total_discount += deal.discount;
+                summary.at_all_time_low += usize::from(deal.at_all_time_low);
+            }
+        }
+
+        if summary.priced > 0 {
+            summary.average_discount = Some(total_discount / summary.priced as f64);
+        }
+
+        summary
+    }
+}
+
+/// The departments listed directly under a department, or the top level departments, by name
+pub fn children<'a>(departments: &'a [DepartmentNode], parent: Option<&str>) -> Vec<&'a DepartmentNode> {
+    let mut children: Vec<_> = departments
+        .iter()
+        .filter(|department| department.parent.as_deref() == parent)
+        .collect();
+
+    children.sort_by(|a, b| a.name.cmp(&b.name));
+    children
+}
+
+/// The department and every department below it
+pub fn subtree<'a>(departments: &'a [DepartmentNode], id: &'a str) -> HashSet<&'a str> {
+    let mut found = HashSet::from([id]);
+    let mut pending = vec![id];
+    while let Some(parent) = pending.pop() {
+        for department in departments {
+            if department.parent.as_deref() == Some(parent) && found.insert(&department.id) {
+                pending.push(&department.id);
+            }
+        }
+    }
+
+    found
+}
+
+/// The path from the top level down to the department, which is empty for unknown departments
+pub fn breadcrumb<'a>(departments: &'a [DepartmentNode], id: &str) -> Vec<&'a DepartmentNode> {
+    let mut path: Vec<&DepartmentNode> = Vec::new();
+    let mut next = Some(id);
+    while let Some(id) = next {
+        // A loop in the parents would otherwise never end
+        if path.iter().any(|department| department.id == id) {
+            break;
+        }
+
+        match departments.iter().find(|department| department.id == id) {
+            Some(department) => {
+                path.push(department);
+                next = department.parent.as_deref();
+            }
+            None => break,
+        }
+    }
+
+    path.reverse();
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn department(id: &str, name: &str, parent: Option<&str>) -> DepartmentNode {
+        DepartmentNode {
+            id: id.to_string(),
+            name: name.to_string(),
+            node: None,
+            parent: parent.map(str::to_string),
+        }
+    }
+
+    fn stats(condition: &str, current: f64, median: f64, all_time_low: f64) -> PriceStats {
+        PriceStats {
+            asin: "B07VGRJDFY".to_string(),
+            condition: condition.to_string(),
+            current,
+            updated_at: Utc::now(),
+            all_time_low,
+            all_time_high: median * 2.0,
+            average_30_days: None,
+            average_90_days: None,
+            average_365_days: None,
+            median,
+            percentile_rank: 0.0,
+            volatility: 0.0,
+            days_since_low: 0,
+            samples: 10,
+        }
+    }
+
+    #[test]
+    pub fn department_tree() {
+        let departments = vec![
+            department("a", "Electronics", None),
+            department("b", "Computers", Some("a")),
+            department("c", "Accessories", Some("b")),
+            department("d", "Cameras", Some("a")),
+            department("e", "Books", None),
+        ];
+
+        let names = |list: Vec<&DepartmentNode>| list.iter().map(|department| department.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(children(&departments, None)), vec!["Books", "Electronics"]);
+        assert_eq!(names(children(&departments, Some("a"))), vec!["Cameras", "Computers"]);
+        assert_eq!(names(breadcrumb(&departments, "c")), vec!["Electronics", "Computers", "Accessories"]);
+        assert!(breadcrumb(&departments, "z").is_empty());
+
+        assert_eq!(subtree(&departments, "a"), HashSet::from(["a", "b", "c", "d"]));
+        assert_eq!(subtree(&departments, "e"), HashSet::from(["e"]));
+
+        // Parents which loop back on themselves
+        let looped = vec![department("a", "A", Some("b")), department("b", "B", Some("a"))];
+        assert_eq!(breadcrumb(&looped, "a").len(), 2);
+        assert_eq!(subtree(&looped, "a").len(), 2);
+    }
+
+    #[test]
+    pub fn deal_summary() {
+        let deal = Deal::from_stats(&[stats("New", 80.0, 100.0, 75.0), stats("UsedGood", 60.0, 80.0, 60.0)]).unwrap();
+        assert_eq!(deal.current, 60.0);
+        assert_eq!(deal.discount, 25.0);
+        assert!(deal.at_all_time_low);
+        assert_eq!(Deal::from_stats(&[]), None);
+
+        let other = Deal::from_stats(&[stats("New", 110.0, 100.0, 90.0)]).unwrap();
+        assert_eq!(other.discount, -10.0);
+        assert!(!other.at_all_time_low);
+
+        let summary = DealSummary::new([Some(&deal), Some(&other), None]);
+        assert_eq!((summary.products, summary.priced, summary.at_all_time_low), (3, 2, 1));
+        assert_eq!(summary.average_discount, Some(7.5));
+        assert_eq!(DealSummary::new([]).average_discount, None);
+    }
+}
@@ -0,0 +1,420 @@
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use sqlx::FromRow;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Colors of the series, reused once they run out
+const PALETTE: [&str; 8] = ["#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd", "#8c564b", "#e377c2", "#7f7f7f"];
+
+/// Candidate spacing of the date axis ticks, in days
+const DATE_STEPS: [i64; 9] = [1, 2, 7, 14, 30, 61, 91, 182, 365];
+
+/// Most ticks drawn on either axis
+const MAX_TICKS: i64 = 8;
+
+/// Space around the plot of a full chart for the axes and legend
+const MARGIN_LEFT: f64 = 60.0;
+const MARGIN_RIGHT: f64 = 20.0;
+const MARGIN_TOP: f64 = 30.0;
+const MARGIN_BOTTOM: f64 = 30.0;
+
+/// Prices and the times they were collected
+type Points = Vec<(DateTime<Utc>, f64)>;
+
+/// A listing collected for a product, with columns named to match the chart query
+#[derive(Debug, Clone, FromRow)]
+pub struct ChartPoint {
+    pub condition: Option<String>,
+    pub seller: Option<String>,
+    pub datetime: DateTime<Utc>,
+    pub price: f64,
+}
+
+/// A line drawn on a chart
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub label: String,
+    pub points: Points,
+}
+
+/// Split the listings into a series for each condition and seller, ordered by condition and then
+/// seller. Points without a seller, such as compacted prices, are a series for the condition.
+pub fn series_by_listing(points: &[ChartPoint]) -> Vec<Series> {
+    let mut groups: BTreeMap<(&str, Option<&str>), Points> = BTreeMap::new();
+    for point in points {
+        let condition = point.condition.as_deref().unwrap_or("Unknown");
+        groups
+            .entry((condition, point.seller.as_deref()))
+            .or_default()
+            .push((point.datetime, point.price));
+    }
+
+    groups
+        .into_iter()
+        .map(|((condition, seller), mut points)| {
+            points.sort_by_key(|&(datetime, _)| datetime);
+            Series {
+                label: match seller {
+                    Some(seller) => format!("{} from {}", condition, seller),
+                    None => condition.to_string(),
+                },
+                points,
+            }
+        })
+        .collect()
+}
+
+/// The lowest price collected at each time, oldest first
+pub fn lowest_prices(points: &[ChartPoint]) -> Points {
+    let mut lowest: BTreeMap<DateTime<Utc>, f64> = BTreeMap::new();
+    for point in points {
+        lowest
+            .entry(point.datetime)
+            .and_modify(|price| *price = price.min(point.price))
+            .or_insert(point.price);
+    }
+
+    lowest.into_iter().collect()
+}
+
+/// Maps times and prices onto an area of the chart
+struct Scale {
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    low: f64,
+    high: f64,
+    left: f64,
+    top: f64,
+    width: f64,
+    height: f64,
+}
+
+impl Scale {
+    fn x(&self, datetime: DateTime<Utc>) -> f64 {
+        let span = (self.end - self.start).num_seconds() as f64;
+        self.left + self.width * (datetime - self.start).num_seconds() as f64 / span
+    }
+
+    fn y(&self, price: f64) -> f64 {
+        self.top + self.height * (self.high - price) / (self.high - self.low)
+    }
+
+    fn polyline(&self, points: &[(DateTime<Utc>, f64)]) -> String {
+        points
+            .iter()
+            .map(|&(datetime, price)| format!("{:.1},{:.1}", self.x(datetime), self.y(price)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// The first and last time of the points, widened by a day on each side when they are equal
+fn time_range<'a>(points: impl Iterator<Item = &'a (DateTime<Utc>, f64)>) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let (start, end) = points.fold(None, |range, &(datetime, _)| match range {
+        None => Some((datetime, datetime)),
+        Some((start, end)) => Some((datetime.min(start), datetime.max(end))),
+    })?;
+
+    if start == end {
+        Some((start - Duration::days(1), end + Duration::days(1)))
+    } else {
+        Some((start, end))
+    }
+}
+
+/// A round step between price ticks which gives at most [MAX_TICKS] ticks over the range
+fn price_step(range: f64) -> f64 {
+    let raw = range / (MAX_TICKS - 1) as f64;
+    let magnitude = 10f64.powf(raw.log10().floor());
+    [1.0, 2.0, 5.0, 10.0]
+        .into_iter()
+        .map(|factor| factor * magnitude)
+        .find(|&step| step >= raw)
+        .unwrap_or(10.0 * magnitude)
+}
+
+/// The lowest and highest price of the points rounded out to whole steps, along with the step
+fn price_range<'a>(points: impl Iterator<Item = &'a (DateTime<Utc>, f64)>) -> (f64, f64, f64) {
+    let (low, high) = points.fold((f64::INFINITY, f64::NEG_INFINITY), |(low, high), &(_, price)| {
+        (low.min(price), high.max(price))
+    });
+
+    // A flat line is drawn in the middle of the chart
+    let (low, high) = if high - low < 0.01 { ((low - 1.0).max(0.0), high + 1.0) } else { (low, high) };
+    let step = price_step(high - low);
+    ((low / step).floor() * step, (high / step).ceil() * step, step)
+}
+
+/// Midnight of each day with a tick on the date axis
+fn date_ticks(start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<DateTime<Utc>> {
+    let days = (end - start).num_days();
+    let step = DATE_STEPS
+        .into_iter()
+        .find(|&step| days / step < MAX_TICKS)
+        .unwrap_or_else(|| days / MAX_TICKS + 1);
+
+    let midnight = start.duration_trunc(Duration::days(1)).unwrap_or(start);
+    let first = if midnight < start { midnight + Duration::days(1) } else { midnight };
+
+    (0..)
+        .map(|i| first + Duration::days(i * step))
+        .take_while(|&tick| tick <= end)
+        .collect()
+}
+
+fn format_price(price: f64, step: f64) -> String {
+    if step < 1.0 {
+        format!("${:.2}", price)
+    } else {
+        format!("${:.0}", price)
+    }
+}
+
+/// Escape text placed inside an SVG element or attribute
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn open_svg(svg: &mut String, width: u32, height: u32) {
+    let _ = write!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{0}" height="{1}" viewBox="0 0 {0} {1}" font-family="sans-serif" font-size="11">"#,
+        width, height
+    );
+}
+
+/// Label the lowest or highest point of the chart with its price
+fn annotate(svg: &mut String, scale: &Scale, (datetime, price): (DateTime<Utc>, f64), label: &str, above: bool) {
+    let (x, y) = (scale.x(datetime), scale.y(price));
+    let anchor = if x > scale.left + scale.width / 2.0 { "end" } else { "start" };
+    let offset = if above { -8.0 } else { 16.0 };
+
+    let _ = write!(
+        svg,
+        r#"<circle cx="{:.1}" cy="{:.1}" r="4" fill="none" stroke="black"/><text class="annotation" x="{:.1}" y="{:.1}" text-anchor="{}">{} ${:.2}</text>"#,
+        x, y, x, y + offset, anchor, label, price
+    );
+}
+
+/// Draw a line chart of the series with price and date axes, a legend and the lowest and highest
+/// price marked
+pub fn line_chart(series: &[Series], width: u32, height: u32) -> String {
+    let mut svg = String::new();
+    open_svg(&mut svg, width, height);
+
+    let points = || series.iter().flat_map(|series| series.points.iter());
+    let (start, end) = match time_range(points()) {
+        Some(range) => range,
+        None => {
+            let _ = write!(
+                svg,
+                r#"<text x="{}" y="{}" text-anchor="middle">No prices have been collected yet</text></svg>"#,
+                width / 2,
+                height / 2
+            );
+            return svg;
+        }
+    };
+
+    let (low, high, step) = price_range(points());
+    let scale = Scale {
+        start,
+        end,
+        low,
+        high,
+        left: MARGIN_LEFT,
+        top: MARGIN_TOP,
+        width: (width as f64 - MARGIN_LEFT - MARGIN_RIGHT).max(1.0),
+        height: (height as f64 - MARGIN_TOP - MARGIN_BOTTOM).max(1.0),
+    };
+    let (left, right) = (scale.left, scale.left + scale.width);
+    let (top, bottom) = (scale.top, scale.top + scale.height);
+
+    // Price axis with a grid line at each tick
+    let mut price = low;
+    while price <= high + step / 2.0 {
+        let y = scale.y(price);
+        let _ = write!(
+            svg,
+            r##"<line x1="{:.1}" y1="{:.1}" x2="{:.1}" y2="{:.1}" stroke="#e0e0e0"/><text x="{:.1}" y="{:.1}" text-anchor="end">{}</text>"##,
+            left, y, right, y, left - 6.0, y + 4.0, format_price(price, step)
+        );
+        price += step;
+    }
+
+    // Date axis
+    let _ = write!(
+        svg,
+        r#"<line x1="{:.1}" y1="{:.1}" x2="{:.1}" y2="{:.1}" stroke="black"/>"#,
+        left, bottom, right, bottom
+    );
+    for tick in date_ticks(start, end) {
+        let x = scale.x(tick);
+        let _ = write!(
+            svg,
+            r#"<line x1="{:.1}" y1="{:.1}" x2="{:.1}" y2="{:.1}" stroke="black"/><text x="{:.1}" y="{:.1}" text-anchor="middle">{}</text>"#,
+            x, bottom, x, bottom + 4.0, x, bottom + 18.0, tick.format("%Y-%m-%d")
+        );
+    }
+
+    // Lines and legend
+    let mut legend_x = left;
+    for (series, color) in series.iter().zip(PALETTE.iter().cycle()) {
+        if series.points.is_empty() {
+            continue;
+        }
+
+        let _ = write!(
+            svg,
+            r#"<polyline fill="none" stroke="{}" stroke-width="2" points="{}"/>"#,
+            color, scale.polyline(&series.points)
+        );
+        if let [(datetime, price)] = series.points[..] {
+            let _ = write!(svg, r#"<circle cx="{:.1}" cy="{:.1}" r="3" fill="{}"/>"#, scale.x(datetime), scale.y(price), color);
+        }
+
+        let _ = write!(
+            svg,
+            r#"<rect x="{:.1}" y="{:.1}" width="10" height="10" fill="{}"/><text x="{:.1}" y="{:.1}">{}</text>"#,
+            legend_x, top - 22.0, color, legend_x + 14.0, top - 13.0, escape(&series.label)
+        );
+        legend_x += 34.0 + 6.5 * series.label.chars().count() as f64;
+    }
+
+    let lowest = points().copied().min_by(|a, b| a.1.total_cmp(&b.1));
+    let highest = points().copied().max_by(|a, b| a.1.total_cmp(&b.1));
+    if let (Some(lowest), Some(highest)) = (lowest, highest) {
+        annotate(&mut svg, &scale, highest, "High", true);
+        if lowest.1 < highest.1 {
+            annotate(&mut svg, &scale, lowest, "Low", false);
+        }
+    }
+
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Draw a small line of the prices without axes, ending in a dot at the latest price
+pub fn sparkline(points: &[(DateTime<Utc>, f64)], width: u32, height: u32) -> String {
+    let mut svg = String::new();
+    open_svg(&mut svg, width, height);
+
+    if let Some((start, end)) = time_range(points.iter()) {
+        let (low, high) = points.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(low, high), &(_, price)| {
+            (low.min(price), high.max(price))
+        });
+        let (low, high) = if high - low < 0.01 { (low - 1.0, high + 1.0) } else { (low, high) };
+        let scale = Scale {
+            start,
+            end,
+            low,
+            high,
+            left: 3.0,
+            top: 3.0,
+            width: (width as f64 - 6.0).max(1.0),
+            height: (height as f64 - 6.0).max(1.0),
+        };
+
+        let _ = write!(
+            svg,
+            r##"<polyline fill="none" stroke="#1f77b4" stroke-width="1.5" points="{}"/>"##,
+            scale.polyline(points)
+        );
+        if let Some(&(datetime, price)) = points.last() {
+            let _ = write!(svg, r##"<circle cx="{:.1}" cy="{:.1}" r="2" fill="#1f77b4"/>"##, scale.x(datetime), scale.y(price));
+        }
+    }
+
+    svg.push_str("</svg>");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn day(day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 3, day, 12, 0, 0).unwrap()
+    }
+
+    fn point(condition: &str, seller: &str, day_of_month: u32, price: f64) -> ChartPoint {
+        ChartPoint {
+            condition: Some(condition.to_string()),
+            seller: Some(seller.to_string()),
+            datetime: day(day_of_month),
+            price,
+        }
+    }
+
+    #[test]
+    pub fn series_per_condition_and_seller() {
+        let points = vec![
+            point("New", "Amazon.com", 2, 20.0),
+            point("UsedGood", "Resale", 1, 12.0),
+            point("New", "Amazon.com", 1, 25.0),
+            point("New", "Gadgets", 1, 22.0),
+        ];
+
+        let series = series_by_listing(&points);
+        let labels: Vec<_> = series.iter().map(|series| series.label.as_str()).collect();
+        assert_eq!(labels, vec!["New from Amazon.com", "New from Gadgets", "UsedGood from Resale"]);
+        assert_eq!(series[0].points, vec![(day(1), 25.0), (day(2), 20.0)]);
+
+        assert_eq!(lowest_prices(&points), vec![(day(1), 12.0), (day(2), 20.0)]);
+
+        // Compacted prices have no seller
+        let compacted = ChartPoint {
+            seller: None,
+            ..point("New", "", 3, 18.0)
+        };
+        assert_eq!(series_by_listing(&[compacted])[0].label, "New");
+    }
+
+    #[test]
+    pub fn axis_ticks() {
+        assert_eq!(price_step(35.0), 5.0);
+        assert_eq!(price_step(0.6), 0.1);
+        assert_eq!(price_range([(day(1), 12.3), (day(2), 47.9)].iter()), (10.0, 50.0, 10.0));
+
+        let ticks = date_ticks(day(1), day(20));
+        assert_eq!(ticks.len(), 3);
+        assert_eq!(ticks[0], Utc.with_ymd_and_hms(2023, 3, 2, 0, 0, 0).unwrap());
+        assert_eq!(ticks[1] - ticks[0], Duration::days(7));
+    }
+
+    #[test]
+    pub fn chart_markup() {
+        let series = series_by_listing(&[
+            point("New", "Tom & Jerry's", 1, 25.0),
+            point("New", "Tom & Jerry's", 5, 20.0),
+            point("New", "Tom & Jerry's", 9, 30.0),
+        ]);
+
+        let svg = line_chart(&series, 800, 400);
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""));
+        assert!(svg.ends_with("</svg>"));
+        assert!(svg.contains("New from Tom &amp; Jerry&#39;s"));
+        assert!(svg.contains(">High $30.00</text>"));
+        assert!(svg.contains(">Low $20.00</text>"));
+        assert!(svg.contains(">2023-03-02</text>"));
+        assert_eq!(svg.matches("<polyline").count(), 1);
+
+        assert!(line_chart(&[], 800, 400).contains("No prices have been collected yet"));
+    }
+
+    #[test]
+    pub fn sparkline_markup() {
+        let svg = sparkline(&[(day(1), 10.0), (day(2), 20.0)], 100, 20);
+        assert!(svg.contains(r#"points="3.0,17.0 97.0,3.0""#));
+        assert!(svg.contains(r#"<circle cx="97.0" cy="3.0""#));
+
+        // A single price is drawn as a flat dot
+        assert!(sparkline(&[(day(1), 10.0)], 100, 20).contains("<circle"));
+        assert!(!sparkline(&[], 100, 20).contains("<polyline"));
+    }
+}
@@ -0,0 +1,21 @@
+use chrono::{DateTime, Utc};
+use std::sync::{Arc, Mutex};
+
+/// The time used by routes which need to be tested at a known time, such as checking one-time
+/// codes. Follows the system clock unless it has been fixed.
+#[derive(Clone, Default)]
+pub struct Clock {
+    fixed: Arc<Mutex<Option<DateTime<Utc>>>>,
+}
+
+impl Clock {
+    pub fn now(&self) -> DateTime<Utc> {
+        self.fixed.lock().unwrap().unwrap_or_else(Utc::now)
+    }
+
+    /// Stop the clock at the given time
+    #[cfg(test)]
+    pub fn set(&self, time: DateTime<Utc>) {
+        *self.fixed.lock().unwrap() = Some(time);
+    }
+}
@@ -0,0 +1,101 @@
+use crate::chart::{self, ChartPoint, Series};
+use crate::scraper::identifier::ProductIdentifier;
+use serde::Serialize;
+use sqlx::FromRow;
+
+/// Most products shown on the compare page, one for each color of the chart
+pub const MAX_COMPARED: usize = 8;
+
+/// Longest product name shown in the chart legend
+const LABEL_LENGTH: usize = 32;
+
+/// The details of a product shown side by side with others
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct ComparedProduct {
+    pub asin: String,
+    pub name: Option<String>,
+    pub manufacturer: Option<String>,
+    pub department: Option<String>,
+    /// Average rating of the product's reviews out of 5 stars
+    pub rating: Option<f64>,
+}
+
+/// Read the products to compare, given as ASINs, ISBNs or product URLs. Repeated products are only
+/// compared once.
+pub fn parse_products(inputs: &[&str]) -> Result<Vec<String>, String> {
+    let mut asins: Vec<String> = Vec::new();
+    for input in inputs.iter().flat_map(|input| input.split(',')).map(str::trim) {
+        if input.is_empty() {
+            continue;
+        }
+
+        let identifier = ProductIdentifier::parse(input).map_err(|e| format!("Unable to compare {}: {}", input, e))?;
+        if !asins.contains(&identifier.asin) {
+            asins.push(identifier.asin);
+        }
+    }
+
+    if asins.len() > MAX_COMPARED {
+        return Err(format!("At most {} products can be compared at once", MAX_COMPARED));
+    }
+
+    Ok(asins)
+}
+
+/// Put the products found in the order they were asked for
+pub fn in_order(asins: &[String], found: Vec<ComparedProduct>) -> Vec<ComparedProduct> {
+    asins
+        .iter()
+        .filter_map(|asin| found.iter().find(|product| &product.asin == asin).cloned())
+        .collect()
+}
+
+/// Name of the product in the chart legend, shortened when it is long
+fn label(asin: &str, name: Option<&str>) -> String {
+    match name {
+        Some(name) if name.chars().count() > LABEL_LENGTH => {
+            format!("{}…", name.chars().take(LABEL_LENGTH).collect::<String>().trim_end())
+        }
+        Some(name) => name.to_string(),
+        None => asin.to_string(),
+    }
+}
+
+/// A line for each product with the lowest price collected at each refresh
+pub fn overlay_series(products: &[(ComparedProduct, Vec<ChartPoint>)]) -> Vec<Series> {
+    products
+        .iter()
+        .map(|(product, points)| Series {
+            label: label(&product.asin, product.name.as_deref()),
+            points: chart::lowest_prices(points),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn compared_products() {
+        let asins = parse_products(&["B07VGRJDFY", "https://www.amazon.com/dp/B08N5WRWNW, b07vgrjdfy", ""]).unwrap();
+        assert_eq!(asins, vec!["B07VGRJDFY", "B08N5WRWNW"]);
+
+        assert!(parse_products(&["not a product"]).is_err());
+        assert!(parse_products(&[]).unwrap().is_empty());
+
+        let too_many: Vec<String> = (0..=MAX_COMPARED).map(|i| format!("B00000000{}", i)).collect();
+        let too_many: Vec<&str> = too_many.iter().map(String::as_str).collect();
+        assert!(parse_products(&too_many).is_err());
+    }
+
+    #[test]
+    pub fn legend_labels() {
+        assert_eq!(label("B07VGRJDFY", None), "B07VGRJDFY");
+        assert_eq!(label("B07VGRJDFY", Some("Monitor")), "Monitor");
+        assert_eq!(
+            label("B07VGRJDFY", Some("Dell UltraSharp 27 4K USB-C Hub Monitor U2723QE")),
+            "Dell UltraSharp 27 4K USB-C Hub…"
+        );
+    }
+}
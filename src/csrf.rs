@@ -0,0 +1,173 @@
+use crate::session::Session;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{CookieJar, Method, RawStr, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Data, Request};
+use serde::Serialize;
+use std::convert::Infallible;
+
+/// Name of the form field holding the token
+pub const CSRF_FIELD: &str = "csrf_token";
+
+/// Header holding the token for requests which are not sent by a form
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// Like Rocket's `_method` field, the token is read from the start of the body without consuming
+/// it. Forms must place the token before any large fields to stay within this limit.
+const PEEK_BYTES: usize = 512;
+
+/// The token submitted with a state-changing request, if any
+#[derive(Default)]
+struct SubmittedToken(Option<String>);
+
+/// Reads the token submitted with state-changing requests. Request guards can not read the body
+/// without consuming it, so this is done before the route is chosen.
+pub struct CsrfFairing;
+
+#[rocket::async_trait]
+impl Fairing for CsrfFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "CSRF tokens",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, data: &mut Data<'_>) {
+        if matches!(request.method(), Method::Get | Method::Head | Method::Options) {
+            return;
+        }
+
+        let submitted = submitted_token(request, data).await;
+        request.local_cache(|| SubmittedToken(submitted));
+    }
+}
+
+/// The session's token, for pages with forms to pass to their template as `csrf_token`. The
+/// token cookie is only issued once a page asks for it.
+#[derive(Serialize)]
+#[serde(transparent)]
+pub struct CsrfToken(String);
+
+impl<'r> From<&'r CookieJar<'r>> for CsrfToken {
+    fn from(jar: &'r CookieJar<'r>) -> Self {
+        CsrfToken(Session::from(jar).csrf_token())
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CsrfToken {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(CsrfToken::from(request.cookies()))
+    }
+}
+
+/// The token is taken from the header if present, otherwise from the form in the body
+async fn submitted_token(request: &Request<'_>, data: &mut Data<'_>) -> Option<String> {
+    if let Some(token) = request.headers().get_one(CSRF_HEADER) {
+        return Some(token.to_string());
+    }
+
+    let content_type = request.content_type()?;
+    let body = data.peek(PEEK_BYTES).await.to_vec();
+
+    if content_type.is_form() {
+        form_token(&body, data.peek_complete())
+    } else if content_type.is_form_data() {
+        multipart_token(&body)
+    } else {
+        None
+    }
+}
+
+/// Find the token in the start of a `application/x-www-form-urlencoded` body. Unless the whole
+/// body was read, the last field may have been cut off.
+fn form_token(body: &[u8], complete: bool) -> Option<String> {
+    let body = String::from_utf8_lossy(body);
+    let mut fields: Vec<_> = body.split('&').collect();
+    if !complete {
+        fields.pop();
+    }
+
+    fields.into_iter().find_map(|field| {
+        let (name, value) = field.split_once('=')?;
+        if name != CSRF_FIELD {
+            return None;
+        }
+
+        RawStr::new(value).url_decode().ok().map(|value| value.into_owned())
+    })
+}
+
+/// Find the token in the start of a `multipart/form-data` body
+fn multipart_token(body: &[u8]) -> Option<String> {
+    let body = String::from_utf8_lossy(body);
+    let disposition = format!("name=\"{}\"", CSRF_FIELD);
+
+    let (_, part) = body.split_once(&disposition)?;
+    let (_, value) = part.split_once("\r\n\r\n")?;
+    let (value, _) = value.split_once("\r\n")?;
+    Some(value.to_string())
+}
+
+/// Compare without returning early so the time taken does not reveal how much of a guessed token
+/// was correct
+pub(crate) fn tokens_match(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Request guard for state-changing routes. Requests fail with 403 Forbidden unless they carry the
+/// session's CSRF token, either in the `csrf_token` form field or the `X-CSRF-Token` header.
+pub struct CsrfVerified;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CsrfVerified {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = Session::from(request.cookies()).stored_csrf_token();
+        let SubmittedToken(submitted) = request.local_cache(SubmittedToken::default);
+
+        match (token, submitted) {
+            (Some(token), Some(submitted)) if tokens_match(&token, submitted) => Outcome::Success(CsrfVerified),
+            _ => Outcome::Failure((Status::Forbidden, ())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn read_form_token() {
+        assert_eq!(form_token(b"csrf_token=abc123&asin=B07VGRJDFY", true), Some("abc123".to_string()));
+        assert_eq!(form_token(b"_method=delete&csrf_token=a%2Bb", true), Some("a+b".to_string()));
+        assert_eq!(form_token(b"asin=B07VGRJDFY", true), None);
+
+        // A token at the very end of a body which was not read completely may have been cut off
+        assert_eq!(form_token(b"asin=B07VGRJDFY&csrf_token=abc", false), None);
+        assert_eq!(form_token(b"csrf_token=abc123&text=B07VGRJDFY%0AB0", false), Some("abc123".to_string()));
+    }
+
+    #[test]
+    pub fn read_multipart_token() {
+        let body = b"--boundary\r\n\
+            Content-Disposition: form-data; name=\"csrf_token\"\r\n\r\n\
+            abc123\r\n\
+            --boundary\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"list.csv\"\r\n";
+
+        assert_eq!(multipart_token(body), Some("abc123".to_string()));
+        assert_eq!(multipart_token(b"--boundary\r\nContent-Disposition: form-data; name=\"csrf_token\"\r\n\r\nabc"), None);
+    }
+
+    #[test]
+    pub fn compare_tokens() {
+        assert!(tokens_match("abc123", "abc123"));
+        assert!(!tokens_match("abc123", "abc124"));
+        assert!(!tokens_match("abc123", "abc12"));
+    }
+}
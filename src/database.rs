@@ -4,11 +4,25 @@ use rocket::request::FromRequest;
 use rocket::request::Outcome;
 use rocket::{Request, State};
 use sqlx::pool::PoolConnection;
-use sqlx::{Executor, Pool, Sqlite};
+use sqlx::{Executor, Pool, Sqlite, SqliteConnection};
+use std::collections::HashMap;
 use std::ops::{Deref, DerefMut};
 use uuid::Uuid;
+use crate::scraper::offer::Offer;
+use crate::scraper::price::PriceUSD;
 use crate::scraper::product::{DepartmentHierarchy, Product};
-use crate::session::UserId;
+use crate::export::HistoryRow;
+use crate::import::history::HistoryPoint;
+use crate::session::{Role, SessionPolicy, StoredSession, UserId};
+use crate::login_throttle::{FailedLogins, LoginPolicy};
+use crate::account::{AccountProfile, Subscription, TrackedProduct};
+use crate::stats::PricePoint;
+use crate::chart::ChartPoint;
+use crate::compare::ComparedProduct;
+use crate::browse::{CatalogProduct, DepartmentNode};
+use crate::price_changes::{self, SnapshotListing, StoredChange};
+use crate::retention::{self, CollectedPrices, Resolution, Rollup};
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
 
 /// A database connection that can be used in routes to acquire a database handle
 #[repr(transparent)]
@@ -32,6 +46,12 @@ impl<'r, D: sqlx::Database> FromRequest<'r> for Connection<D> {
     }
 }
 
+impl<D: sqlx::Database> From<PoolConnection<D>> for Connection<D> {
+    fn from(connection: PoolConnection<D>) -> Self {
+        Connection { connection }
+    }
+}
+
 impl<D: sqlx::Database> Deref for Connection<D> {
     type Target = PoolConnection<D>;
 
@@ -46,8 +66,111 @@ impl<D: sqlx::Database> DerefMut for Connection<D> {
     }
 }
 
+/// Listings joined with the time they were collected, the product name and the companies shipping and selling them
+const HISTORY_QUERY: &str = "
+    SELECT
+        hlc.ASIN AS asin,
+        spm.name AS name,
+        r.datetime AS datetime,
+        hlc.condition AS condition,
+        hlc.Price AS price,
+        shipper.name AS ships_from,
+        seller.name AS sold_by,
+        hlc.source AS source
+    FROM Has_Listing_collected hlc
+    JOIN For_Product_Data_Refresh r ON r.RefreshID = hlc.RefreshID
+    LEFT JOIN Product_variant_Sold pvs ON pvs.ASIN = hlc.ASIN
+    LEFT JOIN Sold_Product_Manufactured spm ON spm.PID = pvs.PID
+    LEFT JOIN Company shipper ON shipper.ComID = hlc.shipped_comID
+    LEFT JOIN Company seller ON seller.ComID = hlc.sold_ComID";
+
+/// The lowest price of each refresh and condition, with columns named to match [PricePoint].
+/// Followed by [PRICE_POINT_GROUPING] after any conditions.
+const PRICE_POINT_QUERY: &str = "
+    SELECT hlc.ASIN AS asin, hlc.condition AS condition, r.datetime AS datetime, MIN(hlc.Price) AS price
+    FROM Has_Listing_collected hlc
+    JOIN For_Product_Data_Refresh r ON r.RefreshID = hlc.RefreshID";
+
+const PRICE_POINT_GROUPING: &str = "GROUP BY hlc.RefreshID, hlc.condition";
+
+/// The listings of a refresh with columns named to match [SnapshotListing]
+const SNAPSHOT_QUERY: &str = "
+    SELECT COALESCE(hlc.condition, '') AS condition, COALESCE(c.name, '') AS seller, hlc.Price AS price
+    FROM Has_Listing_collected hlc
+    LEFT JOIN Company c ON c.ComID = hlc.sold_ComID
+    WHERE hlc.RefreshID = ?";
+
+/// Rollups with columns named to match [Rollup], followed by the table to read
+const ROLLUP_QUERY: &str = "
+    SELECT ASIN AS asin, condition, period, min_price, max_price, close_price, closed_at, samples
+    FROM";
+
+/// The table holding prices compacted to the resolution, if there is one
+fn rollup_table(resolution: Resolution) -> Option<&'static str> {
+    match resolution {
+        Resolution::Full => None,
+        Resolution::Daily => Some("Price_Daily"),
+        Resolution::Weekly => Some("Price_Weekly"),
+    }
+}
+
+/// SQL limiting a column to an optional inclusive range of times. Each time is bound twice.
+fn time_range(column: &str) -> String {
+    format!("AND (? IS NULL OR datetime({0}) >= datetime(?)) AND (? IS NULL OR datetime({0}) <= datetime(?))", column)
+}
+
+/// Add a rollup to a table of rollups, merging it into the period when it was already compacted
+async fn add_rollup(connection: &mut SqliteConnection, table: &str, rollup: &Rollup) -> sqlx::Result<()> {
+    let query = format!("
+        INSERT INTO {} (ASIN, condition, period, min_price, max_price, close_price, closed_at, samples)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT (ASIN, condition, period) DO UPDATE SET
+            min_price = MIN(min_price, excluded.min_price),
+            max_price = MAX(max_price, excluded.max_price),
+            close_price = CASE WHEN excluded.closed_at >= closed_at THEN excluded.close_price ELSE close_price END,
+            closed_at = MAX(closed_at, excluded.closed_at),
+            samples = samples + excluded.samples", table);
+
+    sqlx::query(&query)
+        .bind(&rollup.asin)
+        .bind(&rollup.condition)
+        .bind(rollup.period)
+        .bind(rollup.min_price)
+        .bind(rollup.max_price)
+        .bind(rollup.close_price)
+        .bind(rollup.closed_at)
+        .bind(rollup.samples)
+        .execute(connection)
+        .await?;
+
+    Ok(())
+}
+
+/// Tables holding rows which belong to a single user, removed along with the user
+const USER_TABLES: [&str; 6] = ["Tracks", "Subscribes_To", "Account_Tokens", "User_Sessions", "Two_Factor", "Recovery_Codes"];
+
+/// Sessions with their columns named to match [StoredSession]
+const SESSION_QUERY: &str = "
+    SELECT SessionID AS id, sid AS user, created_at, last_seen, user_agent, ip
+    FROM User_Sessions";
+
+/// SQL grouping the conditions of a listing into new and used, since imported prices do not say
+/// how worn a used product is
+fn condition_group(condition: &str) -> String {
+    format!("(CASE WHEN {} = 'New' THEN 'New' ELSE 'Used' END)", condition)
+}
+
+/// Optional inclusive date range applied to [HISTORY_QUERY]. Each date is bound twice.
+const HISTORY_FILTER: &str = "
+    AND (? IS NULL OR date(r.datetime) >= ?)
+    AND (? IS NULL OR date(r.datetime) <= ?)
+    ORDER BY hlc.ASIN, r.datetime, hlc.condition, hlc.Price";
+
 impl Connection<Sqlite> {
 
+    /// The first department with the name. Departments in different branches may share a name,
+    /// so prefer [Connection::department_by_node] when the browse node is known.
+    #[cfg(test)]
     pub async fn department_by_name(&mut self, department: &str) -> sqlx::Result<Option<Uuid>> {
         Ok(sqlx::query_as("SELECT DepID FROM Department WHERE name = ?")
             .bind(department)
@@ -56,50 +179,86 @@ impl Connection<Sqlite> {
             .map(|(uuid, )| uuid))
     }
 
-    pub async fn get_or_add_department(&mut self, department_hierarchy: &DepartmentHierarchy) -> sqlx::Result<Uuid> {
-        let last_department = match department_hierarchy.last() {
-            Some(last) => last,
-            None => unreachable!("Department hierarchy must contain at least one item")
-        };
+    #[cfg(test)]
+    pub async fn department_by_node(&mut self, node: u64) -> sqlx::Result<Option<Uuid>> {
+        Ok(sqlx::query_as("SELECT DepID FROM Department WHERE node = ?")
+            .bind(node as i64)
+            .fetch_optional(&mut self.connection)
+            .await?
+            .map(|(uuid, )| uuid))
+    }
 
-        // Check if the department is already present before going through the process to add it
-        if let Some(id) = self.department_by_name(&last_department.name).await? {
-            return Ok(id)
+    /// Add or update every department along the breadcrumb path in one transaction and return the
+    /// last one. Departments are matched by their browse node, taking the name Amazon currently
+    /// gives them, and each is listed under the department before it so categories Amazon moved
+    /// are re-parented. Adding the same path again changes nothing. Returns `None` for an empty
+    /// path, such as from a page without breadcrumbs.
+    pub async fn get_or_add_department(&mut self, department_hierarchy: &DepartmentHierarchy) -> sqlx::Result<Option<Uuid>> {
+        if department_hierarchy.is_empty() {
+            return Ok(None);
         }
 
-        // Add departments until we
-        let mut parent = None;
-        let mut maybe_initialized = true;
+        let mut transaction = sqlx::Acquire::begin(&mut self.connection).await?;
+        let mut path: Vec<Uuid> = Vec::new();
 
         for department in department_hierarchy.iter() {
-            if maybe_initialized {
-                if let Some(id) = self.department_by_name(&last_department.name).await? {
-                    parent = Some(id);
-                    continue
-                } else {
-                    maybe_initialized = false;
-                }
-            }
+            let node = department.node as i64;
 
-            let new_department = Uuid::new_v4();
-            sqlx::query("INSERT INTO Department (DepID, name) VALUES (?, ?)")
-                .bind(new_department)
+            // Departments added before browse nodes were stored are adopted by name. Every
+            // statement writes, so concurrent transactions wait for each other instead of failing
+            // to upgrade a read lock.
+            sqlx::query("
+                UPDATE Department SET node = ?
+                WHERE DepID = (SELECT DepID FROM Department WHERE name = ? AND node IS NULL LIMIT 1)
+                    AND NOT EXISTS (SELECT 1 FROM Department WHERE node = ?)")
+                .bind(node)
                 .bind(&department.name)
-                .execute(&mut self.connection)
+                .bind(node)
+                .execute(&mut *transaction)
                 .await?;
 
-            if let Some(parent_department) = parent {
-                sqlx::query("INSERT INTO Area_within (sub_DepID, Category_DepID) VALUES (?, ?)")
-                    .bind(new_department)
-                    .bind(parent_department)
-                    .execute(&mut self.connection)
-                    .await?;
+            sqlx::query("
+                INSERT INTO Department (DepID, name, node) VALUES (?, ?, ?)
+                ON CONFLICT (node) DO UPDATE SET name = excluded.name")
+                .bind(Uuid::new_v4())
+                .bind(&department.name)
+                .bind(node)
+                .execute(&mut *transaction)
+                .await?;
+
+            let (id,): (Uuid,) = sqlx::query_as("SELECT DepID FROM Department WHERE node = ?")
+                .bind(node)
+                .fetch_one(&mut *transaction)
+                .await?;
+
+            // A breadcrumb listing a department twice would otherwise make it its own ancestor
+            if path.contains(&id) {
+                continue;
             }
 
-            parent = Some(new_department);
+            match path.last() {
+                Some(parent) => {
+                    sqlx::query("
+                        INSERT INTO Area_within (sub_DepID, Category_DepID) VALUES (?, ?)
+                        ON CONFLICT (sub_DepID) DO UPDATE SET Category_DepID = excluded.Category_DepID")
+                        .bind(id)
+                        .bind(parent)
+                        .execute(&mut *transaction)
+                        .await?;
+                }
+                None => {
+                    sqlx::query("DELETE FROM Area_within WHERE sub_DepID = ?")
+                        .bind(id)
+                        .execute(&mut *transaction)
+                        .await?;
+                }
+            }
+
+            path.push(id);
         }
 
-        Ok(parent.unwrap())
+        transaction.commit().await?;
+        Ok(path.last().copied())
     }
 
     pub async fn product_exists(&mut self, asin: &str) -> sqlx::Result<Option<Uuid>> {
@@ -150,9 +309,14 @@ impl Connection<Sqlite> {
         Ok(new_id)
     }
 
-    pub async fn add_product(&mut self, product: &Product) -> sqlx::Result<Uuid> {
+    /// Add a product, returning its id. Products are always filed under a department, so nothing
+    /// is added if the product has none.
+    pub async fn add_product(&mut self, product: &Product) -> sqlx::Result<Option<Uuid>> {
+        let department_id = match self.get_or_add_department(&product.department).await? {
+            Some(department_id) => department_id,
+            None => return Ok(None),
+        };
         let manufacturer_id = self.get_or_add_manufacturer(&product.manufacturer).await?;
-        let department_id = self.get_or_add_department(&product.department).await?;
 
         let url  = format!("https://amazon.com/dp/{}/", &product.asin);
 
@@ -174,38 +338,1188 @@ impl Connection<Sqlite> {
             .execute(&mut self.connection)
             .await?;
 
-        Ok(new_id)
+        Ok(Some(new_id))
+    }
+
+    /// Update the name, department and manufacturer of an existing product. The department is kept
+    /// when the product has none.
+    pub async fn update_product_metadata(&mut self, product: &Product) -> sqlx::Result<()> {
+        let department = self.get_or_add_department(&product.department).await?;
+        let manufacturer = self.get_or_add_manufacturer(&product.manufacturer).await?;
+
+        sqlx::query("
+        UPDATE Sold_Product_Manufactured
+            SET name = ?, DepID = COALESCE(?, DepID), ManuID = ?
+            WHERE PID IN (SELECT PID FROM Product_variant_Sold WHERE ASIN = ?);
+        ")
+            .bind(&product.name)
+            .bind(department)
+            .bind(manufacturer)
+            .bind(&product.asin)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(())
     }
 
-    pub async fn track_product(&mut self, user: UserId, product: Uuid, asin: &str) -> sqlx::Result<()> {
+    /// Record that the listings for a product were collected at the given time. Returns the id of
+    /// the refresh, which is reused if one was already recorded at exactly the same time.
+    pub async fn add_refresh(&mut self, asin: &str, collected_at: DateTime<Utc>) -> sqlx::Result<Uuid> {
+        sqlx::query("INSERT OR IGNORE INTO For_Product_Data_Refresh (RefreshID, ASIN, datetime) VALUES (?, ?, ?)")
+            .bind(Uuid::new_v4())
+            .bind(asin)
+            .bind(collected_at)
+            .execute(&mut self.connection)
+            .await?;
+
+        let (refresh_id,): (Uuid,) = sqlx::query_as("SELECT RefreshID FROM For_Product_Data_Refresh WHERE ASIN = ? AND datetime = ?")
+            .bind(asin)
+            .bind(collected_at)
+            .fetch_one(&mut self.connection)
+            .await?;
+
+        Ok(refresh_id)
+    }
+
+    /// The time of the first refresh of a product within the window following the given time
+    pub async fn refresh_after(
+        &mut self,
+        asin: &str,
+        after: DateTime<Utc>,
+        window: Duration,
+    ) -> sqlx::Result<Option<DateTime<Utc>>> {
+        let refresh: Option<(DateTime<Utc>,)> = sqlx::query_as("
+            SELECT datetime FROM For_Product_Data_Refresh
+            WHERE ASIN = ? AND datetime >= ? AND datetime <= ?
+            ORDER BY datetime
+            LIMIT 1")
+            .bind(asin)
+            .bind(after)
+            .bind(after + window)
+            .fetch_optional(&mut self.connection)
+            .await?;
+
+        Ok(refresh.map(|(datetime,)| datetime))
+    }
+
+    /// Record a refresh, add every offer found during it and record how the listings changed since
+    /// the previous refresh. Returns the number of listings added.
+    pub async fn add_listings(&mut self, asin: &str, collected_at: DateTime<Utc>, offers: &[Offer]) -> sqlx::Result<usize> {
+        let refresh_id = self.add_refresh(asin, collected_at).await?;
+
+        let mut added = 0;
+        for offer in offers {
+            if self.add_listing(asin, refresh_id, collected_at.date_naive(), offer).await? {
+                added += 1;
+            }
+        }
+
+        self.record_changes(asin, refresh_id, collected_at).await?;
+        Ok(added)
+    }
+
+    /// Compare the listings of a refresh with the latest earlier refresh collected from Amazon and
+    /// store the changes, replacing any recorded for the refresh before. Nothing is recorded for
+    /// the first refresh of a product. Returns the number of changes.
+    async fn record_changes(&mut self, asin: &str, refresh_id: Uuid, collected_at: DateTime<Utc>) -> sqlx::Result<usize> {
+        sqlx::query("DELETE FROM Price_Change_Events WHERE RefreshID = ?")
+            .bind(refresh_id)
+            .execute(&mut self.connection)
+            .await?;
+
+        // Refreshes holding imported prices only have the lowest price of the day
+        let previous: Option<(Uuid,)> = sqlx::query_as("
+            SELECT r.RefreshID FROM For_Product_Data_Refresh r
+            WHERE r.ASIN = ? AND r.datetime < ?
+                AND NOT EXISTS(SELECT 1 FROM Has_Listing_collected hlc
+                    WHERE hlc.RefreshID = r.RefreshID AND hlc.source = 'imported')
+            ORDER BY r.datetime DESC
+            LIMIT 1")
+            .bind(asin)
+            .bind(collected_at)
+            .fetch_optional(&mut self.connection)
+            .await?;
+
+        let previous_id = match previous {
+            Some((previous_id,)) => previous_id,
+            None => return Ok(0),
+        };
+
+        let previous: Vec<SnapshotListing> = sqlx::query_as(SNAPSHOT_QUERY)
+            .bind(previous_id)
+            .fetch_all(&mut self.connection)
+            .await?;
+
+        let current: Vec<SnapshotListing> = sqlx::query_as(SNAPSHOT_QUERY)
+            .bind(refresh_id)
+            .fetch_all(&mut self.connection)
+            .await?;
+
+        // Prices compacted by retention still count towards the lowest price
+        let lows: Vec<(String, f64)> = sqlx::query_as("
+            SELECT COALESCE(hlc.condition, ''), MIN(hlc.Price)
+            FROM Has_Listing_collected hlc
+            JOIN For_Product_Data_Refresh r ON r.RefreshID = hlc.RefreshID
+            WHERE r.ASIN = ? AND r.datetime < ?
+            GROUP BY hlc.condition
+            UNION ALL
+            SELECT condition, MIN(min_price) FROM Price_Daily WHERE ASIN = ? AND period < ? GROUP BY condition
+            UNION ALL
+            SELECT condition, MIN(min_price) FROM Price_Weekly WHERE ASIN = ? AND period < ? GROUP BY condition")
+            .bind(asin)
+            .bind(collected_at)
+            .bind(asin)
+            .bind(collected_at)
+            .bind(asin)
+            .bind(collected_at)
+            .fetch_all(&mut self.connection)
+            .await?;
+
+        let mut previous_lows: HashMap<String, f64> = HashMap::new();
+        for (condition, low) in lows {
+            previous_lows
+                .entry(condition)
+                .and_modify(|previous_low| *previous_low = previous_low.min(low))
+                .or_insert(low);
+        }
+
+        let events = price_changes::diff(&previous, &current, &previous_lows);
+        for event in &events {
+            sqlx::query("INSERT INTO Price_Change_Events (EventID,ASIN,RefreshID,occurred_at,kind,\
+                        condition,seller,old_price,new_price) VALUES (?,?,?,?,?,?,?,?,?)")
+                .bind(Uuid::new_v4())
+                .bind(asin)
+                .bind(refresh_id)
+                .bind(collected_at)
+                .bind(event.kind.as_str())
+                .bind(&event.condition)
+                .bind(&event.seller)
+                .bind(event.old_price)
+                .bind(event.new_price)
+                .execute(&mut self.connection)
+                .await?;
+        }
+
+        Ok(events.len())
+    }
+
+    /// Add a single listing to a refresh unless an identical one was already added to it. Returns
+    /// false if the listing was a duplicate.
+    async fn add_listing(&mut self, asin: &str, refresh_id: Uuid, day: NaiveDate, offer: &Offer) -> sqlx::Result<bool> {
+        let condition_str = format!("{:?}", offer.condition);
+        let shipped_by = self.get_or_add_company(&offer.ships_from).await?;
+        let sold_by = self.get_or_add_company(&offer.sold_by).await?;
+        let price = f64::from(offer.price);
+
+        // Prices collected from Amazon replace any imported from a tracker for the same day
+        let imported_filter = format!("
+            WHERE RefreshID IN (SELECT RefreshID FROM For_Product_Data_Refresh WHERE ASIN = ? AND date(datetime) = ?)
+                AND source = 'imported' AND {} = {}",
+            condition_group("condition"), condition_group("?"));
+
+        let imported: Vec<(Uuid,)> = sqlx::query_as(&format!("SELECT DISTINCT RefreshID FROM Has_Listing_collected {}", imported_filter))
+            .bind(asin)
+            .bind(day)
+            .bind(&condition_str)
+            .fetch_all(&mut self.connection)
+            .await?;
+
+        sqlx::query(&format!("DELETE FROM Has_Listing_collected {}", imported_filter))
+            .bind(asin)
+            .bind(day)
+            .bind(&condition_str)
+            .execute(&mut self.connection)
+            .await?;
+
+        // A refresh left without listings would look like one where the product was out of stock
+        for (imported_refresh,) in imported {
+            sqlx::query("
+                DELETE FROM For_Product_Data_Refresh
+                WHERE RefreshID = ? AND RefreshID != ?
+                    AND NOT EXISTS(SELECT 1 FROM Has_Listing_collected WHERE RefreshID = ?)")
+                .bind(imported_refresh)
+                .bind(refresh_id)
+                .bind(imported_refresh)
+                .execute(&mut self.connection)
+                .await?;
+        }
+
+        let (exists,): (bool,) = sqlx::query_as("
+            SELECT EXISTS(SELECT 1 FROM Has_Listing_collected
+                WHERE RefreshID = ? AND condition = ? AND Price = ? AND sold_ComID = ?)")
+            .bind(refresh_id)
+            .bind(&condition_str)
+            .bind(price)
+            .bind(sold_by)
+            .fetch_one(&mut self.connection)
+            .await?;
+
+        if exists {
+            return Ok(false)
+        }
+
+        sqlx::query("INSERT INTO Has_Listing_collected (ListingID,ASIN,condition,\
+                    Price,RefreshID,shipped_comID,sold_ComID,source) VALUES (?,?,?,?,?,?,?,'scraped')")
+            .bind(Uuid::new_v4())
+            .bind(asin)
+            .bind(condition_str)
+            .bind(price)
+            .bind(refresh_id)
+            .bind(shipped_by)
+            .bind(sold_by)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(true)
+    }
+
+    /// Add a price imported from a third-party tracker. Trackers only give the day of each price,
+    /// so it is recorded at midnight UTC. Prices are skipped if one was already collected from
+    /// Amazon or imported for the same day and condition. Returns false if the price was skipped.
+    pub async fn add_imported_listing(&mut self, asin: &str, point: &HistoryPoint) -> sqlx::Result<bool> {
+        let condition = point.series.condition();
+        let company = self.get_or_add_company(point.series.seller()).await?;
+
+        let (exists,): (bool,) = sqlx::query_as(&format!("
+            SELECT EXISTS(SELECT 1 FROM Has_Listing_collected hlc
+                JOIN For_Product_Data_Refresh r ON r.RefreshID = hlc.RefreshID
+                WHERE r.ASIN = ? AND date(r.datetime) = ? AND {} = {}
+                    AND (hlc.source = 'scraped' OR hlc.sold_ComID = ?))",
+            condition_group("hlc.condition"), condition_group("?")))
+            .bind(asin)
+            .bind(point.date)
+            .bind(condition)
+            .bind(company)
+            .fetch_one(&mut self.connection)
+            .await?;
+
+        if exists {
+            return Ok(false)
+        }
+
+        let midnight = point.date.and_hms_opt(0, 0, 0).expect("midnight is a valid time");
+        let refresh_id = self.add_refresh(asin, Utc.from_utc_datetime(&midnight)).await?;
+
+        sqlx::query("INSERT INTO Has_Listing_collected (ListingID,ASIN,condition,\
+                    Price,RefreshID,shipped_comID,sold_ComID,source) VALUES (?,?,?,?,?,?,?,'imported')")
+            .bind(Uuid::new_v4())
+            .bind(asin)
+            .bind(condition)
+            .bind(f64::from(point.price))
+            .bind(refresh_id)
+            .bind(company)
+            .bind(company)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(true)
+    }
+
+    /// Track the product for the user. If the product is already tracked, only the target price is
+    /// updated when one is given.
+    pub async fn track_product(
+        &mut self,
+        user: UserId,
+        product: Uuid,
+        asin: &str,
+        target_price: Option<PriceUSD>,
+    ) -> sqlx::Result<()> {
+        let target_price = target_price.map(f64::from);
+
         let (exits,): (bool,) = sqlx::query_as("SELECT EXISTS(SELECT 1 FROM Tracks WHERE sid = ? AND PID = ?)")
             .bind(&user)
             .bind(product)
             .fetch_one(&mut self.connection)
             .await?;
 
-        if !exits {
-            sqlx::query("INSERT INTO Tracks (sid, PID) VALUES (?, ?)")
+        if exits {
+            if target_price.is_some() {
+                sqlx::query("UPDATE Tracks SET target_price = ? WHERE sid = ? AND PID = ?")
+                    .bind(target_price)
+                    .bind(user)
+                    .bind(product)
+                    .execute(&mut self.connection)
+                    .await?;
+            }
+
+            return Ok(());
+        }
+
+        sqlx::query("INSERT INTO Tracks (sid, PID, target_price) VALUES (?, ?, ?)")
+            .bind(user)
+            .bind(product)
+            .bind(target_price)
+            .execute(&mut self.connection)
+            .await?;
+
+        sqlx::query("INSERT INTO Deal_Alert_on (conditions, ASIN, last_notification) VALUES (?, ?, ?)")
+            .bind("New")
+            .bind(asin)
+            .bind("")
+            .execute(&mut self.connection)
+            .await?;
+
+        sqlx::query("INSERT INTO Subscribes_To (conditions, ASIN, sid) VALUES (?, ?, ?)")
+            .bind("New")
+            .bind(asin)
+            .bind(user)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Check if the user has subscribed to the product
+    pub async fn tracks_asin(&mut self, user: UserId, asin: &str) -> sqlx::Result<bool> {
+        let (tracked,): (bool,) = sqlx::query_as("SELECT EXISTS(SELECT 1 FROM Subscribes_To WHERE sid = ? AND ASIN = ?)")
+            .bind(user)
+            .bind(asin)
+            .fetch_one(&mut self.connection)
+            .await?;
+
+        Ok(tracked)
+    }
+
+    /// All listings collected for a product, oldest first. Either end of the date range may be
+    /// left open.
+    pub async fn product_history(
+        &mut self,
+        asin: &str,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+    ) -> sqlx::Result<Vec<HistoryRow>> {
+        let query = format!("{} WHERE hlc.ASIN = ? {}", HISTORY_QUERY, HISTORY_FILTER);
+
+        sqlx::query_as::<_, HistoryRow>(&query)
+            .bind(asin)
+            .bind(from)
+            .bind(from)
+            .bind(to)
+            .bind(to)
+            .fetch_all(&mut self.connection)
+            .await
+    }
+
+    /// All listings collected for every product the user has subscribed to, grouped by product
+    pub async fn user_history(
+        &mut self,
+        user: UserId,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+    ) -> sqlx::Result<Vec<HistoryRow>> {
+        let query = format!(
+            "{} WHERE hlc.ASIN IN (SELECT ASIN FROM Subscribes_To WHERE sid = ?) {}",
+            HISTORY_QUERY, HISTORY_FILTER
+        );
+
+        sqlx::query_as::<_, HistoryRow>(&query)
+            .bind(user)
+            .bind(from)
+            .bind(from)
+            .bind(to)
+            .bind(to)
+            .fetch_all(&mut self.connection)
+            .await
+    }
+
+    /// Every price collected for a product, used to calculate its [crate::stats::PriceStats]
+    pub async fn price_points(&mut self, asin: &str) -> sqlx::Result<CollectedPrices> {
+        let mut prices = CollectedPrices {
+            points: self.price_points_between(asin, None, None).await?,
+            rollups: Vec::new(),
+        };
+
+        for resolution in [Resolution::Daily, Resolution::Weekly] {
+            prices.rollups.extend(self.product_rollups(resolution, asin, None, None).await?);
+        }
+
+        Ok(prices)
+    }
+
+    /// The lowest price of each refresh and condition of a product between the optional times,
+    /// leaving out prices which have been compacted
+    pub async fn price_points_between(
+        &mut self,
+        asin: &str,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> sqlx::Result<Vec<PricePoint>> {
+        let query = format!(
+            "{} WHERE hlc.ASIN = ? {} {}",
+            PRICE_POINT_QUERY,
+            time_range("r.datetime"),
+            PRICE_POINT_GROUPING
+        );
+
+        sqlx::query_as(&query)
+            .bind(asin)
+            .bind(from)
+            .bind(from)
+            .bind(to)
+            .bind(to)
+            .fetch_all(&mut self.connection)
+            .await
+    }
+
+    /// The compacted prices of a product in periods starting between the optional times. There are
+    /// none at full resolution.
+    pub async fn product_rollups(
+        &mut self,
+        resolution: Resolution,
+        asin: &str,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> sqlx::Result<Vec<Rollup>> {
+        let table = match rollup_table(resolution) {
+            Some(table) => table,
+            None => return Ok(Vec::new()),
+        };
+
+        let query = format!("{} {} WHERE ASIN = ? {} ORDER BY period", ROLLUP_QUERY, table, time_range("period"));
+        sqlx::query_as(&query)
+            .bind(asin)
+            .bind(from)
+            .bind(from)
+            .bind(to)
+            .bind(to)
+            .fetch_all(&mut self.connection)
+            .await
+    }
+
+    /// Every listing collected for a product between the optional times with the seller's name,
+    /// used to draw its charts
+    pub async fn chart_points(
+        &mut self,
+        asin: &str,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> sqlx::Result<Vec<ChartPoint>> {
+        let query = format!("
+            SELECT hlc.condition AS condition, c.name AS seller, r.datetime AS datetime, hlc.Price AS price
+            FROM Has_Listing_collected hlc
+            JOIN For_Product_Data_Refresh r ON r.RefreshID = hlc.RefreshID
+            LEFT JOIN Company c ON c.ComID = hlc.sold_ComID
+            WHERE hlc.ASIN = ? {}
+            ORDER BY r.datetime", time_range("r.datetime"));
+
+        sqlx::query_as(&query)
+            .bind(asin)
+            .bind(from)
+            .bind(from)
+            .bind(to)
+            .bind(to)
+            .fetch_all(&mut self.connection)
+            .await
+    }
+
+    /// The manufacturer, department and average rating of each product which exists, in no
+    /// particular order
+    pub async fn compared_products(&mut self, asins: &[String]) -> sqlx::Result<Vec<ComparedProduct>> {
+        if asins.is_empty() {
+            return Ok(Vec::new())
+        }
+
+        let query = format!("
+            SELECT
+                pvs.ASIN AS asin,
+                spm.name AS name,
+                m.name AS manufacturer,
+                d.name AS department,
+                (SELECT AVG(cr.rating) FROM Contains_Reviews cr WHERE cr.ASIN = pvs.ASIN) AS rating
+            FROM Product_variant_Sold pvs
+            JOIN Sold_Product_Manufactured spm ON spm.PID = pvs.PID
+            LEFT JOIN Manufacturer m ON m.ManuID = spm.ManuID
+            LEFT JOIN Department d ON d.DepID = spm.DepID
+            WHERE pvs.ASIN IN ({})",
+            vec!["?"; asins.len()].join(", "));
+
+        let mut query = sqlx::query_as(&query);
+        for asin in asins {
+            query = query.bind(asin);
+        }
+
+        query.fetch_all(&mut self.connection).await
+    }
+
+    /// Every price collected for the products the user has subscribed to, including compacted prices
+    pub async fn user_price_points(&mut self, user: UserId) -> sqlx::Result<CollectedPrices> {
+        let query = format!(
+            "{} WHERE hlc.ASIN IN (SELECT ASIN FROM Subscribes_To WHERE sid = ?) {}",
+            PRICE_POINT_QUERY, PRICE_POINT_GROUPING
+        );
+
+        let mut prices = CollectedPrices {
+            points: sqlx::query_as(&query)
                 .bind(user)
-                .bind(product)
-                .execute(&mut self.connection)
-                .await?;
+                .fetch_all(&mut self.connection)
+                .await?,
+            rollups: Vec::new(),
+        };
 
-            sqlx::query("INSERT INTO Deal_Alert_on (conditions, ASIN, last_notification) VALUES (?, ?, ?)")
-                .bind("New")
-                .bind(asin)
-                .bind("")
-                .execute(&mut self.connection)
+        for table in ["Price_Daily", "Price_Weekly"] {
+            let query = format!("{} {} WHERE ASIN IN (SELECT ASIN FROM Subscribes_To WHERE sid = ?)", ROLLUP_QUERY, table);
+            let rollups: Vec<Rollup> = sqlx::query_as(&query)
+                .bind(user)
+                .fetch_all(&mut self.connection)
                 .await?;
 
-            sqlx::query("INSERT INTO Subscribes_To (conditions, ASIN, sid) VALUES (?, ?, ?)")
-                .bind("New")
-                .bind(asin)
+            prices.rollups.extend(rollups);
+        }
+
+        Ok(prices)
+    }
+
+    /// Failed logins recorded for an email address or client IP
+    pub async fn failed_logins(&mut self, kind: &str, key: &str) -> sqlx::Result<Option<FailedLogins>> {
+        sqlx::query_as("SELECT failures, last_failure FROM Login_Attempts WHERE kind = ? AND key = ?")
+            .bind(kind)
+            .bind(key)
+            .fetch_optional(&mut self.connection)
+            .await
+    }
+
+    /// Count another failed login in a single statement, so concurrent failures are all counted.
+    /// Like [LoginPolicy::check], failures which have been reset or whose lockout has passed no
+    /// longer count, so the count starts over.
+    pub async fn add_failed_login(
+        &mut self,
+        kind: &str,
+        key: &str,
+        policy: &LoginPolicy,
+        now: DateTime<Utc>,
+    ) -> sqlx::Result<()> {
+        sqlx::query("
+            INSERT INTO Login_Attempts (kind, key, failures, last_failure) VALUES (?, ?, 1, ?)
+            ON CONFLICT (kind, key) DO UPDATE SET
+                failures = CASE
+                    WHEN datetime(excluded.last_failure) >= datetime(last_failure, ?)
+                        OR (failures >= ? AND datetime(excluded.last_failure) >= datetime(last_failure, ?))
+                    THEN 1
+                    ELSE failures + 1
+                END,
+                last_failure = excluded.last_failure
+        ")
+            .bind(kind)
+            .bind(key)
+            .bind(now)
+            .bind(format!("+{} seconds", policy.reset_after.num_seconds()))
+            .bind(policy.lockout_after)
+            .bind(format!("+{} seconds", policy.lockout.num_seconds()))
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn clear_failed_logins(&mut self, kind: &str, key: &str) -> sqlx::Result<()> {
+        sqlx::query("DELETE FROM Login_Attempts WHERE kind = ? AND key = ?")
+            .bind(kind)
+            .bind(key)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(())
+    }
+
+    /// The ID of the user with the email address, and whether the address has been verified
+    pub async fn user_by_email(&mut self, email: &str) -> sqlx::Result<Option<(Uuid, bool)>> {
+        sqlx::query_as("SELECT sid, email_verified FROM Site_users WHERE email = ?")
+            .bind(email)
+            .fetch_optional(&mut self.connection)
+            .await
+    }
+
+    pub async fn user_email(&mut self, user: Uuid) -> sqlx::Result<Option<String>> {
+        let email: Option<(String,)> = sqlx::query_as("SELECT email FROM Site_users WHERE sid = ?")
+            .bind(user)
+            .fetch_optional(&mut self.connection)
+            .await?;
+
+        Ok(email.map(|(email,)| email))
+    }
+
+    pub async fn set_email_verified(&mut self, user: Uuid) -> sqlx::Result<()> {
+        sqlx::query("UPDATE Site_users SET email_verified = 1 WHERE sid = ?")
+            .bind(user)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_password_hash(&mut self, user: Uuid, password_hash: &[u8]) -> sqlx::Result<()> {
+        sqlx::query("UPDATE Site_users SET password_hash = ? WHERE sid = ?")
+            .bind(password_hash)
+            .bind(user)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Store a new account token, replacing any unused tokens the user has for the same purpose
+    pub async fn add_account_token(
+        &mut self,
+        id: Uuid,
+        user: Uuid,
+        purpose: &str,
+        expires_at: DateTime<Utc>,
+    ) -> sqlx::Result<()> {
+        sqlx::query("DELETE FROM Account_Tokens WHERE sid = ? AND purpose = ? AND used_at IS NULL")
+            .bind(user)
+            .bind(purpose)
+            .execute(&mut self.connection)
+            .await?;
+
+        sqlx::query("INSERT INTO Account_Tokens (TokenID, sid, purpose, expires_at) VALUES (?, ?, ?, ?)")
+            .bind(id)
+            .bind(user)
+            .bind(purpose)
+            .bind(expires_at)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(())
+    }
+
+    /// The user and expiry of an unused account token
+    pub async fn account_token(&mut self, id: Uuid, purpose: &str) -> sqlx::Result<Option<(Uuid, DateTime<Utc>)>> {
+        sqlx::query_as("SELECT sid, expires_at FROM Account_Tokens WHERE TokenID = ? AND purpose = ? AND used_at IS NULL")
+            .bind(id)
+            .bind(purpose)
+            .fetch_optional(&mut self.connection)
+            .await
+    }
+
+    /// Mark an account token as used. Returns false if it had already been used.
+    pub async fn use_account_token(&mut self, id: Uuid, now: DateTime<Utc>) -> sqlx::Result<bool> {
+        let result = sqlx::query("UPDATE Account_Tokens SET used_at = ? WHERE TokenID = ? AND used_at IS NULL")
+            .bind(now)
+            .bind(id)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn add_session(
+        &mut self,
+        id: Uuid,
+        user: Uuid,
+        now: DateTime<Utc>,
+        user_agent: Option<&str>,
+        ip: Option<&str>,
+    ) -> sqlx::Result<()> {
+        sqlx::query("
+            INSERT INTO User_Sessions (SessionID, sid, created_at, last_seen, user_agent, ip)
+            VALUES (?, ?, ?, ?, ?, ?)
+        ")
+            .bind(id)
+            .bind(user)
+            .bind(now)
+            .bind(now)
+            .bind(user_agent)
+            .bind(ip)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn session(&mut self, id: Uuid) -> sqlx::Result<Option<StoredSession>> {
+        let query = format!("{} WHERE SessionID = ?", SESSION_QUERY);
+
+        sqlx::query_as(&query)
+            .bind(id)
+            .fetch_optional(&mut self.connection)
+            .await
+    }
+
+    /// The user's sessions, most recently used first
+    pub async fn user_sessions(&mut self, user: UserId) -> sqlx::Result<Vec<StoredSession>> {
+        let query = format!("{} WHERE sid = ? ORDER BY last_seen DESC", SESSION_QUERY);
+
+        sqlx::query_as(&query)
+            .bind(user)
+            .fetch_all(&mut self.connection)
+            .await
+    }
+
+    pub async fn touch_session(&mut self, id: Uuid, now: DateTime<Utc>) -> sqlx::Result<()> {
+        sqlx::query("UPDATE User_Sessions SET last_seen = ? WHERE SessionID = ?")
+            .bind(now)
+            .bind(id)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_session(&mut self, id: Uuid) -> sqlx::Result<()> {
+        sqlx::query("DELETE FROM User_Sessions WHERE SessionID = ?")
+            .bind(id)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Revoke one of the user's sessions. Returns false if the user has no such session.
+    pub async fn delete_user_session(&mut self, user: Uuid, id: Uuid) -> sqlx::Result<bool> {
+        let result = sqlx::query("DELETE FROM User_Sessions WHERE SessionID = ? AND sid = ?")
+            .bind(id)
+            .bind(user)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Revoke every session of the user, returning how many there were
+    pub async fn delete_user_sessions(&mut self, user: Uuid) -> sqlx::Result<u64> {
+        let result = sqlx::query("DELETE FROM User_Sessions WHERE sid = ?")
+            .bind(user)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Remove sessions which have expired without being used again
+    pub async fn delete_expired_sessions(&mut self, policy: &SessionPolicy, now: DateTime<Utc>) -> sqlx::Result<u64> {
+        let result = sqlx::query("
+            DELETE FROM User_Sessions WHERE datetime(last_seen) <= datetime(?) OR datetime(created_at) <= datetime(?)
+        ")
+            .bind(now - policy.idle_timeout)
+            .bind(now - policy.max_lifetime)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// The user's TOTP secret, whether it has been enabled, and the last time step a code was used for
+    pub async fn two_factor(&mut self, user: Uuid) -> sqlx::Result<Option<(Vec<u8>, bool, Option<i64>)>> {
+        sqlx::query_as("SELECT secret, enabled, last_used_step FROM Two_Factor WHERE sid = ?")
+            .bind(user)
+            .fetch_optional(&mut self.connection)
+            .await
+    }
+
+    /// Store a new secret which is not used until it is enabled, replacing any earlier setup
+    pub async fn set_pending_two_factor(&mut self, user: Uuid, secret: &[u8], now: DateTime<Utc>) -> sqlx::Result<()> {
+        sqlx::query("
+            INSERT INTO Two_Factor (sid, secret, enabled, created_at) VALUES (?, ?, 0, ?)
+            ON CONFLICT (sid) DO UPDATE SET
+                secret = excluded.secret, enabled = 0, last_used_step = NULL, created_at = excluded.created_at
+        ")
+            .bind(user)
+            .bind(secret)
+            .bind(now)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn enable_two_factor(&mut self, user: Uuid) -> sqlx::Result<()> {
+        sqlx::query("UPDATE Two_Factor SET enabled = 1 WHERE sid = ?")
+            .bind(user)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Record the time step of a code which was accepted. Returns false if a code for the same or a
+    /// later step was used in the meantime.
+    pub async fn use_two_factor_step(&mut self, user: Uuid, step: i64) -> sqlx::Result<bool> {
+        let result = sqlx::query("
+            UPDATE Two_Factor SET last_used_step = ?
+            WHERE sid = ? AND (last_used_step IS NULL OR last_used_step < ?)
+        ")
+            .bind(step)
+            .bind(user)
+            .bind(step)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn disable_two_factor(&mut self, user: Uuid) -> sqlx::Result<()> {
+        sqlx::query("DELETE FROM Two_Factor WHERE sid = ?")
+            .bind(user)
+            .execute(&mut self.connection)
+            .await?;
+
+        sqlx::query("DELETE FROM Recovery_Codes WHERE sid = ?")
+            .bind(user)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Replace the user's recovery codes
+    pub async fn set_recovery_codes(&mut self, user: Uuid, hashes: &[[u8; 32]]) -> sqlx::Result<()> {
+        sqlx::query("DELETE FROM Recovery_Codes WHERE sid = ?")
+            .bind(user)
+            .execute(&mut self.connection)
+            .await?;
+
+        for hash in hashes {
+            sqlx::query("INSERT INTO Recovery_Codes (sid, code_hash) VALUES (?, ?)")
                 .bind(user)
+                .bind(&hash[..])
                 .execute(&mut self.connection)
                 .await?;
         }
 
         Ok(())
     }
+
+    /// Use up a recovery code. Returns false if the user has no such unused code.
+    pub async fn use_recovery_code(&mut self, user: Uuid, hash: &[u8; 32], now: DateTime<Utc>) -> sqlx::Result<bool> {
+        let result = sqlx::query("
+            UPDATE Recovery_Codes SET used_at = ? WHERE sid = ? AND code_hash = ? AND used_at IS NULL
+        ")
+            .bind(now)
+            .bind(user)
+            .bind(&hash[..])
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn unused_recovery_codes(&mut self, user: Uuid) -> sqlx::Result<i64> {
+        let (count,) = sqlx::query_as("SELECT COUNT(*) FROM Recovery_Codes WHERE sid = ? AND used_at IS NULL")
+            .bind(user)
+            .fetch_one(&mut self.connection)
+            .await?;
+
+        Ok(count)
+    }
+
+    /// Check the user is an administrator whose account has not been disabled
+    pub async fn is_admin(&mut self, user: Uuid) -> sqlx::Result<bool> {
+        let (is_admin,): (bool,) = sqlx::query_as("SELECT EXISTS(SELECT 1 FROM Site_users WHERE sid = ? AND role = ? AND disabled = 0)")
+            .bind(user)
+            .bind(Role::Admin.as_str())
+            .fetch_one(&mut self.connection)
+            .await?;
+
+        Ok(is_admin)
+    }
+
+    /// Change the role of the user with the email address. Returns false if there is no such user.
+    pub async fn set_role(&mut self, email: &str, role: Role) -> sqlx::Result<bool> {
+        let result = sqlx::query("UPDATE Site_users SET role = ? WHERE email = ?")
+            .bind(role.as_str())
+            .bind(email)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn is_user_disabled(&mut self, user: Uuid) -> sqlx::Result<bool> {
+        let (disabled,): (bool,) = sqlx::query_as("SELECT EXISTS(SELECT 1 FROM Site_users WHERE sid = ? AND disabled = 1)")
+            .bind(user)
+            .fetch_one(&mut self.connection)
+            .await?;
+
+        Ok(disabled)
+    }
+
+    /// Disable or re-enable a user. Disabling a user also signs out all of their sessions. Returns
+    /// false if there is no such user.
+    pub async fn set_user_disabled(&mut self, user: Uuid, disabled: bool) -> sqlx::Result<bool> {
+        let result = sqlx::query("UPDATE Site_users SET disabled = ? WHERE sid = ?")
+            .bind(disabled)
+            .bind(user)
+            .execute(&mut self.connection)
+            .await?;
+
+        if disabled {
+            self.delete_user_sessions(user).await?;
+        }
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Move the products of a duplicate manufacturer to another manufacturer and remove the
+    /// duplicate, all or nothing. Returns the number of products moved, or `None` without changing
+    /// anything if the manufacturer to keep does not exist or is the duplicate itself.
+    pub async fn merge_manufacturers(&mut self, duplicate: Uuid, into: Uuid) -> sqlx::Result<Option<u64>> {
+        if duplicate == into {
+            return Ok(None);
+        }
+
+        // Checking the entry to keep exists with a write, so concurrent transactions wait for each
+        // other instead of failing to upgrade a read lock
+        let mut transaction = sqlx::Acquire::begin(&mut self.connection).await?;
+        let kept = sqlx::query("UPDATE Manufacturer SET ManuID = ManuID WHERE ManuID = ?")
+            .bind(into)
+            .execute(&mut *transaction)
+            .await?;
+        if kept.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        let moved = sqlx::query("UPDATE Sold_Product_Manufactured SET ManuID = ? WHERE ManuID = ?")
+            .bind(into)
+            .bind(duplicate)
+            .execute(&mut *transaction)
+            .await?;
+
+        sqlx::query("DELETE FROM Manufacturer WHERE ManuID = ?")
+            .bind(duplicate)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+        Ok(Some(moved.rows_affected()))
+    }
+
+    /// Move the listings shipped or sold by a duplicate company to another company and remove the
+    /// duplicate, all or nothing. Returns the number of shipper and seller references moved, or
+    /// `None` without changing anything if the company to keep does not exist or is the duplicate.
+    pub async fn merge_companies(&mut self, duplicate: Uuid, into: Uuid) -> sqlx::Result<Option<u64>> {
+        if duplicate == into {
+            return Ok(None);
+        }
+
+        // Checking the entry to keep exists with a write, so concurrent transactions wait for each
+        // other instead of failing to upgrade a read lock
+        let mut transaction = sqlx::Acquire::begin(&mut self.connection).await?;
+        let kept = sqlx::query("UPDATE Company SET ComID = ComID WHERE ComID = ?")
+            .bind(into)
+            .execute(&mut *transaction)
+            .await?;
+        if kept.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        let shipped = sqlx::query("UPDATE Has_Listing_collected SET shipped_comID = ? WHERE shipped_comID = ?")
+            .bind(into)
+            .bind(duplicate)
+            .execute(&mut *transaction)
+            .await?;
+
+        let sold = sqlx::query("UPDATE Has_Listing_collected SET sold_ComID = ? WHERE sold_ComID = ?")
+            .bind(into)
+            .bind(duplicate)
+            .execute(&mut *transaction)
+            .await?;
+
+        sqlx::query("DELETE FROM Company WHERE ComID = ?")
+            .bind(duplicate)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+        Ok(Some(shipped.rows_affected() + sold.rows_affected()))
+    }
+
+    pub async fn account_profile(&mut self, user: Uuid) -> sqlx::Result<Option<AccountProfile>> {
+        sqlx::query_as("
+            SELECT
+                email,
+                role,
+                email_verified,
+                EXISTS(SELECT 1 FROM Two_Factor tf WHERE tf.sid = u.sid AND tf.enabled) AS two_factor_enabled,
+                deletion_requested_at
+            FROM Site_users u
+            WHERE sid = ?")
+            .bind(user)
+            .fetch_optional(&mut self.connection)
+            .await
+    }
+
+    /// The products tracked by the user along with their target prices
+    pub async fn tracked_products(&mut self, user: Uuid) -> sqlx::Result<Vec<TrackedProduct>> {
+        sqlx::query_as("
+            SELECT pvs.ASIN AS asin, spm.name AS name, t.target_price AS target_price
+            FROM Tracks t
+            LEFT JOIN Sold_Product_Manufactured spm ON spm.PID = t.PID
+            LEFT JOIN Product_variant_Sold pvs ON pvs.PID = t.PID
+            WHERE t.sid = ?
+            ORDER BY pvs.ASIN")
+            .bind(user)
+            .fetch_all(&mut self.connection)
+            .await
+    }
+
+    pub async fn subscriptions(&mut self, user: Uuid) -> sqlx::Result<Vec<Subscription>> {
+        sqlx::query_as("SELECT ASIN AS asin, conditions FROM Subscribes_To WHERE sid = ? ORDER BY ASIN, conditions")
+            .bind(user)
+            .fetch_all(&mut self.connection)
+            .await
+    }
+
+    /// Schedule the user's account to be deleted and sign out all of their sessions
+    pub async fn request_account_deletion(&mut self, user: Uuid, now: DateTime<Utc>) -> sqlx::Result<()> {
+        sqlx::query("UPDATE Site_users SET deletion_requested_at = ? WHERE sid = ?")
+            .bind(now)
+            .bind(user)
+            .execute(&mut self.connection)
+            .await?;
+
+        self.delete_user_sessions(user).await?;
+        Ok(())
+    }
+
+    /// Keep an account which was going to be deleted. Returns false if no deletion was scheduled.
+    pub async fn cancel_account_deletion(&mut self, user: Uuid) -> sqlx::Result<bool> {
+        let result = sqlx::query("UPDATE Site_users SET deletion_requested_at = NULL WHERE sid = ? AND deletion_requested_at IS NOT NULL")
+            .bind(user)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Permanently remove the accounts whose deletion was requested at or before the given time,
+    /// along with everything belonging to them. Returns the number of accounts removed.
+    pub async fn delete_requested_accounts(&mut self, requested_before: DateTime<Utc>) -> sqlx::Result<u64> {
+        // The users are removed last, so an interrupted purge is finished by the next one
+        for table in USER_TABLES {
+            let query = format!("
+                DELETE FROM {} WHERE sid IN (
+                    SELECT sid FROM Site_users WHERE datetime(deletion_requested_at) <= datetime(?)
+                )", table);
+
+            sqlx::query(&query)
+                .bind(requested_before)
+                .execute(&mut self.connection)
+                .await?;
+        }
+
+        // Failed logins are counted by email address rather than by user
+        sqlx::query("
+            DELETE FROM Login_Attempts WHERE kind = 'email' AND key IN (
+                SELECT lower(trim(email)) FROM Site_users WHERE datetime(deletion_requested_at) <= datetime(?)
+            )")
+            .bind(requested_before)
+            .execute(&mut self.connection)
+            .await?;
+
+        let result = sqlx::query("DELETE FROM Site_users WHERE datetime(deletion_requested_at) <= datetime(?)")
+            .bind(requested_before)
+            .execute(&mut self.connection)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// The latest changes to the products the user has subscribed to, newest first
+    pub async fn user_changes(&mut self, user: UserId, limit: u32) -> sqlx::Result<Vec<StoredChange>> {
+        sqlx::query_as("
+            SELECT
+                e.ASIN AS asin,
+                spm.name AS name,
+                e.occurred_at AS occurred_at,
+                e.kind AS kind,
+                e.condition AS condition,
+                e.seller AS seller,
+                e.old_price AS old_price,
+                e.new_price AS new_price
+            FROM Price_Change_Events e
+            LEFT JOIN Product_variant_Sold pvs ON pvs.ASIN = e.ASIN
+            LEFT JOIN Sold_Product_Manufactured spm ON spm.PID = pvs.PID
+            WHERE e.ASIN IN (SELECT ASIN FROM Subscribes_To WHERE sid = ?)
+            ORDER BY e.occurred_at DESC, e.kind
+            LIMIT ?")
+            .bind(user)
+            .bind(limit)
+            .fetch_all(&mut self.connection)
+            .await
+    }
+
+    /// Compact the listings collected before the cutoff into the daily prices of each condition.
+    /// The refreshes are kept along with the changes recorded for them. Returns the number of
+    /// listings removed.
+    pub async fn compact_listings(&mut self, before: DateTime<Utc>) -> sqlx::Result<u64> {
+        let mut transaction = sqlx::Acquire::begin(&mut self.connection).await?;
+
+        let query = format!("{} WHERE r.datetime < ? {}", PRICE_POINT_QUERY, PRICE_POINT_GROUPING);
+        let points: Vec<PricePoint> = sqlx::query_as(&query)
+            .bind(before)
+            .fetch_all(&mut *transaction)
+            .await?;
+
+        for rollup in retention::roll_up(points.iter().map(Rollup::from), Resolution::Daily) {
+            add_rollup(&mut transaction, "Price_Daily", &rollup).await?;
+        }
+
+        let result = sqlx::query("
+            DELETE FROM Has_Listing_collected
+            WHERE RefreshID IN (SELECT RefreshID FROM For_Product_Data_Refresh WHERE datetime < ?)")
+            .bind(before)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Compact the daily prices of days before the cutoff into weekly prices. Returns the number
+    /// of days removed.
+    pub async fn compact_daily_prices(&mut self, before: DateTime<Utc>) -> sqlx::Result<u64> {
+        let mut transaction = sqlx::Acquire::begin(&mut self.connection).await?;
+
+        let query = format!("{} Price_Daily WHERE period < ?", ROLLUP_QUERY);
+        let days: Vec<Rollup> = sqlx::query_as(&query)
+            .bind(before)
+            .fetch_all(&mut *transaction)
+            .await?;
+
+        for rollup in retention::roll_up(days, Resolution::Weekly) {
+            add_rollup(&mut transaction, "Price_Weekly", &rollup).await?;
+        }
+
+        let result = sqlx::query("DELETE FROM Price_Daily WHERE period < ?")
+            .bind(before)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Every department with the first department it is listed under
+    pub async fn departments(&mut self) -> sqlx::Result<Vec<DepartmentNode>> {
+        sqlx::query_as("
+            SELECT
+                lower(hex(d.DepID)) AS id,
+                d.name AS name,
+                d.node AS node,
+                (SELECT lower(hex(aw.Category_DepID)) FROM Area_within aw WHERE aw.sub_DepID = d.DepID LIMIT 1) AS parent
+            FROM Department d
+            ORDER BY d.name")
+            .fetch_all(&mut self.connection)
+            .await
+    }
+
+    /// The products the user tracks with their department and manufacturer, by name
+    pub async fn tracked_catalog(&mut self, user: UserId) -> sqlx::Result<Vec<CatalogProduct>> {
+        sqlx::query_as("
+            SELECT
+                pvs.ASIN AS asin,
+                spm.name AS name,
+                lower(hex(spm.DepID)) AS department,
+                lower(hex(spm.ManuID)) AS manufacturer_id,
+                m.name AS manufacturer
+            FROM Tracks t
+            JOIN Sold_Product_Manufactured spm ON spm.PID = t.PID
+            JOIN Product_variant_Sold pvs ON pvs.PID = t.PID
+            LEFT JOIN Manufacturer m ON m.ManuID = spm.ManuID
+            WHERE t.sid = ?
+            ORDER BY spm.name, pvs.ASIN")
+            .bind(user)
+            .fetch_all(&mut self.connection)
+            .await
+    }
 }
@@ -4,6 +4,7 @@ use std::env;
 use std::env::VarError;
 use std::ffi::OsStr;
 use std::process::exit;
+use std::str::FromStr;
 
 /// Explicitly load .env file so we can log any issues
 pub fn setup_dotenv() {
@@ -38,3 +39,35 @@ pub fn var<K: AsRef<OsStr>>(key: K) -> String {
 
     exit(1)
 }
+
+/// Read an optional environment variable. Unlike [var], a missing variable is not treated as an
+/// error and the program will continue.
+pub fn optional_var<K: AsRef<OsStr>>(key: K) -> Option<String> {
+    match env::var(&key) {
+        Ok(v) => Some(v),
+        Err(VarError::NotPresent) => None,
+        Err(VarError::NotUnicode(_)) => {
+            let key_lossy = key.as_ref().to_string_lossy();
+            error!("Environment variable {} is not valid unicode", key_lossy);
+            None
+        }
+    }
+}
+
+/// Parse an optional environment variable, falling back to `default` if it is missing or can not
+/// be parsed.
+pub fn var_or<K: AsRef<OsStr>, T: FromStr>(key: K, default: T) -> T {
+    let value = match optional_var(&key) {
+        Some(v) => v,
+        None => return default,
+    };
+
+    match value.trim().parse() {
+        Ok(v) => v,
+        Err(_) => {
+            let key_lossy = key.as_ref().to_string_lossy();
+            error!("Unable to parse environment variable {}={:?}, using default", key_lossy, value);
+            default
+        }
+    }
+}
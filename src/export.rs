@@ -0,0 +1,182 @@
+use rocket::form::FromFormField;
+use rocket::http::{ContentType, Header};
+use rocket::response::{self, Responder};
+use rocket::{Request, Response};
+use serde::Serialize;
+use sqlx::FromRow;
+use std::io::Cursor;
+
+/// Column names of CSV exports. The header is written even when there are no rows.
+const CSV_HEADER: [&str; 8] = [
+    "asin", "name", "datetime", "condition", "price", "ships_from", "sold_by", "source",
+];
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, FromFormField)]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    #[field(value = "jsonl")]
+    JsonLines,
+    Json,
+}
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::JsonLines => "jsonl",
+            ExportFormat::Json => "json",
+        }
+    }
+
+    fn content_type(self) -> ContentType {
+        match self {
+            ExportFormat::Csv => ContentType::CSV,
+            ExportFormat::JsonLines => ContentType::new("application", "x-ndjson"),
+            ExportFormat::Json => ContentType::JSON,
+        }
+    }
+}
+
+/// A single listing collected for a product
+#[derive(Debug, FromRow, Serialize)]
+pub struct HistoryRow {
+    pub asin: String,
+    pub name: Option<String>,
+    pub datetime: String,
+    pub condition: Option<String>,
+    pub price: f64,
+    pub ships_from: Option<String>,
+    pub sold_by: Option<String>,
+    /// Whether the price was scraped from Amazon or imported from a tracker
+    pub source: String,
+}
+
+pub fn to_csv(rows: &[HistoryRow]) -> csv::Result<String> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(Vec::new());
+
+    writer.write_record(CSV_HEADER)?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+
+    let bytes = writer.into_inner().map_err(|e| e.into_error())?;
+    Ok(String::from_utf8(bytes).expect("CSV writer produced invalid UTF-8"))
+}
+
+pub fn to_json_lines(rows: &[HistoryRow]) -> serde_json::Result<String> {
+    let mut output = String::new();
+    for row in rows {
+        output.push_str(&serde_json::to_string(row)?);
+        output.push('\n');
+    }
+
+    Ok(output)
+}
+
+/// A file sent as an attachment so browsers save it instead of displaying it
+pub struct Download {
+    pub filename: String,
+    pub format: ExportFormat,
+    pub body: String,
+}
+
+impl Download {
+    /// Serialize the rows in the requested format. The extension is added to the filename.
+    pub fn new(filename: &str, format: ExportFormat, rows: &[HistoryRow]) -> crate::Result<Self> {
+        let body = match format {
+            ExportFormat::Csv => to_csv(rows).map_err(|e| e.to_string())?,
+            ExportFormat::JsonLines => to_json_lines(rows).map_err(|e| e.to_string())?,
+            ExportFormat::Json => serde_json::to_string_pretty(rows).map_err(|e| e.to_string())?,
+        };
+
+        Ok(Download {
+            filename: format!("{}.{}", filename, format.extension()),
+            format,
+            body,
+        })
+    }
+
+    /// Serialize any value as a JSON document
+    pub fn json<T: Serialize>(filename: &str, value: &T) -> crate::Result<Self> {
+        let body = serde_json::to_string_pretty(value).map_err(|e| e.to_string())?;
+
+        Ok(Download {
+            filename: format!("{}.json", filename),
+            format: ExportFormat::Json,
+            body,
+        })
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Download {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .header(self.format.content_type())
+            .header(Header::new(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", self.filename),
+            ))
+            .sized_body(self.body.len(), Cursor::new(self.body))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows() -> Vec<HistoryRow> {
+        vec![
+            HistoryRow {
+                asin: "B07VGRJDFY".to_string(),
+                name: Some("Nintendo Switch, Neon".to_string()),
+                datetime: "2023-04-01".to_string(),
+                condition: Some("New".to_string()),
+                price: 279.5,
+                ships_from: Some("Amazon.com".to_string()),
+                sold_by: Some("Amazon.com".to_string()),
+                source: "scraped".to_string(),
+            },
+            HistoryRow {
+                asin: "B07VGRJDFY".to_string(),
+                name: None,
+                datetime: "2023-04-02".to_string(),
+                condition: Some("Used".to_string()),
+                price: 199.0,
+                ships_from: None,
+                sold_by: Some("GameStop".to_string()),
+                source: "imported".to_string(),
+            },
+        ]
+    }
+
+    #[test]
+    pub fn csv_export() {
+        assert_eq!(
+            to_csv(&rows()).unwrap(),
+            "asin,name,datetime,condition,price,ships_from,sold_by,source\n\
+             B07VGRJDFY,\"Nintendo Switch, Neon\",2023-04-01,New,279.5,Amazon.com,Amazon.com,scraped\n\
+             B07VGRJDFY,,2023-04-02,Used,199.0,,GameStop,imported\n"
+        );
+
+        assert_eq!(to_csv(&[]).unwrap(), "asin,name,datetime,condition,price,ships_from,sold_by,source\n");
+    }
+
+    #[test]
+    pub fn json_lines_export() {
+        let output = to_json_lines(&rows()).unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["name"], "Nintendo Switch, Neon");
+        assert_eq!(lines[1]["name"], serde_json::Value::Null);
+        assert_eq!(lines[1]["sold_by"], "GameStop");
+        assert_eq!(to_json_lines(&[]).unwrap(), "");
+    }
+}
@@ -1,4 +1,5 @@
 use regex::Regex;
+use rocket::fs::TempFile;
 use rocket::{
     serde::{Deserialize, Serialize},
     FromForm,
@@ -15,12 +16,13 @@ pub struct UserCredentials<'a> {
 impl<'a> UserCredentials<'a> {
     /// Check if the given email appears to conform to the address format for RFC5322
     pub fn is_valid_email(&self) -> bool {
+        // Anchored so nothing, such as extra mail headers, can follow the address
         let email_regex = Regex::new("\
-(?:[a-z0-9!#$%&'*+/=?^_`{|}~-]+(?:\\.[a-z0-9!#$%&'*+/=?^_`{|}~-]+)*|\"(?:[\x01-\x08\x0b\x0c\x0e-\x1f\
+(?i)^(?:[a-z0-9!#$%&'*+/=?^_`{|}~-]+(?:\\.[a-z0-9!#$%&'*+/=?^_`{|}~-]+)*|\"(?:[\x01-\x08\x0b\x0c\x0e-\x1f\
 \x21\x23-\x5b\x5d-\x7f]|\\[\x01-\x09\x0b\x0c\x0e-\x7f])*\")@(?:(?:[a-z0-9](?:[a-z0-9-]*[a-z0-9])?\\.\
 )+[a-z0-9](?:[a-z0-9-]*[a-z0-9])?|\\[(?:(?:(2(5[0-5]|[0-4][0-9])|1[0-9][0-9]|[1-9]?[0-9]))\\.){3}(?:\
 (2(5[0-5]|[0-4][0-9])|1[0-9][0-9]|[1-9]?[0-9])|[a-z0-9-]*[a-z0-9]:(?:[\x01-\x08\x0b\x0c\x0e-\x1f\x21\
--\x5a\x53-\x7f]|\\[\x01-\x09\x0b\x0c\x0e-\x7f])+)\\])").expect("Input is valid regex");
+-\x5a\x53-\x7f]|\\[\x01-\x09\x0b\x0c\x0e-\x7f])+)\\])$").expect("Input is valid regex");
 
         email_regex.is_match(self.email)
     }
@@ -49,8 +51,67 @@ impl<'a> UserCredentials<'a> {
     }
 }
 
+#[derive(FromForm)]
+pub struct EmailForm<'a> {
+    /// Form for requesting a password reset or another verification email
+    pub email: &'a str,
+}
+
+#[derive(FromForm)]
+pub struct ResetPasswordForm<'a> {
+    /// Form for choosing a new password with the token from a reset email
+    pub token: &'a str,
+    pub password: &'a str,
+}
+
+#[derive(FromForm)]
+pub struct TwoFactorCodeForm<'a> {
+    /// Form for entering a code from an authenticator app, or a recovery code
+    pub code: &'a str,
+}
+
+#[derive(FromForm)]
+pub struct DeleteAccountForm<'a> {
+    /// Form for deleting the signed in account, which must be confirmed with its password
+    pub password: &'a str,
+}
+
+#[derive(FromForm)]
+pub struct MergeForm<'a> {
+    /// Form for merging a duplicate manufacturer or company into another
+    pub duplicate: &'a str,
+    pub into: &'a str,
+}
+
 #[derive(FromForm, Serialize, Deserialize)]
 pub struct AmazonURLForm<'a> {
     /// Form for logging in the web app
     pub url: &'a str,
 }
+
+#[derive(FromForm)]
+pub struct ProductSelectionForm {
+    /// Form for tracking the products selected from the search results
+    pub asin: Vec<String>,
+}
+
+#[derive(FromForm)]
+pub struct UpdateProductForm<'a> {
+    /// Form for refreshing the listings of a product. Forcing an update skips the response cache.
+    pub asin: &'a str,
+    pub force: bool,
+}
+
+#[derive(FromForm)]
+pub struct ImportUpload<'r> {
+    /// Form for bulk importing products. Either a file or pasted text may be given.
+    pub file: Option<TempFile<'r>>,
+    pub text: Option<&'r str>,
+}
+
+#[derive(FromForm)]
+pub struct HistoryUpload<'r> {
+    /// Form for importing the price history of a tracked product from a third-party tracker
+    pub asin: &'r str,
+    pub file: TempFile<'r>,
+}
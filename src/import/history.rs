@@ -0,0 +1,286 @@
+use crate::import::list::parse_price;
+use crate::import::ImportError;
+use crate::scraper::price::PriceUSD;
+use chrono::NaiveDate;
+use csv::{ReaderBuilder, StringRecord, Trim};
+use std::collections::BTreeMap;
+
+/// Header names recognized for the date column
+const DATE_HEADERS: [&str; 4] = ["date", "time", "timestamp", "datetime"];
+
+/// Header names recognized for the price type column of long layouts
+const TYPE_HEADERS: [&str; 4] = ["type", "price type", "series", "seller"];
+
+/// Header names recognized for the price column of long layouts
+const PRICE_HEADERS: [&str; 2] = ["price", "amount"];
+
+/// The price histories kept by third-party trackers. Each is stored as listings with the condition
+/// and seller it represents.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum PriceSeries {
+    /// Sold and shipped by Amazon
+    Amazon,
+    /// The lowest new offer from other sellers
+    New,
+    /// The lowest used offer from other sellers
+    Used,
+}
+
+impl PriceSeries {
+    /// Recognizes the series names used by Keepa ("Amazon", "New", "Used: Current") and
+    /// camelcamelcamel ("Amazon", "3rd Party New", "3rd Party Used")
+    fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+
+        if name.contains("used") {
+            Some(PriceSeries::Used)
+        } else if name.starts_with("amazon") {
+            Some(PriceSeries::Amazon)
+        } else if name.contains("new") {
+            Some(PriceSeries::New)
+        } else {
+            None
+        }
+    }
+
+    /// The condition stored for the listing. Trackers do not report the grade of used offers.
+    pub fn condition(self) -> &'static str {
+        match self {
+            PriceSeries::Amazon | PriceSeries::New => "New",
+            PriceSeries::Used => "Used",
+        }
+    }
+
+    /// The company stored as both the seller and shipper of the listing
+    pub fn seller(self) -> &'static str {
+        match self {
+            PriceSeries::Amazon => "Amazon.com",
+            PriceSeries::New | PriceSeries::Used => "Third party",
+        }
+    }
+}
+
+/// A single day's price from a tracker export
+#[derive(Debug, Eq, PartialEq)]
+pub struct HistoryPoint {
+    pub date: NaiveDate,
+    pub series: PriceSeries,
+    pub price: PriceUSD,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PriceHistory {
+    pub points: Vec<HistoryPoint>,
+    /// Rows with a date or price that could not be read
+    pub skipped: usize,
+}
+
+/// How the columns of an export are arranged
+#[derive(Debug, Eq, PartialEq)]
+enum Layout {
+    /// Keepa-style, with one row per timestamp and a column for each series
+    Wide {
+        date: usize,
+        series: Vec<(usize, PriceSeries)>,
+    },
+    /// camelcamelcamel-style, with one row per price and a column naming its series. Exports
+    /// without a type column only contain Amazon's price.
+    Long {
+        date: usize,
+        series: Option<usize>,
+        price: usize,
+    },
+}
+
+/// Read a price history exported from a third-party tracker. Trackers may record several prices a
+/// day, but only the last price of each day is kept for each series.
+pub fn parse_history(contents: &str) -> Result<PriceHistory, ImportError> {
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(Trim::All)
+        .from_reader(contents.as_bytes());
+
+    let mut records = reader.records();
+    let layout = match records.next() {
+        Some(header) => {
+            let header = header.map_err(|e| ImportError::Csv(e.to_string()))?;
+            parse_layout(&header).ok_or(ImportError::UnknownLayout)?
+        }
+        None => return Err(ImportError::NoPrices),
+    };
+
+    let mut days = BTreeMap::new();
+    let mut skipped = 0;
+
+    for record in records {
+        let record = record.map_err(|e| ImportError::Csv(e.to_string()))?;
+        if record.iter().all(str::is_empty) {
+            continue;
+        }
+
+        let date = match record.get(layout.date_column()).and_then(parse_date) {
+            Some(date) => date,
+            None => {
+                skipped += 1;
+                continue;
+            }
+        };
+
+        for (series, price) in layout.prices(&record) {
+            // Missing prices are written as an empty cell or a dash while the product was unavailable
+            if price.is_empty() || price == "-" {
+                continue;
+            }
+
+            match (series, parse_price(price)) {
+                (Some(series), Some(price)) => {
+                    days.insert((date, series), price);
+                }
+                _ => skipped += 1,
+            }
+        }
+    }
+
+    if days.is_empty() {
+        return Err(ImportError::NoPrices);
+    }
+
+    Ok(PriceHistory {
+        points: days
+            .into_iter()
+            .map(|((date, series), price)| HistoryPoint { date, series, price })
+            .collect(),
+        skipped,
+    })
+}
+
+impl Layout {
+    fn date_column(&self) -> usize {
+        match self {
+            Layout::Wide { date, .. } | Layout::Long { date, .. } => *date,
+        }
+    }
+
+    /// The price cells of a row along with the series they belong to
+    fn prices<'a>(&self, record: &'a StringRecord) -> Vec<(Option<PriceSeries>, &'a str)> {
+        match self {
+            Layout::Wide { series, .. } => series
+                .iter()
+                .filter_map(|(column, series)| Some((Some(*series), record.get(*column)?)))
+                .collect(),
+            Layout::Long { series, price, .. } => {
+                let series = match series {
+                    Some(column) => record.get(*column).and_then(PriceSeries::from_name),
+                    None => Some(PriceSeries::Amazon),
+                };
+
+                record.get(*price).map(|price| (series, price)).into_iter().collect()
+            }
+        }
+    }
+}
+
+fn parse_layout(header: &StringRecord) -> Option<Layout> {
+    let names: Vec<String> = header.iter().map(str::to_ascii_lowercase).collect();
+    let find = |options: &[&str]| names.iter().position(|name| options.contains(&name.as_str()));
+
+    let date = find(&DATE_HEADERS)?;
+
+    if let Some(price) = find(&PRICE_HEADERS) {
+        return Some(Layout::Long {
+            date,
+            series: find(&TYPE_HEADERS),
+            price,
+        });
+    }
+
+    let series: Vec<_> = names
+        .iter()
+        .enumerate()
+        .filter(|(column, _)| *column != date)
+        .filter_map(|(column, name)| Some((column, PriceSeries::from_name(name)?)))
+        .collect();
+
+    if series.is_empty() {
+        return None;
+    }
+
+    Some(Layout::Wide { date, series })
+}
+
+/// Dates may be followed by a time, which is ignored since prices are collected daily
+fn parse_date(text: &str) -> Option<NaiveDate> {
+    let date = text.split([' ', 'T']).next()?;
+
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .or_else(|_| NaiveDate::parse_from_str(date, "%m/%d/%Y"))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2023, 4, day).unwrap()
+    }
+
+    #[test]
+    pub fn keepa_layout() {
+        let history = parse_history(
+            "Time,Amazon: Price,New: Current,Used: Current,Sales Rank\n\
+             2023-04-01 08:15,$279.99,$265.00,-,1200\n\
+             2023-04-01 19:40,$274.99,,,1180\n\
+             2023-04-02 10:00,-,\"$1,265.00\",$210.50,1300\n\
+             yesterday,$1.00,,,\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            history.points,
+            vec![
+                HistoryPoint { date: day(1), series: PriceSeries::Amazon, price: PriceUSD::new(274, 99) },
+                HistoryPoint { date: day(1), series: PriceSeries::New, price: PriceUSD::new(265, 0) },
+                HistoryPoint { date: day(2), series: PriceSeries::New, price: PriceUSD::new(1265, 0) },
+                HistoryPoint { date: day(2), series: PriceSeries::Used, price: PriceUSD::new(210, 50) },
+            ]
+        );
+        assert_eq!(history.skipped, 1);
+    }
+
+    #[test]
+    pub fn camelcamelcamel_layout() {
+        let history = parse_history(
+            "Date,Price Type,Price\n\
+             04/01/2023,Amazon,279.99\n\
+             04/01/2023,3rd Party New,269.00\n\
+             04/02/2023,3rd Party Used,199.95\n\
+             04/02/2023,Refurbished,150.00\n",
+        )
+        .unwrap();
+
+        let series: Vec<_> = history.points.iter().map(|point| (point.date, point.series)).collect();
+        assert_eq!(
+            series,
+            vec![
+                (day(1), PriceSeries::Amazon),
+                (day(1), PriceSeries::New),
+                (day(2), PriceSeries::Used),
+            ]
+        );
+        assert_eq!(history.skipped, 1);
+
+        // Without a type column every price is Amazon's
+        let history = parse_history("date,price\n2023-04-01,279.99\n").unwrap();
+        assert_eq!(history.points[0].series, PriceSeries::Amazon);
+    }
+
+    #[test]
+    pub fn unrecognized_exports() {
+        assert_eq!(parse_history(""), Err(ImportError::NoPrices));
+        assert_eq!(parse_history("Date,Sales Rank\n2023-04-01,1200\n"), Err(ImportError::UnknownLayout));
+        assert_eq!(parse_history("B07VGRJDFY,279.99\n"), Err(ImportError::UnknownLayout));
+        assert_eq!(parse_history("Date,Amazon\n2023-04-01,-\n"), Err(ImportError::NoPrices));
+    }
+}
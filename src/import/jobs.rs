@@ -0,0 +1,230 @@
+use crate::database::Connection;
+use crate::error::Error;
+use crate::import::ImportRow;
+use crate::scraper::identifier::Marketplace;
+use crate::scraper::AmazonApi;
+use crate::session::UserId;
+use crate::tracking::track_new_product;
+use chrono::{DateTime, Duration, Utc};
+use log::{error, info};
+use serde::Serialize;
+use sqlx::types::Uuid;
+use sqlx::{Pool, Sqlite};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// How long finished imports are kept so their results can still be viewed
+const FINISHED_RETENTION_HOURS: i64 = 1;
+
+/// Imports which are in progress or have recently finished. Imports are only kept in memory, so
+/// they do not survive a restart.
+#[derive(Default)]
+pub struct ImportJobs {
+    jobs: Mutex<HashMap<Uuid, Arc<Mutex<ImportJob>>>>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ImportJob {
+    #[serde(skip)]
+    owner: UserId,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub rows: Vec<RowStatus>,
+}
+
+/// The progress of an import without its rows, as listed for administrators
+#[derive(Serialize)]
+pub struct ImportSummary {
+    pub id: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub rows: usize,
+    pub completed: usize,
+}
+
+#[derive(Clone, Serialize)]
+pub struct RowStatus {
+    pub line: usize,
+    pub input: String,
+    pub target_price: Option<f32>,
+    #[serde(flatten)]
+    pub state: RowState,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum RowState {
+    Pending,
+    Added { asin: String },
+    Failed { reason: String },
+}
+
+impl ImportJob {
+    pub fn is_finished(&self) -> bool {
+        self.finished_at.is_some()
+    }
+
+    /// The number of rows which are no longer pending
+    pub fn completed(&self) -> usize {
+        self.rows
+            .iter()
+            .filter(|row| !matches!(row.state, RowState::Pending))
+            .count()
+    }
+}
+
+impl ImportJobs {
+    /// Start importing the rows in the background and return the id used to check on its progress
+    pub fn start(
+        &self,
+        owner: UserId,
+        rows: Vec<ImportRow>,
+        pool: Pool<Sqlite>,
+        amazon_api: AmazonApi,
+    ) -> Uuid {
+        let job = ImportJob {
+            owner,
+            started_at: Utc::now(),
+            finished_at: None,
+            rows: rows
+                .iter()
+                .map(|row| RowStatus {
+                    line: row.line,
+                    input: row.input.clone(),
+                    target_price: row.target_price.map(f32::from),
+                    state: RowState::Pending,
+                })
+                .collect(),
+        };
+
+        let id = Uuid::new_v4();
+        let job = Arc::new(Mutex::new(job));
+
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, job| !is_expired(&job.lock().unwrap()));
+        jobs.insert(id, job.clone());
+        drop(jobs);
+
+        info!("Starting import {} of {} products", id, rows.len());
+        tokio::spawn(run_import(id, job, owner, rows, pool, amazon_api));
+        id
+    }
+
+    /// Get a snapshot of an import. Imports can only be viewed by the user who started them.
+    pub fn get(&self, id: Uuid, user: UserId) -> Option<ImportJob> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(&id)?.lock().unwrap();
+
+        if *job.owner != *user {
+            return None;
+        }
+
+        Some(job.clone())
+    }
+
+    /// The progress of every import of any user, most recently started first
+    pub fn summaries(&self) -> Vec<ImportSummary> {
+        let jobs = self.jobs.lock().unwrap();
+        let mut summaries: Vec<_> = jobs
+            .iter()
+            .map(|(id, job)| {
+                let job = job.lock().unwrap();
+                ImportSummary {
+                    id: id.simple().to_string(),
+                    started_at: job.started_at,
+                    finished_at: job.finished_at,
+                    rows: job.rows.len(),
+                    completed: job.completed(),
+                }
+            })
+            .collect();
+
+        summaries.sort_by_key(|summary| Reverse(summary.started_at));
+        summaries
+    }
+}
+
+fn is_expired(job: &ImportJob) -> bool {
+    match job.finished_at {
+        Some(finished_at) => Utc::now() - finished_at > Duration::hours(FINISHED_RETENTION_HOURS),
+        None => false,
+    }
+}
+
+async fn run_import(
+    id: Uuid,
+    job: Arc<Mutex<ImportJob>>,
+    owner: UserId,
+    rows: Vec<ImportRow>,
+    pool: Pool<Sqlite>,
+    amazon_api: AmazonApi,
+) {
+    let mut database = match pool.acquire().await {
+        Ok(connection) => Some(Connection::from(connection)),
+        Err(e) => {
+            error!("Unable to acquire database connection for import {}: {}", id, e);
+            None
+        }
+    };
+
+    for (index, row) in rows.iter().enumerate() {
+        let state = match &mut database {
+            Some(database) => import_row(owner, database, &amazon_api, row).await,
+            None => RowState::Failed {
+                reason: "Unable to connect to the database".to_string(),
+            },
+        };
+
+        job.lock().unwrap().rows[index].state = state;
+    }
+
+    let mut job = job.lock().unwrap();
+    job.finished_at = Some(Utc::now());
+
+    let added = job
+        .rows
+        .iter()
+        .filter(|row| matches!(row.state, RowState::Added { .. }))
+        .count();
+    info!("Finished import {}: {} of {} products added", id, added, job.rows.len());
+}
+
+async fn import_row(
+    owner: UserId,
+    database: &mut Connection<Sqlite>,
+    amazon_api: &AmazonApi,
+    row: &ImportRow,
+) -> RowState {
+    let failed = |reason: &str| RowState::Failed {
+        reason: reason.to_string(),
+    };
+
+    if let Some(problem) = row.problem {
+        return failed(problem);
+    }
+
+    let identifier = match amazon_api.resolve_identifier(&row.input).await {
+        Ok(Some(identifier)) => identifier,
+        Ok(None) => return failed("Not an Amazon product URL, ASIN or ISBN"),
+        Err(e) => return failed(&format!("Unable to reach Amazon: {}", e)),
+    };
+
+    if identifier.marketplace != Marketplace::UnitedStates {
+        return failed("Only products on amazon.com can be tracked");
+    }
+
+    match track_new_product(owner, database, amazon_api, &identifier.asin, row.target_price).await {
+        Ok(true) => RowState::Added {
+            asin: identifier.asin,
+        },
+        Ok(false) => failed("Product not found"),
+        Err(Error::ScraperError(e)) => failed(&format!("Unable to reach Amazon: {}", e)),
+        Err(Error::BadRequest(reason)) => failed(&reason),
+        Err(Error::SqlError(e)) => {
+            error!("Encountered SQLx error while importing {}: {}", row.input, e);
+            failed("Unable to save the product")
+        }
+        Err(_) => failed("Unable to add the product"),
+    }
+}
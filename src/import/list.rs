@@ -0,0 +1,201 @@
+use crate::import::{ImportError, ImportRow};
+use crate::scraper::price::PriceUSD;
+use crate::scraper::ProductIdentifier;
+use csv::{ReaderBuilder, StringRecord, Trim};
+use std::str::FromStr;
+
+/// Header names recognized for the product column
+const PRODUCT_HEADERS: [&str; 7] = ["url", "link", "asin", "isbn", "product", "identifier", "id"];
+
+/// Header names recognized for the target price column
+const PRICE_HEADERS: [&str; 4] = ["target_price", "target price", "target", "price"];
+
+/// The columns holding each value
+#[derive(Debug, Eq, PartialEq)]
+struct Columns {
+    product: usize,
+    target_price: Option<usize>,
+}
+
+/// Without a header, the first column is the product and the second is the target price
+const DEFAULT_COLUMNS: Columns = Columns {
+    product: 0,
+    target_price: Some(1),
+};
+
+/// Read a CSV file or a plain list with one product per line. Lines starting with `#` are ignored.
+pub fn parse_list(contents: &str) -> Result<Vec<ImportRow>, ImportError> {
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(Trim::All)
+        .from_reader(contents.as_bytes());
+
+    let mut columns = None;
+    let mut rows = Vec::new();
+
+    for record in reader.records() {
+        let record = record.map_err(|e| ImportError::Csv(e.to_string()))?;
+
+        // The reader's own comment handling would hide these lines from the line count below
+        if matches!(record.get(0), Some(cell) if cell.starts_with('#')) {
+            continue;
+        }
+
+        let columns = match &columns {
+            Some(columns) => columns,
+            None => {
+                let header = parse_header(&record);
+                let is_header = header.is_some();
+                let columns = columns.insert(header.unwrap_or(DEFAULT_COLUMNS));
+
+                if is_header {
+                    continue;
+                }
+
+                columns
+            }
+        };
+
+        let input = match record.get(columns.product) {
+            Some(input) if !input.is_empty() => input.to_string(),
+            _ => continue,
+        };
+
+        let line = match record.position() {
+            Some(position) => line_number(contents, position.byte() as usize),
+            None => rows.len() + 1,
+        };
+
+        let mut row = ImportRow {
+            line,
+            input,
+            target_price: None,
+            problem: None,
+        };
+
+        let target_price = columns.target_price.and_then(|column| record.get(column));
+        match target_price.filter(|price| !price.is_empty()).map(parse_price) {
+            Some(Some(price)) => row.target_price = Some(price),
+            Some(None) => row.problem = Some("Target price must be a number such as 24.99"),
+            None => {}
+        }
+
+        rows.push(row);
+    }
+
+    Ok(rows)
+}
+
+/// The reader does not count blank lines, so the line is found from the byte offset of the record
+/// instead. Records which follow blank lines start at the first of those lines.
+fn line_number(contents: &str, offset: usize) -> usize {
+    let bytes = contents.as_bytes();
+    let start = offset
+        + bytes[offset..]
+            .iter()
+            .take_while(|byte| **byte == b'\n' || **byte == b'\r')
+            .count();
+
+    bytes[..start].iter().filter(|byte| **byte == b'\n').count() + 1
+}
+
+/// A row is a header if none of its cells are products and it names the product column
+fn parse_header(record: &StringRecord) -> Option<Columns> {
+    if record.iter().any(|cell| ProductIdentifier::parse(cell).is_ok()) {
+        return None;
+    }
+
+    let find = |names: &[&str]| {
+        record
+            .iter()
+            .position(|cell| names.contains(&cell.to_ascii_lowercase().as_str()))
+    };
+
+    Some(Columns {
+        product: find(&PRODUCT_HEADERS)?,
+        target_price: find(&PRICE_HEADERS),
+    })
+}
+
+/// Prices may be written with a currency symbol and thousands separators
+pub(super) fn parse_price(text: &str) -> Option<PriceUSD> {
+    let text: String = text.chars().filter(|c| *c != ',').collect();
+    let text = text.trim_start_matches('$');
+
+    // A single digit after the decimal point is tenths rather than cents
+    let price = match text.split_once('.') {
+        Some((dollars, cents)) if cents.len() == 1 => PriceUSD::from_str(&format!("{}.{}0", dollars, cents)),
+        _ => PriceUSD::from_str(text),
+    };
+
+    price.ok().filter(|price| f64::from(*price) > 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn plain_list() {
+        let rows = parse_list(
+            "# Products for the new office\n\
+             https://www.amazon.com/dp/B07VGRJDFY\n\
+             \n\
+             B096S3QHWL\n",
+        )
+        .unwrap();
+
+        let inputs: Vec<_> = rows.iter().map(|row| (row.line, row.input.as_str())).collect();
+        assert_eq!(
+            inputs,
+            vec![(2, "https://www.amazon.com/dp/B07VGRJDFY"), (4, "B096S3QHWL")]
+        );
+        assert!(rows.iter().all(|row| row.target_price.is_none() && row.problem.is_none()));
+    }
+
+    #[test]
+    pub fn csv_with_header() {
+        let rows = parse_list(
+            "Name,Target Price,URL\n\
+             Switch,$279.5,https://www.amazon.com/dp/B07VGRJDFY\n\
+             Bed,,B096S3QHWL\n\
+             Book,cheap,0316769177\n",
+        )
+        .unwrap();
+
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].input, "https://www.amazon.com/dp/B07VGRJDFY");
+        assert_eq!(rows[0].target_price, Some(PriceUSD::new(279, 50)));
+        assert_eq!(rows[1].target_price, None);
+        assert_eq!(rows[1].problem, None);
+        assert!(rows[2].problem.is_some());
+    }
+
+    #[test]
+    pub fn csv_without_header() {
+        let rows = parse_list("B07VGRJDFY, 1,299.99\n\"B096S3QHWL\",\"1,299.99\"\n").unwrap();
+
+        // Unquoted thousands separators split the price into another column
+        assert_eq!(rows[0].target_price, Some(PriceUSD::new(1, 0)));
+        assert_eq!(rows[1].target_price, Some(PriceUSD::new(1299, 99)));
+    }
+
+    #[test]
+    pub fn header_detection() {
+        let record = StringRecord::from(vec!["ASIN", "price"]);
+        assert_eq!(
+            parse_header(&record),
+            Some(Columns {
+                product: 0,
+                target_price: Some(1)
+            })
+        );
+
+        let record = StringRecord::from(vec!["B07VGRJDFY", "price"]);
+        assert_eq!(parse_header(&record), None);
+
+        let record = StringRecord::from(vec!["notes"]);
+        assert_eq!(parse_header(&record), None);
+    }
+}
@@ -0,0 +1,81 @@
+use crate::scraper::price::PriceUSD;
+use std::fmt::{self, Display, Formatter};
+
+pub mod history;
+pub mod jobs;
+mod list;
+mod wishlist;
+
+pub use jobs::ImportJobs;
+
+/// The most rows accepted in a single upload. Every row requires several requests to Amazon, so
+/// larger imports should be split up.
+pub const MAX_IMPORT_ROWS: usize = 500;
+
+/// A single product to be tracked from an upload
+#[derive(Debug, PartialEq)]
+pub struct ImportRow {
+    /// The line or item number in the upload, starting at 1
+    pub line: usize,
+    /// A product URL, ASIN or ISBN
+    pub input: String,
+    pub target_price: Option<PriceUSD>,
+    /// Set when the row could be read but is not usable, such as an unreadable target price
+    pub problem: Option<&'static str>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ImportError {
+    Empty,
+    TooManyRows(usize),
+    Csv(String),
+    UnknownLayout,
+    NoPrices,
+}
+
+impl Display for ImportError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Empty => write!(f, "No products were found in the upload"),
+            ImportError::TooManyRows(rows) => write!(
+                f,
+                "The upload contains {} products, but at most {} can be imported at once",
+                rows, MAX_IMPORT_ROWS
+            ),
+            ImportError::Csv(e) => write!(f, "Unable to read the upload as CSV: {}", e),
+            ImportError::UnknownLayout => write!(
+                f,
+                "The columns were not recognized. Price histories need a date column and either a \
+                 price column or a column for each of the Amazon, new and used prices"
+            ),
+            ImportError::NoPrices => write!(f, "No prices were found in the price history"),
+        }
+    }
+}
+
+/// Read the rows of an upload. Saved wishlist pages are recognized by their HTML, everything else
+/// is read as a CSV file or a plain list with one product per line.
+pub fn parse_upload(contents: &str) -> Result<Vec<ImportRow>, ImportError> {
+    let rows = if is_html(contents) {
+        wishlist::parse_wishlist(contents)
+    } else {
+        list::parse_list(contents)?
+    };
+
+    match rows.len() {
+        0 => Err(ImportError::Empty),
+        n if n > MAX_IMPORT_ROWS => Err(ImportError::TooManyRows(n)),
+        _ => Ok(rows),
+    }
+}
+
+fn is_html(contents: &str) -> bool {
+    let start = contents
+        .trim_start()
+        .chars()
+        .take(256)
+        .collect::<String>()
+        .to_ascii_lowercase();
+
+    start.starts_with("<!doctype html") || start.starts_with("<html") || start.contains("<head")
+}
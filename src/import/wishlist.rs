@@ -0,0 +1,67 @@
+use crate::import::ImportRow;
+use crate::scraper::rules::{self, WishlistRules};
+use select::document::Document;
+
+/// Links on a saved wishlist page are relative to the site they were saved from
+const BASE_URL: &str = "https://www.amazon.com";
+
+/// Read the items from a wishlist page saved from the browser
+pub fn parse_wishlist(contents: &str) -> Vec<ImportRow> {
+    let document = Document::from(contents);
+    wishlist_items(&document, &rules::current().wishlist)
+}
+
+fn wishlist_items(document: &Document, rules: &WishlistRules) -> Vec<ImportRow> {
+    rules
+        .container
+        .nodes(document)
+        .into_iter()
+        .filter_map(|node| rules.link.first(node))
+        .enumerate()
+        .map(|(index, link)| ImportRow {
+            line: index + 1,
+            input: if link.starts_with('/') {
+                format!("{}{}", BASE_URL, link)
+            } else {
+                link
+            },
+            target_price: None,
+            problem: None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scraper::rules::ExtractionRules;
+
+    #[test]
+    pub fn wishlist_links() {
+        let rules = ExtractionRules::default();
+        let document = Document::from(
+            "<ul id=\"g-items\">
+                <li class=\"a-spacing-none g-item-sortable\" data-itemid=\"I1\">
+                    <div class=\"g-itemImage\"><a href=\"/dp/B07VGRJDFY/?coliid=I1&colid=L1\"><img></a></div>
+                    <h2><a id=\"itemName_I1\" href=\"/dp/B07VGRJDFY/?coliid=I1&colid=L1\">Nintendo Switch</a></h2>
+                </li>
+                <li class=\"a-spacing-none g-item-sortable\" data-itemid=\"I2\">
+                    <div class=\"g-itemImage\"><a href=\"https://www.amazon.com/dp/0316769177\"><img></a></div>
+                </li>
+                <li class=\"a-spacing-none g-item-sortable\" data-itemid=\"I3\">
+                    <span>This item is no longer available</span>
+                </li>
+            </ul>",
+        );
+
+        let rows = wishlist_items(&document, &rules.wishlist);
+        let inputs: Vec<_> = rows.iter().map(|row| (row.line, row.input.as_str())).collect();
+        assert_eq!(
+            inputs,
+            vec![
+                (1, "https://www.amazon.com/dp/B07VGRJDFY/?coliid=I1&colid=L1"),
+                (2, "https://www.amazon.com/dp/0316769177"),
+            ]
+        );
+    }
+}
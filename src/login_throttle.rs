@@ -0,0 +1,237 @@
+use crate::database::Connection;
+use crate::env::var_or;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{FromRow, Sqlite};
+use std::net::IpAddr;
+
+/// Failures are counted separately for each email address and client IP
+const EMAIL: &str = "email";
+const IP: &str = "ip";
+
+/// Many users may share an address behind a NAT, so addresses are allowed this many times as many
+/// failures as a single email address
+const IP_ALLOWANCE: u32 = 5;
+
+/// Limits on failed logins for a single email address or client IP. Like
+/// [crate::scraper::rate_limit::RateLimit], attempts are not allowed until a cool down has passed
+/// since the previous failure, but here the cool down doubles with each further failure.
+#[derive(Debug, Copy, Clone)]
+pub struct LoginPolicy {
+    /// Failures allowed before further attempts are delayed
+    pub free_attempts: u32,
+    /// Delay after the last free attempt fails
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Failures after which attempts are refused until the lockout has passed
+    pub lockout_after: u32,
+    pub lockout: Duration,
+    /// Failures are forgotten when none have happened for this long
+    pub reset_after: Duration,
+}
+
+/// Failed logins stored for an email address or client IP
+#[derive(Debug, Copy, Clone, Eq, PartialEq, FromRow)]
+pub struct FailedLogins {
+    pub failures: u32,
+    pub last_failure: DateTime<Utc>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Verdict {
+    Allowed,
+    /// Too many recent failures, so attempts are not allowed until the given time
+    Delayed(DateTime<Utc>),
+    /// Attempts are refused until the given time
+    LockedOut(DateTime<Utc>),
+}
+
+impl LoginPolicy {
+    /// Decide if another attempt is allowed after the given failures
+    pub fn check(&self, failed: Option<FailedLogins>, now: DateTime<Utc>) -> Verdict {
+        let failed = match self.current(failed, now) {
+            Some(failed) => failed,
+            None => return Verdict::Allowed,
+        };
+
+        if failed.failures >= self.lockout_after {
+            return Verdict::LockedOut(failed.last_failure + self.lockout);
+        }
+
+        match self.delay(failed.failures) {
+            Some(delay) if now < failed.last_failure + delay => Verdict::Delayed(failed.last_failure + delay),
+            _ => Verdict::Allowed,
+        }
+    }
+
+    /// The delay following the given number of failures, doubling after each failure past the
+    /// free attempts
+    fn delay(&self, failures: u32) -> Option<Duration> {
+        let doublings = failures.checked_sub(self.free_attempts)?;
+        let factor = 1i32.checked_shl(doublings).filter(|factor| *factor > 0).unwrap_or(i32::MAX);
+
+        Some(self.base_delay.checked_mul(factor).unwrap_or(self.max_delay).min(self.max_delay))
+    }
+
+    /// Failures are no longer counted once they have been reset or a lockout has passed
+    fn current(&self, failed: Option<FailedLogins>, now: DateTime<Utc>) -> Option<FailedLogins> {
+        failed.filter(|failed| {
+            let locked_out = failed.failures >= self.lockout_after;
+
+            now < failed.last_failure + self.reset_after
+                && !(locked_out && now >= failed.last_failure + self.lockout)
+        })
+    }
+}
+
+impl Verdict {
+    /// Of two verdicts, the one which blocks attempts for longer
+    fn stricter(self, other: Verdict) -> Verdict {
+        match (self, other) {
+            (Verdict::LockedOut(a), Verdict::LockedOut(b)) => Verdict::LockedOut(a.max(b)),
+            (Verdict::LockedOut(_), _) => self,
+            (_, Verdict::LockedOut(_)) => other,
+            (Verdict::Delayed(a), Verdict::Delayed(b)) => Verdict::Delayed(a.max(b)),
+            (Verdict::Delayed(_), _) => self,
+            _ => other,
+        }
+    }
+}
+
+/// Limits failed logins by both the email address being signed in to and the client's address
+pub struct LoginThrottle {
+    pub email: LoginPolicy,
+    pub ip: LoginPolicy,
+}
+
+impl LoginThrottle {
+    pub fn from_env() -> Self {
+        let email = LoginPolicy {
+            free_attempts: var_or("LOGIN_FREE_ATTEMPTS", 3),
+            base_delay: Duration::seconds(1),
+            max_delay: Duration::minutes(1),
+            lockout_after: var_or("LOGIN_LOCKOUT_ATTEMPTS", 10),
+            lockout: Duration::minutes(var_or("LOGIN_LOCKOUT_MINUTES", 15)),
+            reset_after: Duration::days(1),
+        };
+
+        let ip = LoginPolicy {
+            free_attempts: email.free_attempts * IP_ALLOWANCE,
+            lockout_after: email.lockout_after * IP_ALLOWANCE,
+            ..email
+        };
+
+        LoginThrottle { email, ip }
+    }
+
+    /// Check if the client may attempt to sign in to the account
+    pub async fn check(
+        &self,
+        database: &mut Connection<Sqlite>,
+        email: &str,
+        ip: Option<IpAddr>,
+        now: DateTime<Utc>,
+    ) -> sqlx::Result<Verdict> {
+        let email = email_key(email);
+        let mut verdict = self.email.check(database.failed_logins(EMAIL, &email).await?, now);
+
+        if let Some(ip) = ip {
+            let failed = database.failed_logins(IP, &ip.to_string()).await?;
+            verdict = verdict.stricter(self.ip.check(failed, now));
+        }
+
+        Ok(verdict)
+    }
+
+    pub async fn record_failure(
+        &self,
+        database: &mut Connection<Sqlite>,
+        email: &str,
+        ip: Option<IpAddr>,
+        now: DateTime<Utc>,
+    ) -> sqlx::Result<()> {
+        database.add_failed_login(EMAIL, &email_key(email), &self.email, now).await?;
+        if let Some(ip) = ip {
+            database.add_failed_login(IP, &ip.to_string(), &self.ip, now).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Forget the failures for the account. Failures from the client's address are kept, otherwise
+    /// signing in to an account of their own would let an attacker keep guessing passwords.
+    pub async fn record_success(&self, database: &mut Connection<Sqlite>, email: &str) -> sqlx::Result<()> {
+        database.clear_failed_logins(EMAIL, &email_key(email)).await
+    }
+}
+
+fn email_key(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn policy() -> LoginPolicy {
+        LoginPolicy {
+            free_attempts: 3,
+            base_delay: Duration::seconds(1),
+            max_delay: Duration::seconds(30),
+            lockout_after: 10,
+            lockout: Duration::minutes(15),
+            reset_after: Duration::days(1),
+        }
+    }
+
+    fn failed(failures: u32, last_failure: DateTime<Utc>) -> Option<FailedLogins> {
+        Some(FailedLogins { failures, last_failure })
+    }
+
+    #[test]
+    pub fn progressive_delays() {
+        let policy = policy();
+        let delays: Vec<_> = (0..10).map(|failures| policy.delay(failures).map(|d| d.num_seconds())).collect();
+        assert_eq!(
+            delays,
+            vec![None, None, None, Some(1), Some(2), Some(4), Some(8), Some(16), Some(30), Some(30)]
+        );
+        assert_eq!(policy.delay(u32::MAX), Some(Duration::seconds(30)));
+
+        let now = Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap();
+        assert_eq!(policy.check(None, now), Verdict::Allowed);
+        assert_eq!(policy.check(failed(2, now), now), Verdict::Allowed);
+        assert_eq!(policy.check(failed(5, now), now), Verdict::Delayed(now + Duration::seconds(4)));
+        assert_eq!(policy.check(failed(5, now), now + Duration::seconds(4)), Verdict::Allowed);
+    }
+
+    #[test]
+    pub fn lockout() {
+        let policy = policy();
+        let now = Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap();
+        let until = now + Duration::minutes(15);
+
+        assert_eq!(policy.check(failed(10, now), now + Duration::minutes(5)), Verdict::LockedOut(until));
+        assert_eq!(policy.check(failed(10, now), until), Verdict::Allowed);
+    }
+
+    #[test]
+    pub fn failures_reset() {
+        let policy = policy();
+        let now = Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap();
+        let later = now + Duration::days(1);
+
+        assert_eq!(policy.check(failed(8, now), later), Verdict::Allowed);
+    }
+
+    #[test]
+    pub fn stricter_verdict() {
+        let now = Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap();
+        let later = now + Duration::minutes(1);
+
+        assert_eq!(Verdict::Allowed.stricter(Verdict::Delayed(now)), Verdict::Delayed(now));
+        assert_eq!(Verdict::Delayed(later).stricter(Verdict::LockedOut(now)), Verdict::LockedOut(now));
+        assert_eq!(Verdict::Delayed(later).stricter(Verdict::Delayed(now)), Verdict::Delayed(later));
+        assert_eq!(Verdict::LockedOut(now).stricter(Verdict::Allowed), Verdict::LockedOut(now));
+    }
+}
@@ -0,0 +1,239 @@
+use crate::env::{optional_var, var_or};
+use log::{error, info};
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// A plain text email
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Mail {
+    /// Line breaks in a header would start new headers, such as extra recipients
+    fn check_headers(&self) -> Result<(), MailError> {
+        for (name, value) in [("To", &self.to), ("Subject", &self.subject)] {
+            if value.contains(['\r', '\n']) {
+                return Err(MailError::InvalidHeader(name));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Format the mail as an RFC 5322 message
+    pub fn to_message(&self, from: &str) -> String {
+        format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}",
+            from,
+            self.to,
+            self.subject,
+            self.body.replace('\n', "\r\n")
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum MailError {
+    Io(io::Error),
+    /// The mail program refused to send the message
+    Rejected(String),
+    /// The named header contains a line break
+    InvalidHeader(&'static str),
+}
+
+impl Display for MailError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MailError::Io(e) => write!(f, "{}", e),
+            MailError::Rejected(reason) => write!(f, "{}", reason),
+            MailError::InvalidHeader(name) => write!(f, "The {} header contains a line break", name),
+        }
+    }
+}
+
+impl From<io::Error> for MailError {
+    fn from(error: io::Error) -> Self {
+        MailError::Io(error)
+    }
+}
+
+/// Delivers mail sent by the site. Selected with `MAIL_TRANSPORT` so a development or test setup
+/// does not need a mail server.
+#[rocket::async_trait]
+pub trait MailTransport: Send + Sync {
+    async fn send(&self, from: &str, mail: &Mail) -> Result<(), MailError>;
+}
+
+/// Logs each message instead of sending it
+pub struct StdoutTransport;
+
+#[rocket::async_trait]
+impl MailTransport for StdoutTransport {
+    async fn send(&self, from: &str, mail: &Mail) -> Result<(), MailError> {
+        info!("Mail to {}:\n{}", mail.to, mail.to_message(from));
+        Ok(())
+    }
+}
+
+/// Writes each message to a separate `.eml` file in a directory, named by the time it was sent
+pub struct FileTransport {
+    pub dir: PathBuf,
+}
+
+#[rocket::async_trait]
+impl MailTransport for FileTransport {
+    async fn send(&self, from: &str, mail: &Mail) -> Result<(), MailError> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+
+        let sent_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let path = self.dir.join(format!("{}-{}.eml", sent_at.as_nanos(), Uuid::new_v4().simple()));
+        tokio::fs::write(path, mail.to_message(from)).await?;
+        Ok(())
+    }
+}
+
+/// Hands messages to a local `sendmail` compatible program, which reads the recipient from the
+/// message headers
+pub struct SendmailTransport {
+    pub program: String,
+}
+
+#[rocket::async_trait]
+impl MailTransport for SendmailTransport {
+    async fn send(&self, from: &str, mail: &Mail) -> Result<(), MailError> {
+        let program = self.program.clone();
+        let message = mail.to_message(from);
+
+        let status = tokio::task::spawn_blocking(move || {
+            let mut child = Command::new(program)
+                .args(["-t", "-i"])
+                .stdin(Stdio::piped())
+                .spawn()?;
+
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin.write_all(message.as_bytes())?;
+            }
+
+            child.wait()
+        })
+        .await
+        .map_err(|e| MailError::Rejected(e.to_string()))??;
+
+        if !status.success() {
+            return Err(MailError::Rejected(format!("{} exited with {}", self.program, status)));
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes the account emails sent to users
+pub struct Mailer {
+    transport: Box<dyn MailTransport>,
+    from: String,
+    /// Address the site is reached at, used for the links in emails
+    site_url: String,
+}
+
+impl Mailer {
+    pub fn new(transport: Box<dyn MailTransport>, from: String, site_url: String) -> Self {
+        Mailer {
+            transport,
+            from,
+            site_url: site_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let transport: Box<dyn MailTransport> = match optional_var("MAIL_TRANSPORT").as_deref() {
+            Some("file") => Box::new(FileTransport {
+                dir: PathBuf::from(var_or("MAIL_DIR", "mail".to_string())),
+            }),
+            Some("sendmail") => Box::new(SendmailTransport {
+                program: var_or("SENDMAIL_PATH", "sendmail".to_string()),
+            }),
+            Some("stdout") | None => Box::new(StdoutTransport),
+            Some(other) => {
+                error!("Unknown MAIL_TRANSPORT {}, mail will be logged instead", other);
+                Box::new(StdoutTransport)
+            }
+        };
+
+        Mailer::new(
+            transport,
+            var_or("MAIL_FROM", "Amazon Tracker <noreply@localhost>".to_string()),
+            var_or("SITE_URL", "http://localhost:8000".to_string()),
+        )
+    }
+
+    pub async fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        mail.check_headers()?;
+        self.transport.send(&self.from, mail).await
+    }
+
+    pub fn verification_mail(&self, to: &str, token: &str) -> Mail {
+        Mail {
+            to: to.to_string(),
+            subject: "Confirm your email address".to_string(),
+            body: format!(
+                "Thanks for signing up to Amazon Tracker. Confirm your email address to sign in:\n\n\
+                 {}/verify?token={}\n\n\
+                 If you did not create an account, you can ignore this email.\n",
+                self.site_url, token
+            ),
+        }
+    }
+
+    pub fn password_reset_mail(&self, to: &str, token: &str) -> Mail {
+        Mail {
+            to: to.to_string(),
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "A password reset was requested for your Amazon Tracker account. Choose a new \
+                 password within the next hour:\n\n\
+                 {}/reset?token={}\n\n\
+                 If you did not request a reset, you can ignore this email.\n",
+                self.site_url, token
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn account_mail() {
+        let mailer = Mailer::new(Box::new(StdoutTransport), "site@example.com".to_string(), "https://example.com/".to_string());
+        let mail = mailer.password_reset_mail("user@example.com", "abc.123");
+
+        assert!(mail.body.contains("https://example.com/reset?token=abc.123\n"));
+        assert!(mailer.verification_mail("user@example.com", "abc.123").body.contains("https://example.com/verify?token=abc.123\n"));
+
+        let message = mail.to_message("site@example.com");
+        assert!(message.starts_with("From: site@example.com\r\nTo: user@example.com\r\nSubject: Reset your password\r\n"));
+        assert!(!message.replace("\r\n", "").contains('\n'));
+    }
+
+    #[tokio::test]
+    pub async fn header_injection() {
+        let mailer = Mailer::new(Box::new(StdoutTransport), "site@example.com".to_string(), "https://example.com".to_string());
+        let mail = mailer.verification_mail("x\r\nBcc: victim@example.com\r\n a@b.co", "abc.123");
+        assert!(matches!(mailer.send(&mail).await, Err(MailError::InvalidHeader("To"))));
+
+        let mail = Mail {
+            subject: "Hello\nBcc: victim@example.com".to_string(),
+            ..mailer.password_reset_mail("user@example.com", "abc.123")
+        };
+        assert!(matches!(mailer.send(&mail).await, Err(MailError::InvalidHeader("Subject"))));
+        assert!(mailer.send(&mailer.password_reset_mail("user@example.com", "abc.123")).await.is_ok());
+    }
+}
@@ -1,20 +1,52 @@
-use crate::env::{setup_dotenv, var};
-use log::{error, warn, LevelFilter};
+use crate::env::{optional_var, setup_dotenv, var, var_or};
+use log::{error, info, warn, LevelFilter};
 use rocket::{Build, Rocket};
+use sqlx::{Pool, Sqlite};
 use rocket_dyn_templates::Template;
+use std::time::Duration;
 
-use crate::scraper::AmazonApi;
+use crate::account::DeletionPolicy;
+use crate::account_token::AccountTokens;
+use crate::clock::Clock;
+use crate::csrf::CsrfFairing;
+use crate::import::ImportJobs;
+use crate::login_throttle::LoginThrottle;
+use crate::mail::Mailer;
+use crate::retention::RetentionPolicy;
+use crate::session::{Role, SessionPolicy};
+use crate::scraper::archive::PageArchive;
+use crate::scraper::rules::ExtractionRules;
+use crate::scraper::{AmazonApi, ResponseCache};
 use crate::templates::{setup_template_loader, TemplateUrlLoader};
 use error::MixedResult as Result;
 
+mod account;
+mod account_token;
+mod background;
+mod browse;
+mod chart;
+mod clock;
+mod compare;
+mod csrf;
 mod database;
 mod env;
 mod error;
+mod export;
 mod forms;
+mod import;
+mod login_throttle;
+mod mail;
+mod prediction;
+mod price_changes;
+mod reparse;
+mod retention;
 mod routes;
 mod scraper;
 mod session;
+mod stats;
 mod templates;
+mod totp;
+mod tracking;
 
 type AnyResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
@@ -22,6 +54,8 @@ fn main() {
     setup_logging();
     setup_dotenv();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
     // Begin tokio async runtime
     let err: AnyResult<()> = tokio::runtime::Builder::new_multi_thread()
         .thread_name("rocket-worker-thread")
@@ -29,8 +63,22 @@ fn main() {
         .build()
         .unwrap()
         .block_on(async {
-            let _ = build_rocket().await?.launch().await?;
-            Ok(())
+            setup_extraction_rules()?;
+
+            match args.first().map(String::as_str) {
+                Some("reparse") => {
+                    let pool = connect_database().await?;
+                    reparse::run(pool, args.get(1).map(String::as_str)).await
+                }
+                Some("make-admin") => {
+                    let pool = connect_database().await?;
+                    make_admin(pool, args.get(1).map(String::as_str)).await
+                }
+                _ => {
+                    let _ = build_rocket().await?.launch().await?;
+                    Ok(())
+                }
+            }
         });
 
     match err {
@@ -39,13 +87,33 @@ fn main() {
     }
 }
 
-async fn build_rocket() -> AnyResult<Rocket<Build>> {
-    // Create database pool
+async fn connect_database() -> AnyResult<Pool<Sqlite>> {
     let pool = sqlx::sqlite::SqlitePoolOptions::new()
         .max_connections(5)
         .connect(&var("DATABASE_URL"))
         .await?;
 
+    Ok(pool)
+}
+
+/// Entry point for `cargo run -- make-admin EMAIL`, which gives an existing user access to the
+/// admin pages
+async fn make_admin(pool: Pool<Sqlite>, email: Option<&str>) -> AnyResult<()> {
+    let email = email.ok_or("An email address is required: make-admin EMAIL")?;
+    let mut database = database::Connection::from(pool.acquire().await?);
+
+    if !database.set_role(email, Role::Admin).await? {
+        return Err(format!("There is no user with the email address {}", email).into());
+    }
+
+    info!("{} is now an administrator", email);
+    Ok(())
+}
+
+async fn build_rocket() -> AnyResult<Rocket<Build>> {
+    // Create database pool
+    let pool = connect_database().await?;
+
     // Create and launch rocket server and initialize managed resources
     let app = routes::build_app();
     let url_loader = TemplateUrlLoader::from(&app);
@@ -57,9 +125,55 @@ async fn build_rocket() -> AnyResult<Rocket<Build>> {
         Ok(())
     });
 
-    let amazon_api = AmazonApi::default();
+    let amazon_api = setup_amazon_api(&pool)?;
+
+    Ok(app
+        .attach(templates)
+        .attach(CsrfFairing)
+        .attach(background::fairing())
+        .manage(pool)
+        .manage(amazon_api)
+        .manage(ImportJobs::default())
+        .manage(LoginThrottle::from_env())
+        .manage(AccountTokens::from_env())
+        .manage(Mailer::from_env())
+        .manage(SessionPolicy::from_env())
+        .manage(DeletionPolicy::from_env())
+        .manage(RetentionPolicy::from_env())
+        .manage(Clock::default()))
+}
+
+/// The built-in extraction rules are replaced by the file at `EXTRACTION_RULES` when it is set. The
+/// file is checked for changes while the server is running by [background::fairing].
+fn setup_extraction_rules() -> AnyResult<()> {
+    if let Some(path) = optional_var("EXTRACTION_RULES") {
+        scraper::rules::replace(ExtractionRules::load(path)?);
+    }
+
+    Ok(())
+}
+
+/// Response caching is optional and enabled by setting `SCRAPER_CACHE_TTL` to a number of seconds.
+/// Cached pages are also written to `SCRAPER_CACHE_DIR` when it is set. Every fetched page is
+/// archived to `SCRAPER_ARCHIVE_DIR` when it is set.
+fn setup_amazon_api(pool: &Pool<Sqlite>) -> AnyResult<AmazonApi> {
+    let mut amazon_api = AmazonApi::default();
+
+    let ttl = var_or("SCRAPER_CACHE_TTL", 0u64);
+    if ttl > 0 {
+        let mut cache = ResponseCache::new(Duration::from_secs(ttl));
+        if let Some(directory) = optional_var("SCRAPER_CACHE_DIR") {
+            cache = cache.with_directory(directory);
+        }
+
+        amazon_api = amazon_api.with_cache(cache);
+    }
+
+    if let Some(directory) = optional_var("SCRAPER_ARCHIVE_DIR") {
+        amazon_api = amazon_api.with_archive(PageArchive::new(directory, pool.clone())?);
+    }
 
-    Ok(app.attach(templates).manage(pool).manage(amazon_api))
+    Ok(amazon_api)
 }
 
 fn setup_logging() {
@@ -0,0 +1,494 @@
+use crate::stats::{mean, median, PricePoint};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Fewest days with a price before advice is given
+const MIN_HISTORY_DAYS: usize = 14;
+
+/// Days over which the recent trend is measured
+const TREND_DAYS: i64 = 14;
+
+/// Days either side of a price used to find its usual price
+const BASELINE_DAYS: i64 = 30;
+
+/// How far below its usual price a price must be to count as a sale
+const SALE_DEPTH: f64 = 0.15;
+
+/// Sales in different years this many days apart in the calendar are counted as the same event
+const SALE_MATCH_DAYS: i64 = 14;
+
+/// How far ahead an expected sale is a reason to wait
+const SALE_LOOKAHEAD_DAYS: i64 = 45;
+
+/// Smallest difference between weekdays or months which counts as seasonal
+const SEASONAL_THRESHOLD: f64 = 0.01;
+
+/// Scores at or above this are a good time to buy, and at or below 100 minus this a time to wait
+const BUY_SCORE: u8 = 65;
+
+const LOW_WEIGHT: f64 = 35.0;
+const TREND_WEIGHT: f64 = 15.0;
+const WEEKDAY_WEIGHT: f64 = 10.0;
+const MONTH_WEIGHT: f64 = 10.0;
+const SALE_WEIGHT: f64 = 30.0;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Buy,
+    Fair,
+    Wait,
+}
+
+/// Whether now is a good time to buy a product in a single condition
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BuyAdvice {
+    pub condition: String,
+    /// From 0 for a bad time to buy up to 100 for a good one
+    pub score: u8,
+    pub verdict: Verdict,
+    /// Explanations of the score, most important first
+    pub reasons: Vec<String>,
+}
+
+/// Part of the score. The value is between -1 and 1, where positive values favour buying now.
+struct Factor {
+    weight: f64,
+    value: f64,
+    reason: String,
+}
+
+impl Factor {
+    fn new(weight: f64, value: f64, reason: String) -> Self {
+        Factor {
+            weight,
+            value: value.clamp(-1.0, 1.0),
+            reason,
+        }
+    }
+}
+
+/// Advise on each condition of a product with enough history, ordered by condition
+pub fn buy_advice(points: &[PricePoint], now: DateTime<Utc>) -> Vec<BuyAdvice> {
+    let mut conditions: BTreeMap<&str, Vec<(DateTime<Utc>, f64)>> = BTreeMap::new();
+    for point in points {
+        let condition = point.condition.as_deref().unwrap_or_default();
+        conditions.entry(condition).or_default().push((point.datetime, point.price));
+    }
+
+    conditions
+        .into_iter()
+        .filter_map(|(condition, prices)| {
+            let (score, verdict, reasons) = advise(&prices, now)?;
+            Some(BuyAdvice {
+                condition: condition.to_string(),
+                score,
+                verdict,
+                reasons,
+            })
+        })
+        .collect()
+}
+
+/// Score the latest price of a series against its history. Returns `None` when there are fewer
+/// than [MIN_HISTORY_DAYS] days of prices.
+pub fn advise(prices: &[(DateTime<Utc>, f64)], now: DateTime<Utc>) -> Option<(u8, Verdict, Vec<String>)> {
+    let days = daily_lows(prices);
+    if days.len() < MIN_HISTORY_DAYS {
+        return None;
+    }
+
+    let today = now.date_naive();
+    let baselines = baselines(&days);
+    let mut factors: Vec<Factor> = [
+        Some(distance_from_low(&days)),
+        trend(&days),
+        weekday_seasonality(&days, &baselines, today),
+        month_seasonality(&days, &baselines, today),
+        sale_events(&days, &baselines, today),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    let total_weight: f64 = factors.iter().map(|factor| factor.weight).sum();
+    let weighted: f64 = factors.iter().map(|factor| factor.weight * factor.value).sum();
+    let score = (50.0 + 50.0 * weighted / total_weight).round().clamp(0.0, 100.0) as u8;
+
+    let verdict = if score >= BUY_SCORE {
+        Verdict::Buy
+    } else if score <= 100 - BUY_SCORE {
+        Verdict::Wait
+    } else {
+        Verdict::Fair
+    };
+
+    factors.sort_by(|a, b| (b.weight * b.value.abs()).total_cmp(&(a.weight * a.value.abs())));
+    let reasons = factors.into_iter().map(|factor| factor.reason).collect();
+    Some((score, verdict, reasons))
+}
+
+/// The lowest price of each day, oldest first
+fn daily_lows(prices: &[(DateTime<Utc>, f64)]) -> Vec<(NaiveDate, f64)> {
+    let mut days: BTreeMap<NaiveDate, f64> = BTreeMap::new();
+    for &(datetime, price) in prices {
+        days.entry(datetime.date_naive())
+            .and_modify(|low| *low = low.min(price))
+            .or_insert(price);
+    }
+
+    days.into_iter().collect()
+}
+
+/// The usual price around each day, taken as the median within [BASELINE_DAYS] either side so
+/// short sales do not move it
+fn baselines(days: &[(NaiveDate, f64)]) -> Vec<f64> {
+    days.iter()
+        .map(|&(date, _)| {
+            let window: Vec<f64> = days
+                .iter()
+                .filter(|&&(other, _)| (other - date).num_days().abs() <= BASELINE_DAYS)
+                .map(|&(_, price)| price)
+                .collect();
+            median(&window)
+        })
+        .collect()
+}
+
+fn distance_from_low(days: &[(NaiveDate, f64)]) -> Factor {
+    let current = days[days.len() - 1].1;
+    let low = days.iter().map(|&(_, price)| price).fold(f64::INFINITY, f64::min);
+    let high = days.iter().map(|&(_, price)| price).fold(f64::NEG_INFINITY, f64::max);
+
+    if high <= low {
+        return Factor::new(LOW_WEIGHT, 0.0, format!("The price has always been ${:.2}", current));
+    }
+
+    // 1 at the all-time low and -1 at the all-time high
+    let position = (current - low) / (high - low);
+    let reason = if current <= low {
+        format!("${:.2} is the lowest price seen", current)
+    } else {
+        format!("The price is {:.0}% above the all-time low of ${:.2}", 100.0 * (current - low) / low, low)
+    };
+
+    Factor::new(LOW_WEIGHT, 1.0 - 2.0 * position, reason)
+}
+
+/// A rising price is a reason to buy before it rises further, while a falling one may keep
+/// falling
+fn trend(days: &[(NaiveDate, f64)]) -> Option<Factor> {
+    let (last, _) = days[days.len() - 1];
+    let recent: Vec<(f64, f64)> = days
+        .iter()
+        .filter(|&&(date, _)| (last - date).num_days() < TREND_DAYS)
+        .map(|&(date, price)| ((date - last).num_days() as f64, price))
+        .collect();
+    if recent.len() < 3 {
+        return None;
+    }
+
+    // Least squares slope of the price per day
+    let mean_x = mean(recent.iter().map(|&(x, _)| x))?;
+    let mean_y = mean(recent.iter().map(|&(_, y)| y))?;
+    let covariance: f64 = recent.iter().map(|&(x, y)| (x - mean_x) * (y - mean_y)).sum();
+    let variance: f64 = recent.iter().map(|&(x, _)| (x - mean_x).powi(2)).sum();
+    if variance == 0.0 || mean_y <= 0.0 {
+        return None;
+    }
+
+    let change = covariance / variance * TREND_DAYS as f64 / mean_y;
+    let reason = if change.abs() < 0.01 {
+        "The price has been steady over the last two weeks".to_string()
+    } else if change > 0.0 {
+        format!("The price rose about {:.0}% over the last two weeks", 100.0 * change)
+    } else {
+        format!("The price fell about {:.0}% over the last two weeks and may keep falling", -100.0 * change)
+    };
+
+    Some(Factor::new(TREND_WEIGHT, change / 0.10, reason))
+}
+
+/// Average difference from the usual price of the days in each group, for groups with at least
+/// two days
+fn seasonal_deviations(
+    days: &[(NaiveDate, f64)],
+    baselines: &[f64],
+    group: impl Fn(NaiveDate) -> u32,
+) -> BTreeMap<u32, f64> {
+    let mut groups: BTreeMap<u32, Vec<f64>> = BTreeMap::new();
+    for (&(date, price), &baseline) in days.iter().zip(baselines) {
+        if baseline > 0.0 {
+            groups.entry(group(date)).or_default().push(price / baseline - 1.0);
+        }
+    }
+
+    groups
+        .into_iter()
+        .filter(|(_, deviations)| deviations.len() >= 2)
+        .filter_map(|(key, deviations)| Some((key, mean(deviations.into_iter())?)))
+        .collect()
+}
+
+/// Compare the usual price of today's group with the others. `describe` gives the reason from the
+/// cheapest group and how far below the usual price it is.
+fn seasonality(
+    deviations: &BTreeMap<u32, f64>,
+    today: u32,
+    weight: f64,
+    describe: impl Fn(u32, f64) -> String,
+) -> Option<Factor> {
+    let (&cheapest, &lowest) = deviations.iter().min_by(|a, b| a.1.total_cmp(b.1))?;
+    if deviations.values().all(|deviation| deviation.abs() < SEASONAL_THRESHOLD) {
+        return None;
+    }
+
+    let today_deviation = deviations.get(&today).copied().unwrap_or_default();
+    let reason = describe(cheapest, -lowest);
+    Some(Factor::new(weight, -today_deviation / 0.05, reason))
+}
+
+fn weekday_seasonality(days: &[(NaiveDate, f64)], baselines: &[f64], today: NaiveDate) -> Option<Factor> {
+    let (first, _) = days[0];
+    let (last, _) = days[days.len() - 1];
+    if (last - first).num_days() < 28 {
+        return None;
+    }
+
+    let deviations = seasonal_deviations(days, baselines, |date| date.weekday().num_days_from_monday());
+    seasonality(&deviations, today.weekday().num_days_from_monday(), WEEKDAY_WEIGHT, |weekday, below| {
+        // Any Monday, offset to the weekday
+        let day = NaiveDate::from_ymd_opt(2024, 1, 1).expect("valid date") + Duration::days(weekday.into());
+        format!("Prices are usually lowest on {}s, {:.0}% below average", day.format("%A"), 100.0 * below)
+    })
+}
+
+fn month_seasonality(days: &[(NaiveDate, f64)], baselines: &[f64], today: NaiveDate) -> Option<Factor> {
+    let (first, _) = days[0];
+    let (last, _) = days[days.len() - 1];
+    if (last - first).num_days() < 365 {
+        return None;
+    }
+
+    let deviations = seasonal_deviations(days, baselines, |date| date.month());
+    seasonality(&deviations, today.month(), MONTH_WEIGHT, |month, below| {
+        let day = NaiveDate::from_ymd_opt(2024, month, 1).expect("valid month");
+        format!("Prices are usually lowest in {}, {:.0}% below average", day.format("%B"), 100.0 * below)
+    })
+}
+
+/// A run of days priced well below the usual price
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SaleEvent {
+    start: NaiveDate,
+    end: NaiveDate,
+    /// Largest drop from the usual price as a fraction of it
+    depth: f64,
+}
+
+fn find_sales(days: &[(NaiveDate, f64)], baselines: &[f64]) -> Vec<SaleEvent> {
+    let mut sales: Vec<SaleEvent> = Vec::new();
+    for (&(date, price), &baseline) in days.iter().zip(baselines) {
+        if baseline <= 0.0 || price > baseline * (1.0 - SALE_DEPTH) {
+            continue;
+        }
+
+        let depth = 1.0 - price / baseline;
+        match sales.last_mut() {
+            // Days missing from the history do not split a sale
+            Some(sale) if (date - sale.end).num_days() <= 3 => {
+                sale.end = date;
+                sale.depth = sale.depth.max(depth);
+            }
+            _ => sales.push(SaleEvent { start: date, end: date, depth }),
+        }
+    }
+
+    sales
+}
+
+/// Days between two dates of the calendar, ignoring the year
+fn calendar_distance(a: NaiveDate, b: NaiveDate) -> i64 {
+    let distance = (a.ordinal() as i64 - b.ordinal() as i64).abs();
+    distance.min(365 - distance)
+}
+
+/// The first anniversary of the date on or after the given day
+fn next_anniversary(date: NaiveDate, on_or_after: NaiveDate) -> Option<NaiveDate> {
+    (on_or_after.year()..=on_or_after.year() + 1)
+        .filter_map(|year| date.with_year(year).or_else(|| NaiveDate::from_ymd_opt(year, date.month(), 28)))
+        .find(|&anniversary| anniversary >= on_or_after)
+}
+
+/// Sales which happened around the same time in different years are expected again. A product on
+/// sale now is a reason to buy, while an expected sale coming up soon is a reason to wait.
+fn sale_events(days: &[(NaiveDate, f64)], baselines: &[f64], today: NaiveDate) -> Option<Factor> {
+    let sales = find_sales(days, baselines);
+    let recurring: Vec<&SaleEvent> = sales
+        .iter()
+        .filter(|sale| {
+            sales.iter().any(|other| {
+                other.start.year() != sale.start.year() && calendar_distance(other.start, sale.start) <= SALE_MATCH_DAYS
+            })
+        })
+        .collect();
+
+    let (last, current) = days[days.len() - 1];
+    if let Some(sale) = sales.last().filter(|sale| sale.end == last) {
+        let usual = baselines[baselines.len() - 1];
+        let reason = if recurring.iter().any(|recurring| recurring.start == sale.start) {
+            format!(
+                "The price is {:.0}% below its usual ${:.2} during a sale which happens around {} each year",
+                100.0 * (1.0 - current / usual),
+                usual,
+                sale.start.format("%B %-d")
+            )
+        } else {
+            format!("The price is {:.0}% below its usual ${:.2}", 100.0 * (1.0 - current / usual), usual)
+        };
+
+        return Some(Factor::new(SALE_WEIGHT, 1.0, reason));
+    }
+
+    let (next, sale) = recurring
+        .iter()
+        .filter_map(|sale| Some((next_anniversary(sale.start, today)?, sale)))
+        .min_by_key(|&(next, _)| next)?;
+    let days_until = (next - today).num_days();
+    if days_until > SALE_LOOKAHEAD_DAYS {
+        return None;
+    }
+
+    let reason = format!(
+        "A sale has cut the price by about {:.0}% around {} each year, next expected in {} days",
+        100.0 * sale.depth,
+        next.format("%B %-d"),
+        days_until
+    );
+    Some(Factor::new(SALE_WEIGHT, days_until as f64 / SALE_LOOKAHEAD_DAYS as f64 - 1.0, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// A daily price at noon for the days before and including the given day
+    fn series(end: NaiveDate, days: i64, price: impl Fn(NaiveDate) -> f64) -> Vec<(DateTime<Utc>, f64)> {
+        (0..days)
+            .rev()
+            .map(|ago| {
+                let date = end - Duration::days(ago);
+                let datetime = Utc.from_utc_datetime(&date.and_hms_opt(12, 0, 0).unwrap());
+                (datetime, price(date))
+            })
+            .collect()
+    }
+
+    fn noon(date: NaiveDate) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&date.and_hms_opt(12, 0, 0).unwrap())
+    }
+
+    #[test]
+    pub fn needs_history() {
+        let today = NaiveDate::from_ymd_opt(2023, 4, 1).unwrap();
+        assert_eq!(advise(&series(today, MIN_HISTORY_DAYS as i64 - 1, |_| 20.0), noon(today)), None);
+        assert!(advise(&series(today, MIN_HISTORY_DAYS as i64, |_| 20.0), noon(today)).is_some());
+    }
+
+    #[test]
+    pub fn buy_at_new_low() {
+        let today = NaiveDate::from_ymd_opt(2023, 4, 1).unwrap();
+        let prices = series(today, 60, |date| if date == today { 70.0 } else { 100.0 });
+
+        let (score, verdict, reasons) = advise(&prices, noon(today)).unwrap();
+        assert_eq!(verdict, Verdict::Buy, "{} {:?}", score, reasons);
+        assert_eq!(reasons[0], "$70.00 is the lowest price seen");
+        assert!(reasons.contains(&"The price is 30% below its usual $100.00".to_string()));
+    }
+
+    #[test]
+    pub fn wait_near_high() {
+        let today = NaiveDate::from_ymd_opt(2023, 4, 1).unwrap();
+        let start = today - Duration::days(60);
+        // Fell from 150 to 80 over a month, then climbed back to 150 and eased off slightly
+        let prices = series(today, 61, |date| {
+            let day = (date - start).num_days() as f64;
+            match day as i64 {
+                0..=30 => 150.0 - day * 70.0 / 30.0,
+                31..=50 => 80.0 + (day - 30.0) * 3.5,
+                _ => 150.0 - (day - 50.0) * 0.5,
+            }
+        });
+
+        let (_, verdict, reasons) = advise(&prices, noon(today)).unwrap();
+        assert_eq!(verdict, Verdict::Wait, "{:?}", reasons);
+        assert_eq!(reasons[0], "The price is 81% above the all-time low of $80.00");
+    }
+
+    #[test]
+    pub fn weekday_pattern() {
+        // Saturday
+        let today = NaiveDate::from_ymd_opt(2023, 4, 1).unwrap();
+        let weekend_sale = |date: NaiveDate| if date.weekday() == chrono::Weekday::Sat { 90.0 } else { 100.0 };
+
+        let (_, _, reasons) = advise(&series(today, 70, weekend_sale), noon(today)).unwrap();
+        assert!(reasons.contains(&"Prices are usually lowest on Saturdays, 10% below average".to_string()), "{:?}", reasons);
+
+        // Buying on a Wednesday is worse than buying on the Saturday
+        let wednesday = today - Duration::days(3);
+        let (saturday_score, _, _) = advise(&series(today, 70, weekend_sale), noon(today)).unwrap();
+        let (wednesday_score, _, _) = advise(&series(wednesday, 70, weekend_sale), noon(wednesday)).unwrap();
+        assert!(wednesday_score < saturday_score);
+    }
+
+    #[test]
+    pub fn recurring_sales() {
+        // The price drops for a few days in mid July each year
+        let prime_day = |date: NaiveDate| match (date.month(), date.day()) {
+            (7, 10..=13) => 70.0,
+            _ => 100.0,
+        };
+
+        let today = NaiveDate::from_ymd_opt(2023, 7, 1).unwrap();
+        let (_, verdict, reasons) = advise(&series(today, 800, prime_day), noon(today)).unwrap();
+        assert_eq!(verdict, Verdict::Wait, "{:?}", reasons);
+        assert!(reasons.contains(&"A sale has cut the price by about 30% around July 10 each year, next expected in 9 days".to_string()), "{:?}", reasons);
+
+        // During the sale
+        let today = NaiveDate::from_ymd_opt(2023, 7, 11).unwrap();
+        let (_, verdict, reasons) = advise(&series(today, 810, prime_day), noon(today)).unwrap();
+        assert_eq!(verdict, Verdict::Buy, "{:?}", reasons);
+        assert!(reasons.iter().any(|reason| reason.ends_with("during a sale which happens around July 10 each year")), "{:?}", reasons);
+
+        // Long after the sale there is nothing to wait for
+        let today = NaiveDate::from_ymd_opt(2023, 10, 1).unwrap();
+        let (_, _, reasons) = advise(&series(today, 900, prime_day), noon(today)).unwrap();
+        assert!(!reasons.iter().any(|reason| reason.contains("sale")), "{:?}", reasons);
+    }
+
+    #[test]
+    pub fn advice_per_condition() {
+        let today = NaiveDate::from_ymd_opt(2023, 4, 1).unwrap();
+        let mut points = Vec::new();
+        for (condition, price) in [("New", 100.0), ("UsedGood", 60.0)] {
+            for (datetime, price) in series(today, 30, |_| price) {
+                points.push(PricePoint {
+                    asin: "B07VGRJDFY".to_string(),
+                    condition: Some(condition.to_string()),
+                    datetime,
+                    price,
+                });
+            }
+        }
+        // Too little history in this condition
+        points.truncate(40);
+
+        let advice = buy_advice(&points, noon(today));
+        assert_eq!(advice.len(), 1);
+        assert_eq!(advice[0].condition, "New");
+        assert_eq!((advice[0].score, advice[0].verdict), (50, Verdict::Fair));
+        assert_eq!(advice[0].reasons[0], "The price has always been $100.00");
+    }
+}
@@ -0,0 +1,334 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::FromRow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// A listing of a product as collected during a single refresh
+#[derive(Debug, Clone, PartialEq, FromRow)]
+pub struct SnapshotListing {
+    pub condition: String,
+    pub seller: String,
+    pub price: f64,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    PriceDrop,
+    PriceRise,
+    NewSeller,
+    SellerGone,
+    OutOfStock,
+    BackInStock,
+    /// The lowest price ever collected for the condition
+    NewLowest,
+}
+
+impl ChangeKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChangeKind::PriceDrop => "price_drop",
+            ChangeKind::PriceRise => "price_rise",
+            ChangeKind::NewSeller => "new_seller",
+            ChangeKind::SellerGone => "seller_gone",
+            ChangeKind::OutOfStock => "out_of_stock",
+            ChangeKind::BackInStock => "back_in_stock",
+            ChangeKind::NewLowest => "new_lowest",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct UnknownChangeKind;
+
+impl FromStr for ChangeKind {
+    type Err = UnknownChangeKind;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "price_drop" => ChangeKind::PriceDrop,
+            "price_rise" => ChangeKind::PriceRise,
+            "new_seller" => ChangeKind::NewSeller,
+            "seller_gone" => ChangeKind::SellerGone,
+            "out_of_stock" => ChangeKind::OutOfStock,
+            "back_in_stock" => ChangeKind::BackInStock,
+            "new_lowest" => ChangeKind::NewLowest,
+            _ => return Err(UnknownChangeKind),
+        })
+    }
+}
+
+/// A change between two refreshes of a product. The condition and seller are empty for changes
+/// to the product as a whole.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeEvent {
+    pub kind: ChangeKind,
+    pub condition: String,
+    pub seller: String,
+    pub old_price: Option<f64>,
+    pub new_price: Option<f64>,
+}
+
+impl ChangeEvent {
+    fn new(kind: ChangeKind, condition: &str, seller: &str, old_price: Option<f64>, new_price: Option<f64>) -> Self {
+        ChangeEvent {
+            kind,
+            condition: condition.to_string(),
+            seller: seller.to_string(),
+            old_price,
+            new_price,
+        }
+    }
+
+    /// The change in price, which is negative when the price dropped
+    pub fn delta(&self) -> Option<f64> {
+        Some(self.new_price? - self.old_price?)
+    }
+
+    /// The change in price as a percentage of the old price
+    pub fn percent(&self) -> Option<f64> {
+        let old_price = self.old_price.filter(|price| *price > 0.0)?;
+        Some(100.0 * self.delta()? / old_price)
+    }
+}
+
+impl Display for ChangeEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let price = |price: Option<f64>| format!("${:.2}", price.unwrap_or_default());
+
+        match self.kind {
+            ChangeKind::PriceDrop | ChangeKind::PriceRise => write!(
+                f,
+                "{} {} by ${:.2} ({:.1}%) to {} from {}",
+                self.condition,
+                if self.kind == ChangeKind::PriceDrop { "dropped" } else { "rose" },
+                self.delta().unwrap_or_default().abs(),
+                self.percent().unwrap_or_default().abs(),
+                price(self.new_price),
+                self.seller
+            ),
+            ChangeKind::NewSeller => write!(f, "{} now sells it {} for {}", self.seller, self.condition, price(self.new_price)),
+            ChangeKind::SellerGone => write!(f, "{} no longer sells it {}", self.seller, self.condition),
+            ChangeKind::OutOfStock => write!(f, "Out of stock"),
+            ChangeKind::BackInStock => write!(f, "Back in stock from {}", price(self.new_price)),
+            ChangeKind::NewLowest => write!(
+                f,
+                "Lowest {} price yet at {}, down from {}",
+                self.condition,
+                price(self.new_price),
+                price(self.old_price)
+            ),
+        }
+    }
+}
+
+/// A change recorded for a product, as read from `Price_Change_Events`
+#[derive(Debug, Clone, FromRow)]
+pub struct StoredChange {
+    pub asin: String,
+    pub name: Option<String>,
+    pub occurred_at: DateTime<Utc>,
+    pub kind: String,
+    pub condition: String,
+    pub seller: String,
+    pub old_price: Option<f64>,
+    pub new_price: Option<f64>,
+}
+
+impl StoredChange {
+    /// The recorded change, unless its kind is unknown
+    pub fn event(&self) -> Option<ChangeEvent> {
+        let kind = self.kind.parse().ok()?;
+        Some(ChangeEvent::new(kind, &self.condition, &self.seller, self.old_price, self.new_price))
+    }
+}
+
+/// The lowest price of each seller and condition in a snapshot
+fn by_seller(listings: &[SnapshotListing]) -> BTreeMap<(&str, &str), f64> {
+    let mut prices = BTreeMap::new();
+    for listing in listings {
+        prices
+            .entry((listing.condition.as_str(), listing.seller.as_str()))
+            .and_modify(|price: &mut f64| *price = price.min(listing.price))
+            .or_insert(listing.price);
+    }
+
+    prices
+}
+
+/// Compare a refresh with the one before it. The lowest earlier price of each condition is used
+/// to find new lows. A product with no offers is out of stock, in which case the sellers which
+/// left are not listed individually, and likewise for sellers returning when it is back in stock.
+pub fn diff(
+    previous: &[SnapshotListing],
+    current: &[SnapshotListing],
+    previous_lows: &HashMap<String, f64>,
+) -> Vec<ChangeEvent> {
+    let mut events = Vec::new();
+    let before = by_seller(previous);
+    let after = by_seller(current);
+
+    match (before.is_empty(), after.is_empty()) {
+        (true, true) => return events,
+        (false, true) => {
+            events.push(ChangeEvent::new(ChangeKind::OutOfStock, "", "", None, None));
+            return events;
+        }
+        (true, false) => {
+            let lowest = after.values().copied().fold(f64::INFINITY, f64::min);
+            events.push(ChangeEvent::new(ChangeKind::BackInStock, "", "", None, Some(lowest)));
+        }
+        (false, false) => {
+            for (&(condition, seller), &new_price) in &after {
+                match before.get(&(condition, seller)) {
+                    Some(&old_price) if new_price < old_price => events.push(ChangeEvent::new(
+                        ChangeKind::PriceDrop,
+                        condition,
+                        seller,
+                        Some(old_price),
+                        Some(new_price),
+                    )),
+                    Some(&old_price) if new_price > old_price => events.push(ChangeEvent::new(
+                        ChangeKind::PriceRise,
+                        condition,
+                        seller,
+                        Some(old_price),
+                        Some(new_price),
+                    )),
+                    Some(_) => {}
+                    None => events.push(ChangeEvent::new(ChangeKind::NewSeller, condition, seller, None, Some(new_price))),
+                }
+            }
+
+            for (&(condition, seller), &old_price) in &before {
+                if !after.contains_key(&(condition, seller)) {
+                    events.push(ChangeEvent::new(ChangeKind::SellerGone, condition, seller, Some(old_price), None));
+                }
+            }
+        }
+    }
+
+    // The lowest price of each condition, compared with every earlier price
+    let mut lowest: BTreeMap<&str, f64> = BTreeMap::new();
+    for (&(condition, _), &price) in &after {
+        lowest
+            .entry(condition)
+            .and_modify(|low| *low = low.min(price))
+            .or_insert(price);
+    }
+
+    for (condition, price) in lowest {
+        match previous_lows.get(condition) {
+            Some(&previous_low) if price < previous_low => events.push(ChangeEvent::new(
+                ChangeKind::NewLowest,
+                condition,
+                "",
+                Some(previous_low),
+                Some(price),
+            )),
+            _ => {}
+        }
+    }
+
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(condition: &str, seller: &str, price: f64) -> SnapshotListing {
+        SnapshotListing {
+            condition: condition.to_string(),
+            seller: seller.to_string(),
+            price,
+        }
+    }
+
+    fn kinds(events: &[ChangeEvent]) -> Vec<(ChangeKind, &str)> {
+        events.iter().map(|event| (event.kind, event.seller.as_str())).collect()
+    }
+
+    #[test]
+    pub fn seller_changes() {
+        let previous = vec![
+            listing("New", "Amazon.com", 50.0),
+            listing("New", "Gadgets", 55.0),
+            listing("UsedGood", "Resale", 30.0),
+        ];
+        let current = vec![
+            listing("New", "Amazon.com", 45.0),
+            listing("New", "Gadgets", 60.0),
+            listing("New", "Newcomer", 52.0),
+        ];
+
+        let events = diff(&previous, &current, &HashMap::new());
+        assert_eq!(
+            kinds(&events),
+            vec![
+                (ChangeKind::PriceDrop, "Amazon.com"),
+                (ChangeKind::PriceRise, "Gadgets"),
+                (ChangeKind::NewSeller, "Newcomer"),
+                (ChangeKind::SellerGone, "Resale"),
+            ]
+        );
+
+        assert_eq!(events[0].delta(), Some(-5.0));
+        assert_eq!(events[0].percent(), Some(-10.0));
+        assert_eq!(events[0].to_string(), "New dropped by $5.00 (10.0%) to $45.00 from Amazon.com");
+        assert_eq!(events[1].to_string(), "New rose by $5.00 (9.1%) to $60.00 from Gadgets");
+        assert_eq!(events[3].old_price, Some(30.0));
+
+        // Nothing changed
+        assert!(diff(&current, &current, &HashMap::new()).is_empty());
+    }
+
+    #[test]
+    pub fn stock_changes() {
+        let in_stock = vec![listing("New", "Amazon.com", 50.0), listing("UsedGood", "Resale", 30.0)];
+
+        let events = diff(&in_stock, &[], &HashMap::new());
+        assert_eq!(kinds(&events), vec![(ChangeKind::OutOfStock, "")]);
+        assert_eq!(events[0].to_string(), "Out of stock");
+
+        let events = diff(&[], &in_stock, &HashMap::new());
+        assert_eq!(kinds(&events), vec![(ChangeKind::BackInStock, "")]);
+        assert_eq!(events[0].new_price, Some(30.0));
+
+        assert!(diff(&[], &[], &HashMap::new()).is_empty());
+    }
+
+    #[test]
+    pub fn new_lowest_price() {
+        let previous = vec![listing("New", "Amazon.com", 50.0)];
+        let current = vec![listing("New", "Amazon.com", 40.0), listing("New", "Gadgets", 39.0)];
+        let lows = HashMap::from([("New".to_string(), 42.0)]);
+
+        let events = diff(&previous, &current, &lows);
+        let lowest: Vec<_> = events.iter().filter(|event| event.kind == ChangeKind::NewLowest).collect();
+        assert_eq!(lowest.len(), 1);
+        assert_eq!((lowest[0].old_price, lowest[0].new_price), (Some(42.0), Some(39.0)));
+        assert_eq!(lowest[0].to_string(), "Lowest New price yet at $39.00, down from $42.00");
+
+        // Matching the previous low is not a new low
+        let lows = HashMap::from([("New".to_string(), 39.0)]);
+        assert!(diff(&previous, &current, &lows).iter().all(|event| event.kind != ChangeKind::NewLowest));
+    }
+
+    #[test]
+    pub fn kind_names() {
+        for kind in [
+            ChangeKind::PriceDrop,
+            ChangeKind::PriceRise,
+            ChangeKind::NewSeller,
+            ChangeKind::SellerGone,
+            ChangeKind::OutOfStock,
+            ChangeKind::BackInStock,
+            ChangeKind::NewLowest,
+        ] {
+            assert_eq!(ChangeKind::from_str(kind.as_str()).ok(), Some(kind));
+        }
+    }
+}
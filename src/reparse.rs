@@ -0,0 +1,188 @@
+use crate::database::Connection;
+use crate::env::optional_var;
+use crate::scraper::archive::{ArchivedPage, PageArchive, PageKind};
+use crate::scraper::health::ParserHealth;
+use crate::scraper::offer::{offers_on_page, Offer};
+use crate::scraper::product::Product;
+use crate::scraper::rules;
+use crate::AnyResult;
+use chrono::{DateTime, Duration, Utc};
+use log::{info, warn};
+use select::document::Document;
+use sqlx::{Pool, Sqlite};
+use std::collections::HashMap;
+
+/// Refreshes are recorded after their pages are fetched, so an archived page belongs to the first
+/// refresh of the product within this many minutes of the fetch
+const REFRESH_MATCH_MINUTES: i64 = 5;
+
+#[derive(Debug, Default)]
+pub struct ReparseSummary {
+    pub pages: usize,
+    pub products_updated: usize,
+    pub listings_added: usize,
+    pub failures: usize,
+}
+
+/// The data recovered from a single archived page
+enum Parsed {
+    Product(Product),
+    Offers(Vec<Offer>),
+    /// The page does not contain anything that needs to be backfilled
+    Skipped,
+    Failed,
+}
+
+/// Entry point for `cargo run -- reparse [ASIN]`
+pub async fn run(pool: Pool<Sqlite>, asin: Option<&str>) -> AnyResult<()> {
+    let directory = match optional_var("SCRAPER_ARCHIVE_DIR") {
+        Some(directory) => directory,
+        None => return Err("SCRAPER_ARCHIVE_DIR must be set to re-parse archived pages".into()),
+    };
+
+    let archive = PageArchive::new(directory, pool.clone())?;
+    let summary = reparse_archive(&pool, &archive, asin).await?;
+
+    info!(
+        "Re-parsed {} archived pages: {} products updated, {} listings added, {} failures",
+        summary.pages, summary.products_updated, summary.listings_added, summary.failures
+    );
+    Ok(())
+}
+
+/// Replay archived pages through the current parsers, updating product metadata and backfilling
+/// any listings which were lost when a parser failed on the original fetch.
+pub async fn reparse_archive(
+    pool: &Pool<Sqlite>,
+    archive: &PageArchive,
+    asin: Option<&str>,
+) -> sqlx::Result<ReparseSummary> {
+    let mut database = Connection::from(pool.acquire().await?);
+    let mut summary = ReparseSummary::default();
+
+    // Tracked separately from the live counters so replaying old pages does not skew them
+    let health = ParserHealth::default();
+
+    // The offers read so far from the offer list fetched for each ASIN, along with when its first
+    // page was fetched. The pages of a fetch are added together so they form a single refresh.
+    let mut fetches: HashMap<String, (DateTime<Utc>, Vec<Offer>)> = HashMap::new();
+    let window = Duration::minutes(REFRESH_MATCH_MINUTES);
+
+    for page in archive.pages(asin).await? {
+        // Error pages would only produce parser failures
+        if page.status != 200 {
+            continue;
+        }
+
+        let page_asin = match &page.asin {
+            Some(asin) => asin.as_str(),
+            None => continue,
+        };
+
+        // The first page of an offer list starts a new fetch, even if it can not be read
+        if page.kind() == (PageKind::Offers { page: 1 }) {
+            if let Some((fetched_at, offers)) = fetches.remove(page_asin) {
+                summary.listings_added += add_fetched_offers(&mut database, page_asin, fetched_at, &offers).await?;
+            }
+        }
+
+        let body = match archive.load(&page.hash).await {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Unable to read archived page {} ({}): {}", page.url, page.hash, e);
+                summary.failures += 1;
+                continue;
+            }
+        };
+
+        summary.pages += 1;
+
+        match parse_page(&page, page_asin, &body, &health) {
+            Parsed::Product(product) => {
+                if database.product_exists(&product.asin).await?.is_none() {
+                    continue;
+                }
+
+                database.update_product_metadata(&product).await?;
+                summary.products_updated += 1;
+            }
+            Parsed::Offers(offers) => {
+                if page.kind() == (PageKind::Offers { page: 1 }) {
+                    fetches.insert(page_asin.to_string(), (page.fetched_at, offers));
+                    continue;
+                }
+
+                match fetches.get_mut(page_asin) {
+                    // Later pages belong to the fetch of the first page shortly before them
+                    Some((fetched_at, fetched)) if page.fetched_at - *fetched_at <= window => fetched.extend(offers),
+                    // Without the first page the rest of the offer list would be incomplete
+                    _ => warn!("Skipping archived offer page {} without the first page of its fetch", page.url),
+                }
+            }
+            Parsed::Skipped => {}
+            Parsed::Failed => summary.failures += 1,
+        }
+    }
+
+    for (asin, (fetched_at, offers)) in fetches {
+        summary.listings_added += add_fetched_offers(&mut database, &asin, fetched_at, &offers).await?;
+    }
+
+    for (field, counts) in health.take_counts() {
+        if counts.failures > 0 {
+            warn!(
+                "Field {} failed to parse on {} of {} archived pages",
+                field,
+                counts.failures,
+                counts.successes + counts.failures
+            );
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Add the offers read from the pages of an offer list to the refresh recorded when they were
+/// originally fetched. Returns the number of listings added.
+async fn add_fetched_offers(
+    database: &mut Connection<Sqlite>,
+    asin: &str,
+    fetched_at: DateTime<Utc>,
+    offers: &[Offer],
+) -> sqlx::Result<usize> {
+    if database.product_exists(asin).await?.is_none() {
+        return Ok(0);
+    }
+
+    let window = Duration::minutes(REFRESH_MATCH_MINUTES);
+    let collected_at = database.refresh_after(asin, fetched_at, window).await?.unwrap_or(fetched_at);
+    database.add_listings(asin, collected_at, offers).await
+}
+
+/// Parsing is kept separate from the database updates since [Document] can not be held across an
+/// await point.
+fn parse_page(page: &ArchivedPage, asin: &str, body: &str, health: &ParserHealth) -> Parsed {
+    let document = Document::from(body);
+
+    match page.kind() {
+        PageKind::Product => {
+            let product = Product::try_from(&document);
+            health.record_product(asin, &product);
+
+            match product {
+                Ok(product) => Parsed::Product(product),
+                Err(e) => {
+                    warn!("Failed to parse archived product page {}: {:?}", page.url, e);
+                    Parsed::Failed
+                }
+            }
+        }
+        // Every offer is stored, so each page of the offer list is read. The pages are combined
+        // into the refresh of their fetch by the caller.
+        PageKind::Offers { .. } => {
+            let rules = rules::current();
+            Parsed::Offers(offers_on_page(asin, &document, &rules.offer, health))
+        }
+        PageKind::Other => Parsed::Skipped,
+    }
+}
@@ -0,0 +1,342 @@
+use crate::chart::ChartPoint;
+use crate::database::Connection;
+use crate::env::var_or;
+use crate::stats::PricePoint;
+use chrono::{DateTime, Datelike, Duration, DurationRound, NaiveDate, Utc};
+use serde::Serialize;
+use sqlx::{FromRow, Sqlite};
+use std::collections::BTreeMap;
+
+/// How finely prices are kept
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolution {
+    /// Every refresh
+    Full,
+    Daily,
+    /// Weeks starting on Monday
+    Weekly,
+}
+
+impl Resolution {
+    /// The start of the period containing the time
+    pub fn period(self, datetime: DateTime<Utc>) -> DateTime<Utc> {
+        let midnight = datetime.duration_trunc(Duration::days(1)).unwrap_or(datetime);
+        match self {
+            Resolution::Full => datetime,
+            Resolution::Daily => midnight,
+            Resolution::Weekly => midnight - Duration::days(datetime.weekday().num_days_from_monday().into()),
+        }
+    }
+}
+
+/// How long prices are kept at each resolution. Listings are kept for `full`, then as daily
+/// prices until `daily` has passed, and as weekly prices after that.
+#[derive(Debug, Copy, Clone)]
+pub struct RetentionPolicy {
+    pub full: Duration,
+    pub daily: Duration,
+}
+
+impl RetentionPolicy {
+    pub fn from_env() -> Self {
+        RetentionPolicy {
+            full: Duration::days(var_or("RETENTION_FULL_DAYS", 90)),
+            daily: Duration::days(var_or("RETENTION_DAILY_DAYS", 365)),
+        }
+    }
+
+    /// Listings collected before this are compacted into days. Compaction stops at midnight so a
+    /// day is never split between listings and a daily price.
+    pub fn daily_cutoff(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        Resolution::Daily.period(now - self.full)
+    }
+
+    /// Daily prices before this are compacted into weeks
+    pub fn weekly_cutoff(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        Resolution::Weekly.period(now - self.daily)
+    }
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            full: Duration::days(90),
+            daily: Duration::days(365),
+        }
+    }
+}
+
+/// The prices of a product in one condition over a period, as stored in `Price_Daily` and
+/// `Price_Weekly`. A single price collected at full resolution is a period of its own.
+#[derive(Debug, Clone, PartialEq, FromRow)]
+pub struct Rollup {
+    pub asin: String,
+    pub condition: String,
+    pub period: DateTime<Utc>,
+    pub min_price: f64,
+    pub max_price: f64,
+    pub close_price: f64,
+    pub closed_at: DateTime<Utc>,
+    pub samples: i64,
+}
+
+impl From<&PricePoint> for Rollup {
+    fn from(point: &PricePoint) -> Self {
+        Rollup {
+            asin: point.asin.clone(),
+            condition: point.condition.clone().unwrap_or_default(),
+            period: point.datetime,
+            min_price: point.price,
+            max_price: point.price,
+            close_price: point.price,
+            closed_at: point.datetime,
+            samples: 1,
+        }
+    }
+}
+
+impl Rollup {
+    fn merge(&mut self, other: &Rollup) {
+        self.min_price = self.min_price.min(other.min_price);
+        self.max_price = self.max_price.max(other.max_price);
+        if other.closed_at >= self.closed_at {
+            self.close_price = other.close_price;
+            self.closed_at = other.closed_at;
+        }
+        self.samples += other.samples;
+    }
+
+    /// The lowest, highest and closing price as separate points, for calculations which expect a
+    /// price per sample. The lowest and highest price are placed at the start of the period.
+    pub fn price_points(&self) -> Vec<PricePoint> {
+        let point = |datetime, price| PricePoint {
+            asin: self.asin.clone(),
+            condition: Some(self.condition.clone()),
+            datetime,
+            price,
+        };
+
+        let mut points = vec![self.close_point()];
+        if self.min_price < self.close_price {
+            points.push(point(self.period, self.min_price));
+        }
+        if self.max_price > self.close_price {
+            points.push(point(self.period, self.max_price));
+        }
+
+        points
+    }
+
+    /// The closing price at the time it was collected, the only price of the period known to have
+    /// been seen at a particular time
+    pub fn close_point(&self) -> PricePoint {
+        PricePoint {
+            asin: self.asin.clone(),
+            condition: Some(self.condition.clone()),
+            datetime: self.closed_at,
+            price: self.close_price,
+        }
+    }
+}
+
+/// The prices collected for products, keeping compacted periods apart from the prices which have
+/// not been compacted since only their closing price has a time of its own
+#[derive(Debug, Default)]
+pub struct CollectedPrices {
+    pub points: Vec<PricePoint>,
+    pub rollups: Vec<Rollup>,
+}
+
+impl CollectedPrices {
+    /// Every price to calculate [crate::stats::PriceStats] from, including the lowest, highest and
+    /// closing price of compacted periods
+    pub fn statistics(&self) -> Vec<PricePoint> {
+        let compacted = self.rollups.iter().flat_map(Rollup::price_points);
+        self.points.iter().cloned().chain(compacted).collect()
+    }
+
+    /// The prices to advise on. Compacted periods only add their closing price, since their lowest
+    /// and highest price placed at the start of the period would skew patterns by weekday or month.
+    pub fn closes(&self) -> Vec<PricePoint> {
+        let compacted = self.rollups.iter().map(Rollup::close_point);
+        self.points.iter().cloned().chain(compacted).collect()
+    }
+}
+
+/// Combine rollups into periods of the resolution, ordered by ASIN, condition and period
+pub fn roll_up(rollups: impl IntoIterator<Item = Rollup>, resolution: Resolution) -> Vec<Rollup> {
+    let mut periods: BTreeMap<(String, String, DateTime<Utc>), Rollup> = BTreeMap::new();
+    for rollup in rollups {
+        let period = resolution.period(rollup.period);
+        let key = (rollup.asin.clone(), rollup.condition.clone(), period);
+        match periods.get_mut(&key) {
+            Some(existing) => existing.merge(&rollup),
+            None => {
+                periods.insert(key, Rollup { period, ..rollup });
+            }
+        }
+    }
+
+    periods.into_values().collect()
+}
+
+/// The times covered by an optional range of days, including the whole of the last day
+pub fn day_range(from: Option<NaiveDate>, to: Option<NaiveDate>) -> (Option<DateTime<Utc>>, Option<DateTime<Utc>>) {
+    let midnight = |date: NaiveDate| date.and_hms_opt(0, 0, 0).map(|datetime| datetime.and_utc());
+    (from.and_then(midnight), to.and_then(midnight).map(|end| end + Duration::days(1) - Duration::seconds(1)))
+}
+
+/// The prices of a product between the optional times. Once any part of the range has been
+/// compacted, the whole range is read at the coarsest resolution kept for it so every period is
+/// shown alike.
+pub async fn load_history(
+    database: &mut Connection<Sqlite>,
+    asin: &str,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> sqlx::Result<(Resolution, Vec<Rollup>)> {
+    let mut rollups: Vec<Rollup> = database
+        .price_points_between(asin, from, to)
+        .await?
+        .iter()
+        .map(Rollup::from)
+        .collect();
+
+    let mut resolution = Resolution::Full;
+    for stored in [Resolution::Daily, Resolution::Weekly] {
+        let compacted = database.product_rollups(stored, asin, from, to).await?;
+        if !compacted.is_empty() {
+            resolution = stored;
+        }
+        rollups.extend(compacted);
+    }
+
+    Ok((resolution, roll_up(rollups, resolution)))
+}
+
+/// The prices of a product between the optional times to calculate statistics and advice from,
+/// along with the coarsest resolution they were kept at
+pub async fn load_price_points(
+    database: &mut Connection<Sqlite>,
+    asin: &str,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> sqlx::Result<(Resolution, CollectedPrices)> {
+    let mut prices = CollectedPrices {
+        points: database.price_points_between(asin, from, to).await?,
+        rollups: Vec::new(),
+    };
+
+    let mut resolution = Resolution::Full;
+    for stored in [Resolution::Daily, Resolution::Weekly] {
+        let compacted = database.product_rollups(stored, asin, from, to).await?;
+        if !compacted.is_empty() {
+            resolution = stored;
+        }
+        prices.rollups.extend(compacted);
+    }
+
+    Ok((resolution, prices))
+}
+
+/// The prices of a product to chart. Listings are charted by seller, while compacted periods only
+/// have the closing price of each condition.
+pub async fn chart_history(
+    database: &mut Connection<Sqlite>,
+    asin: &str,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> sqlx::Result<Vec<ChartPoint>> {
+    let (resolution, rollups) = load_history(database, asin, from, to).await?;
+    if resolution == Resolution::Full {
+        return database.chart_points(asin, from, to).await;
+    }
+
+    Ok(rollups
+        .into_iter()
+        .map(|rollup| ChartPoint {
+            condition: Some(rollup.condition),
+            seller: None,
+            datetime: rollup.closed_at,
+            price: rollup.close_price,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn point(condition: &str, datetime: DateTime<Utc>, price: f64) -> Rollup {
+        Rollup::from(&PricePoint {
+            asin: "B07VGRJDFY".to_string(),
+            condition: Some(condition.to_string()),
+            datetime,
+            price,
+        })
+    }
+
+    #[test]
+    pub fn periods() {
+        // Thursday
+        let datetime = Utc.with_ymd_and_hms(2023, 3, 30, 15, 42, 7).unwrap();
+        assert_eq!(Resolution::Full.period(datetime), datetime);
+        assert_eq!(Resolution::Daily.period(datetime), Utc.with_ymd_and_hms(2023, 3, 30, 0, 0, 0).unwrap());
+        assert_eq!(Resolution::Weekly.period(datetime), Utc.with_ymd_and_hms(2023, 3, 27, 0, 0, 0).unwrap());
+    }
+
+    #[test]
+    pub fn rolled_up_prices() {
+        let day = Utc.with_ymd_and_hms(2023, 3, 30, 0, 0, 0).unwrap();
+        let points = vec![
+            point("New", day + Duration::hours(18), 25.0),
+            point("New", day + Duration::hours(6), 20.0),
+            point("New", day + Duration::hours(12), 30.0),
+            point("UsedGood", day + Duration::hours(12), 15.0),
+            point("New", day + Duration::days(1), 22.0),
+        ];
+
+        let daily = roll_up(points, Resolution::Daily);
+        assert_eq!(daily.len(), 3);
+        assert_eq!(daily[0].period, day);
+        assert_eq!((daily[0].min_price, daily[0].max_price, daily[0].close_price), (20.0, 30.0, 25.0));
+        assert_eq!((daily[0].closed_at, daily[0].samples), (day + Duration::hours(18), 3));
+        assert_eq!(daily[1].period, day + Duration::days(1));
+        assert_eq!(daily[2].condition, "UsedGood");
+
+        // Days roll up into the week in the same way
+        let weekly = roll_up(daily, Resolution::Weekly);
+        assert_eq!(weekly.len(), 2);
+        assert_eq!((weekly[0].min_price, weekly[0].max_price, weekly[0].close_price), (20.0, 30.0, 22.0));
+        assert_eq!(weekly[0].samples, 4);
+
+        let prices: Vec<f64> = weekly[0].price_points().iter().map(|point| point.price).collect();
+        assert_eq!(prices, vec![22.0, 20.0, 30.0]);
+        assert_eq!(weekly[1].price_points().len(), 1);
+
+        let close = weekly[0].close_point();
+        assert_eq!((close.datetime, close.price), (day + Duration::days(1), 22.0));
+
+        // Statistics see the lowest and highest price of compacted periods, but advice does not
+        let prices = CollectedPrices {
+            points: vec![point("New", day + Duration::days(7), 24.0).close_point()],
+            rollups: weekly,
+        };
+        let statistics: Vec<f64> = prices.statistics().iter().map(|point| point.price).collect();
+        assert_eq!(statistics, vec![24.0, 22.0, 20.0, 30.0, 15.0]);
+        let closes: Vec<f64> = prices.closes().iter().map(|point| point.price).collect();
+        assert_eq!(closes, vec![24.0, 22.0, 15.0]);
+    }
+
+    #[test]
+    pub fn cutoffs() {
+        let policy = RetentionPolicy::default();
+        let now = Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap();
+
+        assert_eq!(policy.daily_cutoff(now), Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap());
+        // A year before is Friday the 1st of April, so the week starts on the Monday before it
+        assert_eq!(policy.weekly_cutoff(now), Utc.with_ymd_and_hms(2022, 3, 28, 0, 0, 0).unwrap());
+    }
+}
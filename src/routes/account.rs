@@ -0,0 +1,88 @@
+use crate::account::{export_account, DeletionPolicy};
+use crate::clock::Clock;
+use crate::csrf::{CsrfToken, CsrfVerified};
+use crate::database::Connection;
+use crate::error::Error;
+use crate::export::Download;
+use crate::forms::{DeleteAccountForm, UserCredentials};
+use crate::session::{Session, UserId};
+use log::info;
+use rocket::form::Form;
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
+use rocket::{get, post, State};
+use rocket_dyn_templates::{context, Template};
+use sqlx::Sqlite;
+
+#[get("/")]
+pub async fn account_page(
+    user: UserId,
+    csrf_token: CsrfToken,
+    mut database: Connection<Sqlite>,
+    policy: &State<DeletionPolicy>,
+    flash: Option<FlashMessage<'_>>,
+) -> crate::Result<Template> {
+    let email = database.user_email(*user).await?.unwrap_or_default();
+
+    Ok(Template::render("account", context! {
+        csrf_token,
+        email,
+        grace_days: policy.grace_period.num_days(),
+        flash: flash.map(FlashMessage::into_inner),
+    }))
+}
+
+/// Download everything stored about the user as a single JSON document
+#[get("/export")]
+pub async fn export(
+    user: UserId,
+    mut database: Connection<Sqlite>,
+    clock: &State<Clock>,
+) -> crate::Result<Download> {
+    match export_account(&mut database, user, clock.now()).await? {
+        Some(export) => Download::json("account", &export),
+        None => Err(Error::from("Unknown user")),
+    }
+}
+
+/// Sign out everywhere and schedule the account to be deleted once the grace period has passed
+#[post("/delete", data = "<form>")]
+pub async fn delete(
+    user: UserId,
+    session: Session<'_>,
+    _csrf: CsrfVerified,
+    mut database: Connection<Sqlite>,
+    policy: &State<DeletionPolicy>,
+    clock: &State<Clock>,
+    form: Form<DeleteAccountForm<'_>>,
+) -> crate::Result<Flash<Redirect>> {
+    // Only the password is needed to hash it
+    let credentials = UserCredentials {
+        email: "",
+        password: form.password,
+    };
+
+    let (password_matches,): (bool,) =
+        sqlx::query_as("SELECT EXISTS(SELECT 1 FROM Site_users WHERE sid = ? AND password_hash = ?)")
+            .bind(user)
+            .bind(&credentials.password_hash()[..])
+            .fetch_one(&mut *database)
+            .await?;
+
+    if !password_matches {
+        return Ok(Flash::error(Redirect::to("/account"), "Incorrect password"));
+    }
+
+    let now = clock.now();
+    database.request_account_deletion(*user, now).await?;
+    session.end(&mut database).await?;
+    info!("User {} requested deletion of their account", *user);
+
+    Ok(Flash::success(
+        Redirect::to("/login"),
+        format!(
+            "Your account will be deleted on {}. Sign in before then to keep it.",
+            policy.deletes_at(now).format("%Y-%m-%d")
+        ),
+    ))
+}
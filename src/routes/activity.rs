@@ -0,0 +1,43 @@
+use crate::csrf::CsrfToken;
+use crate::database::Connection;
+use crate::price_changes::ChangeKind;
+use crate::session::UserId;
+use chrono::{DateTime, Utc};
+use rocket::get;
+use rocket_dyn_templates::{context, Template};
+use serde::Serialize;
+use sqlx::Sqlite;
+
+/// Number of changes shown in the feed
+const FEED_LENGTH: u32 = 100;
+
+#[derive(Serialize)]
+struct FeedItem {
+    asin: String,
+    name: Option<String>,
+    occurred_at: DateTime<Utc>,
+    kind: ChangeKind,
+    description: String,
+}
+
+/// Price and stock changes to the products the user subscribed to, newest first
+#[get("/")]
+pub async fn feed(user: UserId, csrf_token: CsrfToken, mut database: Connection<Sqlite>) -> crate::Result<Template> {
+    let items: Vec<FeedItem> = database
+        .user_changes(user, FEED_LENGTH)
+        .await?
+        .into_iter()
+        .filter_map(|change| {
+            let event = change.event()?;
+            Some(FeedItem {
+                description: event.to_string(),
+                kind: event.kind,
+                asin: change.asin,
+                name: change.name,
+                occurred_at: change.occurred_at,
+            })
+        })
+        .collect();
+
+    Ok(Template::render("activity", context! { csrf_token, items }))
+}
@@ -0,0 +1,327 @@
+use crate::csrf::{CsrfToken, CsrfVerified};
+use crate::database::Connection;
+use crate::error::Error;
+use crate::forms::MergeForm;
+use crate::import::ImportJobs;
+use crate::scraper::{AmazonApi, CacheMode};
+use crate::session::AdminUser;
+use crate::tracking::refresh_product;
+use log::info;
+use rocket::form::Form;
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
+use rocket::{get, post, State};
+use rocket_dyn_templates::{context, Template};
+use serde::Serialize;
+use sqlx::types::Uuid;
+use sqlx::{FromRow, Sqlite};
+
+/// How far back the parser health page looks
+const HEALTH_WINDOW: &str = "-14 days";
+
+/// The most rows shown in each list on the admin console
+const CONSOLE_LIMIT: i64 = 200;
+
+/// The number of recent scrape failures shown on the admin console
+const FAILURE_LIMIT: usize = 50;
+
+#[derive(FromRow, Serialize)]
+struct UserSummary {
+    id: String,
+    email: String,
+    role: String,
+    email_verified: bool,
+    disabled: bool,
+    tracked: i64,
+    last_seen: Option<String>,
+}
+
+#[derive(FromRow, Serialize)]
+struct ProductSummary {
+    asin: String,
+    name: Option<String>,
+    manufacturer: Option<String>,
+    trackers: i64,
+    last_refresh: Option<String>,
+}
+
+/// A manufacturer or company along with how often it is referenced
+#[derive(FromRow, Serialize)]
+struct NamedEntity {
+    id: String,
+    name: Option<String>,
+    uses: i64,
+}
+
+#[derive(FromRow, Serialize)]
+struct FailedFetch {
+    asin: Option<String>,
+    url: String,
+    status: i64,
+    fetched_at: String,
+}
+
+#[derive(FromRow, Serialize)]
+struct FieldHealth {
+    day: Option<String>,
+    field: String,
+    successes: i64,
+    failures: i64,
+    failure_percent: f64,
+}
+
+#[derive(FromRow, Serialize)]
+struct ParserAlert {
+    day: String,
+    field: String,
+    failure_percent: f64,
+    raised_at: String,
+}
+
+#[get("/")]
+pub async fn console(
+    _admin: AdminUser,
+    csrf_token: CsrfToken,
+    mut database: Connection<Sqlite>,
+    amazon_api: &State<AmazonApi>,
+    import_jobs: &State<ImportJobs>,
+    flash: Option<FlashMessage<'_>>,
+) -> crate::Result<Template> {
+    let users = sqlx::query_as::<_, UserSummary>("
+        SELECT
+            lower(hex(u.sid)) AS id,
+            u.email AS email,
+            u.role AS role,
+            u.email_verified AS email_verified,
+            u.disabled AS disabled,
+            (SELECT COUNT(DISTINCT s.ASIN) FROM Subscribes_To s WHERE s.sid = u.sid) AS tracked,
+            (SELECT MAX(us.last_seen) FROM User_Sessions us WHERE us.sid = u.sid) AS last_seen
+        FROM Site_users u
+        ORDER BY u.email
+        LIMIT ?")
+        .bind(CONSOLE_LIMIT)
+        .fetch_all(&mut *database)
+        .await?;
+
+    // Products tracked by the most users first, since those are refreshed most often
+    let products = sqlx::query_as::<_, ProductSummary>("
+        SELECT
+            pvs.ASIN AS asin,
+            spm.name AS name,
+            m.name AS manufacturer,
+            (SELECT COUNT(DISTINCT s.sid) FROM Subscribes_To s WHERE s.ASIN = pvs.ASIN) AS trackers,
+            (SELECT MAX(r.datetime) FROM For_Product_Data_Refresh r WHERE r.ASIN = pvs.ASIN) AS last_refresh
+        FROM Product_variant_Sold pvs
+        LEFT JOIN Sold_Product_Manufactured spm ON spm.PID = pvs.PID
+        LEFT JOIN Manufacturer m ON m.ManuID = spm.ManuID
+        ORDER BY trackers DESC, pvs.ASIN
+        LIMIT ?")
+        .bind(CONSOLE_LIMIT)
+        .fetch_all(&mut *database)
+        .await?;
+
+    let manufacturers = sqlx::query_as::<_, NamedEntity>("
+        SELECT
+            lower(hex(m.ManuID)) AS id,
+            m.name AS name,
+            (SELECT COUNT(*) FROM Sold_Product_Manufactured spm WHERE spm.ManuID = m.ManuID) AS uses
+        FROM Manufacturer m
+        ORDER BY m.name")
+        .fetch_all(&mut *database)
+        .await?;
+
+    let companies = sqlx::query_as::<_, NamedEntity>("
+        SELECT
+            lower(hex(c.ComID)) AS id,
+            c.name AS name,
+            (SELECT COUNT(*) FROM Has_Listing_collected hlc
+                WHERE hlc.shipped_comID = c.ComID OR hlc.sold_ComID = c.ComID) AS uses
+        FROM Company c
+        ORDER BY c.name")
+        .fetch_all(&mut *database)
+        .await?;
+
+    // Only recorded when pages are archived, see SCRAPER_ARCHIVE_DIR
+    let failed_fetches = sqlx::query_as::<_, FailedFetch>("
+        SELECT ASIN AS asin, URL AS url, status, fetched_at
+        FROM Page_Archive
+        WHERE status >= 400
+        ORDER BY fetched_at DESC
+        LIMIT ?")
+        .bind(FAILURE_LIMIT as i64)
+        .fetch_all(&mut *database)
+        .await?;
+
+    let mut parse_failures = amazon_api.health().recent_failures();
+    parse_failures.truncate(FAILURE_LIMIT);
+
+    Ok(Template::render("admin", context! {
+        csrf_token,
+        users: &users,
+        products: &products,
+        manufacturers: &manufacturers,
+        companies: &companies,
+        failed_fetches: &failed_fetches,
+        parse_failures: &parse_failures,
+        imports: import_jobs.summaries(),
+        flash: flash.map(FlashMessage::into_inner),
+    }))
+}
+
+#[post("/users/<id>/disable")]
+pub async fn disable_user(
+    admin: AdminUser,
+    _csrf: CsrfVerified,
+    mut database: Connection<Sqlite>,
+    id: &str,
+) -> crate::Result<Flash<Redirect>> {
+    let user = parse_id(id).map_err(Error::from)?;
+    if user == **admin {
+        return Ok(Flash::error(Redirect::to("/admin"), "You can not disable your own account"));
+    }
+
+    if !database.set_user_disabled(user, true).await? {
+        return Ok(Flash::error(Redirect::to("/admin"), "Unknown user"));
+    }
+
+    info!("User {} disabled by {}", user, **admin);
+    Ok(Flash::success(Redirect::to("/admin"), "The user has been disabled and signed out"))
+}
+
+#[post("/users/<id>/enable")]
+pub async fn enable_user(
+    admin: AdminUser,
+    _csrf: CsrfVerified,
+    mut database: Connection<Sqlite>,
+    id: &str,
+) -> crate::Result<Flash<Redirect>> {
+    let user = parse_id(id).map_err(Error::from)?;
+    if !database.set_user_disabled(user, false).await? {
+        return Ok(Flash::error(Redirect::to("/admin"), "Unknown user"));
+    }
+
+    info!("User {} enabled by {}", user, **admin);
+    Ok(Flash::success(Redirect::to("/admin"), "The user has been enabled"))
+}
+
+#[post("/manufacturers/merge", data = "<form>")]
+pub async fn merge_manufacturers(
+    admin: AdminUser,
+    _csrf: CsrfVerified,
+    mut database: Connection<Sqlite>,
+    form: Form<MergeForm<'_>>,
+) -> crate::Result<Flash<Redirect>> {
+    let (duplicate, into) = merge_ids(&form).map_err(Error::from)?;
+    let moved = match database.merge_manufacturers(duplicate, into).await? {
+        Some(moved) => moved,
+        None => return Ok(Flash::error(Redirect::to("/admin"), "Unknown manufacturer to merge into")),
+    };
+
+    info!("Manufacturer {} merged into {} by {}", duplicate, into, **admin);
+    Ok(Flash::success(
+        Redirect::to("/admin"),
+        format!("Merged the manufacturers, moving {} product{}", moved, if moved == 1 { "" } else { "s" }),
+    ))
+}
+
+#[post("/companies/merge", data = "<form>")]
+pub async fn merge_companies(
+    admin: AdminUser,
+    _csrf: CsrfVerified,
+    mut database: Connection<Sqlite>,
+    form: Form<MergeForm<'_>>,
+) -> crate::Result<Flash<Redirect>> {
+    let (duplicate, into) = merge_ids(&form).map_err(Error::from)?;
+    let moved = match database.merge_companies(duplicate, into).await? {
+        Some(moved) => moved,
+        None => return Ok(Flash::error(Redirect::to("/admin"), "Unknown company to merge into")),
+    };
+
+    info!("Company {} merged into {} by {}", duplicate, into, **admin);
+    Ok(Flash::success(
+        Redirect::to("/admin"),
+        format!("Merged the companies, updating {} listing{}", moved, if moved == 1 { "" } else { "s" }),
+    ))
+}
+
+/// Fetch a product from Amazon again, skipping the response cache
+#[post("/products/<asin>/refresh")]
+pub async fn rescrape(
+    _admin: AdminUser,
+    _csrf: CsrfVerified,
+    mut database: Connection<Sqlite>,
+    amazon_api: &State<AmazonApi>,
+    asin: &str,
+) -> crate::Result<Flash<Redirect>> {
+    if !refresh_product(&mut database, amazon_api, asin, CacheMode::Bypass).await? {
+        return Ok(Flash::error(Redirect::to("/admin"), format!("Unable to read the page for {}", asin)));
+    }
+
+    Ok(Flash::success(Redirect::to("/admin"), format!("Refreshed {}", asin)))
+}
+
+fn parse_id(id: &str) -> Result<Uuid, &'static str> {
+    Uuid::parse_str(id).map_err(|_| "Invalid ID")
+}
+
+fn merge_ids(form: &MergeForm<'_>) -> Result<(Uuid, Uuid), &'static str> {
+    let (duplicate, into) = (parse_id(form.duplicate)?, parse_id(form.into)?);
+    if duplicate == into {
+        return Err("Choose two different entries to merge");
+    }
+
+    Ok((duplicate, into))
+}
+
+#[get("/parser")]
+pub async fn parser_health(
+    _admin: AdminUser,
+    csrf_token: CsrfToken,
+    mut database: Connection<Sqlite>,
+    amazon_api: &State<AmazonApi>,
+) -> crate::Result<Template> {
+    let totals = sqlx::query_as::<_, FieldHealth>("
+        SELECT
+            NULL AS day,
+            field,
+            SUM(successes) AS successes,
+            SUM(failures) AS failures,
+            ROUND(100.0 * SUM(failures) / MAX(SUM(successes) + SUM(failures), 1), 1) AS failure_percent
+        FROM Parser_Health
+        WHERE day >= date('now', ?)
+        GROUP BY field
+        ORDER BY failure_percent DESC, field")
+        .bind(HEALTH_WINDOW)
+        .fetch_all(&mut *database)
+        .await?;
+
+    let daily = sqlx::query_as::<_, FieldHealth>("
+        SELECT
+            day,
+            field,
+            successes,
+            failures,
+            ROUND(100.0 * failures / MAX(successes + failures, 1), 1) AS failure_percent
+        FROM Parser_Health
+        WHERE day >= date('now', ?)
+        ORDER BY day DESC, field")
+        .bind(HEALTH_WINDOW)
+        .fetch_all(&mut *database)
+        .await?;
+
+    let alerts = sqlx::query_as::<_, ParserAlert>("
+        SELECT day, field, ROUND(100.0 * failure_rate, 1) AS failure_percent, raised_at
+        FROM Parser_Alerts
+        ORDER BY day DESC, field
+        LIMIT 50")
+        .fetch_all(&mut *database)
+        .await?;
+
+    Ok(Template::render("parser_health", context! {
+        csrf_token,
+        totals: &totals,
+        daily: &daily,
+        alerts: &alerts,
+        recent_failures: amazon_api.health().recent_failures(),
+    }))
+}
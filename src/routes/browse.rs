@@ -0,0 +1,175 @@
+use crate::browse::{breadcrumb, children, subtree, CatalogProduct, Deal, DealSummary};
+use crate::clock::Clock;
+use crate::csrf::CsrfToken;
+use crate::database::Connection;
+use crate::error::Error;
+use crate::session::UserId;
+use crate::stats::price_stats;
+use chrono::{DateTime, Utc};
+use rocket::{get, State};
+use rocket_dyn_templates::{context, Template};
+use serde::Serialize;
+use sqlx::Sqlite;
+use std::collections::{BTreeMap, HashMap};
+
+/// A department or manufacturer with the deals on the tracked products filed under it
+#[derive(Serialize)]
+struct CategoryRow<'a> {
+    id: &'a str,
+    name: &'a str,
+    /// Amazon's browse node ID of a department
+    node: Option<i64>,
+    summary: DealSummary,
+}
+
+#[derive(Serialize)]
+struct ProductRow<'a> {
+    product: &'a CatalogProduct,
+    deal: Option<Deal>,
+}
+
+/// The deal on each product with collected prices, by ASIN
+async fn deals(
+    database: &mut Connection<Sqlite>,
+    products: &[CatalogProduct],
+    now: DateTime<Utc>,
+) -> sqlx::Result<HashMap<String, Deal>> {
+    let mut deals = HashMap::new();
+    for product in products {
+        let stats = price_stats(&database.price_points(&product.asin).await?.statistics(), now);
+        if let Some(deal) = Deal::from_stats(&stats) {
+            deals.insert(product.asin.clone(), deal);
+        }
+    }
+
+    Ok(deals)
+}
+
+fn product_rows<'a>(
+    products: impl Iterator<Item = &'a CatalogProduct>,
+    deals: &HashMap<String, Deal>,
+) -> Vec<ProductRow<'a>> {
+    products
+        .map(|product| ProductRow {
+            product,
+            deal: deals.get(&product.asin).copied(),
+        })
+        .collect()
+}
+
+/// Browse the tracked products by department, starting from the top level departments. Each
+/// department lists the departments below it which hold tracked products and every tracked
+/// product within it.
+#[get("/departments?<id>")]
+pub async fn departments(
+    user: UserId,
+    csrf_token: CsrfToken,
+    mut database: Connection<Sqlite>,
+    clock: &State<Clock>,
+    id: Option<&str>,
+) -> crate::Result<Template> {
+    let departments = database.departments().await?;
+    let products = database.tracked_catalog(user).await?;
+    let deals = deals(&mut database, &products, clock.now()).await?;
+
+    let id = id.map(str::to_ascii_lowercase);
+    let path = match &id {
+        Some(id) => match breadcrumb(&departments, id) {
+            path if path.is_empty() => return Err(Error::from("Unknown department")),
+            path => path,
+        },
+        None => Vec::new(),
+    };
+
+    let rows: Vec<CategoryRow> = children(&departments, id.as_deref())
+        .into_iter()
+        .filter_map(|department| {
+            let below = subtree(&departments, &department.id);
+            let summary = DealSummary::new(
+                products
+                    .iter()
+                    .filter(|product| below.contains(product.department.as_str()))
+                    .map(|product| deals.get(&product.asin)),
+            );
+
+            (summary.products > 0).then_some(CategoryRow {
+                id: &department.id,
+                name: &department.name,
+                node: department.node,
+                summary,
+            })
+        })
+        .collect();
+
+    let listed = match &id {
+        Some(id) => {
+            let below = subtree(&departments, id);
+            product_rows(products.iter().filter(|product| below.contains(product.department.as_str())), &deals)
+        }
+        None => Vec::new(),
+    };
+
+    let summary = DealSummary::new(listed.iter().map(|row| row.deal.as_ref()));
+    Ok(Template::render("browse", context! {
+        csrf_token,
+        heading: "Departments",
+        link: "/browse/departments",
+        path,
+        rows,
+        products: listed,
+        summary,
+    }))
+}
+
+/// Browse the tracked products by manufacturer
+#[get("/manufacturers?<id>")]
+pub async fn manufacturers(
+    user: UserId,
+    csrf_token: CsrfToken,
+    mut database: Connection<Sqlite>,
+    clock: &State<Clock>,
+    id: Option<&str>,
+) -> crate::Result<Template> {
+    let products = database.tracked_catalog(user).await?;
+    let deals = deals(&mut database, &products, clock.now()).await?;
+
+    let mut by_manufacturer: BTreeMap<&str, Vec<&CatalogProduct>> = BTreeMap::new();
+    for product in &products {
+        by_manufacturer.entry(&product.manufacturer_id).or_default().push(product);
+    }
+
+    let id = id.map(str::to_ascii_lowercase);
+    let (heading, rows, listed) = match id.as_deref() {
+        Some(id) => {
+            let products = by_manufacturer
+                .get(id)
+                .ok_or_else(|| Error::from("None of your products are made by that manufacturer"))?;
+            let name = products[0].manufacturer.as_deref().unwrap_or("Unknown manufacturer");
+            (name, Vec::new(), product_rows(products.iter().copied(), &deals))
+        }
+        None => {
+            let mut rows: Vec<CategoryRow> = by_manufacturer
+                .iter()
+                .map(|(id, products)| CategoryRow {
+                    id,
+                    name: products[0].manufacturer.as_deref().unwrap_or("Unknown manufacturer"),
+                    node: None,
+                    summary: DealSummary::new(products.iter().map(|product| deals.get(&product.asin))),
+                })
+                .collect();
+
+            rows.sort_by(|a, b| a.name.cmp(b.name));
+            ("Manufacturers", rows, Vec::new())
+        }
+    };
+
+    let summary = DealSummary::new(listed.iter().map(|row| row.deal.as_ref()));
+    Ok(Template::render("browse", context! {
+        csrf_token,
+        heading,
+        link: "/browse/manufacturers",
+        rows,
+        products: listed,
+        summary,
+    }))
+}
@@ -1,3 +1,4 @@
+use crate::csrf::CsrfToken;
 use rocket::Request;
 use rocket::catch;
 use rocket_dyn_templates::{context, Template};
@@ -5,6 +6,7 @@ use rocket_dyn_templates::{context, Template};
 #[catch(404)]
 pub fn not_found(req: &Request<'_>) -> Template {
     Template::render("404", context! {
+        csrf_token: CsrfToken::from(req.cookies()),
         uri: req.uri()
     })
 }
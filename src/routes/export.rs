@@ -0,0 +1,52 @@
+use crate::database::Connection;
+use crate::error::Error;
+use crate::export::{Download, ExportFormat};
+use crate::session::UserId;
+use chrono::NaiveDate;
+use rocket::get;
+use sqlx::Sqlite;
+
+/// Read an optional date given as `YYYY-MM-DD`
+pub fn parse_date(name: &str, date: Option<&str>) -> Result<Option<NaiveDate>, String> {
+    match date.filter(|date| !date.is_empty()) {
+        Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .map(Some)
+            .map_err(|_| format!("The {} date must be written as YYYY-MM-DD", name)),
+        None => Ok(None),
+    }
+}
+
+#[get("/product/<asin>?<format>&<from>&<to>")]
+pub async fn product_history(
+    user: UserId,
+    mut database: Connection<Sqlite>,
+    asin: &str,
+    format: Option<ExportFormat>,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> crate::Result<Download> {
+    let from = parse_date("from", from)?;
+    let to = parse_date("to", to)?;
+
+    if !database.tracks_asin(user, asin).await? {
+        return Err(Error::from("Only the history of tracked products can be exported"));
+    }
+
+    let rows = database.product_history(asin, from, to).await?;
+    Download::new(&format!("{}-history", asin), format.unwrap_or_default(), &rows)
+}
+
+#[get("/all?<format>&<from>&<to>")]
+pub async fn user_history(
+    user: UserId,
+    mut database: Connection<Sqlite>,
+    format: Option<ExportFormat>,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> crate::Result<Download> {
+    let from = parse_date("from", from)?;
+    let to = parse_date("to", to)?;
+
+    let rows = database.user_history(user, from, to).await?;
+    Download::new("price-history", format.unwrap_or_default(), &rows)
+}
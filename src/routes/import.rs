@@ -0,0 +1,140 @@
+use crate::csrf::{CsrfToken, CsrfVerified};
+use crate::database::Connection;
+use crate::error::Error;
+use crate::forms::{HistoryUpload, ImportUpload};
+use crate::import::history::parse_history;
+use crate::import::{parse_upload, ImportJobs};
+use crate::scraper::AmazonApi;
+use crate::session::UserId;
+use log::{info, warn};
+use rocket::form::Form;
+use rocket::fs::TempFile;
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
+use rocket::{get, post, State};
+use rocket_dyn_templates::{context, Template};
+use sqlx::types::Uuid;
+use sqlx::{Pool, Sqlite};
+use tokio::io::AsyncReadExt;
+
+#[get("/")]
+pub async fn import_page(
+    _user_id: UserId,
+    csrf_token: CsrfToken,
+    flash: Option<FlashMessage<'_>>,
+) -> crate::Result<Template> {
+    Ok(Template::render("import", context! {csrf_token, flash: flash.map(FlashMessage::into_inner)}))
+}
+
+#[post("/", data = "<upload>")]
+pub async fn upload(
+    user_id: UserId,
+    _csrf: CsrfVerified,
+    pool: &State<Pool<Sqlite>>,
+    amazon_api: &State<AmazonApi>,
+    jobs: &State<ImportJobs>,
+    upload: Form<ImportUpload<'_>>,
+) -> crate::Result<Redirect> {
+    let mut contents = String::new();
+
+    if let Some(file) = upload.file.as_ref().filter(|file| file.len() > 0) {
+        read_file(file, &mut contents, "The file must be a text, CSV or HTML file").await?;
+    }
+
+    if let Some(text) = upload.text.filter(|text| !text.trim().is_empty()) {
+        contents.push('\n');
+        contents.push_str(text);
+    }
+
+    let rows = match parse_upload(&contents) {
+        Ok(rows) => rows,
+        Err(e) => return Err(Error::from(Flash::error(Redirect::to("/import"), e.to_string()))),
+    };
+
+    let id = jobs.start(user_id, rows, pool.inner().clone(), amazon_api.inner().clone());
+    Ok(Redirect::to(format!("/import/{}", id)))
+}
+
+#[post("/history", data = "<upload>")]
+pub async fn history(
+    user_id: UserId,
+    _csrf: CsrfVerified,
+    mut database: Connection<Sqlite>,
+    upload: Form<HistoryUpload<'_>>,
+) -> crate::Result<Flash<Redirect>> {
+    let asin = upload.asin.trim();
+    if !database.tracks_asin(user_id, asin).await? {
+        let flash_error = Flash::error(Redirect::to("/import"), "Price history can only be imported for tracked products");
+        return Err(Error::from(flash_error));
+    }
+
+    let mut contents = String::new();
+    read_file(&upload.file, &mut contents, "The price history must be a CSV file").await?;
+
+    let history = match parse_history(&contents) {
+        Ok(history) => history,
+        Err(e) => return Err(Error::from(Flash::error(Redirect::to("/import"), e.to_string()))),
+    };
+
+    let mut added = 0;
+    for point in &history.points {
+        if database.add_imported_listing(asin, point).await? {
+            added += 1;
+        }
+    }
+
+    let already_collected = history.points.len() - added;
+    info!("Imported {} of {} prices for {}", added, history.points.len(), asin);
+
+    let mut message = format!(
+        "Imported {} prices for {}. {} were skipped since a price was already collected for that day.",
+        added, asin, already_collected
+    );
+    if history.skipped > 0 {
+        message.push_str(&format!(" {} rows could not be read.", history.skipped));
+    }
+
+    Ok(Flash::success(Redirect::to("/import"), message))
+}
+
+/// Read an uploaded file as text, or redirect back to the import page with the message
+async fn read_file(file: &TempFile<'_>, contents: &mut String, message: &'static str) -> crate::Result<()> {
+    let read = match file.open().await {
+        Ok(mut reader) => reader.read_to_string(contents).await,
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = read {
+        warn!("Unable to read uploaded import file: {}", e);
+        return Err(Error::from(Flash::error(Redirect::to("/import"), message)));
+    }
+
+    Ok(())
+}
+
+#[get("/<id>")]
+pub async fn progress(
+    user_id: UserId,
+    csrf_token: CsrfToken,
+    jobs: &State<ImportJobs>,
+    id: &str,
+) -> crate::Result<Template> {
+    let job = Uuid::parse_str(id)
+        .ok()
+        .and_then(|id| jobs.get(id, user_id));
+
+    let job = match job {
+        Some(job) => job,
+        None => {
+            let flash_error = Flash::error(Redirect::to("/import"), "That import could not be found. Finished imports are only kept for an hour.");
+            return Err(Error::from(flash_error));
+        }
+    };
+
+    Ok(Template::render("import_progress", context! {
+        csrf_token,
+        completed: job.completed(),
+        finished: job.is_finished(),
+        job: &job,
+    }))
+}
@@ -5,6 +5,14 @@ use rocket::{routes, Build, Rocket,get,catchers};
 pub mod render_routes;
 pub mod user;
 pub mod errors;
+pub mod account;
+pub mod activity;
+pub mod browse;
+pub mod admin;
+pub mod import;
+pub mod export;
+pub mod sessions;
+pub mod two_factor;
 
 mod products;
 #[cfg(test)]
@@ -18,7 +26,17 @@ pub fn build_app() -> Rocket<Build> {
         .register("/", catchers![errors::not_found])
         .mount(
             "/",
-            routes![user::index, user::register, user::logout, user::login],
+            routes![
+                user::index,
+                user::register,
+                user::logout,
+                user::login,
+                user::login_two_factor,
+                user::verify_email,
+                user::resend_verification,
+                user::forgot_password,
+                user::reset_password
+            ],
         )
         .mount(
             "/",
@@ -26,6 +44,10 @@ pub fn build_app() -> Rocket<Build> {
                 render_routes::index_page,
                 render_routes::login_page,
                 render_routes::signup_page,
+                render_routes::two_factor_login_page,
+                render_routes::forgot_password_page,
+                render_routes::resend_verification_page,
+                render_routes::reset_password_page,
                 render_routes::about_page
             ],
         )
@@ -34,13 +56,56 @@ pub fn build_app() -> Rocket<Build> {
             "/product",
             routes![
                 products::add_product,
+                products::add_many,
+                products::search,
                 products::remove_product,
                 products::historic,
+                products::price_chart,
+                products::price_sparkline,
+                products::compare_page,
+                products::compare_chart,
                 products::update_now,
                 products::tracked_product_list,
                 products::product_info,
             ],
         )
+        .mount(
+            "/admin",
+            routes![
+                admin::console,
+                admin::parser_health,
+                admin::disable_user,
+                admin::enable_user,
+                admin::merge_manufacturers,
+                admin::merge_companies,
+                admin::rescrape
+            ],
+        )
+        .mount(
+            "/import",
+            routes![import::import_page, import::upload, import::history, import::progress],
+        )
+        .mount(
+            "/export",
+            routes![export::product_history, export::user_history],
+        )
+        .mount(
+            "/two-factor",
+            routes![
+                two_factor::two_factor_page,
+                two_factor::setup,
+                two_factor::confirm,
+                two_factor::regenerate_recovery_codes,
+                two_factor::disable
+            ],
+        )
+        .mount("/account", routes![account::account_page, account::export, account::delete])
+        .mount("/activity", routes![activity::feed])
+        .mount("/browse", routes![browse::departments, browse::manufacturers])
+        .mount(
+            "/sessions",
+            routes![sessions::sessions_page, sessions::revoke, sessions::revoke_all],
+        )
 
 }
 
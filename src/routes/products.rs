@@ -1,107 +1,263 @@
-use chrono::Utc;
+use crate::chart;
+use crate::compare::{self, ComparedProduct};
+use crate::clock::Clock;
+use crate::csrf::{CsrfToken, CsrfVerified};
 use crate::database::Connection;
+use crate::tracking::{refresh_product, track_new_product};
 use crate::session::Session;
-use rocket::http::RawStr;
+use rocket::http::{ContentType, RawStr};
 use rocket::response::{Flash, Redirect};
 use crate::error::Error;
-use crate::scraper::{extract_asin, AmazonApi};
+use crate::forms::{AmazonURLForm, ProductSelectionForm, UpdateProductForm};
+use crate::scraper::{AmazonApi, CacheMode};
+use crate::scraper::identifier::{Marketplace, ProductIdentifier};
 use crate::session::UserId;
-use rocket::{get, State};
+use crate::prediction::buy_advice;
+use crate::retention;
+use super::export::parse_date;
+use crate::stats::{price_stats, PriceStats};
+use rocket::form::Form;
+use rocket::{delete, get, post, State};
 use rocket_dyn_templates::{context, Template};
 use sqlx::Sqlite;
+use std::collections::{BTreeSet, HashMap};
 use log::info;
-use sqlx::FromRow;
-use serde::Serialize;
-use uuid::Uuid;
 use crate::scraper::product::{DepartmentHierarchy, Product};
 
-#[derive(FromRow,Serialize)]
-struct ProductStory {Price: f32, datetime:String }
-impl ProductStory {
-    fn to_vectors(&self) -> (Vec<String>, Vec<f32>) {
-        let mut timestamps = Vec::new();
-        let mut prices = Vec::new();
+/// Size of the chart on the historic page
+const CHART_SIZE: (u32, u32) = (900, 400);
 
-        timestamps.push(self.datetime.clone());
-        prices.push(self.Price);
-
-        (timestamps, prices)
-    }
-}
+/// Size of the sparklines in the table of tracked products
+const SPARKLINE_SIZE: (u32, u32) = (120, 30);
 
 
-#[get("/add?<url>")]
+#[post("/add", data = "<form>")]
 pub async fn add_product(
     user_id: UserId,
+    _csrf: CsrfVerified,
     mut database: Connection<Sqlite>,
     amazon_api: &State<AmazonApi>,
-    url: &str,
+    form: Form<AmazonURLForm<'_>>,
 ) -> crate::Result<Flash<Redirect>> {
     // This method should results in adding the product information to the database
     // There should be no template as response
-    info!("The requested URL is \n {}",&url);
-
-    let asin = match extract_asin(url) {
-        Some(asin) => asin.to_ascii_uppercase(),
-        None => return Ok(Flash::error(Redirect::to("/index"), "URL must be a valid Amazon product URL")),
-    };
+    info!("The requested URL is \n {}",&form.url);
 
-    let product = match amazon_api.get_product_info(&asin).await? {
-        Some(product) => product,
-        None => {
-            let flash_error = Flash::error(Redirect::to("/index"), "Product not found");
-            return Err(Error::from(flash_error));
+    let asin = match amazon_api.resolve_identifier(form.url).await? {
+        Some(identifier) if identifier.marketplace != Marketplace::UnitedStates => {
+            return Ok(Flash::error(Redirect::to("/index"), "Only products on amazon.com can be tracked"))
         }
+        Some(identifier) => {
+            info!("Identified product {} from {:?}", identifier.asin, identifier.source);
+            identifier.asin
+        }
+        None => return Ok(Flash::error(Redirect::to("/index"), "URL must be a valid Amazon product URL, ASIN or ISBN")),
     };
 
+    if !track_new_product(user_id, &mut database, amazon_api, &asin, None).await? {
+        let flash_error = Flash::error(Redirect::to("/index"), "Product not found");
+        return Err(Error::from(flash_error));
+    }
 
-    let product_id = match database.product_exists(&product.asin).await? {
-        Some(id) => id,
-        None =>  database.add_product(&product).await?,
-    };
+    Ok(Flash::success(Redirect::to("/index"),"Added new product" ))
+}
 
-    database.track_product(user_id, product_id, &asin).await?;
+#[get("/search?<query>")]
+pub async fn search(
+    _user_id: UserId,
+    csrf_token: CsrfToken,
+    amazon_api: &State<AmazonApi>,
+    query: &str,
+) -> crate::Result<Template> {
+    let query = query.trim();
+    if query.is_empty() {
+        return Err(Error::from(Flash::error(Redirect::to("/index"), "Enter something to search for")));
+    }
 
+    let results = amazon_api.search(query, CacheMode::Default).await?;
 
-    update_now(user_id, database, amazon_api, &asin).await?;
+    Ok(Template::render("search", context! {
+        csrf_token,
+        query: query,
+        results: &results,
+    }))
+}
 
-    Ok(Flash::success(Redirect::to("/index"),"Added new product" ))
+/// Track every product selected from the search results page
+#[post("/add_many", data = "<form>")]
+pub async fn add_many(
+    user_id: UserId,
+    _csrf: CsrfVerified,
+    mut database: Connection<Sqlite>,
+    amazon_api: &State<AmazonApi>,
+    form: Form<ProductSelectionForm>,
+) -> crate::Result<Flash<Redirect>> {
+    let asin = &form.asin;
+    if asin.is_empty() {
+        return Ok(Flash::error(Redirect::to("/index"), "No products were selected"));
+    }
+
+    // Entries are checked the same way as a single added product before anything is fetched
+    let mut invalid = Vec::new();
+    let mut not_found = Vec::new();
+    for input in asin {
+        let asin = match ProductIdentifier::parse(input) {
+            Ok(identifier) if identifier.marketplace == Marketplace::UnitedStates => identifier.asin,
+            _ => {
+                invalid.push(input.as_str());
+                continue;
+            }
+        };
+
+        if !track_new_product(user_id, &mut database, amazon_api, &asin, None).await? {
+            not_found.push(asin);
+        }
+    }
+
+    let mut errors = Vec::new();
+    if !invalid.is_empty() {
+        errors.push(format!("Not valid amazon.com products: {}", invalid.join(", ")));
+    }
+    if !not_found.is_empty() {
+        errors.push(format!("Unable to find products: {}", not_found.join(", ")));
+    }
+    if !errors.is_empty() {
+        return Ok(Flash::error(Redirect::to("/index"), errors.join(". ")));
+    }
+
+    Ok(Flash::success(Redirect::to("/index"), format!("Added {} products", asin.len())))
 }
 
-#[get("/historic?<asin>")]
+/// Statistics, buying advice and a chart of the prices of a product between the optional dates,
+/// read at the resolution kept for the range
+#[get("/historic?<asin>&<from>&<to>")]
 pub async fn historic(
     mut database: Connection<Sqlite>,
+    csrf_token: CsrfToken,
+    clock: &State<Clock>,
     asin: &str,
+    from: Option<&str>,
+    to: Option<&str>,
 ) -> crate::Result<Template> {
-    let product_historic = 
-        sqlx::query_as::<_,ProductStory>("SELECT Price, datetime FROM Has_Listing_collected WHERE ASIN = ? ")
-        .bind(asin)
-        .fetch_all(&mut *database)
-        .await?;
-
-    let mut timestamps = Vec::new();
-    let mut prices = Vec::new();
-
-    for story in product_historic.iter() {
-        let (story_timestamps, story_prices) = story.to_vectors();
-        timestamps.extend(story_timestamps);
-        prices.extend(story_prices);
-    }
-    let max_price = prices.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b)); 
-    let min_price = prices.iter().fold(f32::INFINITY, |a, &b| a.min(b)); 
+    let (start, end) = retention::day_range(parse_date("from", from)?, parse_date("to", to)?);
+    let (resolution, prices) = retention::load_price_points(&mut database, asin, start, end).await?;
+    let stats = price_stats(&prices.statistics(), clock.now());
+    let advice = buy_advice(&prices.closes(), clock.now());
     Ok(Template::render("historic",context! {
-        max_price : &max_price,
-        min_price: &min_price,
-       prices: &prices,
-       timestamps: &timestamps
+       csrf_token,
+       asin,
+       from: from.unwrap_or_default(),
+       to: to.unwrap_or_default(),
+       resolution,
+       stats: &stats,
+       advice: &advice
     }))
 
 }
     
 
 
-#[get("/remove?<asin>")]
+/// Line chart of the prices collected for the product between the optional dates, with a line
+/// for each condition and seller. Compacted prices only have a line for each condition.
+#[get("/<asin>/chart.svg?<from>&<to>")]
+pub async fn price_chart(
+    mut database: Connection<Sqlite>,
+    asin: &str,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> crate::Result<(ContentType, String)> {
+    let (start, end) = retention::day_range(parse_date("from", from)?, parse_date("to", to)?);
+    let points = retention::chart_history(&mut database, asin, start, end).await?;
+    let series = chart::series_by_listing(&points);
+    let (width, height) = CHART_SIZE;
+    Ok((ContentType::SVG, chart::line_chart(&series, width, height)))
+}
+
+/// Small chart of the lowest price collected at each refresh of the product
+#[get("/<asin>/sparkline.svg")]
+pub async fn price_sparkline(mut database: Connection<Sqlite>, asin: &str) -> crate::Result<(ContentType, String)> {
+    let points = retention::chart_history(&mut database, asin, None, None).await?;
+    let prices = chart::lowest_prices(&points);
+    let (width, height) = SPARKLINE_SIZE;
+    Ok((ContentType::SVG, chart::sparkline(&prices, width, height)))
+}
+
+/// Compare the details and prices of several products side by side. The products can be picked
+/// from the ones the user tracks or given as ASINs, ISBNs or URLs.
+#[get("/compare?<asin>")]
+pub async fn compare_page(
+    user: UserId,
+    csrf_token: CsrfToken,
+    mut database: Connection<Sqlite>,
+    clock: &State<Clock>,
+    asin: Vec<&str>,
+) -> crate::Result<Template> {
+    let tracked = database.tracked_products(*user).await?;
+    let asins = match compare::parse_products(&asin) {
+        Ok(asins) => asins,
+        Err(message) => {
+            return Ok(Template::render("compare", context! {
+                csrf_token,
+                tracked,
+                selected: &asin,
+                flash: ("error", message),
+            }))
+        }
+    };
+
+    let products = compare::in_order(&asins, database.compared_products(&asins).await?);
+    let missing: Vec<&String> = asins
+        .iter()
+        .filter(|asin| !products.iter().any(|product| &product.asin == *asin))
+        .collect();
+
+    // Statistics of each product by condition, along with every condition any product was seen in
+    let mut stats: HashMap<&str, HashMap<String, PriceStats>> = HashMap::new();
+    let mut conditions = BTreeSet::new();
+    for product in &products {
+        let product_stats = stats.entry(&product.asin).or_default();
+        for condition_stats in price_stats(&database.price_points(&product.asin).await?.statistics(), clock.now()) {
+            conditions.insert(condition_stats.condition.clone());
+            product_stats.insert(condition_stats.condition.clone(), condition_stats);
+        }
+    }
+
+    let chart_query: Vec<String> = products.iter().map(|product| format!("asin={}", product.asin)).collect();
+    let flash = (!missing.is_empty()).then(|| {
+        let missing: Vec<&str> = missing.iter().map(|asin| asin.as_str()).collect();
+        ("error", format!("These products have not been added yet: {}", missing.join(", ")))
+    });
+
+    Ok(Template::render("compare", context! {
+        csrf_token,
+        tracked,
+        selected: &asins,
+        products: &products,
+        conditions,
+        stats,
+        chart_query: chart_query.join("&"),
+        flash,
+    }))
+}
+
+/// Chart of the lowest price of each compared product
+#[get("/compare.svg?<asin>")]
+pub async fn compare_chart(mut database: Connection<Sqlite>, asin: Vec<&str>) -> crate::Result<(ContentType, String)> {
+    let asins = compare::parse_products(&asin)?;
+
+    let mut products: Vec<(ComparedProduct, Vec<chart::ChartPoint>)> = Vec::new();
+    for product in compare::in_order(&asins, database.compared_products(&asins).await?) {
+        let points = retention::chart_history(&mut database, &product.asin, None, None).await?;
+        products.push((product, points));
+    }
+
+    let (width, height) = CHART_SIZE;
+    Ok((ContentType::SVG, chart::line_chart(&compare::overlay_series(&products), width, height)))
+}
+
+#[delete("/<asin>")]
 pub async fn remove_product(
+    _csrf: CsrfVerified,
     mut database: Connection<Sqlite>,
     asin: &str,
 ) -> crate::Result<Flash<Redirect>> {
@@ -115,69 +271,24 @@ pub async fn remove_product(
     Ok(Flash::success(Redirect::to("/index"),"deleted new product" ))
 }
 
-#[get("/update?<asin>")]
+#[post("/update", data = "<form>")]
 pub async fn update_now(
     user: UserId,
+    _csrf: CsrfVerified,
     mut database: Connection<Sqlite>,
     amazon_api: &State<AmazonApi>,
-    asin: &str,
+    form: Form<UpdateProductForm<'_>>,
 ) -> crate::Result<Flash<Redirect>> {
     // TODO: Verify that asin is being tracked by the current user
-    let product = match amazon_api.get_product_info(&asin).await? {
-        Some(product) => product,
-        None => {
-            let flash_error = Flash::error(Redirect::to("/index"), "Product not found");
-            return Err(Error::from(flash_error));
-        }
-    };
-
-    if database.product_exists(&product.asin).await?.is_none() {
-        return Err(Error::from("Product must be added before it can be updated"))
+    let cache_mode = if form.force {
+        CacheMode::Bypass
+    } else {
+        CacheMode::Default
     };
 
-    let department = database.get_or_add_department(&product.department).await?;
-    let manufacturer = database.get_or_add_manufacturer(&product.manufacturer).await?;
-
-    sqlx::query("
-    UPDATE Sold_Product_Manufactured
-        SET name = ?, DepID = ?, ManuID = ?
-        WHERE PID IN (SELECT PID FROM Product_variant_Sold WHERE ASIN = ?);
-    ")
-        .bind(&product.name)
-        .bind(department)
-        .bind(manufacturer)
-        .bind(asin)
-        .execute(&mut *database)
-        .await?;
-
-    let offers = amazon_api.get_offers_for_asin(&asin).await?;
-    // TODO: Add the new offers to database
-
-
-    let today = Utc::now().date_naive();
-    sqlx::query("INSERT INTO For_Product_Data_Refresh (datetime, ASIN) VALUES (?, ?)")
-        .bind(today)
-        .bind(&product.asin)
-        .execute(&mut *database)
-        .await?;
-
-    for offer in offers.into_iter().take(1) {
-        let condition_str = format!("{:?}", offer.condition);
-        let shipped_by = database.get_or_add_company(&offer.ships_from).await?;
-        let sold_by = database.get_or_add_company(&offer.sold_by).await?;
-        let listing_id = Uuid::new_v4();
-
-        sqlx::query("INSERT INTO Has_Listing_collected (ListingID,ASIN,condition,\
-                    Price,datetime,shipped_comID,sold_ComID) VALUES (?,?,?,?,?,?,?)")
-            .bind(listing_id)
-            .bind(&product.asin)
-            .bind(condition_str)
-            .bind(f64::from(offer.price))
-            .bind(today)
-            .bind(shipped_by)
-            .bind(sold_by)
-            .execute(&mut *database)
-            .await?;
+    if !refresh_product(&mut database, amazon_api, form.asin, cache_mode).await? {
+        let flash_error = Flash::error(Redirect::to("/index"), "Product not found");
+        return Err(Error::from(flash_error));
     }
 
     // Return the product page with the newly updated data
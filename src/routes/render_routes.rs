@@ -1,6 +1,13 @@
-use crate::session::Session;
+use crate::account_token::{AccountTokens, TokenPurpose};
+use crate::clock::Clock;
+use crate::csrf::CsrfToken;
+use crate::session::{Session, UserId};
+use crate::stats::{price_stats, PriceStats};
+use std::collections::HashMap;
+use rocket::response::Redirect;
+use chrono::Utc;
 use log::{error, info};
-use rocket::get;
+use rocket::{get, State};
 use rocket_dyn_templates::{context, Template};
 use rocket::request::FlashMessage;
 use sqlx:: Sqlite;
@@ -14,53 +21,55 @@ use rocket::serde::json::json;
 //      Maybe a is_anonymous method could work
 // TODO: The login checks within this file should be done via a middleware
 #[derive(FromRow,Serialize)]
-struct Product  {ASIN: String , Price:f32, datetime:String,name:String }
+struct Product  {ASIN: String , Price:f32, condition: Option<String>, datetime:String,name:String }
 
 
 #[get("/login")]
-pub async fn login_page(session: Session<'_>,mut database: Connection<Sqlite>,
+pub async fn login_page(session: Session<'_>,mut database: Connection<Sqlite>, csrf_token: CsrfToken,
                         flash: Option<FlashMessage<'_>>) -> crate::Result<Template> {
     // Template render of the login page
     if !session.is_logged_in() {
-        Ok(Template::render("login", context! {flash:flash.map(FlashMessage::into_inner)}))
+        Ok(Template::render("login", context! {csrf_token, flash:flash.map(FlashMessage::into_inner)}))
     } 
     else {
 
-        Ok(Template::render("index", context! {}) ) 
+        Ok(Template::render("index", context! { csrf_token }) ) 
         }
 }
 
         
 
 #[get("/signup")]
-pub async fn signup_page(session: Session<'_>,flash: Option<FlashMessage<'_>>) -> crate::Result<Template> {
+pub async fn signup_page(session: Session<'_>, csrf_token: CsrfToken, flash: Option<FlashMessage<'_>>) -> crate::Result<Template> {
     // I need to add here the products_json
     if session.user_id().is_none() {
-        Ok(Template::render("register", context! {flash:flash.map(FlashMessage::into_inner)}))
+        Ok(Template::render("register", context! {csrf_token, flash:flash.map(FlashMessage::into_inner)}))
     } else {
-        Ok(Template::render("index", context! {}))
+        Ok(Template::render("index", context! { csrf_token }))
     }
 }
 
 #[get("/index")]
-pub async fn index_page(session: Session<'_>,mut database: Connection<Sqlite>,
+pub async fn index_page(session: Session<'_>,mut database: Connection<Sqlite>, csrf_token: CsrfToken,
+                        clock: &State<Clock>,
                         flash: Option<FlashMessage<'_>>) -> crate::Result<Template> {
     // Template render of the index
-    if session.user_id().is_none() {
-        Ok(Template::render("login", context! {}))
-    } 
-    else {
+    match session.user_id() {
+        None => Ok(Template::render("login", context! { csrf_token })),
+        Some(user) => {
         // Add more info to the query, we need the name of the product
         let user_products=
             sqlx::query_as::<_, Product>("
                 WITH Latest_Listings AS (
                 SELECT
-                    ASIN,
-                    MAX(datetime) AS latest_datetime
+                    r.ASIN,
+                    MAX(r.datetime) AS latest_datetime
                 FROM
-                    Has_Listing_collected
+                    For_Product_Data_Refresh r
+                JOIN
+                    Has_Listing_collected hlc ON hlc.RefreshID = r.RefreshID
                 WHERE
-                    ASIN IN (
+                    r.ASIN IN (
                         SELECT
                             ASIN
                         FROM
@@ -68,36 +77,98 @@ pub async fn index_page(session: Session<'_>,mut database: Connection<Sqlite>,
                         WHERE
                             sid = ?)
                 GROUP BY
-                    ASIN
+                    r.ASIN
             )
             SELECT
                 hlc.ASIN,
-                hlc.Price,
-                hlc.datetime,
+                MIN(hlc.Price) AS Price,
+                hlc.condition,
+                r.datetime,
                 spm.name
             FROM
                 Has_Listing_collected hlc
             JOIN
-                Latest_Listings ll ON hlc.ASIN = ll.ASIN AND hlc.datetime = ll.latest_datetime
+                For_Product_Data_Refresh r ON hlc.RefreshID = r.RefreshID
+            JOIN
+                Latest_Listings ll ON r.ASIN = ll.ASIN AND r.datetime = ll.latest_datetime
             JOIN
                 Product_variant_Sold pvs ON hlc.ASIN = pvs.ASIN
             JOIN
                 Sold_Product_Manufactured spm ON pvs.PID = spm.PID
+            GROUP BY
+                hlc.ASIN
             ORDER BY
                 hlc.ASIN;")
-                        .bind(&session.user_id())
+                        .bind(user)
                         .fetch_all(&mut *database)
                         .await?;
         info!("Query works!");
 
+        // Statistics of each product in the condition of its latest listing
+        let stats: HashMap<String, PriceStats> = price_stats(&database.user_price_points(UserId(user)).await?.statistics(), clock.now())
+            .into_iter()
+            .filter(|stats| {
+                user_products
+                    .iter()
+                    .any(|product| product.ASIN == stats.asin && product.condition.as_deref().unwrap_or_default() == stats.condition)
+            })
+            .map(|stats| (stats.asin.clone(), stats))
+            .collect();
+
         Ok(Template::render("index", context! {
+            csrf_token,
             products: &user_products,
+            stats: &stats,
             flash: flash.map(FlashMessage::into_inner)
         }))
+        }
+    }
+}
+
+#[get("/login/two-factor")]
+pub async fn two_factor_login_page(
+    session: Session<'_>,
+    csrf_token: CsrfToken,
+    clock: &State<Clock>,
+    flash: Option<FlashMessage<'_>>,
+) -> Result<Template, Redirect> {
+    if session.pending_login(clock.now()).is_none() {
+        return Err(Redirect::to("/login"));
     }
+
+    Ok(Template::render("two_factor_login", context! {csrf_token, flash: flash.map(FlashMessage::into_inner)}))
+}
+
+#[get("/forgot")]
+pub async fn forgot_password_page(csrf_token: CsrfToken, flash: Option<FlashMessage<'_>>) -> crate::Result<Template> {
+    Ok(Template::render("forgot_password", context! {csrf_token, flash: flash.map(FlashMessage::into_inner)}))
+}
+
+#[get("/verify/resend")]
+pub async fn resend_verification_page(csrf_token: CsrfToken, flash: Option<FlashMessage<'_>>) -> crate::Result<Template> {
+    Ok(Template::render("resend_verification", context! {csrf_token, flash: flash.map(FlashMessage::into_inner)}))
+}
+
+/// The token is checked before showing the form, but is only used up once a new password is sent
+#[get("/reset?<token>")]
+pub async fn reset_password_page(
+    mut database: Connection<Sqlite>,
+    csrf_token: CsrfToken,
+    tokens: &State<AccountTokens>,
+    token: &str,
+    flash: Option<FlashMessage<'_>>,
+) -> crate::Result<Template> {
+    let valid = tokens.is_valid(&mut database, token, TokenPurpose::ResetPassword, Utc::now()).await?;
+
+    Ok(Template::render("reset_password", context! {
+        csrf_token,
+        token,
+        valid,
+        flash: flash.map(FlashMessage::into_inner)
+    }))
 }
 
 #[get("/about")]
-pub async fn about_page() -> crate::Result<Template> {
-    Ok(Template::render("about", context! {}))
+pub async fn about_page(csrf_token: CsrfToken) -> crate::Result<Template> {
+    Ok(Template::render("about", context! { csrf_token }))
 }
@@ -0,0 +1,96 @@
+use crate::csrf::{CsrfToken, CsrfVerified};
+use crate::database::Connection;
+use crate::error::Error;
+use crate::session::{Session, SessionPolicy, UserId};
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
+use rocket::{delete, get, post, State};
+use rocket_dyn_templates::{context, Template};
+use serde::Serialize;
+use sqlx::types::Uuid;
+use sqlx::Sqlite;
+
+/// A session as shown on the sessions page
+#[derive(Serialize)]
+struct SessionView {
+    id: String,
+    user_agent: String,
+    ip: String,
+    created_at: String,
+    last_seen: String,
+    expires_at: String,
+    current: bool,
+}
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M UTC";
+
+#[get("/")]
+pub async fn sessions_page(
+    user: UserId,
+    csrf_token: CsrfToken,
+    session: Session<'_>,
+    mut database: Connection<Sqlite>,
+    policy: &State<SessionPolicy>,
+    flash: Option<FlashMessage<'_>>,
+) -> crate::Result<Template> {
+    let current = session.session_id();
+    let sessions: Vec<_> = database
+        .user_sessions(user)
+        .await?
+        .into_iter()
+        .map(|stored| SessionView {
+            id: stored.id.simple().to_string(),
+            user_agent: stored.user_agent.unwrap_or_else(|| "Unknown browser".to_string()),
+            ip: stored.ip.unwrap_or_else(|| "Unknown address".to_string()),
+            created_at: stored.created_at.format(TIME_FORMAT).to_string(),
+            last_seen: stored.last_seen.format(TIME_FORMAT).to_string(),
+            expires_at: policy.expires_at(stored.created_at, stored.last_seen).format(TIME_FORMAT).to_string(),
+            current: Some(stored.id) == current,
+        })
+        .collect();
+
+    Ok(Template::render("sessions", context! {
+        csrf_token,
+        sessions,
+        flash: flash.map(FlashMessage::into_inner)
+    }))
+}
+
+#[delete("/<id>")]
+pub async fn revoke(
+    user: UserId,
+    session: Session<'_>,
+    _csrf: CsrfVerified,
+    mut database: Connection<Sqlite>,
+    id: &str,
+) -> crate::Result<Flash<Redirect>> {
+    let id = Uuid::parse_str(id).map_err(|_| Error::from("Unknown session"))?;
+
+    if Some(id) == session.session_id() {
+        session.end(&mut database).await?;
+        return Ok(Flash::success(Redirect::to("/login"), "Logged out succesfully!"));
+    }
+
+    if !database.delete_user_session(*user, id).await? {
+        return Ok(Flash::error(Redirect::to("/sessions"), "That session has already ended"));
+    }
+
+    Ok(Flash::success(Redirect::to("/sessions"), "The session has been signed out"))
+}
+
+/// Sign out of every session, including the one making the request
+#[post("/revoke-all")]
+pub async fn revoke_all(
+    user: UserId,
+    session: Session<'_>,
+    _csrf: CsrfVerified,
+    mut database: Connection<Sqlite>,
+) -> crate::Result<Flash<Redirect>> {
+    let count = database.delete_user_sessions(*user).await?;
+    session.end(&mut database).await?;
+
+    Ok(Flash::success(
+        Redirect::to("/login"),
+        format!("Logged out of {} session{}", count, if count == 1 { "" } else { "s" }),
+    ))
+}
@@ -1,19 +1,38 @@
+use crate::account::DeletionPolicy;
+use crate::account_token::{AccountTokens, TokenPurpose};
 use crate::build_rocket;
+use crate::clock::Clock;
+use crate::totp::code_at;
+use crate::database::Connection;
 use crate::env::setup_dotenv;
+use crate::login_throttle::LoginThrottle;
+use chrono::{NaiveDate, TimeZone, Utc};
 use rand::Rng;
-use rocket::http::{ContentType, Status};
+use rocket::http::{ContentType, Header, Status};
 use rocket::local::asynchronous::Client;
 use rocket::uri;
 use sqlx::pool::PoolConnection;
 use sqlx::{Pool, Sqlite};
 
 use crate::forms::UserCredentials;
-use crate::session::Session;
+use crate::scraper::offer::{Condition, Offer};
+use crate::scraper::price::PriceUSD;
+use crate::scraper::product::{Department, DepartmentHierarchy, Product};
+use crate::reparse::reparse_archive;
+use crate::retention::{Resolution, RetentionPolicy};
+use crate::scraper::archive::PageArchive;
+use crate::session::{Role, Session, UserId};
 use serial_test::serial;
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
+/// Mail sent during tests is written here instead of being delivered
+const TEST_MAIL_DIR: &str = "target/test-mail";
+
 async fn create_client() -> Client {
     setup_dotenv();
+    std::env::set_var("MAIL_TRANSPORT", "file");
+    std::env::set_var("MAIL_DIR", TEST_MAIL_DIR);
     let rocket = build_rocket().await.unwrap();
 
     Client::tracked(rocket).await.unwrap()
@@ -24,6 +43,132 @@ async fn client_database(client: &Client) -> PoolConnection<Sqlite> {
     pool.acquire().await.unwrap()
 }
 
+/// Load a page to receive the session's CSRF token, which must be sent with every form
+async fn csrf_token(client: &Client) -> String {
+    let page = client
+        .get(uri!(crate::routes::render_routes::login_page))
+        .dispatch()
+        .await
+        .into_string()
+        .await
+        .unwrap();
+
+    let (_, token) = page.split_once("name=\"csrf_token\" value=\"").unwrap();
+    token.split('"').next().unwrap().to_string()
+}
+
+/// The user signed in to the client's session. The session must still be stored in the database.
+async fn signed_in_user(client: &Client) -> Option<Uuid> {
+    let cookies = client.cookies();
+    let id = Session::from(&cookies).session_id()?;
+
+    let mut database = client_database(client).await;
+    let user: Option<(Uuid,)> = sqlx::query_as("SELECT sid FROM User_Sessions WHERE SessionID = ?")
+        .bind(id)
+        .fetch_optional(&mut database)
+        .await
+        .unwrap();
+
+    user.map(|(user,)| user)
+}
+
+/// The token from the link in the most recent mail sent to the address
+fn mailed_token(to: &str, path: &str) -> Option<String> {
+    let mut files: Vec<_> = std::fs::read_dir(TEST_MAIL_DIR)
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .collect();
+    files.sort();
+
+    let header = format!("\r\nTo: {}\r\n", to);
+    let message = files
+        .iter()
+        .rev()
+        .filter_map(|name| std::fs::read_to_string(format!("{}/{}", TEST_MAIL_DIR, name)).ok())
+        .find(|message| message.contains(&header))?;
+    let (_, token) = message.split_once(&format!("{}?token=", path))?;
+    Some(token.split_whitespace().next()?.to_string())
+}
+
+/// Register a new account and confirm its email address
+async fn register_verified(client: &Client, token: &str, email: &str, password: &str) {
+    client
+        .post(uri!(crate::routes::user::register))
+        .body(format!("csrf_token={}&email={}&password={}", token, email, password))
+        .header(ContentType::Form)
+        .dispatch()
+        .await;
+
+    let verify = mailed_token(email, "/verify").unwrap();
+    let response = client.get(format!("/verify?token={}", verify)).dispatch().await;
+    assert_eq!(response.headers().get_one("Location"), Some("/login"));
+}
+
+/// A client signed in to a new account with a verified email address
+async fn signed_in_client() -> (Client, UserId) {
+    let client = create_client().await;
+    let token = csrf_token(&client).await;
+    let email = format!("{}@example.com", rng_str(10));
+    let password = rng_str(16);
+
+    register_verified(&client, &token, &email, &password).await;
+    client
+        .post(uri!(crate::routes::user::login))
+        .body(format!("csrf_token={}&email={}&password={}", token, email, password))
+        .header(ContentType::Form)
+        .dispatch()
+        .await;
+    let user = UserId(signed_in_user(&client).await.unwrap());
+
+    (client, user)
+}
+
+/// An offer shipped and sold by Amazon
+fn offer(dollars: i64, condition: Condition) -> Offer {
+    Offer {
+        condition,
+        condition_description: None,
+        price: PriceUSD::new(dollars, 0),
+        ships_from: "Amazon.com".to_string(),
+        sold_by: "Amazon.com".to_string(),
+        seller_page: None,
+    }
+}
+
+/// Add a product with a random ASIN to the department and manufacturer added by the build script
+async fn add_test_product(database: &mut Connection<Sqlite>, name: &str) -> (Uuid, String) {
+    let asin = format!("T{}", rng_str(9).to_uppercase());
+    let department = database.department_by_name("Cooldep1").await.unwrap().unwrap();
+    let manufacturer = database.get_or_add_manufacturer("Acme").await.unwrap();
+
+    let product = Uuid::new_v4();
+    sqlx::query("INSERT INTO Sold_Product_Manufactured (PID, URL, name, DepID, ManuID) VALUES (?, ?, ?, ?, ?)")
+        .bind(product)
+        .bind(format!("https://amazon.com/dp/{}/", asin))
+        .bind(name)
+        .bind(department)
+        .bind(manufacturer)
+        .execute(&mut **database)
+        .await
+        .unwrap();
+
+    sqlx::query("INSERT INTO Product_variant_Sold (ASIN, variation, type, PID) VALUES (?, 'default', '', ?)")
+        .bind(&asin)
+        .bind(product)
+        .execute(&mut **database)
+        .await
+        .unwrap();
+
+    (product, asin)
+}
+
+/// Add a test product which the user tracks
+async fn add_tracked_product(database: &mut Connection<Sqlite>, user: UserId, name: &str) -> (Uuid, String) {
+    let (product, asin) = add_test_product(database, name).await;
+    database.track_product(user, product, &asin, None).await.unwrap();
+    (product, asin)
+}
+
 /// Creates a completely random string of characters between a and z.
 fn rng_str(length: usize) -> String {
     let mut buffer = String::new();
@@ -40,10 +185,11 @@ fn rng_str(length: usize) -> String {
 #[serial]
 pub async fn test_register() {
     let client = create_client().await;
+    let token = csrf_token(&client).await;
 
     let response = client
         .post(uri!(crate::routes::user::register))
-        .body("email=not_an_email&password=password123")
+        .body(format!("csrf_token={}&email=not_an_email&password=password123", token))
         .header(ContentType::Form)
         .dispatch()
         .await
@@ -54,6 +200,18 @@ pub async fn test_register() {
         response,
         Some("Email must be a valid email address".to_string())
     );
+
+    // Line breaks would add headers to the confirmation mail, such as extra recipients
+    let response = client
+        .post(uri!(crate::routes::user::register))
+        .body(format!("csrf_token={}&email=x%0D%0ABcc%3A+victim%40evil%0D%0A+a%40b.co&password=password123", token))
+        .header(ContentType::Form)
+        .dispatch()
+        .await
+        .into_string()
+        .await;
+
+    assert_eq!(response, Some("Email must be a valid email address".to_string()));
 }
 
 #[tokio::test]
@@ -69,9 +227,10 @@ pub async fn test_signup_new_user() {
         password: &password,
     };
 
+    let token = csrf_token(&client).await;
     let response = client
         .post(uri!(crate::routes::user::register))
-        .body(format!("email={}&password={}", data.email, data.password))
+        .body(format!("csrf_token={}&email={}&password={}", token, data.email, data.password))
         .header(ContentType::Form)
         .dispatch()
         .await
@@ -106,28 +265,23 @@ pub async fn test_login_logout() {
         password: &password,
     };
 
-    let _ = client
-        .post(uri!(crate::routes::user::register))
-        .body(format!("email={}&password={}", data.email, data.password))
-        .header(ContentType::Form)
-        .dispatch()
-        .await;
+    let token = csrf_token(&client).await;
+    register_verified(&client, &token, data.email, data.password).await;
 
     // Replace with completely new client to test login
     drop(client);
     let client = create_client().await;
 
+    let token = csrf_token(&client).await;
     let response = client
         .post(uri!(crate::routes::user::login))
-        .body(format!("email={}&password={}", data.email, data.password))
+        .body(format!("csrf_token={}&email={}&password={}", token, data.email, data.password))
         .header(ContentType::Form)
         .dispatch()
         .await;
 
     assert_eq!(response.status(), Status::SeeOther);
 
-    let session = Session::from(response.cookies());
-
     // Fetch new entry from the database and ensure it matches the requested password
     let database = client_database(&client);
     let (user_id,): (Uuid,) = sqlx::query_as("SELECT sid FROM Site_users WHERE email = ?")
@@ -136,14 +290,1386 @@ pub async fn test_login_logout() {
         .await
         .unwrap();
 
-    assert_eq!(session.user_id().unwrap(), user_id);
+    assert_eq!(signed_in_user(&client).await, Some(user_id));
 
     let response = client
-        .get(uri!(crate::routes::user::logout))
+        .post(uri!(crate::routes::user::logout))
+        .header(Header::new("X-CSRF-Token", token))
         .dispatch()
         .await;
 
     assert_eq!(response.status(), Status::SeeOther);
-    let session = Session::from(response.cookies());
-    assert_eq!(session.user_id(), None);
+    assert_eq!(signed_in_user(&client).await, None);
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_export_history() {
+    let client = create_client().await;
+
+    let email = format!("{}@example.com", rng_str(10));
+    let password = rng_str(16);
+    let token = csrf_token(&client).await;
+
+    register_verified(&client, &token, &email, &password).await;
+    client
+        .post(uri!(crate::routes::user::login))
+        .body(format!("csrf_token={}&email={}&password={}", token, email, password))
+        .header(ContentType::Form)
+        .dispatch()
+        .await;
+
+    // A new user has no history, but the CSV header is still included
+    let response = client.get("/export/all?format=csv").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::CSV));
+    assert_eq!(
+        response.into_string().await.unwrap(),
+        "asin,name,datetime,condition,price,ships_from,sold_by,source\n"
+    );
+
+    let response = client.get("/export/all?from=last-week").dispatch().await;
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let response = client.get("/export/product/B07VGRJDFY?format=jsonl").dispatch().await;
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_import_price_history() {
+    let client = create_client().await;
+
+    // The test user created by build.rs tracks AAAAAAAAAA
+    let token = csrf_token(&client).await;
+    client
+        .post(uri!(crate::routes::user::login))
+        .body(format!("csrf_token={}&email=test@test.me&password=12345678", token))
+        .header(ContentType::Form)
+        .dispatch()
+        .await;
+
+    let boundary = "history-boundary";
+    let body = format!(
+        "--{b}\r\n\
+         Content-Disposition: form-data; name=\"csrf_token\"\r\n\r\n\
+         {token}\r\n\
+         --{b}\r\n\
+         Content-Disposition: form-data; name=\"asin\"\r\n\r\n\
+         AAAAAAAAAA\r\n\
+         --{b}\r\n\
+         Content-Disposition: form-data; name=\"file\"; filename=\"keepa.csv\"\r\n\
+         Content-Type: text/csv\r\n\r\n\
+         Time,Amazon,New,Used\n\
+         2001-02-03 10:00,$19.99,,$12.50\n\r\n\
+         --{b}--\r\n",
+        b = boundary,
+        token = token
+    );
+
+    // Importing the same history twice only stores each price once
+    for _ in 0..2 {
+        let response = client
+            .post("/import/history")
+            .header(ContentType::new("multipart", "form-data").with_params(("boundary", boundary)))
+            .body(&body)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::SeeOther);
+    }
+
+    let response = client
+        .get("/export/product/AAAAAAAAAA?from=2001-02-03&to=2001-02-03")
+        .dispatch()
+        .await;
+    let export = response.into_string().await.unwrap();
+    let rows: Vec<_> = export.lines().skip(1).collect();
+
+    assert_eq!(
+        rows,
+        vec![
+            "AAAAAAAAAA,Super prod,2001-02-03T00:00:00+00:00,New,19.99,Amazon.com,Amazon.com,imported",
+            "AAAAAAAAAA,Super prod,2001-02-03T00:00:00+00:00,Used,12.5,Third party,Third party,imported",
+        ]
+    );
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_scrape_replaces_imported_prices() {
+    let (client, user) = signed_in_client().await;
+    let token = csrf_token(&client).await;
+    let mut database = Connection::from(client_database(&client).await);
+    let (_, asin) = add_tracked_product(&mut database, user, "Imported").await;
+
+    let boundary = "history-boundary";
+    let body = format!(
+        "--{b}\r\n\
+         Content-Disposition: form-data; name=\"csrf_token\"\r\n\r\n\
+         {token}\r\n\
+         --{b}\r\n\
+         Content-Disposition: form-data; name=\"asin\"\r\n\r\n\
+         {asin}\r\n\
+         --{b}\r\n\
+         Content-Disposition: form-data; name=\"file\"; filename=\"keepa.csv\"\r\n\
+         Content-Type: text/csv\r\n\r\n\
+         Time,Amazon\n\
+         2001-03-01 10:00,$20.00\n\
+         2001-03-02 10:00,$20.00\n\r\n\
+         --{b}--\r\n",
+        b = boundary,
+        token = token,
+        asin = asin
+    );
+    let response = client
+        .post("/import/history")
+        .header(ContentType::new("multipart", "form-data").with_params(("boundary", boundary)))
+        .body(&body)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::SeeOther);
+
+    // The product stays in stock at the same price, so scraping it records no changes
+    for day in [1, 2] {
+        let collected_at = Utc.with_ymd_and_hms(2001, 3, day, 12, 0, 0).unwrap();
+        database.add_listings(&asin, collected_at, &[offer(20, Condition::New)]).await.unwrap();
+    }
+    assert!(database.user_changes(user, 100).await.unwrap().is_empty());
+
+    // The imported refreshes are removed along with their prices
+    let (refreshes,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM For_Product_Data_Refresh WHERE ASIN = ?")
+        .bind(&asin)
+        .fetch_one(&mut *database)
+        .await
+        .unwrap();
+    assert_eq!(refreshes, 2);
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_refresh_several_times_a_day() {
+    let client = create_client().await;
+    let mut database = Connection::from(client_database(&client).await);
+
+    let offer = Offer {
+        condition: Condition::New,
+        condition_description: None,
+        price: PriceUSD::new(24, 99),
+        ships_from: "Amazon.com".to_string(),
+        sold_by: "Amazon.com".to_string(),
+        seller_page: None,
+    };
+
+    // Refreshes used to be keyed by their day, so a second refresh on the same day failed
+    let morning = Utc.with_ymd_and_hms(2001, 2, 4, 9, 0, 0).unwrap();
+    let evening = Utc.with_ymd_and_hms(2001, 2, 4, 21, 30, 0).unwrap();
+    for collected_at in [morning, evening, evening] {
+        database.add_listings("AAAAAAAAAA", collected_at, std::slice::from_ref(&offer)).await.unwrap();
+    }
+
+    let day = NaiveDate::from_ymd_opt(2001, 2, 4);
+    let history = database.product_history("AAAAAAAAAA", day, day).await.unwrap();
+    let times: Vec<_> = history.iter().map(|row| row.datetime.as_str()).collect();
+    assert_eq!(times, vec!["2001-02-04T09:00:00+00:00", "2001-02-04T21:30:00+00:00"]);
+
+    let window = chrono::Duration::minutes(5);
+    let refresh = database.refresh_after("AAAAAAAAAA", evening - window, window).await.unwrap();
+    assert_eq!(refresh, Some(evening));
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_csrf_token_required() {
+    let client = create_client().await;
+    let token = csrf_token(&client).await;
+
+    let email = format!("{}@example.com", rng_str(10));
+    let password = rng_str(16);
+
+    // A form posted from another site does not know the session's token
+    for body in [
+        format!("email={}&password={}", email, password),
+        format!("csrf_token={}&email={}&password={}", rng_str(64), email, password),
+    ] {
+        let response = client
+            .post(uri!(crate::routes::user::register))
+            .body(body)
+            .header(ContentType::Form)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    let response = client
+        .post(uri!(crate::routes::user::register))
+        .body(format!("email={}&password={}", email, password))
+        .header(ContentType::Form)
+        .header(Header::new("X-CSRF-Token", token.clone()))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::SeeOther);
+
+    // Removing a product is sent as a form overriding the method with DELETE
+    let response = client
+        .post("/product/AAAAAAAAAB")
+        .body("_method=delete")
+        .header(ContentType::Form)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+
+    // Pages with forms are given the session's token
+    let page = client.get("/forgot").dispatch().await.into_string().await.unwrap();
+    assert!(page.contains(&format!("name=\"csrf_token\" value=\"{}\"", token)));
+
+    // Responses which do not need a token leave the cookie unset
+    let client = create_client().await;
+    let response = client.get("/cart.json").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert!(response.cookies().get("csrf_token").is_none());
+    assert!(client.cookies().get_private("csrf_token").is_none());
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_login_lockout() {
+    let client = create_client().await;
+    let email = format!("{}@example.com", rng_str(10));
+    let password = rng_str(16);
+    // Failures from the address are kept between test runs, so use a new one each time
+    let ip = format!("198.18.{}.{}", rand::thread_rng().gen_range(0..=255), rand::thread_rng().gen_range(1..=254));
+    let remote = format!("{}:4000", ip).parse().unwrap();
+
+    // A failed attempt is counted for both the email address and the client's address
+    let token = csrf_token(&client).await;
+    client
+        .post(uri!(crate::routes::user::login))
+        .remote(remote)
+        .body(format!("csrf_token={}&email={}&password={}", token, email, password))
+        .header(ContentType::Form)
+        .dispatch()
+        .await;
+
+    let mut database = Connection::from(client_database(&client).await);
+    let failures: Vec<(String, u32)> = sqlx::query_as(
+        "SELECT key, failures FROM Login_Attempts WHERE key IN (?, ?) ORDER BY kind"
+    )
+        .bind(&email)
+        .bind(&ip)
+        .fetch_all(&mut *database)
+        .await
+        .unwrap();
+    assert_eq!(failures, vec![(email.clone(), 1), (ip, 1)]);
+
+    // Counters are kept in the database, so an account locked out before a restart stays locked
+    sqlx::query("UPDATE Login_Attempts SET failures = 10, last_failure = ? WHERE kind = 'email' AND key = ?")
+        .bind(Utc::now())
+        .bind(&email)
+        .execute(&mut *database)
+        .await
+        .unwrap();
+    drop(database);
+    drop(client);
+
+    let client = create_client().await;
+    let token = csrf_token(&client).await;
+    let response = client
+        .post(uri!(crate::routes::user::login))
+        .body(format!("csrf_token={}&email={}&password={}", token, email.to_uppercase(), password))
+        .header(ContentType::Form)
+        .dispatch()
+        .await;
+    assert_eq!(response.headers().get_one("Location"), Some("/login"));
+
+    let page = client.get(uri!(crate::routes::render_routes::login_page)).dispatch().await.into_string().await.unwrap();
+    assert!(page.contains("Signing in has been locked after too many failed attempts. Try again in 15 minutes."));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[serial]
+pub async fn test_failed_login_count() {
+    let client = create_client().await;
+    let pool = client.rocket().state::<Pool<Sqlite>>().unwrap().clone();
+    let policy = LoginThrottle::from_env().email;
+    let key = format!("{}@example.com", rng_str(10));
+    let now = Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap();
+
+    // Failures sent at the same time are each counted
+    let mut tasks = Vec::new();
+    for _ in 0..policy.lockout_after {
+        let (pool, key) = (pool.clone(), key.clone());
+        tasks.push(tokio::spawn(async move {
+            let mut database = Connection::from(pool.acquire().await.unwrap());
+            database.add_failed_login("email", &key, &policy, now).await.unwrap();
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    let mut database = Connection::from(pool.acquire().await.unwrap());
+    let failed = database.failed_logins("email", &key).await.unwrap().unwrap();
+    assert_eq!(failed.failures, policy.lockout_after);
+
+    // The count starts over once the lockout has passed, and again once failures are reset
+    let until = now + policy.lockout;
+    for expected in [1, 2] {
+        database.add_failed_login("email", &key, &policy, until).await.unwrap();
+        assert_eq!(database.failed_logins("email", &key).await.unwrap().unwrap().failures, expected);
+    }
+
+    let later = until + policy.reset_after;
+    database.add_failed_login("email", &key, &policy, later).await.unwrap();
+    let failed = database.failed_logins("email", &key).await.unwrap().unwrap();
+    assert_eq!((failed.failures, failed.last_failure), (1, later));
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_email_verification_and_password_reset() {
+    let client = create_client().await;
+    let token = csrf_token(&client).await;
+    let email = format!("{}@example.com", rng_str(10));
+    let password = rng_str(16);
+
+    let login = |password: String| {
+        client
+            .post(uri!(crate::routes::user::login))
+            .body(format!("csrf_token={}&email={}&password={}", token, email, password))
+            .header(ContentType::Form)
+            .dispatch()
+    };
+
+    // Registering no longer signs in, and the account can not be used until the address is confirmed
+    client
+        .post(uri!(crate::routes::user::register))
+        .body(format!("csrf_token={}&email={}&password={}", token, email, password))
+        .header(ContentType::Form)
+        .dispatch()
+        .await;
+    assert_eq!(signed_in_user(&client).await, None);
+
+    let response = login(password.clone()).await;
+    assert_eq!(response.headers().get_one("Location"), Some("/login"));
+    assert_eq!(signed_in_user(&client).await, None);
+
+    // Confirmation links can only be used once
+    let verify = mailed_token(&email, "/verify").unwrap();
+    for expected in ["/login", "/verify/resend"] {
+        let response = client.get(format!("/verify?token={}", verify)).dispatch().await;
+        assert_eq!(response.headers().get_one("Location"), Some(expected));
+    }
+
+    let response = login(password.clone()).await;
+    assert_eq!(response.headers().get_one("Location"), Some("/index"));
+
+    // Request a password reset. Each request replaces the previous link.
+    for _ in 0..2 {
+        client
+            .post(uri!(crate::routes::user::forgot_password))
+            .body(format!("csrf_token={}&email={}", token, email))
+            .header(ContentType::Form)
+            .dispatch()
+            .await;
+    }
+    let reset = mailed_token(&email, "/reset").unwrap();
+
+    let page = client.get(format!("/reset?token={}", reset)).dispatch().await.into_string().await.unwrap();
+    assert!(page.contains(&format!("name=\"token\" value=\"{}\"", reset)));
+
+    // A tampered signature is rejected
+    let (id, _) = reset.split_once('.').unwrap();
+    let page = client.get(format!("/reset?token={}.{}", id, "0".repeat(64))).dispatch().await.into_string().await.unwrap();
+    assert!(page.contains("This reset link is invalid or has expired"));
+
+    let new_password = rng_str(16);
+    for expected in ["/login", "/forgot"] {
+        let response = client
+            .post(uri!(crate::routes::user::reset_password))
+            .body(format!("csrf_token={}&token={}&password={}", token, reset, new_password))
+            .header(ContentType::Form)
+            .dispatch()
+            .await;
+        assert_eq!(response.headers().get_one("Location"), Some(expected));
+    }
+
+    // Changing the password signed out the earlier session
+    login(password).await;
+    assert_eq!(signed_in_user(&client).await, None);
+    let response = login(new_password).await;
+    assert_eq!(response.headers().get_one("Location"), Some("/index"));
+
+    // Expired tokens are rejected even though they are correctly signed
+    let mut database = Connection::from(client_database(&client).await);
+    let (user, _) = database.user_by_email(&email).await.unwrap().unwrap();
+    let tokens: &AccountTokens = client.rocket().state().unwrap();
+    let expired = tokens
+        .issue(&mut database, user, TokenPurpose::ResetPassword, Utc::now() - chrono::Duration::hours(2))
+        .await
+        .unwrap();
+    drop(database);
+
+    let page = client.get(format!("/reset?token={}", expired)).dispatch().await.into_string().await.unwrap();
+    assert!(page.contains("This reset link is invalid or has expired"));
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_sessions() {
+    let email = format!("{}@example.com", rng_str(10));
+    let password = rng_str(16);
+
+    // Sign in from two browsers
+    let mut clients = Vec::new();
+    for user_agent in ["Browser A", "Browser B"] {
+        let client = create_client().await;
+        let token = csrf_token(&client).await;
+        if clients.is_empty() {
+            register_verified(&client, &token, &email, &password).await;
+        }
+
+        client
+            .post(uri!(crate::routes::user::login))
+            .header(Header::new("User-Agent", user_agent))
+            .body(format!("csrf_token={}&email={}&password={}", token, email, password))
+            .header(ContentType::Form)
+            .dispatch()
+            .await;
+        assert!(signed_in_user(&client).await.is_some());
+        clients.push((client, token));
+    }
+
+    let (first, first_token) = &clients[0];
+    let (second, second_token) = &clients[1];
+
+    let page = first.get("/sessions").dispatch().await.into_string().await.unwrap();
+    assert!(page.contains("Browser A <strong>(this browser)</strong>"));
+    assert!(page.contains("Browser B</td>"));
+
+    // Revoking the second session signs it out on its next request
+    let second_id = Session::from(&second.cookies()).session_id().unwrap();
+    let response = first
+        .post(format!("/sessions/{}", second_id.simple()))
+        .body(format!("_method=delete&csrf_token={}", first_token))
+        .header(ContentType::Form)
+        .dispatch()
+        .await;
+    assert_eq!(response.headers().get_one("Location"), Some("/sessions"));
+    assert_eq!(signed_in_user(second).await, None);
+    assert_ne!(second.get("/sessions").dispatch().await.status(), Status::Ok);
+
+    // Sessions which have not been used for longer than the idle timeout have expired
+    let first_id = Session::from(&first.cookies()).session_id().unwrap();
+    let mut database = Connection::from(client_database(first).await);
+    sqlx::query("UPDATE User_Sessions SET last_seen = ? WHERE SessionID = ?")
+        .bind(Utc::now() - chrono::Duration::days(4))
+        .bind(first_id)
+        .execute(&mut *database)
+        .await
+        .unwrap();
+    drop(database);
+
+    assert_ne!(first.get("/sessions").dispatch().await.status(), Status::Ok);
+    assert_eq!(signed_in_user(first).await, None);
+
+    // Logging out everywhere signs out every session
+    for (client, token) in &clients {
+        client
+            .post(uri!(crate::routes::user::login))
+            .body(format!("csrf_token={}&email={}&password={}", token, email, password))
+            .header(ContentType::Form)
+            .dispatch()
+            .await;
+    }
+
+    let response = second
+        .post("/sessions/revoke-all")
+        .header(Header::new("X-CSRF-Token", second_token.clone()))
+        .dispatch()
+        .await;
+    assert_eq!(response.headers().get_one("Location"), Some("/login"));
+    assert_eq!(signed_in_user(first).await, None);
+    assert_eq!(signed_in_user(second).await, None);
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_two_factor_login() {
+    let client = create_client().await;
+    let token = csrf_token(&client).await;
+    let email = format!("{}@example.com", rng_str(10));
+    let password = rng_str(16);
+
+    let clock: &Clock = client.rocket().state().unwrap();
+    let mut now = Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap();
+    clock.set(now);
+
+    register_verified(&client, &token, &email, &password).await;
+    let login = || {
+        client
+            .post(uri!(crate::routes::user::login))
+            .body(format!("csrf_token={}&email={}&password={}", token, email, password))
+            .header(ContentType::Form)
+            .dispatch()
+    };
+    let post_code = |path: &'static str, code: String| {
+        client
+            .post(path)
+            .body(format!("csrf_token={}&code={}", token, code))
+            .header(ContentType::Form)
+            .dispatch()
+    };
+
+    // Set up two-factor authentication. A wrong code does not turn it on.
+    login().await;
+    client.post("/two-factor/setup").header(Header::new("X-CSRF-Token", token.clone())).dispatch().await;
+    let page = client.get("/two-factor").dispatch().await.into_string().await.unwrap();
+    let (_, secret) = page.split_once("Key: <code>").unwrap();
+    let secret = secret.split('<').next().unwrap().to_string();
+    assert!(page.contains(&format!("otpauth://totp/Amazon%20Tracker:{}?secret={}", email.replace('@', "%40"), secret)));
+
+    let mut database = Connection::from(client_database(&client).await);
+    let (user, _) = database.user_by_email(&email).await.unwrap().unwrap();
+    let (secret_bytes, enabled, _) = database.two_factor(user).await.unwrap().unwrap();
+    assert!(!enabled);
+    assert_eq!(crate::totp::base32(&secret_bytes), secret);
+    drop(database);
+
+    let response = post_code("/two-factor/confirm", "000000".to_string()).await;
+    assert_eq!(response.headers().get_one("Location"), Some("/two-factor"));
+
+    let page = post_code("/two-factor/confirm", code_at(&secret_bytes, now)).await.into_string().await.unwrap();
+    let recovery_codes: Vec<String> = page
+        .split("<li>")
+        .skip(1)
+        .map(|item| item.split('<').next().unwrap().to_string())
+        .collect();
+    assert_eq!(recovery_codes.len(), 10);
+
+    // The password alone no longer signs in
+    client.post(uri!(crate::routes::user::logout)).header(Header::new("X-CSRF-Token", token.clone())).dispatch().await;
+    let response = login().await;
+    assert_eq!(response.headers().get_one("Location"), Some("/login/two-factor"));
+    assert_eq!(signed_in_user(&client).await, None);
+
+    // The code used to turn on two-factor authentication can not be used again
+    let response = post_code("/login/two-factor", code_at(&secret_bytes, now)).await;
+    assert_eq!(response.headers().get_one("Location"), Some("/login/two-factor"));
+
+    now = now + chrono::Duration::seconds(30);
+    clock.set(now);
+    let response = post_code("/login/two-factor", code_at(&secret_bytes, now)).await;
+    assert_eq!(response.headers().get_one("Location"), Some("/index"));
+    assert_eq!(signed_in_user(&client).await, Some(user));
+
+    // Recovery codes work once each
+    client.post(uri!(crate::routes::user::logout)).header(Header::new("X-CSRF-Token", token.clone())).dispatch().await;
+    for expected in ["/index", "/login/two-factor"] {
+        login().await;
+        let response = post_code("/login/two-factor", recovery_codes[0].to_uppercase()).await;
+        assert_eq!(response.headers().get_one("Location"), Some(expected));
+    }
+
+    // The second step must be completed soon after the password is entered
+    now = now + chrono::Duration::minutes(10);
+    clock.set(now);
+    let response = post_code("/login/two-factor", recovery_codes[1].clone()).await;
+    assert_eq!(response.headers().get_one("Location"), Some("/login"));
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_admin_console() {
+    let admin = create_client().await;
+    let admin_token = csrf_token(&admin).await;
+    let admin_email = format!("{}@example.com", rng_str(10));
+    let password = rng_str(16);
+
+    let credentials = |email: &str, token: &str| format!("csrf_token={}&email={}&password={}", token, email, password);
+
+    // Regular users are refused
+    register_verified(&admin, &admin_token, &admin_email, &password).await;
+    admin
+        .post(uri!(crate::routes::user::login))
+        .body(credentials(&admin_email, &admin_token))
+        .header(ContentType::Form)
+        .dispatch()
+        .await;
+    assert_eq!(admin.get("/admin").dispatch().await.status(), Status::Forbidden);
+    assert_eq!(admin.get("/admin/parser").dispatch().await.status(), Status::Forbidden);
+
+    let mut database = Connection::from(client_database(&admin).await);
+    assert!(database.set_role(&admin_email, Role::Admin).await.unwrap());
+
+    let user = create_client().await;
+    let user_token = csrf_token(&user).await;
+    let user_email = format!("{}@example.com", rng_str(10));
+    register_verified(&user, &user_token, &user_email, &password).await;
+    user.post(uri!(crate::routes::user::login))
+        .body(credentials(&user_email, &user_token))
+        .header(ContentType::Form)
+        .dispatch()
+        .await;
+    let user_id = signed_in_user(&user).await.unwrap();
+
+    let page = admin.get("/admin").dispatch().await.into_string().await.unwrap();
+    assert!(page.contains(&admin_email));
+    assert!(page.contains(&format!("/admin/users/{}/disable", user_id.simple())));
+
+    // Disabling a user signs them out and stops them signing in again
+    let response = admin
+        .post(format!("/admin/users/{}/disable", user_id.simple()))
+        .header(Header::new("X-CSRF-Token", admin_token.clone()))
+        .dispatch()
+        .await;
+    assert_eq!(response.headers().get_one("Location"), Some("/admin"));
+    assert_eq!(signed_in_user(&user).await, None);
+
+    let response = user
+        .post(uri!(crate::routes::user::login))
+        .body(credentials(&user_email, &user_token))
+        .header(ContentType::Form)
+        .dispatch()
+        .await;
+    assert_eq!(response.headers().get_one("Location"), Some("/login"));
+    assert_eq!(signed_in_user(&user).await, None);
+
+    admin
+        .post(format!("/admin/users/{}/enable", user_id.simple()))
+        .header(Header::new("X-CSRF-Token", admin_token.clone()))
+        .dispatch()
+        .await;
+    user.post(uri!(crate::routes::user::login))
+        .body(credentials(&user_email, &user_token))
+        .header(ContentType::Form)
+        .dispatch()
+        .await;
+    assert_eq!(signed_in_user(&user).await, Some(user_id));
+
+    // Merging manufacturers moves their products to the one which is kept
+    let duplicate = database.get_or_add_manufacturer(&format!("Acme {}", rng_str(8))).await.unwrap();
+    let kept = database.get_or_add_manufacturer(&format!("ACME {}", rng_str(8))).await.unwrap();
+    let department = database.department_by_name("Cooldep1").await.unwrap().unwrap();
+    let product = Uuid::new_v4();
+    sqlx::query("INSERT INTO Sold_Product_Manufactured (PID, URL, name, DepID, ManuID) VALUES (?, ?, ?, ?, ?)")
+        .bind(product)
+        .bind(format!("https://amazon.com/dp/{}/", rng_str(10)))
+        .bind("Anvil")
+        .bind(department)
+        .bind(duplicate)
+        .execute(&mut *database)
+        .await
+        .unwrap();
+
+    let response = admin
+        .post("/admin/manufacturers/merge")
+        .body(format!("csrf_token={}&duplicate={}&into={}", admin_token, duplicate.simple(), kept.simple()))
+        .header(ContentType::Form)
+        .dispatch()
+        .await;
+    assert_eq!(response.headers().get_one("Location"), Some("/admin"));
+
+    let (manufacturer,): (Uuid,) = sqlx::query_as("SELECT ManuID FROM Sold_Product_Manufactured WHERE PID = ?")
+        .bind(product)
+        .fetch_one(&mut *database)
+        .await
+        .unwrap();
+    assert_eq!(manufacturer, kept);
+
+    let (remaining,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM Manufacturer WHERE ManuID = ?")
+        .bind(duplicate)
+        .fetch_one(&mut *database)
+        .await
+        .unwrap();
+    assert_eq!(remaining, 0);
+
+    // Users can not change anything either
+    let response = user
+        .post(format!("/admin/users/{}/disable", user_id.simple()))
+        .header(Header::new("X-CSRF-Token", user_token.clone()))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_merge_needs_existing_target() {
+    let client = create_client().await;
+    let mut database = Connection::from(client_database(&client).await);
+
+    // Nothing is removed when the entry to keep is missing or is the duplicate itself
+    let manufacturer = database.get_or_add_manufacturer(&format!("Acme {}", rng_str(8))).await.unwrap();
+    assert_eq!(database.merge_manufacturers(manufacturer, Uuid::new_v4()).await.unwrap(), None);
+    assert_eq!(database.merge_manufacturers(manufacturer, manufacturer).await.unwrap(), None);
+
+    let (remaining,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM Manufacturer WHERE ManuID = ?")
+        .bind(manufacturer)
+        .fetch_one(&mut *database)
+        .await
+        .unwrap();
+    assert_eq!(remaining, 1);
+
+    let company = database.get_or_add_company(&format!("Acme {}", rng_str(8))).await.unwrap();
+    assert_eq!(database.merge_companies(company, Uuid::new_v4()).await.unwrap(), None);
+    assert_eq!(database.merge_companies(company, company).await.unwrap(), None);
+
+    let (remaining,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM Company WHERE ComID = ?")
+        .bind(company)
+        .fetch_one(&mut *database)
+        .await
+        .unwrap();
+    assert_eq!(remaining, 1);
+
+    let kept = database.get_or_add_company(&format!("ACME {}", rng_str(8))).await.unwrap();
+    assert_eq!(database.merge_companies(company, kept).await.unwrap(), Some(0));
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_account_export_and_deletion() {
+    let client = create_client().await;
+    let token = csrf_token(&client).await;
+    let email = format!("{}@example.com", rng_str(10));
+    let password = rng_str(16);
+    let login = || {
+        client
+            .post(uri!(crate::routes::user::login))
+            .body(format!("csrf_token={}&email={}&password={}", token, email, password))
+            .header(ContentType::Form)
+            .dispatch()
+    };
+
+    register_verified(&client, &token, &email, &password).await;
+    login().await;
+    let user = signed_in_user(&client).await.unwrap();
+
+    // Track the product added by the build script
+    let mut database = Connection::from(client_database(&client).await);
+    let product = database.product_exists("AAAAAAAAAA").await.unwrap().unwrap();
+    sqlx::query("INSERT INTO Tracks (sid, PID, target_price) VALUES (?, ?, 25.0)")
+        .bind(user)
+        .bind(product)
+        .execute(&mut *database)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO Subscribes_To (conditions, ASIN, sid) VALUES ('GOOD', 'AAAAAAAAAA', ?)")
+        .bind(user)
+        .execute(&mut *database)
+        .await
+        .unwrap();
+
+    let response = client.get("/account/export").dispatch().await;
+    assert_eq!(response.headers().get_one("Content-Disposition"), Some("attachment; filename=\"account.json\""));
+    let export: serde_json::Value = serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(export["profile"]["email"], email.as_str());
+    assert_eq!(export["profile"]["two_factor_enabled"], false);
+    assert_eq!(export["sessions"].as_array().unwrap().len(), 1);
+    assert_eq!(export["tracked_products"][0]["asin"], "AAAAAAAAAA");
+    assert_eq!(export["tracked_products"][0]["target_price"], 25.0);
+    assert_eq!(export["subscriptions"][0]["conditions"], "GOOD");
+    assert!(export["price_history"].is_array());
+    assert!(export["profile"].get("password_hash").is_none());
+
+    // Deleting needs the password, and signs out the session
+    let delete = |password: &str| {
+        client
+            .post("/account/delete")
+            .body(format!("csrf_token={}&password={}", token, password))
+            .header(ContentType::Form)
+            .dispatch()
+    };
+
+    let response = delete("not the password").await;
+    assert_eq!(response.headers().get_one("Location"), Some("/account"));
+    assert_eq!(signed_in_user(&client).await, Some(user));
+
+    let response = delete(&password).await;
+    assert_eq!(response.headers().get_one("Location"), Some("/login"));
+    assert_eq!(signed_in_user(&client).await, None);
+    assert!(database.account_profile(user).await.unwrap().unwrap().deletion_requested_at.is_some());
+
+    // Signing in during the grace period keeps the account
+    login().await;
+    assert_eq!(signed_in_user(&client).await, Some(user));
+    assert!(database.account_profile(user).await.unwrap().unwrap().deletion_requested_at.is_none());
+
+    // Accounts are only removed once the grace period has passed
+    delete(&password).await;
+    let policy = DeletionPolicy::default();
+    let requested_at = database.account_profile(user).await.unwrap().unwrap().deletion_requested_at.unwrap();
+    let purge_at = |now: chrono::DateTime<Utc>| now - policy.grace_period;
+
+    let login_policy = LoginThrottle::from_env().email;
+    database.add_failed_login("email", &email, &login_policy, Utc::now()).await.unwrap();
+
+    let now = policy.deletes_at(requested_at) - chrono::Duration::seconds(1);
+    database.delete_requested_accounts(purge_at(now)).await.unwrap();
+    assert!(database.account_profile(user).await.unwrap().is_some());
+
+    database.delete_requested_accounts(purge_at(policy.deletes_at(requested_at))).await.unwrap();
+    assert!(database.account_profile(user).await.unwrap().is_none());
+    assert!(database.tracked_products(user).await.unwrap().is_empty());
+    assert!(database.subscriptions(user).await.unwrap().is_empty());
+    assert!(database.user_by_email(&email).await.unwrap().is_none());
+    assert!(database.failed_logins("email", &email).await.unwrap().is_none());
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_price_statistics() {
+    let (client, user) = signed_in_client().await;
+
+    let mut database = Connection::from(client_database(&client).await);
+    let (_, asin) = add_tracked_product(&mut database, user, "Statistics").await;
+
+    let clock: &Clock = client.rocket().state().unwrap();
+    let now = Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap();
+    clock.set(now);
+
+    for (days_ago, dollars) in [(40, 30), (10, 20), (5, 50), (0, 40)] {
+        let collected_at = now - chrono::Duration::days(days_ago);
+        database.add_listings(&asin, collected_at, &[offer(dollars, Condition::New)]).await.unwrap();
+    }
+
+    let index = client.get("/index").dispatch().await.into_string().await.unwrap();
+    assert!(index.contains("$20 (10 days ago)"));
+    assert!(index.contains("<td>$36.67</td>"));
+    assert!(index.contains("<td>63%</td>"));
+
+    let page = client.get(format!("/product/historic?asin={}", asin)).dispatch().await.into_string().await.unwrap();
+    assert!(page.contains("<td>$40</td>"));
+    assert!(page.contains("<td>$50</td>"));
+    assert!(page.contains("<td>$35</td>"));
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_activity_feed() {
+    let (client, user) = signed_in_client().await;
+
+    let mut database = Connection::from(client_database(&client).await);
+    let (_, asin) = add_tracked_product(&mut database, user, "Activity").await;
+
+    let sold_by = |dollars, seller: &str| Offer {
+        ships_from: seller.to_string(),
+        sold_by: seller.to_string(),
+        ..offer(dollars, Condition::New)
+    };
+    let now = Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap();
+    let snapshots = [
+        vec![sold_by(50, "Amazon.com"), sold_by(55, "Gadgets")],
+        vec![sold_by(40, "Amazon.com"), sold_by(45, "Newcomer")],
+        vec![],
+        vec![sold_by(60, "Amazon.com")],
+    ];
+    for (hours, offers) in snapshots.iter().enumerate() {
+        let collected_at = now + chrono::Duration::hours(hours as i64);
+        assert_eq!(database.add_listings(&asin, collected_at, offers).await.unwrap(), offers.len());
+    }
+
+    let changes = database.user_changes(user, 100).await.unwrap();
+    let mut kinds: Vec<_> = changes.iter().map(|change| change.kind.as_str()).collect();
+    kinds.sort_unstable();
+    assert_eq!(
+        kinds,
+        vec!["back_in_stock", "new_lowest", "new_seller", "out_of_stock", "price_drop", "seller_gone"]
+    );
+
+    // Adding the same listings again replaces the changes recorded for the refresh
+    let collected_at = now + chrono::Duration::hours(1);
+    database.add_listings(&asin, collected_at, &snapshots[1]).await.unwrap();
+    assert_eq!(database.user_changes(user, 100).await.unwrap().len(), changes.len());
+
+    let page = client.get("/activity").dispatch().await.into_string().await.unwrap();
+    assert!(page.contains("New dropped by $10.00 (20.0%) to $40.00 from Amazon.com"));
+    assert!(page.contains("Newcomer now sells it New for $45.00"));
+    assert!(page.contains("Gadgets no longer sells it New"));
+    assert!(page.contains("Lowest New price yet at $40.00, down from $50.00"));
+    assert!(page.contains("Out of stock"));
+    assert!(page.contains("Back in stock from $60.00"));
+    // Newest first
+    assert!(page.find("Back in stock").unwrap() < page.find("Out of stock").unwrap());
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_price_charts() {
+    let (client, user) = signed_in_client().await;
+
+    let mut database = Connection::from(client_database(&client).await);
+    let (_, asin) = add_tracked_product(&mut database, user, "Charts").await;
+
+    let now = Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap();
+    for (days_ago, dollars) in [(20, 30), (10, 25), (0, 35)] {
+        let offers = [Condition::New, Condition::UsedGood].map(|condition| offer(dollars, condition));
+        database.add_listings(&asin, now - chrono::Duration::days(days_ago), &offers).await.unwrap();
+    }
+
+    let response = client.get(format!("/product/{}/chart.svg", asin)).dispatch().await;
+    assert_eq!(response.content_type(), Some(ContentType::SVG));
+    let svg = response.into_string().await.unwrap();
+    assert!(svg.contains("New from Amazon.com"));
+    assert!(svg.contains("UsedGood from Amazon.com"));
+    assert!(svg.contains(">Low $25.00</text>"));
+    assert!(svg.contains(">High $35.00</text>"));
+    assert_eq!(svg.matches("<polyline").count(), 2);
+
+    let response = client.get(format!("/product/{}/sparkline.svg", asin)).dispatch().await;
+    assert_eq!(response.content_type(), Some(ContentType::SVG));
+    assert_eq!(response.into_string().await.unwrap().matches("<polyline").count(), 1);
+
+    let index = client.get("/index").dispatch().await.into_string().await.unwrap();
+    assert!(index.contains(&format!("/product/{}/sparkline.svg", asin)));
+
+    let page = client.get(format!("/product/historic?asin={}", asin)).dispatch().await.into_string().await.unwrap();
+    assert!(page.contains(&format!("/product/{}/chart.svg", asin)));
+    assert!(!page.contains("chart.js"));
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_add_many_rejects_invalid_products() {
+    let (client, user) = signed_in_client().await;
+    let token = csrf_token(&client).await;
+
+    // Nothing is fetched for entries which are not amazon.com products
+    let response = client
+        .post("/product/add_many")
+        .body(format!("csrf_token={}&asin=nonsense&asin=https%3A%2F%2Fwww.amazon.co.uk%2Fdp%2FB07VGRJDFY", token))
+        .header(ContentType::Form)
+        .dispatch()
+        .await;
+    assert_eq!(response.headers().get_one("Location"), Some("/index"));
+
+    let index = client.get("/index").dispatch().await.into_string().await.unwrap();
+    assert!(index.contains("Not valid amazon.com products: nonsense, https:"));
+    assert!(index.contains("amazon.co.uk"));
+
+    let mut database = Connection::from(client_database(&client).await);
+    assert!(database.tracked_products(*user).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_compare_products() {
+    let (client, user) = signed_in_client().await;
+
+    let mut database = Connection::from(client_database(&client).await);
+    let (_, first_asin) = add_tracked_product(&mut database, user, "First monitor").await;
+    let (second, second_asin) = add_test_product(&mut database, "Second monitor").await;
+
+    sqlx::query("INSERT INTO Contains_Reviews (ASIN, PID, rating, reviewdate) VALUES (?, ?, 4.5, '2023-03-01')")
+        .bind(&second_asin)
+        .bind(second)
+        .execute(&mut *database)
+        .await
+        .unwrap();
+
+    let now = Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap();
+    for (asin, condition, dollars) in [
+        (&first_asin, Condition::New, 200),
+        (&second_asin, Condition::New, 180),
+        (&second_asin, Condition::UsedGood, 150),
+    ] {
+        database.add_listings(asin, now, &[offer(dollars, condition)]).await.unwrap();
+    }
+
+    // Tracked products can be picked from the page
+    let page = client.get("/product/compare").dispatch().await.into_string().await.unwrap();
+    assert!(page.contains(&format!("value=\"{}\"", first_asin)));
+    assert!(!page.contains("<table"));
+
+    let query = format!("asin={}&asin={}", first_asin, second_asin.to_lowercase());
+    let page = client.get(format!("/product/compare?{}", query)).dispatch().await.into_string().await.unwrap();
+    assert!(page.contains("First monitor"));
+    assert!(page.contains("Second monitor"));
+    assert!(page.contains("<td>Acme</td>"));
+    assert!(page.contains("<td>Cooldep1</td>"));
+    assert!(page.contains("<td>4.5 / 5</td>"));
+    assert!(page.contains("<td>$200 on 2023-04-01</td>"));
+    assert!(page.contains("<th colspan=\"3\">UsedGood</th>"));
+    assert!(page.contains(&format!("/product/compare.svg?asin={}&amp;asin={}", first_asin, second_asin)));
+
+    let response = client.get(format!("/product/compare.svg?{}", query)).dispatch().await;
+    assert_eq!(response.content_type(), Some(ContentType::SVG));
+    let svg = response.into_string().await.unwrap();
+    assert_eq!(svg.matches("<polyline").count(), 2);
+    assert!(svg.contains(">Second monitor</text>"));
+
+    let page = client.get("/product/compare?asin=B000000000").dispatch().await.into_string().await.unwrap();
+    assert!(page.contains("These products have not been added yet: B000000000"));
+
+    let page = client.get("/product/compare?asin=nonsense").dispatch().await.into_string().await.unwrap();
+    assert!(page.contains("Unable to compare nonsense"));
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_buy_advice() {
+    let client = create_client().await;
+    let mut database = Connection::from(client_database(&client).await);
+    let (_, asin) = add_test_product(&mut database, "Advice").await;
+
+    let clock: &Clock = client.rocket().state().unwrap();
+    let now = Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap();
+    clock.set(now);
+
+    let page = client.get(format!("/product/historic?asin={}", asin)).dispatch().await.into_string().await.unwrap();
+    assert!(!page.contains("/100)"));
+
+    for days_ago in (0..30).rev() {
+        let dollars = if days_ago == 0 { 70 } else { 100 };
+        let collected_at = now - chrono::Duration::days(days_ago);
+        database.add_listings(&asin, collected_at, &[offer(dollars, Condition::New)]).await.unwrap();
+    }
+
+    let page = client.get(format!("/product/historic?asin={}", asin)).dispatch().await.into_string().await.unwrap();
+    assert!(page.contains("New:\n      Good time to buy"));
+    assert!(page.contains("<li>$70.00 is the lowest price seen</li>"));
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_price_history_retention() {
+    let client = create_client().await;
+    let mut database = Connection::from(client_database(&client).await);
+    let (_, asin) = add_test_product(&mut database, "Retention").await;
+
+    let clock: &Clock = client.rocket().state().unwrap();
+    let now = Utc.with_ymd_and_hms(2001, 6, 1, 12, 0, 0).unwrap();
+    clock.set(now);
+
+    let sold_by = |dollars: i64, seller: &str| Offer {
+        sold_by: seller.to_string(),
+        ..offer(dollars, Condition::New)
+    };
+
+    let day = Utc.with_ymd_and_hms(2001, 1, 1, 0, 0, 0).unwrap();
+    database.add_listings(&asin, day + chrono::Duration::hours(8), &[sold_by(30, "Amazon.com"), sold_by(25, "Gadgets")]).await.unwrap();
+    database.add_listings(&asin, day + chrono::Duration::hours(20), &[sold_by(28, "Amazon.com")]).await.unwrap();
+    database.add_listings(&asin, day + chrono::Duration::days(1), &[sold_by(35, "Amazon.com")]).await.unwrap();
+    database.add_listings(&asin, now - chrono::Duration::days(2), &[sold_by(40, "Amazon.com")]).await.unwrap();
+
+    let policy = RetentionPolicy::default();
+    // Every product is compacted, including those added by other tests
+    assert!(database.compact_listings(policy.daily_cutoff(now)).await.unwrap() >= 4);
+    assert_eq!(database.price_points_between(&asin, None, None).await.unwrap().len(), 1);
+
+    let days = database.product_rollups(Resolution::Daily, &asin, None, None).await.unwrap();
+    assert_eq!(days.len(), 2);
+    assert_eq!((days[0].min_price, days[0].max_price, days[0].close_price), (25.0, 28.0, 28.0));
+    assert_eq!(days[0].samples, 2);
+
+    // Compacted prices still count towards the statistics
+    let prices: Vec<f64> = database.price_points(&asin).await.unwrap().statistics().iter().map(|point| point.price).collect();
+    assert!(prices.contains(&25.0) && prices.contains(&40.0));
+
+    let page = client
+        .get(format!("/product/historic?asin={}&from=2001-01-01&to=2001-01-31", asin))
+        .dispatch()
+        .await
+        .into_string()
+        .await
+        .unwrap();
+    assert!(page.contains("daily prices are shown"));
+    assert!(page.contains("<td>$25</td>"));
+
+    let page = client.get(format!("/product/historic?asin={}&from=2001-05-25", asin)).dispatch().await.into_string().await.unwrap();
+    assert!(!page.contains("prices are shown"));
+
+    let response = client.get(format!("/product/historic?asin={}&from=January", asin)).dispatch().await;
+    assert_ne!(response.status(), Status::Ok);
+
+    // The first Monday after the compacted days
+    let monday = Utc.with_ymd_and_hms(2001, 1, 8, 0, 0, 0).unwrap();
+    assert!(database.compact_daily_prices(monday).await.unwrap() >= 2);
+    assert!(database.product_rollups(Resolution::Daily, &asin, None, None).await.unwrap().is_empty());
+
+    let weeks = database.product_rollups(Resolution::Weekly, &asin, None, None).await.unwrap();
+    assert_eq!(weeks.len(), 1);
+    assert_eq!((weeks[0].min_price, weeks[0].max_price, weeks[0].close_price), (25.0, 35.0, 35.0));
+    assert_eq!(weeks[0].samples, 3);
+
+    let chart = client.get(format!("/product/{}/chart.svg?from=2001-01-01", asin)).dispatch().await;
+    assert_eq!(chart.status(), Status::Ok);
+    assert!(chart.into_string().await.unwrap().contains(">Low $35.00</text>"));
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_browse_departments_and_manufacturers() {
+    let (client, user) = signed_in_client().await;
+
+    let mut database = Connection::from(client_database(&client).await);
+    let (top, sub) = (format!("Top {}", rng_str(8)), format!("Sub {}", rng_str(8)));
+    let node = rand::thread_rng().gen_range(1_000_000..u32::MAX as u64);
+    let hierarchy = DepartmentHierarchy::from(vec![
+        Department { name: top.clone(), node },
+        Department { name: sub.clone(), node: node + 1 },
+    ]);
+    let department = database.get_or_add_department(&hierarchy).await.unwrap().unwrap();
+
+    let mut products = Vec::new();
+    for name in ["Cheap lamp", "Steady lamp"] {
+        let (product, asin) = add_tracked_product(&mut database, user, name).await;
+        sqlx::query("UPDATE Sold_Product_Manufactured SET DepID = ? WHERE PID = ?")
+            .bind(department)
+            .bind(product)
+            .execute(&mut *database)
+            .await
+            .unwrap();
+        products.push(asin);
+    }
+
+    // The first lamp drops to its lowest price while the second stays the same
+    let now = Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap();
+    for (days_ago, first_price) in [(2, 100), (1, 100), (0, 80)] {
+        for (asin, dollars) in [(&products[0], first_price), (&products[1], 50)] {
+            let collected_at = now - chrono::Duration::days(days_ago);
+            database.add_listings(asin, collected_at, &[offer(dollars, Condition::New)]).await.unwrap();
+        }
+    }
+
+    let stored: Option<i64> = sqlx::query_as("SELECT node FROM Department WHERE DepID = ?")
+        .bind(department)
+        .fetch_one(&mut *database)
+        .await
+        .map(|(node,)| node)
+        .unwrap();
+    assert_eq!(stored, Some(node as i64 + 1));
+
+    // The top level department holds both products through its sub-department
+    let page = client.get("/browse/departments").dispatch().await.into_string().await.unwrap();
+    assert!(page.contains(&top));
+    assert!(!page.contains(&sub));
+
+    let departments = database.departments().await.unwrap();
+    let top_id = &departments.iter().find(|department| department.name == top).unwrap().id;
+    let page = client.get(format!("/browse/departments?id={}", top_id)).dispatch().await.into_string().await.unwrap();
+    assert!(page.contains(&sub));
+    assert!(page.contains("<td>2</td>"));
+    assert!(page.contains("10%"));
+    assert!(page.contains(&format!("node={}", node)));
+
+    let sub_id = &departments.iter().find(|department| department.name == sub).unwrap().id;
+    let page = client.get(format!("/browse/departments?id={}", sub_id)).dispatch().await.into_string().await.unwrap();
+    assert!(page.contains(&format!("?id={}\">{}</a>", top_id, top)));
+    assert!(page.contains("Cheap lamp") && page.contains("Steady lamp"));
+    assert!(page.contains("2 products, 10% below their median price on average"));
+
+    let response = client.get("/browse/departments?id=0000").dispatch().await;
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let page = client.get("/browse/manufacturers").dispatch().await.into_string().await.unwrap();
+    assert!(page.contains("Acme"));
+    let manufacturer = database.tracked_catalog(user).await.unwrap()[0].manufacturer_id.clone();
+    let page = client.get(format!("/browse/manufacturers?id={}", manufacturer)).dispatch().await.into_string().await.unwrap();
+    assert!(page.contains("Cheap lamp"));
+    assert!(page.contains("2 at their all-time low"));
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_reparse_archived_offers() {
+    let client = create_client().await;
+    let pool: &Pool<Sqlite> = client.rocket().state().unwrap();
+    let mut database = Connection::from(client_database(&client).await);
+    let (_, asin) = add_test_product(&mut database, "Archived").await;
+
+    let directory = format!("target/test-archive/{}", rng_str(10));
+    let archive = PageArchive::new(&directory, pool.clone()).unwrap();
+    let page_path = |body: &str| {
+        let hash = format!("{:x}", Sha256::digest(body.as_bytes()));
+        format!("{}/{}/{}.html.gz", directory, &hash[..2], hash)
+    };
+    let offers_url = format!("https://www.amazon.com/gp/product/ajax/?asin={}&experienceId=aodAjaxMain", asin);
+    let offers_page = |condition: &str, price: &str, seller: &str| format!(
+        "<div id=\"aod-offer\">
+            <div id=\"aod-offer-heading\"><h5>{}</h5></div>
+            <span class=\"a-price\"><span class=\"a-offscreen\">{}</span></span>
+            <div id=\"aod-offer-shipsFrom\"><div class=\"a-col-right\"><span>Amazon.com</span></div></div>
+            <div id=\"aod-offer-soldBy\"><div class=\"a-col-right\"><a href=\"/seller\">{}</a></div></div>
+        </div>",
+        condition, price, seller
+    );
+
+    // Both pages of the offer list belong to the same fetch
+    let first_page = offers_page("New", "$24.99", "Gadgets");
+    let second_page = offers_page("Used - Good", "$19.50", "Bargains");
+    archive.store(Some(&asin), &offers_url, 200, first_page.as_bytes()).await;
+    archive.store(Some(&asin), &format!("{}&pageno=2", offers_url), 200, second_page.as_bytes()).await;
+
+    // The refresh recorded after the fetch, whose offers were lost to a parser failure
+    let refreshed_at = Utc::now();
+    database.add_listings(&asin, refreshed_at, &[]).await.unwrap();
+
+    // Pages which can not be read back are counted as failures without stopping the others
+    let (missing, corrupt) = (format!("<html>{}</html>", rng_str(16)), format!("<html>{}</html>", rng_str(16)));
+    archive.store(Some(&asin), &offers_url, 200, missing.as_bytes()).await;
+    archive.store(Some(&asin), &offers_url, 200, corrupt.as_bytes()).await;
+    std::fs::remove_file(page_path(&missing)).unwrap();
+    std::fs::write(page_path(&corrupt), "not gzip").unwrap();
+
+    let summary = reparse_archive(pool, &archive, Some(&asin)).await.unwrap();
+    assert_eq!((summary.pages, summary.listings_added, summary.failures), (2, 2, 2));
+
+    let mut points = database.price_points_between(&asin, None, None).await.unwrap();
+    points.sort_by(|a, b| a.price.total_cmp(&b.price));
+    let points: Vec<_> = points.iter().map(|point| (point.datetime, point.price)).collect();
+    assert_eq!(points, vec![(refreshed_at, 19.5), (refreshed_at, 24.99)]);
+
+    // Pages are not added as refreshes of their own, where sellers would seem to come and go
+    let (refreshes,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM For_Product_Data_Refresh WHERE ASIN = ?")
+        .bind(&asin)
+        .fetch_one(&mut *database)
+        .await
+        .unwrap();
+    assert_eq!(refreshes, 1);
+}
+
+/// A breadcrumb path of departments with the given names and browse nodes
+fn hierarchy(departments: &[(&str, u64)]) -> DepartmentHierarchy {
+    DepartmentHierarchy::from(
+        departments
+            .iter()
+            .map(|&(name, node)| Department { name: name.to_string(), node })
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// The department a department is listed under
+async fn parent_department(database: &mut Connection<Sqlite>, department: Uuid) -> Option<Uuid> {
+    sqlx::query_as("SELECT Category_DepID FROM Area_within WHERE sub_DepID = ?")
+        .bind(department)
+        .fetch_optional(&mut **database)
+        .await
+        .unwrap()
+        .map(|(parent,)| parent)
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_department_hierarchy_upsert() {
+    let client = create_client().await;
+    let mut database = Connection::from(client_database(&client).await);
+    let node = rand::thread_rng().gen_range(1_000_000..u32::MAX as u64);
+    let (electronics, computers, accessories) = (node, node + 1, node + 2);
+
+    // Departments in different branches can share a name
+    let computer_accessories = hierarchy(&[("Electronics", electronics), ("Computers", computers), ("Accessories", accessories)]);
+    let first = database.get_or_add_department(&computer_accessories).await.unwrap().unwrap();
+    let camera_accessories = database
+        .get_or_add_department(&hierarchy(&[("Cameras", node + 3), ("Accessories", node + 4)]))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_ne!(first, camera_accessories);
+    assert_eq!(database.department_by_node(accessories).await.unwrap(), Some(first));
+
+    // Adding the same path again changes nothing
+    assert_eq!(database.get_or_add_department(&computer_accessories).await.unwrap().unwrap(), first);
+    let computers_id = database.department_by_node(computers).await.unwrap().unwrap();
+    let electronics_id = database.department_by_node(electronics).await.unwrap().unwrap();
+    assert_eq!(parent_department(&mut database, first).await, Some(computers_id));
+    assert_eq!(parent_department(&mut database, computers_id).await, Some(electronics_id));
+    assert_eq!(parent_department(&mut database, electronics_id).await, None);
+
+    // Amazon moves computer accessories to the top level under a new name
+    let moved = hierarchy(&[("Computer Accessories", accessories)]);
+    assert_eq!(database.get_or_add_department(&moved).await.unwrap().unwrap(), first);
+    assert_eq!(parent_department(&mut database, first).await, None);
+
+    // And then under another department
+    let moved = hierarchy(&[("Office", node + 5), ("Computer Accessories", accessories)]);
+    assert_eq!(database.get_or_add_department(&moved).await.unwrap().unwrap(), first);
+    let office = database.department_by_node(node + 5).await.unwrap().unwrap();
+    assert_eq!(parent_department(&mut database, first).await, Some(office));
+
+    let departments = database.departments().await.unwrap();
+    let accessories_row = departments.iter().find(|department| department.node == Some(accessories as i64)).unwrap();
+    assert_eq!(accessories_row.name, "Computer Accessories");
+
+    // A department stored before browse nodes were kept is adopted by name
+    let legacy_name = format!("Legacy {}", rng_str(8));
+    let legacy = Uuid::new_v4();
+    sqlx::query("INSERT INTO Department (DepID, name) VALUES (?, ?)")
+        .bind(legacy)
+        .bind(&legacy_name)
+        .execute(&mut *database)
+        .await
+        .unwrap();
+    let adopted = database.get_or_add_department(&hierarchy(&[(&legacy_name, node + 6)])).await.unwrap().unwrap();
+    assert_eq!(adopted, legacy);
+}
+
+#[tokio::test]
+#[serial]
+pub async fn test_empty_department_path() {
+    let client = create_client().await;
+    let mut database = Connection::from(client_database(&client).await);
+    let empty = DepartmentHierarchy::from(Vec::new());
+    assert_eq!(database.get_or_add_department(&empty).await.unwrap(), None);
+
+    // A product page without breadcrumbs can not add the product
+    let product = |asin: &str| Product {
+        asin: asin.to_string(),
+        name: "Unfiled".to_string(),
+        department: DepartmentHierarchy::from(Vec::new()),
+        manufacturer: "Acme".to_string(),
+    };
+    let unfiled = format!("T{}", rng_str(9).to_uppercase());
+    assert_eq!(database.add_product(&product(&unfiled)).await.unwrap(), None);
+    assert_eq!(database.product_exists(&unfiled).await.unwrap(), None);
+
+    // An existing product keeps its department
+    let (id, asin) = add_test_product(&mut database, "Filed").await;
+    database.update_product_metadata(&product(&asin)).await.unwrap();
+    let (name, department): (String, Uuid) = sqlx::query_as("SELECT name, DepID FROM Sold_Product_Manufactured WHERE PID = ?")
+        .bind(id)
+        .fetch_one(&mut *database)
+        .await
+        .unwrap();
+    assert_eq!(name, "Unfiled");
+    assert_eq!(Some(department), database.department_by_name("Cooldep1").await.unwrap());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[serial]
+pub async fn test_concurrent_department_adds() {
+    let client = create_client().await;
+    let pool = client.rocket().state::<Pool<Sqlite>>().unwrap().clone();
+    let node = rand::thread_rng().gen_range(1_000_000..u32::MAX as u64);
+
+    // Two branches sharing their top level department, added from several connections at once
+    let mut tasks = Vec::new();
+    for task in 0..8u64 {
+        let pool = pool.clone();
+        tasks.push(tokio::spawn(async move {
+            let leaf = node + 1 + task % 2;
+            let path = hierarchy(&[("Home", node), ("Kitchen", leaf)]);
+            let mut database = Connection::from(pool.acquire().await.unwrap());
+            (leaf, database.get_or_add_department(&path).await.unwrap().unwrap())
+        }));
+    }
+
+    let mut added = Vec::new();
+    for task in tasks {
+        added.push(task.await.unwrap());
+    }
+
+    let mut database = Connection::from(pool.acquire().await.unwrap());
+    let top = database.department_by_node(node).await.unwrap().unwrap();
+    for (leaf, department) in added {
+        assert_eq!(database.department_by_node(leaf).await.unwrap(), Some(department));
+        assert_eq!(parent_department(&mut database, department).await, Some(top));
+    }
+
+    let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM Department WHERE node BETWEEN ? AND ?")
+        .bind(node as i64)
+        .bind(node as i64 + 2)
+        .fetch_one(&mut *database)
+        .await
+        .unwrap();
+    assert_eq!(count, 3);
 }
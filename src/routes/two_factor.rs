@@ -0,0 +1,134 @@
+use crate::clock::Clock;
+use crate::csrf::{CsrfToken, CsrfVerified};
+use crate::database::Connection;
+use crate::forms::TwoFactorCodeForm;
+use crate::session::UserId;
+use crate::totp::{
+    base32, check_second_factor, generate_recovery_codes, generate_secret, hash_recovery_code, provisioning_uri,
+    verify,
+};
+use rocket::form::Form;
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
+use rocket::{get, post, State};
+use rocket_dyn_templates::{context, Template};
+use sqlx::types::Uuid;
+use sqlx::Sqlite;
+
+/// Name shown for the account in authenticator apps
+const ISSUER: &str = "Amazon Tracker";
+
+#[get("/")]
+pub async fn two_factor_page(
+    user: UserId,
+    csrf_token: CsrfToken,
+    mut database: Connection<Sqlite>,
+    flash: Option<FlashMessage<'_>>,
+) -> crate::Result<Template> {
+    let flash = flash.map(FlashMessage::into_inner);
+
+    match database.two_factor(*user).await? {
+        Some((_, true, _)) => {
+            let recovery_codes_left = database.unused_recovery_codes(*user).await?;
+            Ok(Template::render("two_factor", context! {csrf_token, enabled: true, recovery_codes_left, flash}))
+        }
+        Some((secret, false, _)) => {
+            let email = database.user_email(*user).await?.unwrap_or_default();
+            Ok(Template::render("two_factor", context! {
+                csrf_token,
+                enabled: false,
+                secret: base32(&secret),
+                uri: provisioning_uri(&secret, ISSUER, &email),
+                flash
+            }))
+        }
+        None => Ok(Template::render("two_factor", context! {csrf_token, enabled: false, flash})),
+    }
+}
+
+/// Create a new secret to add to an authenticator app. It is only used once confirmed.
+#[post("/setup")]
+pub async fn setup(
+    user: UserId,
+    _csrf: CsrfVerified,
+    mut database: Connection<Sqlite>,
+    clock: &State<Clock>,
+) -> crate::Result<Flash<Redirect>> {
+    if let Some((_, true, _)) = database.two_factor(*user).await? {
+        return Ok(Flash::error(Redirect::to("/two-factor"), "Two-factor authentication is already enabled"));
+    }
+
+    database.set_pending_two_factor(*user, &generate_secret(), clock.now()).await?;
+    Ok(Flash::success(Redirect::to("/two-factor"), "Scan the QR code with your authenticator app"))
+}
+
+/// Enable two-factor authentication once the user shows their app produces the right codes
+#[post("/confirm", data = "<form>")]
+pub async fn confirm(
+    user: UserId,
+    _csrf: CsrfVerified,
+    csrf_token: CsrfToken,
+    mut database: Connection<Sqlite>,
+    clock: &State<Clock>,
+    form: Form<TwoFactorCodeForm<'_>>,
+) -> crate::Result<Result<Template, Flash<Redirect>>> {
+    let secret = match database.two_factor(*user).await? {
+        Some((secret, false, _)) => secret,
+        _ => return Ok(Err(Flash::error(Redirect::to("/two-factor"), "Start setting up two-factor authentication first"))),
+    };
+
+    let step = match verify(&secret, form.code, clock.now(), None) {
+        Some(step) => step,
+        None => return Ok(Err(Flash::error(Redirect::to("/two-factor"), "Incorrect code, try again"))),
+    };
+
+    database.enable_two_factor(*user).await?;
+    database.use_two_factor_step(*user, step).await?;
+    Ok(Ok(show_recovery_codes(&mut database, *user, csrf_token).await?))
+}
+
+/// Replace the recovery codes, for example after using some of them
+#[post("/recovery-codes", data = "<form>")]
+pub async fn regenerate_recovery_codes(
+    user: UserId,
+    _csrf: CsrfVerified,
+    csrf_token: CsrfToken,
+    mut database: Connection<Sqlite>,
+    clock: &State<Clock>,
+    form: Form<TwoFactorCodeForm<'_>>,
+) -> crate::Result<Result<Template, Flash<Redirect>>> {
+    if !check_second_factor(&mut database, *user, form.code, clock.now()).await? {
+        return Ok(Err(Flash::error(Redirect::to("/two-factor"), "Incorrect code")));
+    }
+
+    Ok(Ok(show_recovery_codes(&mut database, *user, csrf_token).await?))
+}
+
+#[post("/disable", data = "<form>")]
+pub async fn disable(
+    user: UserId,
+    _csrf: CsrfVerified,
+    mut database: Connection<Sqlite>,
+    clock: &State<Clock>,
+    form: Form<TwoFactorCodeForm<'_>>,
+) -> crate::Result<Flash<Redirect>> {
+    if !check_second_factor(&mut database, *user, form.code, clock.now()).await? {
+        return Ok(Flash::error(Redirect::to("/two-factor"), "Incorrect code"));
+    }
+
+    database.disable_two_factor(*user).await?;
+    Ok(Flash::success(Redirect::to("/two-factor"), "Two-factor authentication has been turned off"))
+}
+
+/// Create new recovery codes. They are only shown once, since only their hashes are stored.
+async fn show_recovery_codes(
+    database: &mut Connection<Sqlite>,
+    user: Uuid,
+    csrf_token: CsrfToken,
+) -> sqlx::Result<Template> {
+    let codes = generate_recovery_codes();
+    let hashes: Vec<_> = codes.iter().map(|code| hash_recovery_code(code)).collect();
+    database.set_recovery_codes(user, &hashes).await?;
+
+    Ok(Template::render("two_factor", context! {csrf_token, enabled: true, recovery_codes: codes}))
+}
@@ -1,11 +1,19 @@
+use crate::account_token::{AccountTokens, TokenPurpose};
+use crate::clock::Clock;
+use crate::csrf::CsrfVerified;
 use crate::database::Connection;
 use crate::error::Error;
-use crate::forms::UserCredentials;
-use crate::session::Session;
-use log::info;
+use crate::forms::{EmailForm, ResetPasswordForm, TwoFactorCodeForm, UserCredentials};
+use crate::login_throttle::{LoginThrottle, Verdict};
+use crate::session::{Role, Session};
+use crate::mail::Mailer;
+use crate::totp::check_second_factor;
+use log::{error, info};
 use rocket::form::Form;
 use rocket::response::{Redirect,Flash};
-use rocket::{get, post};
+use rocket::{get, post, State};
+use chrono::{DateTime, Utc};
+use std::net::IpAddr;
 use sqlx::types::Uuid;
 use sqlx::Sqlite;
 
@@ -22,36 +30,154 @@ pub async fn index(session: Session<'_>) -> crate::Result<Redirect> {
 #[post("/login", data = "<credentials>")]
 pub async fn login(
     session: Session<'_>,
+    _csrf: CsrfVerified,
     mut database: Connection<Sqlite>,
+    throttle: &State<LoginThrottle>,
+    clock: &State<Clock>,
+    client_ip: Option<IpAddr>,
     credentials: Form<UserCredentials<'_>>,
 ) -> crate::Result<Flash<Redirect>> {
+    let now = clock.now();
 
+    let verdict = throttle.check(&mut database, credentials.email, client_ip, now).await?;
+    if let Some(message) = throttled_message(verdict, now) {
+        return Ok(Flash::error(Redirect::to("/login"), message));
+    }
 
-    let user_id =
-        sqlx::query_as("SELECT sid FROM Site_users WHERE email = ? AND password_hash = ?")
+    let user: Option<(Uuid, bool, bool)> =
+        sqlx::query_as("SELECT sid, email_verified, disabled FROM Site_users WHERE email = ? AND password_hash = ?")
             .bind(credentials.email)
             .bind(&credentials.password_hash()[..])
             .fetch_optional(&mut *database)
             .await?;
 
-    match user_id {
-        Some((id,)) => {
-            session.set_user_id(id);
-            Ok(Flash::success(Redirect::to("/index"),"Successfully logged in"))
+    match user {
+        Some((_, _, true)) => {
+            throttle.record_success(&mut database, credentials.email).await?;
+            Ok(Flash::error(Redirect::to("/login"), "This account has been disabled"))
+        }
+        Some((_, false, _)) => {
+            throttle.record_success(&mut database, credentials.email).await?;
+            Ok(Flash::error(
+                Redirect::to("/login"),
+                "Confirm your email address using the link we sent you before signing in",
+            ))
+        }
+        Some((id, true, _)) => {
+            throttle.record_success(&mut database, credentials.email).await?;
+
+            if let Some((_, true, _)) = database.two_factor(id).await? {
+                session.set_pending_login(id, now);
+                return Ok(Flash::success(
+                    Redirect::to("/login/two-factor"),
+                    "Enter the code from your authenticator app",
+                ));
+            }
+
+            finish_login(&session, &mut database, id).await
         }
-        None => Ok(Flash::success(Redirect::to("/login"), "Incorrect password/user"))
+        None => {
+            throttle.record_failure(&mut database, credentials.email, client_ip, now).await?;
+            Ok(Flash::success(Redirect::to("/login"), "Incorrect password/user"))
+        }
+    }
+}
+
+/// The second step of signing in to an account with two-factor authentication, taking either a code
+/// from the authenticator app or a recovery code. Wrong codes count as failed logins.
+#[post("/login/two-factor", data = "<form>")]
+pub async fn login_two_factor(
+    session: Session<'_>,
+    _csrf: CsrfVerified,
+    mut database: Connection<Sqlite>,
+    throttle: &State<LoginThrottle>,
+    clock: &State<Clock>,
+    client_ip: Option<IpAddr>,
+    form: Form<TwoFactorCodeForm<'_>>,
+) -> crate::Result<Flash<Redirect>> {
+    let now = clock.now();
+
+    let (user, email) = match session.pending_login(now) {
+        Some(user) => match database.user_email(user).await? {
+            Some(email) => (user, email),
+            None => return Ok(Flash::error(Redirect::to("/login"), "Sign in again to continue")),
+        },
+        None => return Ok(Flash::error(Redirect::to("/login"), "Sign in again to continue")),
+    };
+
+    let verdict = throttle.check(&mut database, &email, client_ip, now).await?;
+    if let Some(message) = throttled_message(verdict, now) {
+        session.clear_pending_login();
+        return Ok(Flash::error(Redirect::to("/login"), message));
+    }
+
+    // The account may have been disabled after the password was entered
+    if database.is_user_disabled(user).await? {
+        session.clear_pending_login();
+        return Ok(Flash::error(Redirect::to("/login"), "This account has been disabled"));
+    }
+
+    if !check_second_factor(&mut database, user, form.code, now).await? {
+        throttle.record_failure(&mut database, &email, client_ip, now).await?;
+        return Ok(Flash::error(Redirect::to("/login/two-factor"), "Incorrect code"));
+    }
+
+    throttle.record_success(&mut database, &email).await?;
+    session.clear_pending_login();
+    finish_login(&session, &mut database, user).await
+}
+
+/// Start a session for a user who has been authenticated. Signing in also keeps an account which
+/// was going to be deleted.
+async fn finish_login(
+    session: &Session<'_>,
+    database: &mut Connection<Sqlite>,
+    user: Uuid,
+) -> crate::Result<Flash<Redirect>> {
+    session.start(database, user).await?;
+
+    if database.cancel_account_deletion(user).await? {
+        return Ok(Flash::success(
+            Redirect::to("/index"),
+            "Successfully logged in. Your account is no longer going to be deleted.",
+        ));
+    }
+
+    Ok(Flash::success(Redirect::to("/index"), "Successfully logged in"))
+}
+
+/// The message shown when the throttle does not allow another attempt yet
+fn throttled_message(verdict: Verdict, now: DateTime<Utc>) -> Option<String> {
+    match verdict {
+        Verdict::Allowed => None,
+        Verdict::Delayed(until) => Some(format!("Too many failed attempts. Try again in {}.", time_until(until, now))),
+        Verdict::LockedOut(until) => Some(format!(
+            "Signing in has been locked after too many failed attempts. Try again in {}.",
+            time_until(until, now)
+        )),
+    }
+}
+
+/// Time left before another login may be attempted, rounded up to whole seconds or minutes
+fn time_until(until: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let seconds = (until - now).num_seconds().max(1);
+
+    match seconds {
+        1 => "1 second".to_string(),
+        2..=59 => format!("{} seconds", seconds),
+        60 => "1 minute".to_string(),
+        _ => format!("{} minutes", (seconds + 59) / 60),
     }
 }
 
 #[post("/register", data = "<credentials>")]
 pub async fn register(
-    session: Session<'_>,
+    _csrf: CsrfVerified,
     mut database: Connection<Sqlite>,
+    tokens: &State<AccountTokens>,
+    mailer: &State<Mailer>,
     credentials: Form<UserCredentials<'_>>,
 ) -> crate::Result<Flash<Redirect>> {
-    // Some funny notes:
-    // * When you register, you automatically are logged in( I didnt intend
-    // to do that) 
     if !credentials.is_valid_email() {
         return Err(Error::from("Email must be a valid email address"));
     }
@@ -76,19 +202,141 @@ pub async fn register(
         credentials.email, new_user_id
     );
 
-    sqlx::query("INSERT INTO Site_users (sid, email, password_hash) VALUES (?, ?, ?)")
+    sqlx::query("INSERT INTO Site_users (sid, email, password_hash, role) VALUES (?, ?, ?, ?)")
         .bind(new_user_id)
         .bind(credentials.email)
         .bind(&credentials.password_hash()[..])
+        .bind(Role::User.as_str())
         .execute(&mut *database)
         .await?;
 
-    session.set_user_id(new_user_id);
-    Ok(Flash::success(Redirect::to("/login"),"Succesfully registed"))
+    // The account can only be signed in to once the address has been confirmed
+    let token = tokens.issue(&mut database, new_user_id, TokenPurpose::VerifyEmail, Utc::now()).await?;
+    if let Err(e) = mailer.send(&mailer.verification_mail(credentials.email, &token)).await {
+        error!("Unable to send verification email to {}: {}", credentials.email, e);
+        return Ok(Flash::error(
+            Redirect::to("/verify/resend"),
+            "Your account was created, but the confirmation email could not be sent. Try sending it again.",
+        ));
+    }
+
+    Ok(Flash::success(
+        Redirect::to("/login"),
+        "Succesfully registed. Check your email for a link to confirm your address.",
+    ))
+}
+
+#[get("/verify?<token>")]
+pub async fn verify_email(
+    mut database: Connection<Sqlite>,
+    tokens: &State<AccountTokens>,
+    token: &str,
+) -> crate::Result<Flash<Redirect>> {
+    match tokens.redeem(&mut database, token, TokenPurpose::VerifyEmail, Utc::now()).await? {
+        Some(user) => {
+            database.set_email_verified(user).await?;
+            Ok(Flash::success(Redirect::to("/login"), "Your email address has been confirmed"))
+        }
+        None => Ok(Flash::error(
+            Redirect::to("/verify/resend"),
+            "This confirmation link is invalid or has expired",
+        )),
+    }
+}
+
+/// The same message is shown whether or not the address belongs to an account, so the form can
+/// not be used to find out who has signed up
+#[post("/verify/resend", data = "<form>")]
+pub async fn resend_verification(
+    _csrf: CsrfVerified,
+    mut database: Connection<Sqlite>,
+    tokens: &State<AccountTokens>,
+    mailer: &State<Mailer>,
+    form: Form<EmailForm<'_>>,
+) -> crate::Result<Flash<Redirect>> {
+    if let Some((user, false)) = database.user_by_email(form.email).await? {
+        let token = tokens.issue(&mut database, user, TokenPurpose::VerifyEmail, Utc::now()).await?;
+        if let Err(e) = mailer.send(&mailer.verification_mail(form.email, &token)).await {
+            error!("Unable to send verification email to {}: {}", form.email, e);
+        }
+    }
+
+    Ok(Flash::success(
+        Redirect::to("/login"),
+        "If that address has an unconfirmed account, a new confirmation link has been sent to it",
+    ))
+}
+
+#[post("/forgot", data = "<form>")]
+pub async fn forgot_password(
+    _csrf: CsrfVerified,
+    mut database: Connection<Sqlite>,
+    tokens: &State<AccountTokens>,
+    mailer: &State<Mailer>,
+    form: Form<EmailForm<'_>>,
+) -> crate::Result<Flash<Redirect>> {
+    if let Some((user, _)) = database.user_by_email(form.email).await? {
+        let token = tokens.issue(&mut database, user, TokenPurpose::ResetPassword, Utc::now()).await?;
+        if let Err(e) = mailer.send(&mailer.password_reset_mail(form.email, &token)).await {
+            error!("Unable to send password reset email to {}: {}", form.email, e);
+        }
+    }
+
+    Ok(Flash::success(
+        Redirect::to("/login"),
+        "If that address has an account, a link to reset the password has been sent to it",
+    ))
+}
+
+#[post("/reset", data = "<form>")]
+pub async fn reset_password(
+    _csrf: CsrfVerified,
+    mut database: Connection<Sqlite>,
+    tokens: &State<AccountTokens>,
+    throttle: &State<LoginThrottle>,
+    form: Form<ResetPasswordForm<'_>>,
+) -> crate::Result<Flash<Redirect>> {
+    // Only the password is needed to check and hash it. Check it before the token is used up so
+    // the user can try again.
+    let credentials = UserCredentials {
+        email: "",
+        password: form.password,
+    };
+
+    if let Some(issue) = credentials.check_password_for_issues() {
+        return Ok(Flash::error(Redirect::to(format!("/reset?token={}", form.token)), issue));
+    }
+
+    let user = match tokens.redeem(&mut database, form.token, TokenPurpose::ResetPassword, Utc::now()).await? {
+        Some(user) => user,
+        None => {
+            return Ok(Flash::error(
+                Redirect::to("/forgot"),
+                "This reset link is invalid or has expired",
+            ))
+        }
+    };
+
+    database.set_password_hash(user, &credentials.password_hash()).await?;
+
+    // Sign out everywhere in case someone else knew the old password
+    database.delete_user_sessions(user).await?;
+
+    // Following the emailed link also proves the user owns the address, and lifts any lockout
+    database.set_email_verified(user).await?;
+    if let Some(email) = database.user_email(user).await? {
+        throttle.record_success(&mut database, &email).await?;
+    }
+
+    Ok(Flash::success(Redirect::to("/login"), "Your password has been changed"))
 }
 
-#[get("/logout")]
-pub async fn logout(session: Session<'_>) -> crate::Result<Flash<Redirect>> {
-    session.remove_user_id();
+#[post("/logout")]
+pub async fn logout(
+    session: Session<'_>,
+    _csrf: CsrfVerified,
+    mut database: Connection<Sqlite>,
+) -> crate::Result<Flash<Redirect>> {
+    session.end(&mut database).await?;
     Ok(Flash::success(Redirect::to("/login"), "Logged out succesfully!"))
 }
@@ -1,78 +1,134 @@
-use crate::scraper::offer::Offer;
+use crate::scraper::archive::PageArchive;
+use crate::scraper::cache::{CacheMode, CachedResponse, Lookup, ResponseCache};
+use crate::scraper::health::ParserHealth;
+use crate::scraper::identifier::{IdentifierError, IdentifierSource, ProductIdentifier};
+use crate::scraper::offer::{offer_count, offers_on_page, Offer};
 use crate::scraper::product::Product;
 use crate::scraper::rate_limit::RateLimit;
+use crate::scraper::rules;
+use crate::scraper::search::{search_results, SearchResult};
 use futures::{stream, StreamExt};
 use html5ever::tendril::ByteTendril;
-use log::{error, warn};
-use reqwest::header::CONTENT_TYPE;
-use reqwest::{Client, IntoUrl};
+use log::{debug, error, warn};
+use reqwest::header::{CONTENT_TYPE, IF_MODIFIED_SINCE, IF_NONE_MATCH};
+use reqwest::{Client, RequestBuilder, Response, StatusCode, Url};
 use select::document::Document;
-use select::predicate::{Attr, Name, Text};
-use std::str::FromStr;
+use select::predicate::{Name, Text};
+use std::sync::Arc;
 use std::time::Duration;
 
-/// I call it an API, but it is really just a web scraper with helper functions
+/// I call it an API, but it is really just a web scraper with helper functions. Clones share the
+/// same rate limit, cache and archive.
+#[derive(Clone)]
 pub struct AmazonApi {
     client: Client,
-    rate_limit: RateLimit,
+    rate_limit: Arc<RateLimit>,
+    cache: Option<Arc<ResponseCache>>,
+    archive: Option<Arc<PageArchive>>,
+    health: Arc<ParserHealth>,
 }
 
 impl Default for AmazonApi {
     fn default() -> Self {
         AmazonApi {
             client: Client::new(),
-            rate_limit: RateLimit::new(20, Duration::from_millis(50)),
+            rate_limit: Arc::new(RateLimit::new(20, Duration::from_millis(50))),
+            cache: None,
+            archive: None,
+            health: Arc::default(),
         }
     }
 }
 
 impl AmazonApi {
-    async fn get_text<U: IntoUrl>(&self, url: U) -> reqwest::Result<Document> {
-        let mut response = self
-            .rate_limit
-            .perform_rate_limited(move || self.client.get(url).send())
-            .await?;
-
-        let is_utf8 = response
-            .headers()
-            .get(CONTENT_TYPE)
-            .and_then(|x| x.to_str().ok())
-            .map(|x| x.ends_with("charset=UTF-8") || x.ends_with("charset=utf-8"))
-            .unwrap_or(false);
-
-        if !is_utf8 {
-            // If the request is not utf-8 we can let reqwest buffer it for us, but we will have to
-            // spend extra time copying the response into the document.
-            return Ok(Document::from(response.text().await?.as_str()));
+    /// Enable caching of responses so repeated requests for the same page within the TTL do not
+    /// count against the rate limit.
+    pub fn with_cache(mut self, cache: ResponseCache) -> Self {
+        self.cache = Some(Arc::new(cache));
+        self
+    }
+
+    /// Save a compressed copy of every fetched page so it can be re-parsed later
+    pub fn with_archive(mut self, archive: PageArchive) -> Self {
+        self.archive = Some(Arc::new(archive));
+        self
+    }
+
+    /// Parse success counters for the pages fetched by this API
+    pub fn health(&self) -> &Arc<ParserHealth> {
+        &self.health
+    }
+
+    async fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        self.rate_limit
+            .perform_rate_limited(move || request.send())
+            .await
+    }
+
+    async fn get_text(
+        &self,
+        url: String,
+        asin: Option<&str>,
+        mode: CacheMode,
+    ) -> reqwest::Result<Document> {
+        if self.cache.is_none() && self.archive.is_none() {
+            return read_document(self.send(self.client.get(url)).await?).await;
         }
 
-        struct UnsafeSend(ByteTendril);
+        let mut request = self.client.get(&url);
+        let mut stale_entry = None;
+
+        if let Some(cache) = &self.cache {
+            match cache.lookup(&url, mode).await {
+                Lookup::Fresh(entry) => {
+                    debug!("Using cached response for {}", url);
+                    return Ok(Document::from(entry.body.as_str()));
+                }
+                Lookup::Stale(entry) => {
+                    if let Some(etag) = &entry.etag {
+                        request = request.header(IF_NONE_MATCH, etag);
+                    }
+
+                    if let Some(last_modified) = &entry.last_modified {
+                        request = request.header(IF_MODIFIED_SINCE, last_modified);
+                    }
+
+                    stale_entry = Some((cache, entry));
+                }
+                Lookup::Miss => {}
+            }
+        }
 
-        /// # Safety
-        /// By default, tendrils do not implement Send when non-atomic. This is because they are
-        /// internally reference counted so sending a non-atomic tendril to another thread could
-        /// result in synchronous access leading to undefined behavior.
-        ///
-        /// However, a non-atomic tendril can be safely passed to another thread so long as it stays
-        /// on a single thread.
-        unsafe impl Send for UnsafeSend {}
+        let response = self.send(request).await?;
 
-        let mut tendril = UnsafeSend(ByteTendril::new());
-        if let Some(length) = response.content_length() {
-            tendril.0.reserve(length as u32);
+        if response.status() == StatusCode::NOT_MODIFIED {
+            if let Some((cache, entry)) = stale_entry {
+                debug!("Revalidated cached response for {}", url);
+                let entry = cache.mark_revalidated(entry).await;
+                return Ok(Document::from(entry.body.as_str()));
+            }
         }
 
-        while let Some(chunk) = response.chunk().await? {
-            tendril.0.push_slice(&*chunk);
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.text().await?;
+
+        // Documents are not Send, so the document can only be created after the last await point
+        if let Some(archive) = &self.archive {
+            archive
+                .store(asin, &url, status.as_u16(), body.as_bytes())
+                .await;
         }
 
-        match tendril.0.try_reinterpret() {
-            Ok(str_tendril) => Ok(Document::from(str_tendril)),
-            Err(tendril) => {
-                error!("Request with Content-Type=UTF-8 contained non-utf8 data, performing lossy conversion");
-                Ok(Document::from(&*String::from_utf8_lossy(&tendril)))
+        // Only cache successful responses so a temporary error page does not get served again
+        if let Some(cache) = &self.cache {
+            if status.is_success() {
+                let entry = CachedResponse::new(url, &headers, body.clone());
+                cache.store(entry).await;
             }
         }
+
+        Ok(Document::from(body.as_str()))
     }
 
     pub async fn is_valid_asin(&self, asin: &str) -> reqwest::Result<bool> {
@@ -81,7 +137,7 @@ impl AmazonApi {
         }
 
         let url = format!("https://www.amazon.com/dp/{}", asin);
-        let document = self.get_text(url).await?;
+        let document = self.get_text(url, Some(asin), CacheMode::Default).await?;
 
         let is_not_found = document
             .find(Name("title"))
@@ -92,7 +148,42 @@ impl AmazonApi {
         Ok(!is_not_found)
     }
 
-    pub async fn get_offer_page(&self, asin: &str, page: u32) -> reqwest::Result<Document> {
+    /// Parse a product URL or identifier, following the redirects of short links to find the
+    /// product they point to. Returns None if no product could be identified.
+    pub async fn resolve_identifier(
+        &self,
+        input: &str,
+    ) -> reqwest::Result<Option<ProductIdentifier>> {
+        let url = match ProductIdentifier::parse(input) {
+            Ok(identifier) => return Ok(Some(identifier)),
+            Err(IdentifierError::ShortLink(url)) => url,
+            Err(e) => {
+                debug!("Unable to identify product from {:?}: {}", input, e);
+                return Ok(None);
+            }
+        };
+
+        // Redirects are followed by the client, so the final URL is the product page
+        let response = self.send(self.client.get(url)).await?;
+
+        match ProductIdentifier::parse(response.url().as_str()) {
+            Ok(identifier) => Ok(Some(ProductIdentifier {
+                source: IdentifierSource::ShortLink,
+                ..identifier
+            })),
+            Err(e) => {
+                warn!("Short link {:?} redirected to {}: {}", input, response.url(), e);
+                Ok(None)
+            }
+        }
+    }
+
+    pub async fn get_offer_page(
+        &self,
+        asin: &str,
+        page: u32,
+        mode: CacheMode,
+    ) -> reqwest::Result<Document> {
         assert!(page >= 1);
 
         // The first page is special because it also includes the header and side-bar
@@ -101,14 +192,20 @@ impl AmazonApi {
             _ => format!("https://www.amazon.com/gp/product/ajax/ref=aod_page_{0}?asin={1}&pc=dp&isonlyrenderofferlist=true&pageno={0}&experienceId=aodAjaxMain", page, asin),
         };
 
-        self.get_text(url).await
+        self.get_text(url, Some(asin), mode).await
     }
 
-    pub async fn get_product_info(&self, asin: &str) -> reqwest::Result<Option<Product>> {
+    pub async fn get_product_info(
+        &self,
+        asin: &str,
+        mode: CacheMode,
+    ) -> reqwest::Result<Option<Product>> {
         let url = format!("https://www.amazon.com/dp/{}", asin);
-        let document = self.get_text(url).await?;
+        let document = self.get_text(url, Some(asin), mode).await?;
 
         let product = Product::try_from(&document);
+        self.health.record_product(asin, &product);
+
         if let Err(e) = &product {
             error!("Got error while parsing product: {:?}", e);
         }
@@ -116,34 +213,47 @@ impl AmazonApi {
         Ok(product.ok())
     }
 
-    pub async fn get_offers_for_asin(&self, asin: &str) -> reqwest::Result<Vec<Offer>> {
+    /// Fetch the first page of search results for a keyword query
+    pub async fn search(&self, query: &str, mode: CacheMode) -> reqwest::Result<Vec<SearchResult>> {
+        let url = Url::parse_with_params("https://www.amazon.com/s", &[("k", query)])
+            .expect("Search URL is valid");
+
+        let document = self.get_text(url.into(), None, mode).await?;
+        let results = search_results(&document, &rules::current().search, &self.health);
+
+        if results.is_empty() {
+            warn!("Found no search results for {:?}. Either nothing matched or an error may have occurred.", query);
+        }
+
+        Ok(results)
+    }
+
+    pub async fn get_offers_for_asin(
+        &self,
+        asin: &str,
+        mode: CacheMode,
+    ) -> reqwest::Result<Vec<Offer>> {
         const OFFERS_PER_PAGE: u32 = 10;
 
         let mut offer_list = Vec::new();
 
+        // Use the same rules for every page even if they are reloaded part way through
+        let rules = rules::current();
+
         let total_offers = {
-            let first_page = self.get_offer_page(asin, 1).await?;
+            let first_page = self.get_offer_page(asin, 1, mode).await?;
 
-            for node in first_page.find(Attr("id", "aod-offer")) {
-                match Offer::try_from(node) {
-                    Ok(offer) => offer_list.push(offer),
-                    Err(err) => warn!("Failed to parse offer for item {}: {:?}", asin, err),
-                }
-            }
+            offer_list.extend(offers_on_page(asin, &first_page, &rules.offer, &self.health));
 
-            let total_offers = first_page
-                .find(Attr("id", "aod-filter-offer-count-string"))
-                .flat_map(|node| node.find(Text))
-                .filter_map(|node| node.as_text())
-                .filter_map(|s| {
-                    // We are expecting a string of the form "123 options"
-                    u32::from_str(s.strip_suffix(" options")?).ok()
-                })
-                .next();
+            let total_offers = offer_count(&first_page, &rules.offer);
 
             match total_offers {
-                Some(n) => n,
+                Some(n) => {
+                    self.health.record_success("offer_page.offer_count");
+                    n
+                }
                 None => {
+                    self.health.record_failure("offer_page.offer_count", asin);
                     warn!("Failed to find offer count for item {}. Either no offers are present or an error may have occurred.", asin);
                     return Ok(Vec::new());
                 }
@@ -153,25 +263,62 @@ impl AmazonApi {
         let num_offer_pages = (total_offers + OFFERS_PER_PAGE - 1) / OFFERS_PER_PAGE;
 
         let mut offer_pages =
-            stream::iter((2..=num_offer_pages).map(|page| self.get_offer_page(asin, page)))
+            stream::iter((2..=num_offer_pages).map(|page| self.get_offer_page(asin, page, mode)))
                 .buffer_unordered(self.rate_limit.max_sync_usages());
 
         while let Some(document) = offer_pages.next().await {
-            let mut offers_on_page = 0;
-
-            for node in document?.find(Attr("id", "aod-offer")) {
-                offers_on_page += 1;
-                match Offer::try_from(node) {
-                    Ok(offer) => offer_list.push(offer),
-                    Err(err) => warn!("Failed to parse offer for item {}: {:?}", asin, err),
-                }
-            }
+            let offers = offers_on_page(asin, &document?, &rules.offer, &self.health);
 
-            if offers_on_page == 0 {
+            if offers.is_empty() {
                 warn!("Found no offers on page for item {}. This may indicate that some offers were removed or an error occurred", asin);
             }
+
+            offer_list.extend(offers);
         }
 
         Ok(offer_list)
     }
 }
+
+async fn read_document(mut response: Response) -> reqwest::Result<Document> {
+    let is_utf8 = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.ends_with("charset=UTF-8") || x.ends_with("charset=utf-8"))
+        .unwrap_or(false);
+
+    if !is_utf8 {
+        // If the request is not utf-8 we can let reqwest buffer it for us, but we will have to
+        // spend extra time copying the response into the document.
+        return Ok(Document::from(response.text().await?.as_str()));
+    }
+
+    struct UnsafeSend(ByteTendril);
+
+    /// # Safety
+    /// By default, tendrils do not implement Send when non-atomic. This is because they are
+    /// internally reference counted so sending a non-atomic tendril to another thread could
+    /// result in synchronous access leading to undefined behavior.
+    ///
+    /// However, a non-atomic tendril can be safely passed to another thread so long as it stays
+    /// on a single thread.
+    unsafe impl Send for UnsafeSend {}
+
+    let mut tendril = UnsafeSend(ByteTendril::new());
+    if let Some(length) = response.content_length() {
+        tendril.0.reserve(length as u32);
+    }
+
+    while let Some(chunk) = response.chunk().await? {
+        tendril.0.push_slice(&*chunk);
+    }
+
+    match tendril.0.try_reinterpret() {
+        Ok(str_tendril) => Ok(Document::from(str_tendril)),
+        Err(tendril) => {
+            error!("Request with Content-Type=UTF-8 contained non-utf8 data, performing lossy conversion");
+            Ok(Document::from(&*String::from_utf8_lossy(&tendril)))
+        }
+    }
+}
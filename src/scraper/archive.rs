@@ -0,0 +1,219 @@
+use crate::scraper::cache::hex_digest;
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::warn;
+use sqlx::{FromRow, Pool, Sqlite};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// A row of the `Page_Archive` index table
+#[derive(FromRow)]
+pub struct ArchivedPage {
+    pub hash: String,
+    #[sqlx(rename = "ASIN")]
+    pub asin: Option<String>,
+    #[sqlx(rename = "URL")]
+    pub url: String,
+    pub fetched_at: DateTime<Utc>,
+    pub status: u16,
+}
+
+/// The kind of page an archived document holds. This is derived from the URL since that is what
+/// [AmazonApi](crate::scraper::AmazonApi) used to decide what to fetch.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PageKind {
+    Product,
+    /// An offer list page. Page numbers start at 1.
+    Offers { page: u32 },
+    Other,
+}
+
+impl ArchivedPage {
+    pub fn kind(&self) -> PageKind {
+        if self.url.contains("/gp/product/ajax/") {
+            let page = self
+                .url
+                .split(&['?', '&'][..])
+                .find_map(|param| param.strip_prefix("pageno="))
+                .and_then(|page| page.parse().ok())
+                .unwrap_or(1);
+
+            return PageKind::Offers { page };
+        }
+
+        if self.url.contains("/dp/") {
+            return PageKind::Product;
+        }
+
+        PageKind::Other
+    }
+}
+
+/// Content-addressed storage for every document fetched from Amazon. Pages are compressed and
+/// stored under the hex encoded SHA256 digest of their contents, so repeated fetches of an
+/// unchanged page only add a row to the index.
+pub struct PageArchive {
+    directory: PathBuf,
+    pool: Pool<Sqlite>,
+}
+
+impl PageArchive {
+    pub fn new<P: Into<PathBuf>>(directory: P, pool: Pool<Sqlite>) -> io::Result<Self> {
+        let directory = directory.into();
+        std::fs::create_dir_all(&directory)?;
+
+        Ok(PageArchive { directory, pool })
+    }
+
+    /// Archive a fetched page. Failures are logged instead of returned since a missing archive
+    /// entry should never stop a scrape from completing.
+    pub async fn store(&self, asin: Option<&str>, url: &str, status: u16, body: &[u8]) {
+        let hash = hex_digest(body);
+
+        if let Err(e) = self.write_page(&hash, body).await {
+            warn!("Failed to archive page {} ({}): {}", url, hash, e);
+            return;
+        }
+
+        let result = sqlx::query(
+            "INSERT INTO Page_Archive (ArchiveID, hash, ASIN, URL, fetched_at, status) VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(Uuid::new_v4())
+        .bind(&hash)
+        .bind(asin)
+        .bind(url)
+        .bind(Utc::now())
+        .bind(status)
+        .execute(&self.pool)
+        .await;
+
+        if let Err(e) = result {
+            warn!("Failed to add page {} ({}) to archive index: {}", url, hash, e);
+        }
+    }
+
+    /// Read back the contents of an archived page
+    pub async fn load(&self, hash: &str) -> io::Result<String> {
+        let compressed = tokio::fs::read(self.page_path(hash)).await?;
+
+        let mut body = String::new();
+        GzDecoder::new(&compressed[..]).read_to_string(&mut body)?;
+        Ok(body)
+    }
+
+    /// List archived pages in the order they were fetched, optionally limited to a single ASIN
+    pub async fn pages(&self, asin: Option<&str>) -> sqlx::Result<Vec<ArchivedPage>> {
+        sqlx::query_as(
+            "SELECT hash, ASIN, URL, fetched_at, status FROM Page_Archive
+                WHERE ? IS NULL OR ASIN = ?
+                ORDER BY fetched_at",
+        )
+        .bind(asin)
+        .bind(asin)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn write_page(&self, hash: &str, body: &[u8]) -> io::Result<()> {
+        let path = self.page_path(hash);
+        if tokio::fs::try_exists(&path).await? {
+            return Ok(());
+        }
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body)?;
+        let compressed = encoder.finish()?;
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        tokio::fs::write(path, compressed).await
+    }
+
+    fn page_path(&self, hash: &str) -> PathBuf {
+        // Split pages into sub-directories by the first byte of their hash to keep the directory
+        // sizes manageable
+        let (prefix, _) = hash.split_at(2.min(hash.len()));
+        self.directory.join(prefix).join(format!("{}.html.gz", hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    /// An archive in its own directory with an index in an in-memory database
+    async fn test_archive() -> PageArchive {
+        // A single connection, since every connection to `sqlite::memory:` has its own database
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+
+        sqlx::query(
+            "CREATE TABLE Page_Archive (ArchiveID BINARY(16), hash CHAR(64) NOT NULL, ASIN CHAR(10),
+                URL VARCHAR(1024) NOT NULL, fetched_at DATETIME NOT NULL, status INTEGER NOT NULL)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        PageArchive::new(format!("target/test-archive/{}", Uuid::new_v4().simple()), pool).unwrap()
+    }
+
+    #[tokio::test]
+    pub async fn store_and_load() {
+        let archive = test_archive().await;
+        let body = "<html><body>Widget</body></html>";
+        let url = "https://www.amazon.com/dp/B07VGRJDFY/";
+
+        archive.store(Some("B07VGRJDFY"), url, 200, body.as_bytes()).await;
+        archive.store(Some("B07VGRJDFY"), url, 200, body.as_bytes()).await;
+        archive.store(None, "https://www.amazon.com/s?k=widget", 503, b"Busy").await;
+
+        // Each fetch is indexed, but an unchanged page is only stored once
+        let pages = archive.pages(Some("B07VGRJDFY")).await.unwrap();
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].hash, hex_digest(body.as_bytes()));
+        assert_eq!(pages[0].hash, pages[1].hash);
+        assert_eq!((pages[0].url.as_str(), pages[0].status), (url, 200));
+        assert_eq!(pages[0].kind(), PageKind::Product);
+        assert_eq!(archive.pages(None).await.unwrap().len(), 3);
+
+        assert_eq!(archive.load(&pages[0].hash).await.unwrap(), body);
+        assert_ne!(std::fs::read(archive.page_path(&pages[0].hash)).unwrap(), body.as_bytes());
+    }
+
+    #[tokio::test]
+    pub async fn load_missing_or_corrupt_page() {
+        let archive = test_archive().await;
+        assert!(archive.load(&hex_digest(b"never stored")).await.is_err());
+
+        let body = b"<html></html>";
+        archive.store(None, "https://www.amazon.com/", 200, body).await;
+        std::fs::write(archive.page_path(&hex_digest(body)), b"not gzip").unwrap();
+        assert!(archive.load(&hex_digest(body)).await.is_err());
+    }
+
+    #[test]
+    pub fn page_kinds() {
+        let page = |url: &str| ArchivedPage {
+            hash: String::new(),
+            asin: None,
+            url: url.to_string(),
+            fetched_at: Utc::now(),
+            status: 200,
+        };
+
+        let offers = "https://www.amazon.com/gp/product/ajax/?asin=B07VGRJDFY&experienceId=aodAjaxMain";
+        assert_eq!(page(offers).kind(), PageKind::Offers { page: 1 });
+        assert_eq!(page(&format!("{}&pageno=3", offers)).kind(), PageKind::Offers { page: 3 });
+        assert_eq!(page("https://www.amazon.com/s?k=widget").kind(), PageKind::Other);
+    }
+}
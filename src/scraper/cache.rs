@@ -0,0 +1,271 @@
+use log::warn;
+use reqwest::header::{HeaderMap, HeaderName, ETAG, LAST_MODIFIED};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Upper bound on the number of responses kept in memory. Entries are also written to disk when a
+/// cache directory is set, so evicting from memory only costs a file read.
+const MAX_MEMORY_ENTRIES: usize = 512;
+
+/// Controls whether a request may be answered from the [ResponseCache].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CacheMode {
+    /// Use a fresh cached response if one is available, otherwise revalidate or fetch the page
+    Default,
+    /// Always fetch the page from Amazon. The new response still replaces the cached entry.
+    Bypass,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub url: String,
+    /// Seconds since the unix epoch at which the response was received or last revalidated
+    pub fetched_at: u64,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub body: String,
+}
+
+impl CachedResponse {
+    pub fn new(url: String, headers: &HeaderMap, body: String) -> Self {
+        let header_str = |name: HeaderName| {
+            headers
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .map(str::to_string)
+        };
+
+        CachedResponse {
+            url,
+            fetched_at: unix_time(),
+            etag: header_str(ETAG),
+            last_modified: header_str(LAST_MODIFIED),
+            body,
+        }
+    }
+
+    /// Returns true if this response can be revalidated with a conditional request
+    pub fn has_validators(&self) -> bool {
+        self.etag.is_some() || self.last_modified.is_some()
+    }
+
+    fn age(&self) -> Duration {
+        Duration::from_secs(unix_time().saturating_sub(self.fetched_at))
+    }
+}
+
+pub enum Lookup {
+    /// The cached response is within the TTL and can be used as is
+    Fresh(CachedResponse),
+    /// The response has expired, but can be revalidated using its ETag or Last-Modified headers
+    Stale(CachedResponse),
+    Miss,
+}
+
+/// An in-memory cache of successful responses keyed by URL. When a directory is provided, entries
+/// are additionally written to disk so they survive restarts.
+pub struct ResponseCache {
+    ttl: Duration,
+    directory: Option<PathBuf>,
+    entries: Mutex<HashMap<String, CachedResponse>>,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration) -> Self {
+        ResponseCache {
+            ttl,
+            directory: None,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_directory<P: Into<PathBuf>>(mut self, directory: P) -> Self {
+        let directory = directory.into();
+        if let Err(e) = std::fs::create_dir_all(&directory) {
+            warn!(
+                "Unable to create response cache directory {}, responses will only be cached in memory: {}",
+                directory.display(),
+                e
+            );
+            return self;
+        }
+
+        self.directory = Some(directory);
+        self
+    }
+
+    pub async fn lookup(&self, url: &str, mode: CacheMode) -> Lookup {
+        if mode == CacheMode::Bypass {
+            return Lookup::Miss;
+        }
+
+        let in_memory = self.entries.lock().unwrap().get(url).cloned();
+        let entry = match in_memory {
+            Some(entry) => entry,
+            None => match self.read_from_disk(url).await {
+                Some(entry) => entry,
+                None => return Lookup::Miss,
+            },
+        };
+
+        if entry.age() < self.ttl {
+            Lookup::Fresh(entry)
+        } else if entry.has_validators() {
+            Lookup::Stale(entry)
+        } else {
+            Lookup::Miss
+        }
+    }
+
+    pub async fn store(&self, entry: CachedResponse) {
+        if let Some(path) = self.entry_path(&entry.url) {
+            match serde_json::to_vec(&entry) {
+                Ok(bytes) => {
+                    if let Err(e) = tokio::fs::write(&path, bytes).await {
+                        warn!("Failed to write cached response to {}: {}", path.display(), e);
+                    }
+                }
+                Err(e) => warn!("Failed to serialize cached response for {}: {}", entry.url, e),
+            }
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(entry.url.clone(), entry);
+
+        if entries.len() > MAX_MEMORY_ENTRIES {
+            let ttl = self.ttl;
+            entries.retain(|_, entry| entry.age() < ttl || entry.has_validators());
+        }
+
+        while entries.len() > MAX_MEMORY_ENTRIES {
+            let oldest = entries
+                .values()
+                .min_by_key(|entry| entry.fetched_at)
+                .map(|entry| entry.url.clone());
+
+            match oldest {
+                Some(url) => entries.remove(&url),
+                None => break,
+            };
+        }
+    }
+
+    /// Reset the age of an entry after Amazon confirmed it has not changed
+    pub async fn mark_revalidated(&self, mut entry: CachedResponse) -> CachedResponse {
+        entry.fetched_at = unix_time();
+        self.store(entry.clone()).await;
+        entry
+    }
+
+    async fn read_from_disk(&self, url: &str) -> Option<CachedResponse> {
+        let path = self.entry_path(url)?;
+
+        let bytes = match tokio::fs::read(&path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Failed to read cached response from {}: {}", path.display(), e);
+                return None;
+            }
+        };
+
+        match serde_json::from_slice::<CachedResponse>(&bytes) {
+            // Guard against the unlikely case of a hash collision
+            Ok(entry) if entry.url == url => Some(entry),
+            Ok(_) => None,
+            Err(e) => {
+                warn!("Ignoring corrupt cached response {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn entry_path(&self, url: &str) -> Option<PathBuf> {
+        let directory = self.directory.as_ref()?;
+        Some(directory.join(format!("{}.json", hex_digest(url.as_bytes()))))
+    }
+}
+
+/// Hex encoded SHA256 digest of the given bytes
+pub fn hex_digest(bytes: &[u8]) -> String {
+    let digest = Sha256::digest(bytes);
+
+    let mut buffer = String::with_capacity(2 * digest.len());
+    for byte in digest {
+        if write!(buffer, "{:02x}", byte).is_err() {
+            unreachable!("Writing to a string will never fail")
+        }
+    }
+
+    buffer
+}
+
+fn unix_time() -> u64 {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(v) => v.as_secs(),
+        Err(_) => unreachable!("Unix epoch should always be before current system time"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(url: &str, etag: Option<&str>) -> CachedResponse {
+        CachedResponse {
+            url: url.to_string(),
+            fetched_at: unix_time(),
+            etag: etag.map(str::to_string),
+            last_modified: None,
+            body: "<html></html>".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    pub async fn fresh_entry_is_returned() {
+        let cache = ResponseCache::new(Duration::from_secs(60));
+        cache.store(entry("https://example.com/a", None)).await;
+
+        assert!(matches!(
+            cache.lookup("https://example.com/a", CacheMode::Default).await,
+            Lookup::Fresh(_)
+        ));
+        assert!(matches!(
+            cache.lookup("https://example.com/b", CacheMode::Default).await,
+            Lookup::Miss
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn bypass_ignores_cache() {
+        let cache = ResponseCache::new(Duration::from_secs(60));
+        cache.store(entry("https://example.com/a", None)).await;
+
+        assert!(matches!(
+            cache.lookup("https://example.com/a", CacheMode::Bypass).await,
+            Lookup::Miss
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn expired_entry_requires_validators() {
+        let cache = ResponseCache::new(Duration::ZERO);
+        cache.store(entry("https://example.com/a", None)).await;
+        cache.store(entry("https://example.com/b", Some("\"abc\""))).await;
+
+        assert!(matches!(
+            cache.lookup("https://example.com/a", CacheMode::Default).await,
+            Lookup::Miss
+        ));
+        assert!(matches!(
+            cache.lookup("https://example.com/b", CacheMode::Default).await,
+            Lookup::Stale(_)
+        ));
+    }
+}
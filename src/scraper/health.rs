@@ -0,0 +1,288 @@
+use crate::scraper::offer::{MissingOfferField, Offer};
+use crate::scraper::product::{MissingField, Product};
+use chrono::{DateTime, NaiveDate, Utc};
+use log::error;
+use serde::Serialize;
+use sqlx::{Pool, Sqlite};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Fields of a product page in the order they are read by [Product::try_from]
+pub const PRODUCT_FIELDS: [&str; 4] = ["asin", "manufacturer", "department", "name"];
+
+/// Fields of an offer in the order they are read by [Offer::try_from]
+pub const OFFER_FIELDS: [&str; 4] = ["price", "condition", "ships_from", "sold_by"];
+
+/// The number of recent failures kept in memory for the admin page
+const RECENT_FAILURE_LIMIT: usize = 100;
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct FieldCounts {
+    pub successes: u64,
+    pub failures: u64,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ParseFailure {
+    pub field: String,
+    pub asin: String,
+    pub time: DateTime<Utc>,
+}
+
+/// Counts how often each field could be read from the pages returned by Amazon. Counts are kept
+/// in memory and periodically written to the `Parser_Health` table by [ParserHealth::flush].
+#[derive(Default)]
+pub struct ParserHealth {
+    pending: Mutex<HashMap<String, FieldCounts>>,
+    recent_failures: Mutex<VecDeque<ParseFailure>>,
+}
+
+/// Thresholds used to decide when a field is failing often enough to raise an alert
+#[derive(Debug, Copy, Clone)]
+pub struct AlertPolicy {
+    /// Fraction of failed reads in a day which will raise an alert
+    pub threshold: f64,
+    /// Minimum number of reads in a day before alerts are considered
+    pub min_samples: u64,
+}
+
+impl ParserHealth {
+    pub fn record_success(&self, field: &str) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.entry(field.to_string()).or_default().successes += 1;
+    }
+
+    pub fn record_failure(&self, field: &str, asin: &str) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.entry(field.to_string()).or_default().failures += 1;
+        drop(pending);
+
+        let mut recent_failures = self.recent_failures.lock().unwrap();
+        if recent_failures.len() == RECENT_FAILURE_LIMIT {
+            recent_failures.pop_front();
+        }
+
+        recent_failures.push_back(ParseFailure {
+            field: field.to_string(),
+            asin: asin.to_string(),
+            time: Utc::now(),
+        });
+    }
+
+    pub fn record_product(&self, asin: &str, result: &Result<Product, MissingField>) {
+        match result {
+            Ok(product) => {
+                for field in PRODUCT_FIELDS {
+                    // The breadcrumbs are optional for parsing, but an empty hierarchy means the
+                    // selector no longer matches
+                    if field == "department" && product.department.is_empty() {
+                        self.record_failure(product_field(field), asin);
+                    } else {
+                        self.record_success(product_field(field));
+                    }
+                }
+            }
+            Err(e) => self.record_ordered(&PRODUCT_FIELDS, e.field(), asin, product_field),
+        }
+    }
+
+    pub fn record_offer(&self, asin: &str, result: &Result<Offer, MissingOfferField>) {
+        match result {
+            Ok(_) => OFFER_FIELDS
+                .iter()
+                .for_each(|field| self.record_success(offer_field(field))),
+            Err(e) => self.record_ordered(&OFFER_FIELDS, e.field(), asin, offer_field),
+        }
+    }
+
+    /// Fields are read in order, so every field before the missing one was read successfully and
+    /// nothing is known about the fields after it.
+    fn record_ordered(
+        &self,
+        fields: &[&'static str],
+        missing: &str,
+        asin: &str,
+        name: fn(&str) -> &'static str,
+    ) {
+        for field in fields {
+            if *field == missing {
+                self.record_failure(name(field), asin);
+                return;
+            }
+
+            self.record_success(name(field));
+        }
+
+        // Sub-fields such as "department_node" do not appear in the field list
+        self.record_failure(missing, asin);
+    }
+
+    /// Remove and return the counts collected since the last call
+    pub fn take_counts(&self) -> HashMap<String, FieldCounts> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+
+    pub fn recent_failures(&self) -> Vec<ParseFailure> {
+        let recent_failures = self.recent_failures.lock().unwrap();
+        recent_failures.iter().rev().cloned().collect()
+    }
+
+    /// Write the pending counts to the database and raise alerts for any field whose failure rate
+    /// for the day has crossed the policy threshold.
+    pub async fn flush(&self, pool: &Pool<Sqlite>, policy: AlertPolicy) -> sqlx::Result<()> {
+        let counts = self.take_counts();
+        if counts.is_empty() {
+            return Ok(());
+        }
+
+        let today = Utc::now().date_naive();
+        let mut remaining = counts.into_iter();
+
+        while let Some((field, count)) = remaining.next() {
+            let result = sqlx::query(
+                "INSERT INTO Parser_Health (day, field, successes, failures) VALUES (?, ?, ?, ?)
+                    ON CONFLICT (day, field) DO UPDATE SET
+                        successes = successes + excluded.successes,
+                        failures = failures + excluded.failures",
+            )
+            .bind(today)
+            .bind(&field)
+            .bind(count.successes as i64)
+            .bind(count.failures as i64)
+            .execute(pool)
+            .await;
+
+            if let Err(e) = result {
+                // Put the unwritten counts back so they are included in the next flush
+                let mut pending = self.pending.lock().unwrap();
+                for (field, count) in std::iter::once((field, count)).chain(remaining) {
+                    let entry = pending.entry(field).or_default();
+                    entry.successes += count.successes;
+                    entry.failures += count.failures;
+                }
+                return Err(e);
+            }
+        }
+
+        check_alerts(pool, today, policy).await
+    }
+}
+
+async fn check_alerts(pool: &Pool<Sqlite>, day: NaiveDate, policy: AlertPolicy) -> sqlx::Result<()> {
+    let totals: Vec<(String, i64, i64)> =
+        sqlx::query_as("SELECT field, successes, failures FROM Parser_Health WHERE day = ?")
+            .bind(day)
+            .fetch_all(pool)
+            .await?;
+
+    for (field, successes, failures) in totals {
+        let counts = FieldCounts {
+            successes: successes as u64,
+            failures: failures as u64,
+        };
+
+        let rate = match policy.failure_rate(counts) {
+            Some(rate) => rate,
+            None => continue,
+        };
+
+        let inserted = sqlx::query(
+            "INSERT OR IGNORE INTO Parser_Alerts (day, field, failure_rate, raised_at) VALUES (?, ?, ?, ?)",
+        )
+        .bind(day)
+        .bind(&field)
+        .bind(rate)
+        .bind(Utc::now())
+        .execute(pool)
+        .await?;
+
+        // Only log the first time the alert is raised each day
+        if inserted.rows_affected() > 0 {
+            error!(
+                "Parser alert: {:.1}% of reads for field {:?} failed today ({} of {}). Amazon may have changed their markup.",
+                100.0 * rate,
+                field,
+                counts.failures,
+                counts.successes + counts.failures
+            );
+        }
+    }
+
+    Ok(())
+}
+
+impl AlertPolicy {
+    /// Returns the failure rate if it should raise an alert
+    pub fn failure_rate(&self, counts: FieldCounts) -> Option<f64> {
+        let total = counts.successes + counts.failures;
+        if total == 0 || total < self.min_samples {
+            return None;
+        }
+
+        let rate = counts.failures as f64 / total as f64;
+        if rate < self.threshold {
+            return None;
+        }
+
+        Some(rate)
+    }
+}
+
+fn product_field(field: &str) -> &'static str {
+    match field {
+        "asin" => "product.asin",
+        "manufacturer" => "product.manufacturer",
+        "department" => "product.department",
+        "name" => "product.name",
+        _ => "product.other",
+    }
+}
+
+fn offer_field(field: &str) -> &'static str {
+    match field {
+        "price" => "offer.price",
+        "condition" => "offer.condition",
+        "ships_from" => "offer.ships_from",
+        "sold_by" => "offer.sold_by",
+        _ => "offer.other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn alert_policy_threshold() {
+        let policy = AlertPolicy {
+            threshold: 0.25,
+            min_samples: 10,
+        };
+
+        let counts = |successes, failures| FieldCounts {
+            successes,
+            failures,
+        };
+
+        assert_eq!(policy.failure_rate(counts(100, 0)), None);
+        assert_eq!(policy.failure_rate(counts(80, 20)), None);
+        assert_eq!(policy.failure_rate(counts(75, 25)), Some(0.25));
+        // Not enough samples to be meaningful
+        assert_eq!(policy.failure_rate(counts(1, 5)), None);
+    }
+
+    #[test]
+    pub fn failures_count_preceding_fields() {
+        let health = ParserHealth::default();
+        health.record_ordered(&OFFER_FIELDS, "ships_from", "B07VGRJDFY", offer_field);
+
+        let counts = health.take_counts();
+        assert_eq!(counts["offer.price"].successes, 1);
+        assert_eq!(counts["offer.condition"].successes, 1);
+        assert_eq!(counts["offer.ships_from"].failures, 1);
+        assert!(!counts.contains_key("offer.sold_by"));
+
+        assert_eq!(health.recent_failures().len(), 1);
+        assert!(health.take_counts().is_empty());
+    }
+}
@@ -0,0 +1,425 @@
+use reqwest::Url;
+use std::fmt::{self, Display, Formatter};
+
+/// The Amazon storefronts which product links are recognized for
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Marketplace {
+    UnitedStates,
+    Canada,
+    Mexico,
+    Brazil,
+    UnitedKingdom,
+    Germany,
+    France,
+    Italy,
+    Spain,
+    Netherlands,
+    Japan,
+    India,
+    Australia,
+}
+
+impl Marketplace {
+    const ALL: [Marketplace; 13] = [
+        Marketplace::UnitedStates,
+        Marketplace::Canada,
+        Marketplace::Mexico,
+        Marketplace::Brazil,
+        Marketplace::UnitedKingdom,
+        Marketplace::Germany,
+        Marketplace::France,
+        Marketplace::Italy,
+        Marketplace::Spain,
+        Marketplace::Netherlands,
+        Marketplace::Japan,
+        Marketplace::India,
+        Marketplace::Australia,
+    ];
+
+    pub fn domain(&self) -> &'static str {
+        match self {
+            Marketplace::UnitedStates => "amazon.com",
+            Marketplace::Canada => "amazon.ca",
+            Marketplace::Mexico => "amazon.com.mx",
+            Marketplace::Brazil => "amazon.com.br",
+            Marketplace::UnitedKingdom => "amazon.co.uk",
+            Marketplace::Germany => "amazon.de",
+            Marketplace::France => "amazon.fr",
+            Marketplace::Italy => "amazon.it",
+            Marketplace::Spain => "amazon.es",
+            Marketplace::Netherlands => "amazon.nl",
+            Marketplace::Japan => "amazon.co.jp",
+            Marketplace::India => "amazon.in",
+            Marketplace::Australia => "amazon.com.au",
+        }
+    }
+
+    /// Find the marketplace for a host name such as `www.amazon.co.uk` or `smile.amazon.com`
+    pub fn from_host(host: &str) -> Option<Self> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+
+        Marketplace::ALL.into_iter().find(|marketplace| {
+            let domain = marketplace.domain();
+            host == domain || host.ends_with(&format!(".{}", domain))
+        })
+    }
+}
+
+/// The form the identifier was given in
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IdentifierSource {
+    /// `/dp/ASIN`, optionally preceded by a product name slug
+    DetailPage,
+    /// `/gp/product/ASIN`
+    GpProduct,
+    /// `/gp/aw/d/ASIN` used by the mobile site
+    Mobile,
+    /// `/exec/obidos/ASIN/ASIN` and `/exec/obidos/tg/detail/-/ASIN` from old links
+    Obidos,
+    /// `/o/ASIN/ASIN` from old links
+    LegacyO,
+    /// An `asin` query parameter, such as on the offer list
+    QueryParameter,
+    /// An `amzn.to` or `a.co` link which had to be resolved by following redirects
+    ShortLink,
+    /// An ASIN on its own
+    Asin,
+    /// An ISBN-10 on its own. ISBN-10s are used as the ASIN for books.
+    Isbn10,
+    /// An ISBN-13 either on its own or in place of the ASIN in a link
+    Isbn13,
+}
+
+/// A product identified from a URL or identifier pasted by a user
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ProductIdentifier {
+    pub marketplace: Marketplace,
+    pub asin: String,
+    pub source: IdentifierSource,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum IdentifierError {
+    /// The input is a short link which needs to be resolved with
+    /// [AmazonApi::resolve_identifier](crate::scraper::AmazonApi::resolve_identifier)
+    ShortLink(Url),
+    /// The input is a URL for a site other than Amazon
+    UnknownHost(String),
+    /// No product identifier could be found in the input
+    Unrecognized,
+}
+
+impl Display for IdentifierError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            IdentifierError::ShortLink(url) => write!(f, "{} is a short link", url),
+            IdentifierError::UnknownHost(host) => write!(f, "{} is not an Amazon site", host),
+            IdentifierError::Unrecognized => write!(f, "no product identifier was found"),
+        }
+    }
+}
+
+/// Hosts which only redirect to product pages
+const SHORT_LINK_HOSTS: [&str; 4] = ["amzn.to", "a.co", "amzn.eu", "amzn.asia"];
+
+impl ProductIdentifier {
+    /// Parse a product URL, ASIN or ISBN. Short links can not be parsed without making a request
+    /// and are returned as [IdentifierError::ShortLink].
+    pub fn parse(input: &str) -> Result<Self, IdentifierError> {
+        let input = input.trim();
+
+        // Anything without a path or domain can only be an identifier on its own
+        if !input.contains(&['/', '.'][..]) {
+            return parse_bare(input).ok_or(IdentifierError::Unrecognized);
+        }
+
+        // Links are often pasted without the scheme
+        let url = match Url::parse(input) {
+            Ok(url) if url.has_host() => url,
+            _ => Url::parse(&format!("https://{}", input)).map_err(|_| IdentifierError::Unrecognized)?,
+        };
+
+        let host = url.host_str().ok_or(IdentifierError::Unrecognized)?;
+        let host = host.strip_prefix("www.").unwrap_or(host).to_ascii_lowercase();
+        let segments = path_segments(&url);
+
+        if SHORT_LINK_HOSTS.contains(&host.as_str()) {
+            return Err(IdentifierError::ShortLink(url));
+        }
+
+        // amzn.com/ASIN is an old short form which contains the ASIN directly
+        if host == "amzn.com" {
+            let (asin, source) = segments
+                .first()
+                .and_then(|segment| normalize_asin(segment))
+                .ok_or(IdentifierError::Unrecognized)?;
+
+            let source = source.unwrap_or(IdentifierSource::DetailPage);
+            return Ok(ProductIdentifier::new(Marketplace::UnitedStates, asin, source));
+        }
+
+        let marketplace = match Marketplace::from_host(&host) {
+            Some(marketplace) => marketplace,
+            None => return Err(IdentifierError::UnknownHost(host)),
+        };
+
+        if let Some((asin, source)) = find_in_path(&segments) {
+            return Ok(ProductIdentifier::new(marketplace, asin, source));
+        }
+
+        url.query_pairs()
+            .filter(|(key, _)| key.eq_ignore_ascii_case("asin"))
+            .find_map(|(_, value)| normalize_asin(&value))
+            .map(|(asin, source)| {
+                let source = source.unwrap_or(IdentifierSource::QueryParameter);
+                ProductIdentifier::new(marketplace, asin, source)
+            })
+            .ok_or(IdentifierError::Unrecognized)
+    }
+
+    fn new(marketplace: Marketplace, asin: String, source: IdentifierSource) -> Self {
+        ProductIdentifier {
+            marketplace,
+            asin,
+            source,
+        }
+    }
+}
+
+/// Parse an identifier given without a URL. ISBNs may be written with hyphens or spaces.
+fn parse_bare(input: &str) -> Option<ProductIdentifier> {
+    let compact: String = input.chars().filter(|c| *c != '-' && *c != ' ').collect();
+    let compact = compact.to_ascii_uppercase();
+
+    let source = if compact.len() == 13 {
+        IdentifierSource::Isbn13
+    } else if is_isbn10(&compact) {
+        IdentifierSource::Isbn10
+    } else if input.len() == 10 && is_asin(&compact) {
+        IdentifierSource::Asin
+    } else {
+        return None;
+    };
+
+    let (asin, _) = normalize_asin(&compact)?;
+    Some(ProductIdentifier::new(Marketplace::UnitedStates, asin, source))
+}
+
+fn path_segments(url: &Url) -> Vec<&str> {
+    url.path_segments()
+        .map(|segments| segments.filter(|segment| !segment.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+/// Search the path for one of the known product URL forms. Anything after the identifier, such as
+/// a `ref=` segment, is ignored.
+fn find_in_path(segments: &[&str]) -> Option<(String, IdentifierSource)> {
+    let lower: Vec<String> = segments.iter().map(|s| s.to_ascii_lowercase()).collect();
+    let lower: Vec<&str> = lower.iter().map(String::as_str).collect();
+
+    for index in 0..lower.len() {
+        let (offset, source) = match &lower[index..] {
+            ["dp", "product", ..] => (2, IdentifierSource::DetailPage),
+            ["dp", ..] => (1, IdentifierSource::DetailPage),
+            ["gp", "product", ..] => (2, IdentifierSource::GpProduct),
+            ["gp", "aw", "d", ..] => (3, IdentifierSource::Mobile),
+            ["exec", "obidos", "tg", "detail", "-", ..] => (5, IdentifierSource::Obidos),
+            ["exec", "obidos", "asin", ..] => (3, IdentifierSource::Obidos),
+            ["o", "asin", ..] => (2, IdentifierSource::LegacyO),
+            _ => continue,
+        };
+
+        if let Some((asin, isbn)) = segments.get(index + offset).and_then(|s| normalize_asin(s)) {
+            return Some((asin, isbn.unwrap_or(source)));
+        }
+    }
+
+    None
+}
+
+/// Convert an ASIN or ISBN to the ASIN used by Amazon. If the identifier was an ISBN-13 it is
+/// converted to the equivalent ISBN-10 and the source is returned as well.
+fn normalize_asin(text: &str) -> Option<(String, Option<IdentifierSource>)> {
+    let text = text.to_ascii_uppercase();
+
+    if text.len() == 13 {
+        return Some((isbn13_to_isbn10(&text)?, Some(IdentifierSource::Isbn13)));
+    }
+
+    if is_asin(&text) {
+        return Some((text, None));
+    }
+
+    None
+}
+
+fn is_asin(text: &str) -> bool {
+    text.len() == 10 && text.chars().all(|c| c.is_ascii_digit() || c.is_ascii_uppercase())
+}
+
+fn is_isbn10(text: &str) -> bool {
+    let mut chars = text.chars();
+    let check = match chars.next_back() {
+        Some('X') => 10,
+        Some(c) => match c.to_digit(10) {
+            Some(digit) => digit,
+            None => return false,
+        },
+        None => return false,
+    };
+
+    let digits: Option<Vec<u32>> = chars.map(|c| c.to_digit(10)).collect();
+    match digits {
+        Some(digits) if digits.len() == 9 => isbn10_check_digit(&digits) == check,
+        _ => false,
+    }
+}
+
+fn isbn10_check_digit(digits: &[u32]) -> u32 {
+    let sum: u32 = digits
+        .iter()
+        .enumerate()
+        .map(|(index, digit)| (10 - index as u32) * digit)
+        .sum();
+
+    (11 - sum % 11) % 11
+}
+
+/// Only ISBN-13s with the 978 prefix have an equivalent ISBN-10
+fn isbn13_to_isbn10(text: &str) -> Option<String> {
+    let digits: Vec<u32> = text.chars().map(|c| c.to_digit(10)).collect::<Option<_>>()?;
+    if digits.len() != 13 || digits[..3] != [9, 7, 8] {
+        return None;
+    }
+
+    let weighted: u32 = digits[..12]
+        .iter()
+        .enumerate()
+        .map(|(index, digit)| if index % 2 == 0 { *digit } else { 3 * digit })
+        .sum();
+
+    if (10 - weighted % 10) % 10 != digits[12] {
+        return None;
+    }
+
+    let mut isbn10: String = text[3..12].to_string();
+    match isbn10_check_digit(&digits[3..12]) {
+        10 => isbn10.push('X'),
+        check => isbn10.push_str(&check.to_string()),
+    }
+
+    Some(isbn10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdentifierSource::*;
+    use super::Marketplace::*;
+    use super::*;
+
+    #[test]
+    pub fn recognized_identifiers() {
+        let cases = [
+            ("https://www.amazon.com/dp/B07VGRJDFY", UnitedStates, "B07VGRJDFY", DetailPage),
+            ("https://www.amazon.com/dp/B07VGRJDFY/", UnitedStates, "B07VGRJDFY", DetailPage),
+            ("https://www.amazon.com/Nintendo-Switch-Neon-Joy%E2%80%91/dp/B07VGRJDFY/ref=sr_1_3?keywords=switch&qid=1680000000&sr=8-3", UnitedStates, "B07VGRJDFY", DetailPage),
+            ("https://www.amazon.com/dp/B07VGRJDFY/ref=cm_sw_r_cp_api_i_abc?th=1", UnitedStates, "B07VGRJDFY", DetailPage),
+            ("https://www.amazon.com/dp/product/B07VGRJDFY", UnitedStates, "B07VGRJDFY", DetailPage),
+            ("https://www.amazon.com/dp/b07vgrjdfy", UnitedStates, "B07VGRJDFY", DetailPage),
+            ("amazon.com/dp/B07VGRJDFY", UnitedStates, "B07VGRJDFY", DetailPage),
+            ("www.amazon.com/dp/B07VGRJDFY?psc=1", UnitedStates, "B07VGRJDFY", DetailPage),
+            ("http://smile.amazon.com/dp/B07VGRJDFY", UnitedStates, "B07VGRJDFY", DetailPage),
+            ("https://www.amazon.com/gp/product/B07VGRJDFY", UnitedStates, "B07VGRJDFY", GpProduct),
+            ("https://www.amazon.com/gp/product/B07VGRJDFY/ref=ppx_yo_dt_b_asin_title_o00_s00?ie=UTF8&psc=1", UnitedStates, "B07VGRJDFY", GpProduct),
+            ("https://www.amazon.com/gp/aw/d/B07VGRJDFY", UnitedStates, "B07VGRJDFY", Mobile),
+            ("https://m.amazon.com/gp/aw/d/B07VGRJDFY/ref=mp_s_a_1_1", UnitedStates, "B07VGRJDFY", Mobile),
+            ("https://www.amazon.com/exec/obidos/ASIN/0316769177", UnitedStates, "0316769177", Obidos),
+            ("https://www.amazon.com/exec/obidos/asin/0316769177/someassociate-20", UnitedStates, "0316769177", Obidos),
+            ("https://www.amazon.com/exec/obidos/tg/detail/-/0316769177/", UnitedStates, "0316769177", Obidos),
+            ("https://www.amazon.com/o/ASIN/0316769177", UnitedStates, "0316769177", LegacyO),
+            ("https://www.amazon.com/Catcher-Rye-J-D-Salinger/dp/0316769177", UnitedStates, "0316769177", DetailPage),
+            ("https://www.amazon.com/dp/9780316769174", UnitedStates, "0316769177", Isbn13),
+            ("https://www.amazon.com/dp/9780804429573/ref=sr_1_1", UnitedStates, "080442957X", Isbn13),
+            ("https://www.amazon.com/gp/product/ajax/ref=dp_aod_ALL_mbc?asin=B07VGRJDFY&pc=dp", UnitedStates, "B07VGRJDFY", QueryParameter),
+            ("https://www.amazon.co.uk/dp/B07VGRJDFY", UnitedKingdom, "B07VGRJDFY", DetailPage),
+            ("https://www.amazon.de/-/en/dp/B07VGRJDFY", Germany, "B07VGRJDFY", DetailPage),
+            ("https://www.amazon.co.jp/gp/product/B07VGRJDFY", Japan, "B07VGRJDFY", GpProduct),
+            ("https://www.amazon.com.au/dp/B07VGRJDFY", Australia, "B07VGRJDFY", DetailPage),
+            ("https://www.amazon.com.mx/dp/B07VGRJDFY", Mexico, "B07VGRJDFY", DetailPage),
+            ("https://www.amazon.ca/dp/B07VGRJDFY", Canada, "B07VGRJDFY", DetailPage),
+            ("https://amzn.com/B07VGRJDFY", UnitedStates, "B07VGRJDFY", DetailPage),
+            ("B07VGRJDFY", UnitedStates, "B07VGRJDFY", Asin),
+            (" b07vgrjdfy ", UnitedStates, "B07VGRJDFY", Asin),
+            ("0316769177", UnitedStates, "0316769177", Isbn10),
+            ("080442957X", UnitedStates, "080442957X", Isbn10),
+            ("0-316-76917-7", UnitedStates, "0316769177", Isbn10),
+            ("9780316769174", UnitedStates, "0316769177", Isbn13),
+            ("978-0-316-76917-4", UnitedStates, "0316769177", Isbn13),
+            ("978 0 8044 2957 3", UnitedStates, "080442957X", Isbn13),
+        ];
+
+        for (input, marketplace, asin, source) in cases {
+            let expected = ProductIdentifier::new(marketplace, asin.to_string(), source);
+            assert_eq!(ProductIdentifier::parse(input), Ok(expected), "{}", input);
+        }
+    }
+
+    #[test]
+    pub fn short_links_need_resolving() {
+        let cases = [
+            "https://amzn.to/3abcXYZ",
+            "amzn.to/3abcXYZ",
+            "https://a.co/d/abc1234",
+            "https://amzn.eu/d/abc1234",
+            "https://amzn.asia/d/abc1234",
+        ];
+
+        for input in cases {
+            let result = ProductIdentifier::parse(input);
+            assert!(matches!(result, Err(IdentifierError::ShortLink(_))), "{}: {:?}", input, result);
+        }
+    }
+
+    #[test]
+    pub fn rejected_identifiers() {
+        let cases = [
+            ("", IdentifierError::Unrecognized),
+            ("not a product", IdentifierError::Unrecognized),
+            ("B07VGRJDF", IdentifierError::Unrecognized),
+            ("B07VGRJDFY1", IdentifierError::Unrecognized),
+            ("B07VGRJD-Y", IdentifierError::Unrecognized),
+            // Invalid check digits
+            ("9780316769175", IdentifierError::Unrecognized),
+            ("https://www.amazon.com/dp/9780316769175", IdentifierError::Unrecognized),
+            // 979 ISBNs have no ISBN-10 equivalent
+            ("9791234567896", IdentifierError::Unrecognized),
+            ("https://www.amazon.com/", IdentifierError::Unrecognized),
+            ("https://www.amazon.com/s?k=switch", IdentifierError::Unrecognized),
+            ("https://www.amazon.com/dp/", IdentifierError::Unrecognized),
+            ("https://www.amazon.com/dp/B07VGRJ", IdentifierError::Unrecognized),
+            ("https://www.amazon.com/gp/help/customer/display.html", IdentifierError::Unrecognized),
+            ("https://amzn.com/", IdentifierError::Unrecognized),
+            ("https://www.example.com/dp/B07VGRJDFY", IdentifierError::UnknownHost("example.com".to_string())),
+            ("https://notamazon.com/dp/B07VGRJDFY", IdentifierError::UnknownHost("notamazon.com".to_string())),
+            ("https://amazon.com.evil.net/dp/B07VGRJDFY", IdentifierError::UnknownHost("amazon.com.evil.net".to_string())),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(ProductIdentifier::parse(input), Err(expected), "{}", input);
+        }
+    }
+
+    #[test]
+    pub fn isbn_conversion() {
+        assert_eq!(isbn13_to_isbn10("9780316769174").as_deref(), Some("0316769177"));
+        assert_eq!(isbn13_to_isbn10("9780306406157").as_deref(), Some("0306406152"));
+        assert_eq!(isbn13_to_isbn10("9780804429573").as_deref(), Some("080442957X"));
+        assert_eq!(isbn13_to_isbn10("9780306406158"), None);
+        assert_eq!(isbn13_to_isbn10("9791234567896"), None);
+
+        assert!(is_isbn10("0306406152"));
+        assert!(is_isbn10("080442957X"));
+        assert!(!is_isbn10("0306406153"));
+        assert!(!is_isbn10("B07VGRJDFY"));
+    }
+}
@@ -1,6 +1,9 @@
+use crate::scraper::health::ParserHealth;
 use crate::scraper::price::PriceUSD;
+use crate::scraper::rules::{self, OfferRules};
+use log::warn;
+use select::document::Document;
 use select::node::Node;
-use select::predicate::{And, Attr, Class, Name, Text};
 use std::fmt::Debug;
 use std::str::FromStr;
 
@@ -18,78 +21,46 @@ pub struct Offer {
 #[derive(Debug)]
 pub struct MissingOfferField(&'static str);
 
-impl<'a> TryFrom<Node<'a>> for Offer {
-    type Error = MissingOfferField;
+impl MissingOfferField {
+    /// The name of the field which could not be found
+    pub fn field(&self) -> &'static str {
+        self.0
+    }
+}
 
-    fn try_from(value: Node) -> Result<Self, Self::Error> {
-        let price = value
-            .find(Class("a-price"))
-            .flat_map(|node| node.find(Class("a-offscreen")))
-            .flat_map(|node| node.find(Text))
-            .filter_map(|node| node.as_text())
+impl Offer {
+    pub fn parse(value: Node, rules: &OfferRules) -> Result<Self, MissingOfferField> {
+        let price = rules
+            .price
+            .values(value)
+            .iter()
             .filter_map(|text| PriceUSD::from_str(text).ok())
             .next()
             .ok_or(MissingOfferField("price"))?;
 
-        let condition = value
-            .find(Attr("id", "aod-offer-heading"))
-            .flat_map(|node| node.find(Text))
-            .filter_map(|node| node.as_text())
+        let condition = rules
+            .condition
+            .values(value)
+            .iter()
             .filter_map(|text| Condition::from_str(text).ok())
             .next()
             .ok_or(MissingOfferField("condition"))?;
 
-        let condition_description = value
-            .find(Attr("id", "aod-condition-container"))
-            .flat_map(|node| node.find(Class("expandable-expanded-text")))
-            .flat_map(|node| node.find(Text))
-            .filter_map(|node| node.as_text())
-            .next()
-            .map(str::to_string);
-
-        let ships_from = value
-            .find(Attr("id", "aod-offer-shipsFrom"))
-            .flat_map(|node| node.find(Class("a-col-right")))
-            .flat_map(|node| node.find(Text))
-            .filter_map(|node| node.as_text())
-            .map(|text| text.trim())
-            .collect::<String>();
-
-        if ships_from.is_empty() {
-            return Err(MissingOfferField("ships_from"));
-        }
+        let condition_description = rules.condition_description.first(value);
 
-        let seller = value
-            .find(Attr("id", "aod-offer-soldBy"))
-            .flat_map(|node| node.find(Class("a-col-right")))
-            .next()
+        let ships_from = rules
+            .ships_from
+            .first(value)
+            .ok_or(MissingOfferField("ships_from"))?;
+
+        // The seller "Amazon.com" does not have a seller page, so it is only matched by the
+        // fallback selectors
+        let sold_by = rules
+            .sold_by
+            .first(value)
             .ok_or(MissingOfferField("sold_by"))?;
 
-        let (sold_by, seller_page) = match seller.find(Name("a")).next() {
-            Some(link) => {
-                let seller_name = link
-                    .find(Text)
-                    .filter_map(|node| node.as_text())
-                    .map(|text| text.trim())
-                    .collect();
-
-                (seller_name, link.attr("href").map(str::to_string))
-            }
-            None => {
-                // As far as I know, this is only done when the seller is "Amazon.com"
-                let seller_name = seller
-                    .find(And(
-                        Name("span"),
-                        Attr("class", "a-size-small a-color-base"),
-                    ))
-                    .flat_map(|node| node.find(Text))
-                    .filter_map(|node| node.as_text())
-                    .map(|text| text.trim())
-                    .collect::<String>();
-
-                (seller_name, None)
-            }
-        };
+        let seller_page = rules.seller_page.first(value);
 
         Ok(Offer {
             condition,
@@ -102,6 +73,49 @@ impl<'a> TryFrom<Node<'a>> for Offer {
     }
 }
 
+impl<'a> TryFrom<Node<'a>> for Offer {
+    type Error = MissingOfferField;
+
+    fn try_from(value: Node) -> Result<Self, Self::Error> {
+        Offer::parse(value, &rules::current().offer)
+    }
+}
+
+/// Parse all of the offers on a page of the offer list, logging any which could not be read.
+pub fn offers_on_page(
+    asin: &str,
+    document: &Document,
+    rules: &OfferRules,
+    health: &ParserHealth,
+) -> Vec<Offer> {
+    let mut offers = Vec::new();
+
+    for node in rules.container.nodes(document) {
+        let offer = Offer::parse(node, rules);
+        health.record_offer(asin, &offer);
+
+        match offer {
+            Ok(offer) => offers.push(offer),
+            Err(err) => warn!("Failed to parse offer for item {}: {:?}", asin, err),
+        }
+    }
+
+    offers
+}
+
+/// Read the total number of offers from the first page of the offer list
+pub fn offer_count(document: &Document, rules: &OfferRules) -> Option<u32> {
+    rules
+        .count
+        .values(document)
+        .iter()
+        .filter_map(|s| {
+            // We are expecting a string of the form "123 options"
+            u32::from_str(s.strip_suffix(" options")?).ok()
+        })
+        .next()
+}
+
 /// https://www.amazon.com/gp/help/customer/display.html?nodeId=202074290
 #[derive(Debug, Eq, PartialEq)]
 pub enum Condition {
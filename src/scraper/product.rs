@@ -1,8 +1,8 @@
+use crate::scraper::rules::{self, DepartmentRules, ExtractionRules};
 use lazy_static::lazy_static;
 use regex::Regex;
 use select::document::Document;
 use select::node::Node;
-use select::predicate::{Attr, Name, Text};
 use std::ops::Deref;
 use serde::Serialize;
 
@@ -15,28 +15,27 @@ pub struct Product {
     pub manufacturer: String,
 }
 
-impl<'a> TryFrom<&'a Document> for Product {
-    type Error = MissingField;
+impl Product {
+    pub fn parse(document: &Document, rules: &ExtractionRules) -> Result<Self, MissingField> {
+        let asin = rules
+            .product
+            .asin
+            .first(document)
+            .ok_or(MissingField("asin"))?;
 
-    fn try_from(document: &'a Document) -> Result<Self, Self::Error> {
-        let asin = read_product_info(document, "ASIN")
-            .ok_or(MissingField("asin"))?
-            .to_string();
+        let manufacturer = rules
+            .product
+            .manufacturer
+            .first(document)
+            .ok_or(MissingField("manufacturer"))?;
 
-        let manufacturer = read_product_info(document, "Manufacturer")
-            .ok_or(MissingField("manufacturer"))?
-            .to_string();
+        let department = DepartmentHierarchy::parse(document, &rules.department);
 
-        let department = DepartmentHierarchy::try_from(document)?;
-
-        let name = document
-            .find(Attr("id", "productTitle"))
-            .flat_map(|node| node.find(Text))
-            .filter_map(|node| node.as_text())
-            .map(|text| text.trim())
-            .next()
-            .ok_or(MissingField("name"))?
-            .to_string();
+        let name = rules
+            .product
+            .name
+            .first(document)
+            .ok_or(MissingField("name"))?;
 
         Ok(Product {
             asin,
@@ -47,27 +46,24 @@ impl<'a> TryFrom<&'a Document> for Product {
     }
 }
 
-fn read_product_info<'a>(node: &'a Document, key: &str) -> Option<&'a str> {
-    node.find(Attr("id", "productDetails_techSpec_section_1"))
-        .chain(node.find(Attr("id", "productDetails_detailBullets_sections1")))
-        .flat_map(|node| node.find(Name("tr")))
-        .filter(|node| {
-            node.find(Name("th"))
-                .flat_map(|node| node.find(Text))
-                .filter_map(|node| node.as_text())
-                .map(|text| text.trim())
-                .any(|text| text == key)
-        })
-        .flat_map(|node| node.find(Name("td")))
-        .flat_map(|node| node.find(Text))
-        .filter_map(|node| node.as_text())
-        .map(|text| text.trim())
-        .next()
+impl<'a> TryFrom<&'a Document> for Product {
+    type Error = MissingField;
+
+    fn try_from(document: &'a Document) -> Result<Self, Self::Error> {
+        Product::parse(document, &rules::current())
+    }
 }
 
 #[derive(Debug)]
 pub struct MissingField(&'static str);
 
+impl MissingField {
+    /// The name of the field which could not be found
+    pub fn field(&self) -> &'static str {
+        self.0
+    }
+}
+
 #[derive(Serialize)]
 pub struct Department {
     pub name: String,
@@ -78,28 +74,31 @@ impl Department {
     pub fn url(&self) -> String {
         format!("https://amazon.com/b/?node={}", self.node)
     }
-}
 
-impl<'a> TryFrom<Node<'a>> for Department {
-    type Error = MissingField;
-
-    fn try_from(value: Node<'a>) -> Result<Self, Self::Error> {
+    pub fn parse(value: Node, rules: &DepartmentRules) -> Result<Self, MissingField> {
         lazy_static! {
             static ref NODE_REGEX: Regex = Regex::new(r"[?&]node=(\d+)$").unwrap();
         }
 
-        let href = value.attr("href").ok_or(MissingField("department_node"))?;
+        let href = rules.href.first(value).ok_or(MissingField("department_node"))?;
 
         let node = NODE_REGEX
-            .captures(href)
+            .captures(&href)
             .and_then(|matches| matches.get(1))
             .and_then(|node| node.as_str().parse::<u64>().ok())
-            .ok_or(MissingField("department_name"))?;
+            .ok_or(MissingField("department_node"))?;
 
-        Ok(Department {
-            name: value.inner_html().trim().to_string(),
-            node,
-        })
+        let name = rules.name.first(value).ok_or(MissingField("department_name"))?;
+
+        Ok(Department { name, node })
+    }
+}
+
+impl<'a> TryFrom<Node<'a>> for Department {
+    type Error = MissingField;
+
+    fn try_from(value: Node<'a>) -> Result<Self, Self::Error> {
+        Department::parse(value, &rules::current().department)
     }
 }
 
@@ -108,17 +107,31 @@ pub struct DepartmentHierarchy {
     departments: Vec<Department>,
 }
 
+impl DepartmentHierarchy {
+    pub fn parse(document: &Document, rules: &DepartmentRules) -> Self {
+        DepartmentHierarchy {
+            departments: rules
+                .links
+                .nodes(document)
+                .into_iter()
+                .filter_map(|node| Department::parse(node, rules).ok())
+                .collect(),
+        }
+    }
+}
+
+/// A breadcrumb path of departments, starting from the top level
+impl From<Vec<Department>> for DepartmentHierarchy {
+    fn from(departments: Vec<Department>) -> Self {
+        DepartmentHierarchy { departments }
+    }
+}
+
 impl<'a> TryFrom<&'a Document> for DepartmentHierarchy {
     type Error = MissingField;
 
     fn try_from(value: &'a Document) -> Result<Self, Self::Error> {
-        Ok(DepartmentHierarchy {
-            departments: value
-                .find(Attr("id", "wayfinding-breadcrumbs_feature_div"))
-                .flat_map(|node| node.find(Name("a")))
-                .filter_map(|node| Department::try_from(node).ok())
-                .collect(),
-        })
+        Ok(DepartmentHierarchy::parse(value, &rules::current().department))
     }
 }
 
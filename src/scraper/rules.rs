@@ -0,0 +1,494 @@
+use lazy_static::lazy_static;
+use log::{error, info};
+use select::document::Document;
+use select::node::Node;
+use select::predicate::{Class, Name, Predicate, Text};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+/// The rules used when no rule file has been loaded. These mirror the selectors the parsers were
+/// originally written with.
+const DEFAULT_RULES: &str = include_str!("../../extraction_rules.json");
+
+lazy_static! {
+    static ref CURRENT_RULES: RwLock<Arc<ExtractionRules>> =
+        RwLock::new(Arc::new(ExtractionRules::default()));
+}
+
+/// Get the extraction rules currently in use by the parsers
+pub fn current() -> Arc<ExtractionRules> {
+    CURRENT_RULES.read().unwrap().clone()
+}
+
+/// Replace the rules used by the parsers. Parses which are already in progress will finish with
+/// the rules they started with.
+pub fn replace(rules: ExtractionRules) {
+    *CURRENT_RULES.write().unwrap() = Arc::new(rules);
+}
+
+/// Declarative description of how to read each field from the pages returned by Amazon
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExtractionRules {
+    pub product: ProductRules,
+    pub department: DepartmentRules,
+    pub offer: OfferRules,
+    /// Rule files written before search was added do not include these rules
+    #[serde(default = "default_search_rules")]
+    pub search: SearchRules,
+    #[serde(default = "default_wishlist_rules")]
+    pub wishlist: WishlistRules,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProductRules {
+    pub asin: FieldRule,
+    pub manufacturer: FieldRule,
+    pub name: FieldRule,
+}
+
+/// Departments are read from the breadcrumb links. The `href` and `name` rules are evaluated
+/// relative to each link.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DepartmentRules {
+    pub links: FieldRule,
+    pub href: FieldRule,
+    pub name: FieldRule,
+}
+
+/// The `container` and `count` rules are evaluated on the offer page, all other rules are
+/// evaluated relative to each offer container.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OfferRules {
+    pub container: FieldRule,
+    pub count: FieldRule,
+    pub price: FieldRule,
+    pub condition: FieldRule,
+    pub condition_description: FieldRule,
+    pub ships_from: FieldRule,
+    pub sold_by: FieldRule,
+    pub seller_page: FieldRule,
+}
+
+/// The `container` rule is evaluated on the search results page, all other rules are evaluated
+/// relative to each result card.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SearchRules {
+    pub container: FieldRule,
+    pub asin: FieldRule,
+    pub title: FieldRule,
+    pub thumbnail: FieldRule,
+    pub price: FieldRule,
+    pub rating: FieldRule,
+}
+
+fn default_search_rules() -> SearchRules {
+    ExtractionRules::default().search
+}
+
+/// Used to read saved copies of Amazon wishlist pages. The `link` rule is evaluated relative to
+/// each item container.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WishlistRules {
+    pub container: FieldRule,
+    pub link: FieldRule,
+}
+
+fn default_wishlist_rules() -> WishlistRules {
+    ExtractionRules::default().wishlist
+}
+
+/// A list of selectors for a single field. Selectors are tried in order so later selectors act as
+/// fallbacks when Amazon changes their markup.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct FieldRule(pub Vec<Selector>);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Selector {
+    /// Each step searches the descendants of the nodes matched by the previous step. An empty
+    /// path refers to the node the rule is evaluated on.
+    pub path: Vec<Step>,
+    #[serde(default)]
+    pub extract: Extract,
+}
+
+/// A single step in a selector path. Every condition which is present must match.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Step {
+    pub id: Option<String>,
+    pub class: Option<String>,
+    pub name: Option<String>,
+    /// An exact match on the value of an attribute
+    pub attr: Option<(String, String)>,
+    /// Select the cells of table rows whose header matches this text. Can not be combined with
+    /// other conditions.
+    pub row: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Extract {
+    /// Each text node produces a separate value
+    #[default]
+    Text,
+    /// All text within the node is joined into a single value
+    JoinedText,
+    Attr(String),
+    InnerHtml,
+}
+
+/// The place a rule is evaluated from
+#[derive(Copy, Clone)]
+pub enum Scope<'a> {
+    Document(&'a Document),
+    Node(Node<'a>),
+}
+
+impl<'a> From<&'a Document> for Scope<'a> {
+    fn from(document: &'a Document) -> Self {
+        Scope::Document(document)
+    }
+}
+
+impl<'a> From<Node<'a>> for Scope<'a> {
+    fn from(node: Node<'a>) -> Self {
+        Scope::Node(node)
+    }
+}
+
+impl<'a> Scope<'a> {
+    fn find<P: Predicate>(self, predicate: P) -> Vec<Node<'a>> {
+        match self {
+            Scope::Document(document) => document.find(predicate).collect(),
+            Scope::Node(node) => node.find(predicate).collect(),
+        }
+    }
+}
+
+impl FieldRule {
+    /// All non-empty values matched by the selectors in order of preference
+    pub fn values<'a, S: Into<Scope<'a>>>(&self, scope: S) -> Vec<String> {
+        let scope = scope.into();
+
+        self.0
+            .iter()
+            .flat_map(|selector| {
+                selector
+                    .select(scope)
+                    .into_iter()
+                    .flat_map(move |node| selector.extract(node))
+            })
+            .filter(|value| !value.is_empty())
+            .collect()
+    }
+
+    /// The first non-empty value matched by the selectors
+    pub fn first<'a, S: Into<Scope<'a>>>(&self, scope: S) -> Option<String> {
+        self.values(scope).into_iter().next()
+    }
+
+    /// The nodes matched by the first selector that matches anything
+    pub fn nodes<'a, S: Into<Scope<'a>>>(&self, scope: S) -> Vec<Node<'a>> {
+        let scope = scope.into();
+
+        self.0
+            .iter()
+            .map(|selector| selector.select(scope))
+            .find(|nodes| !nodes.is_empty())
+            .unwrap_or_default()
+    }
+}
+
+impl Selector {
+    fn select<'a>(&self, scope: Scope<'a>) -> Vec<Node<'a>> {
+        let mut steps = self.path.iter();
+
+        let mut nodes = match (steps.next(), scope) {
+            (Some(step), _) => step.apply(scope),
+            (None, Scope::Node(node)) => vec![node],
+            (None, Scope::Document(_)) => Vec::new(),
+        };
+
+        for step in steps {
+            nodes = nodes
+                .into_iter()
+                .flat_map(|node| step.apply(Scope::Node(node)))
+                .collect();
+        }
+
+        nodes
+    }
+
+    fn extract(&self, node: Node) -> Vec<String> {
+        let texts = || {
+            node.find(Text)
+                .filter_map(|node| node.as_text())
+                .map(|text| text.trim())
+        };
+
+        match &self.extract {
+            Extract::Text => texts().map(str::to_string).collect(),
+            Extract::JoinedText => vec![texts().collect()],
+            Extract::Attr(name) => node.attr(name).map(str::to_string).into_iter().collect(),
+            Extract::InnerHtml => vec![node.inner_html().trim().to_string()],
+        }
+    }
+}
+
+impl Step {
+    fn apply<'a>(&self, scope: Scope<'a>) -> Vec<Node<'a>> {
+        if let Some(key) = &self.row {
+            return scope
+                .find(Name("tr"))
+                .into_iter()
+                .filter(|row| {
+                    row.find(Name("th"))
+                        .flat_map(|node| node.find(Text))
+                        .filter_map(|node| node.as_text())
+                        .any(|text| text.trim() == key)
+                })
+                .flat_map(|row| row.find(Name("td")))
+                .collect();
+        }
+
+        scope.find(|node: &Node| self.matches(node))
+    }
+
+    fn matches(&self, node: &Node) -> bool {
+        if let Some(id) = &self.id {
+            if node.attr("id") != Some(id.as_str()) {
+                return false;
+            }
+        }
+
+        if let Some(class) = &self.class {
+            if !Class(class.as_str()).matches(node) {
+                return false;
+            }
+        }
+
+        if let Some(name) = &self.name {
+            if node.name() != Some(name.as_str()) {
+                return false;
+            }
+        }
+
+        if let Some((key, value)) = &self.attr {
+            if node.attr(key) != Some(value.as_str()) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn is_empty(&self) -> bool {
+        self.id.is_none()
+            && self.class.is_none()
+            && self.name.is_none()
+            && self.attr.is_none()
+            && self.row.is_none()
+    }
+}
+
+#[derive(Debug)]
+pub enum RulesError {
+    Io(io::Error),
+    Parse(serde_json::Error),
+    /// The rules parsed, but do not make sense. For example, a field without any selectors.
+    Invalid(String),
+}
+
+impl Display for RulesError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RulesError::Io(e) => write!(f, "unable to read rules: {}", e),
+            RulesError::Parse(e) => write!(f, "unable to parse rules: {}", e),
+            RulesError::Invalid(e) => write!(f, "invalid rules: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RulesError {}
+
+impl Default for ExtractionRules {
+    fn default() -> Self {
+        ExtractionRules::from_json(DEFAULT_RULES).expect("Default extraction rules are valid")
+    }
+}
+
+impl ExtractionRules {
+    pub fn from_json(json: &str) -> Result<Self, RulesError> {
+        let rules: ExtractionRules = serde_json::from_str(json).map_err(RulesError::Parse)?;
+        rules.validate()?;
+        Ok(rules)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, RulesError> {
+        let json = std::fs::read_to_string(path).map_err(RulesError::Io)?;
+        ExtractionRules::from_json(&json)
+    }
+
+    fn validate(&self) -> Result<(), RulesError> {
+        let fields = [
+            ("product.asin", &self.product.asin),
+            ("product.manufacturer", &self.product.manufacturer),
+            ("product.name", &self.product.name),
+            ("department.links", &self.department.links),
+            ("department.href", &self.department.href),
+            ("department.name", &self.department.name),
+            ("offer.container", &self.offer.container),
+            ("offer.count", &self.offer.count),
+            ("offer.price", &self.offer.price),
+            ("offer.condition", &self.offer.condition),
+            ("offer.condition_description", &self.offer.condition_description),
+            ("offer.ships_from", &self.offer.ships_from),
+            ("offer.sold_by", &self.offer.sold_by),
+            ("offer.seller_page", &self.offer.seller_page),
+            ("search.container", &self.search.container),
+            ("search.asin", &self.search.asin),
+            ("search.title", &self.search.title),
+            ("search.thumbnail", &self.search.thumbnail),
+            ("search.price", &self.search.price),
+            ("search.rating", &self.search.rating),
+            ("wishlist.container", &self.wishlist.container),
+            ("wishlist.link", &self.wishlist.link),
+        ];
+
+        for (name, rule) in fields {
+            if rule.0.is_empty() {
+                return Err(RulesError::Invalid(format!("{} has no selectors", name)));
+            }
+
+            for step in rule.0.iter().flat_map(|selector| &selector.path) {
+                if step.is_empty() {
+                    let msg = format!("{} contains a step without any conditions", name);
+                    return Err(RulesError::Invalid(msg));
+                }
+
+                let has_element_conditions = step.id.is_some()
+                    || step.class.is_some()
+                    || step.name.is_some()
+                    || step.attr.is_some();
+
+                if step.row.is_some() && has_element_conditions {
+                    let msg = format!("{} combines a row step with other conditions", name);
+                    return Err(RulesError::Invalid(msg));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reloads the rules from a file whenever it is modified
+pub struct RulesWatcher {
+    path: PathBuf,
+    last_modified: Option<SystemTime>,
+}
+
+impl RulesWatcher {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        RulesWatcher {
+            path: path.into(),
+            last_modified: None,
+        }
+    }
+
+    /// Load the rules if the file has changed since the last call. If the new rules can not be
+    /// loaded, the previous rules are kept.
+    pub fn reload_if_changed(&mut self) -> Result<bool, RulesError> {
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(RulesError::Io)?;
+
+        if self.last_modified == Some(modified) {
+            return Ok(false);
+        }
+
+        // Record the modification time even on failure so a broken file is only reported once
+        self.last_modified = Some(modified);
+
+        match ExtractionRules::load(&self.path) {
+            Ok(rules) => {
+                info!("Loaded extraction rules from {}", self.path.display());
+                replace(rules);
+                Ok(true)
+            }
+            Err(e) => {
+                error!(
+                    "Keeping previous extraction rules since {} could not be loaded: {}",
+                    self.path.display(),
+                    e
+                );
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn default_rules_are_valid() {
+        let rules = ExtractionRules::from_json(DEFAULT_RULES);
+        assert!(rules.is_ok(), "{:?}", rules.err());
+    }
+
+    #[test]
+    pub fn fallback_selectors() {
+        let rules = ExtractionRules::default();
+        let document = Document::from(
+            "<table id=\"productDetails_detailBullets_sections1\">
+                <tr><th> Manufacturer </th><td> Acme </td></tr>
+                <tr><th> ASIN </th><td> B07VGRJDFY </td></tr>
+            </table>",
+        );
+
+        assert_eq!(
+            rules.product.asin.first(&document),
+            Some("B07VGRJDFY".to_string())
+        );
+        assert_eq!(
+            rules.product.manufacturer.first(&document),
+            Some("Acme".to_string())
+        );
+        assert_eq!(rules.product.name.first(&document), None);
+    }
+
+    #[test]
+    pub fn extract_modes() {
+        let rules: FieldRule = serde_json::from_str(
+            r#"[{ "path": [{ "name": "span", "attr": ["class", "seller"] }], "extract": "joined_text" }]"#,
+        )
+        .unwrap();
+
+        let document = Document::from(
+            "<div><span class=\"seller\"> Amazon<b>.com</b> </span><span class=\"seller other\">x</span></div>",
+        );
+
+        assert_eq!(rules.values(&document), vec!["Amazon.com".to_string()]);
+    }
+
+    #[test]
+    pub fn reject_invalid_rules() {
+        let mut rules = ExtractionRules::default();
+        rules.offer.price = FieldRule(Vec::new());
+        assert!(matches!(rules.validate(), Err(RulesError::Invalid(_))));
+    }
+}
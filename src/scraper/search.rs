@@ -0,0 +1,104 @@
+use crate::scraper::health::ParserHealth;
+use crate::scraper::price::PriceUSD;
+use crate::scraper::rules::SearchRules;
+use select::document::Document;
+use serde::Serialize;
+use std::str::FromStr;
+
+/// A single product card from an Amazon search results page
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    pub asin: String,
+    pub title: String,
+    pub thumbnail: Option<String>,
+    pub price: Option<f32>,
+    /// Average rating out of 5 stars
+    pub rating: Option<f32>,
+}
+
+/// Parse the result cards on a search page. Cards without an ASIN, such as the sponsored carousels
+/// mixed in with the results, are skipped.
+pub fn search_results(
+    document: &Document,
+    rules: &SearchRules,
+    health: &ParserHealth,
+) -> Vec<SearchResult> {
+    let mut results = Vec::new();
+
+    for node in rules.container.nodes(document) {
+        let asin = match rules.asin.first(node) {
+            Some(asin) => asin.to_ascii_uppercase(),
+            None => continue,
+        };
+
+        let title = match rules.title.first(node) {
+            Some(title) => {
+                health.record_success("search.title");
+                title
+            }
+            None => {
+                health.record_failure("search.title", &asin);
+                continue;
+            }
+        };
+
+        let price = rules
+            .price
+            .values(node)
+            .iter()
+            .filter_map(|text| PriceUSD::from_str(text).ok())
+            .map(f32::from)
+            .next();
+
+        results.push(SearchResult {
+            asin,
+            title,
+            thumbnail: rules.thumbnail.first(node),
+            price,
+            rating: rules.rating.values(node).iter().find_map(|s| parse_rating(s)),
+        });
+    }
+
+    results
+}
+
+/// Ratings are given as text of the form "4.5 out of 5 stars"
+fn parse_rating(text: &str) -> Option<f32> {
+    let (rating, _) = text.split_once(" out of ")?;
+    f32::from_str(rating).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scraper::rules::ExtractionRules;
+
+    #[test]
+    pub fn parse_result_cards() {
+        let rules = ExtractionRules::default();
+        let document = Document::from(
+            "<div data-component-type=\"s-search-result\" data-asin=\"B07VGRJDFY\">
+                <img class=\"s-image\" src=\"https://m.media-amazon.com/images/I/thumb.jpg\">
+                <h2><a><span>Nintendo Switch with Neon Joy-Con</span></a></h2>
+                <span class=\"a-icon-alt\">4.8 out of 5 stars</span>
+                <span class=\"a-price\"><span class=\"a-offscreen\">$299.99</span></span>
+            </div>
+            <div data-component-type=\"s-search-result\" data-asin=\"\">
+                <h2>Sponsored</h2>
+            </div>",
+        );
+
+        let results = search_results(&document, &rules.search, &ParserHealth::default());
+        assert_eq!(results.len(), 1);
+
+        let result = &results[0];
+        assert_eq!(result.asin, "B07VGRJDFY");
+        assert_eq!(result.title, "Nintendo Switch with Neon Joy-Con");
+        assert_eq!(
+            result.thumbnail.as_deref(),
+            Some("https://m.media-amazon.com/images/I/thumb.jpg")
+        );
+        assert_eq!(result.price, Some(299.99));
+        assert_eq!(result.rating, Some(4.8));
+    }
+}
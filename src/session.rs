@@ -1,5 +1,9 @@
+use crate::database::Connection;
+use crate::env::var_or;
+use chrono::{DateTime, Utc};
+use log::error;
 use rocket::http::{Cookie, CookieJar, Status};
-use rocket::outcome::IntoOutcome;
+use rocket::outcome::{try_outcome, IntoOutcome};
 use rocket::request::{FromRequest, Outcome};
 use rocket::response::Redirect;
 use rocket::time::Duration;
@@ -7,15 +11,75 @@ use rocket::{uri, Request};
 use sqlx::database::HasArguments;
 use sqlx::encode::IsNull;
 use sqlx::types::Uuid;
-use sqlx::{Database, Encode, Type};
+use sqlx::{Database, Encode, FromRow, Pool, Sqlite, Type};
 use std::convert::Infallible;
+use std::net::IpAddr;
 use std::ops::Deref;
 
-const USER_TOKEN: &str = "user_token";
+const SESSION_ID: &str = "session_id";
+const CSRF_TOKEN: &str = "csrf_token";
+const PENDING_LOGIN: &str = "pending_login";
 const SESSION_TTL: Duration = Duration::days(3);
 
+/// Time allowed to enter the second factor after the password has been checked
+const PENDING_LOGIN_SECS: i64 = 5 * 60;
+
+/// The last seen time of a session is only updated once it is this old, so that every request
+/// does not need to write to the database
+const TOUCH_INTERVAL_SECS: i64 = 60;
+
+/// How long sessions stay signed in. Each request pushes back the idle timeout, but sessions
+/// always end once they reach the maximum lifetime.
+#[derive(Debug, Copy, Clone)]
+pub struct SessionPolicy {
+    pub idle_timeout: chrono::Duration,
+    pub max_lifetime: chrono::Duration,
+}
+
+impl SessionPolicy {
+    pub fn from_env() -> Self {
+        SessionPolicy {
+            idle_timeout: chrono::Duration::hours(var_or("SESSION_IDLE_HOURS", 72)),
+            max_lifetime: chrono::Duration::days(var_or("SESSION_MAX_DAYS", 30)),
+        }
+    }
+
+    /// When the session ends unless it is used again
+    pub fn expires_at(&self, created_at: DateTime<Utc>, last_seen: DateTime<Utc>) -> DateTime<Utc> {
+        (last_seen + self.idle_timeout).min(created_at + self.max_lifetime)
+    }
+}
+
+impl Default for SessionPolicy {
+    fn default() -> Self {
+        SessionPolicy {
+            idle_timeout: chrono::Duration::days(3),
+            max_lifetime: chrono::Duration::days(30),
+        }
+    }
+}
+
+/// A signed in session as stored in the database
+#[derive(Debug, FromRow)]
+pub struct StoredSession {
+    pub id: Uuid,
+    pub user: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// The user of the session named by the request's cookie, looked up once per request
+struct CurrentUser(Option<Uuid>);
+
 pub struct Session<'r> {
     jar: &'r CookieJar<'r>,
+    /// Only set when the session cookie names a session which has not expired or been revoked
+    user: Option<Uuid>,
+    policy: SessionPolicy,
+    user_agent: Option<&'r str>,
+    ip: Option<IpAddr>,
 }
 
 impl<'r> Session<'r> {
@@ -24,35 +88,137 @@ impl<'r> Session<'r> {
     }
 
     pub fn user_id(&self) -> Option<Uuid> {
-        let cookie = self.jar.get_private(USER_TOKEN)?;
+        self.user
+    }
+
+    /// The ID of the session named by the cookie, whether or not it is still valid
+    pub fn session_id(&self) -> Option<Uuid> {
+        let cookie = self.jar.get_private(SESSION_ID)?;
         Uuid::parse_str(cookie.value()).ok()
     }
 
-    pub fn remove_user_id(&self) {
-        if let Some(cookie) = self.jar.get_private(USER_TOKEN) {
+    /// Sign in to a new session, replacing the current one
+    pub async fn start(&self, database: &mut Connection<Sqlite>, user: Uuid) -> sqlx::Result<()> {
+        if let Some(id) = self.session_id() {
+            database.delete_session(id).await?;
+        }
+
+        let id = Uuid::new_v4();
+        let ip = self.ip.map(|ip| ip.to_string());
+        database.add_session(id, user, Utc::now(), self.user_agent, ip.as_deref()).await?;
+
+        self.jar.add_private(self.session_cookie(id));
+        Ok(())
+    }
+
+    /// Sign out, revoking the session so the cookie can not be used again
+    pub async fn end(&self, database: &mut Connection<Sqlite>) -> sqlx::Result<()> {
+        if let Some(id) = self.session_id() {
+            database.delete_session(id).await?;
+        }
+
+        self.remove_cookie();
+        Ok(())
+    }
+
+    /// Remember a user who has entered their password but still needs to enter a code from their
+    /// authenticator app. The cookie is encrypted, so it can not be forged to skip the password.
+    pub fn set_pending_login(&self, user: Uuid, now: DateTime<Utc>) {
+        let expires = now.timestamp() + PENDING_LOGIN_SECS;
+        self.jar.add_private(Cookie::new(PENDING_LOGIN, format!("{}|{}", user, expires)));
+    }
+
+    pub fn pending_login(&self, now: DateTime<Utc>) -> Option<Uuid> {
+        let cookie = self.jar.get_private(PENDING_LOGIN)?;
+        let (user, expires) = cookie.value().split_once('|')?;
+
+        if now.timestamp() >= expires.parse::<i64>().ok()? {
+            return None;
+        }
+
+        Uuid::parse_str(user).ok()
+    }
+
+    pub fn clear_pending_login(&self) {
+        if let Some(cookie) = self.jar.get_private(PENDING_LOGIN) {
             self.jar.remove_private(cookie);
         }
     }
 
-    pub fn set_user_id(&self, user_id: Uuid) {
-        let cookie_value = user_id.to_string();
+    fn remove_cookie(&self) {
+        if let Some(cookie) = self.jar.get_private(SESSION_ID) {
+            self.jar.remove_private(cookie);
+        }
+    }
 
-        match self.jar.get_private(USER_TOKEN) {
-            Some(mut cookie) => cookie.set_value(cookie_value),
-            None => {
-                let cookie = Cookie::build(USER_TOKEN, cookie_value)
-                    .max_age(SESSION_TTL)
-                    .finish();
+    /// The cookie expires along with the idle timeout, and is sent again whenever the session is
+    /// used so it does not expire before the session does
+    fn session_cookie(&self, id: Uuid) -> Cookie<'static> {
+        Cookie::build(SESSION_ID, id.to_string())
+            .max_age(Duration::seconds(self.policy.idle_timeout.num_seconds()))
+            .finish()
+    }
 
-                self.jar.add_private(cookie)
-            }
+    /// The token which must be submitted with state-changing requests, if one has been issued
+    pub fn stored_csrf_token(&self) -> Option<String> {
+        self.jar.get_private(CSRF_TOKEN).map(|cookie| cookie.value().to_string())
+    }
+
+    /// The token which must be submitted with state-changing requests. A new token is created if
+    /// the session does not have one yet. See [crate::csrf].
+    pub fn csrf_token(&self) -> String {
+        if let Some(token) = self.stored_csrf_token() {
+            return token;
         }
+
+        // Version 4 UUIDs are generated from a secure random source
+        let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        let cookie = Cookie::build(CSRF_TOKEN, token.clone())
+            .max_age(SESSION_TTL)
+            .finish();
+
+        self.jar.add_private(cookie);
+        token
     }
 }
 
+/// Look up the user of a session, ending it if it has expired. Also returns whether the session's
+/// last seen time was updated.
+pub async fn resume_session(
+    database: &mut Connection<Sqlite>,
+    id: Uuid,
+    policy: &SessionPolicy,
+    now: DateTime<Utc>,
+) -> sqlx::Result<Option<(Uuid, bool)>> {
+    let session = match database.session(id).await? {
+        Some(session) => session,
+        None => return Ok(None),
+    };
+
+    if now >= policy.expires_at(session.created_at, session.last_seen) {
+        database.delete_session(id).await?;
+        return Ok(None);
+    }
+
+    let touched = now - session.last_seen >= chrono::Duration::seconds(TOUCH_INTERVAL_SECS);
+    if touched {
+        database.touch_session(id, now).await?;
+    }
+
+    Ok(Some((session.user, touched)))
+}
+
 impl<'r> From<&'r CookieJar<'r>> for Session<'r> {
+    /// A session which only reads cookies. It is never signed in, since that requires checking the
+    /// session in the database.
     fn from(jar: &'r CookieJar) -> Self {
-        Session { jar }
+        Session {
+            jar,
+            user: None,
+            policy: SessionPolicy::default(),
+            user_agent: None,
+            ip: None,
+        }
     }
 }
 
@@ -61,9 +227,46 @@ impl<'r> FromRequest<'r> for Session<'r> {
     type Error = Infallible;
 
     async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        req.guard::<&CookieJar<'r>>()
-            .await
-            .map(|cookies| Session { jar: cookies })
+        let mut session = Session::from(req.cookies());
+        session.policy = req.rocket().state::<SessionPolicy>().copied().unwrap_or_default();
+        session.user_agent = req.headers().get_one("User-Agent");
+        session.ip = req.client_ip();
+
+        let CurrentUser(user) = req
+            .local_cache_async(async { CurrentUser(current_user(req, &session).await) })
+            .await;
+        session.user = *user;
+
+        Outcome::Success(session)
+    }
+}
+
+/// Find the user of the request's session. Invalid session cookies are removed, and the cookie of a
+/// valid session is renewed when it is used.
+async fn current_user(req: &Request<'_>, session: &Session<'_>) -> Option<Uuid> {
+    let id = session.session_id()?;
+    let pool = req.rocket().state::<Pool<Sqlite>>()?;
+
+    let resumed = match pool.acquire().await {
+        Ok(connection) => resume_session(&mut Connection::from(connection), id, &session.policy, Utc::now()).await,
+        Err(e) => Err(e),
+    };
+
+    match resumed {
+        Ok(Some((user, touched))) => {
+            if touched {
+                session.jar.add_private(session.session_cookie(id));
+            }
+            Some(user)
+        }
+        Ok(None) => {
+            session.remove_cookie();
+            None
+        }
+        Err(e) => {
+            error!("Unable to look up session {}: {}", id, e);
+            None
+        }
     }
 }
 
@@ -91,6 +294,61 @@ impl<'r> FromRequest<'r> for UserId {
     }
 }
 
+/// What a user is allowed to do on the site
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Role {
+    User,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// Like [UserId], but also requires the user to be an administrator. Other users are refused with
+/// 403 Forbidden.
+#[derive(Debug, Copy, Clone)]
+pub struct AdminUser(pub UserId);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminUser {
+    type Error = Redirect;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let user = try_outcome!(request.guard::<UserId>().await);
+
+        let is_admin = match request.rocket().state::<Pool<Sqlite>>() {
+            Some(pool) => match pool.acquire().await {
+                Ok(connection) => Connection::from(connection).is_admin(*user).await,
+                Err(e) => Err(e),
+            },
+            None => Ok(false),
+        };
+
+        let is_admin = is_admin.unwrap_or_else(|e| {
+            error!("Unable to look up the role of user {}: {}", *user, e);
+            false
+        });
+
+        is_admin
+            .then_some(AdminUser(user))
+            .into_outcome((Status::Forbidden, Redirect::to("/index")))
+    }
+}
+
+impl Deref for AdminUser {
+    type Target = UserId;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
 impl<D: Database> Type<D> for UserId
 where
     Uuid: Type<D>,
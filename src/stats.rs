@@ -0,0 +1,216 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use sqlx::FromRow;
+use std::collections::BTreeMap;
+
+/// Windows of the moving averages, in days
+const AVERAGE_WINDOWS: [i64; 3] = [30, 90, 365];
+
+/// The prices of a product in a single condition and when they were collected
+type Prices = Vec<(DateTime<Utc>, f64)>;
+
+/// A price collected for a product, as read from `Has_Listing_collected`
+#[derive(Debug, Clone, FromRow)]
+pub struct PricePoint {
+    pub asin: String,
+    pub condition: Option<String>,
+    pub datetime: DateTime<Utc>,
+    pub price: f64,
+}
+
+/// Summary of the prices collected for a product in a single condition
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PriceStats {
+    pub asin: String,
+    pub condition: String,
+    /// The most recently collected price
+    pub current: f64,
+    pub updated_at: DateTime<Utc>,
+    pub all_time_low: f64,
+    pub all_time_high: f64,
+    pub average_30_days: Option<f64>,
+    pub average_90_days: Option<f64>,
+    pub average_365_days: Option<f64>,
+    pub median: f64,
+    /// Percentage of collected prices below the current price, counting equal prices as half
+    pub percentile_rank: f64,
+    /// Standard deviation of the prices as a percentage of their mean
+    pub volatility: f64,
+    /// Days since the price was last at its all-time low, which is 0 while it still is
+    pub days_since_low: i64,
+    pub samples: usize,
+}
+
+/// Calculate the statistics of each product and condition in the points. The results are ordered
+/// by ASIN and then condition.
+pub fn price_stats(points: &[PricePoint], now: DateTime<Utc>) -> Vec<PriceStats> {
+    let mut groups: BTreeMap<(&str, &str), Prices> = BTreeMap::new();
+    for point in points {
+        let condition = point.condition.as_deref().unwrap_or_default();
+        groups
+            .entry((&point.asin, condition))
+            .or_default()
+            .push((point.datetime, point.price));
+    }
+
+    groups
+        .into_iter()
+        .filter_map(|((asin, condition), prices)| summarize(asin, condition, prices, now))
+        .collect()
+}
+
+/// Calculate the statistics of the prices of a single product and condition. Returns `None` when
+/// there are no prices.
+fn summarize(
+    asin: &str,
+    condition: &str,
+    mut prices: Prices,
+    now: DateTime<Utc>,
+) -> Option<PriceStats> {
+    prices.sort_by_key(|&(datetime, _)| datetime);
+    let &(updated_at, current) = prices.last()?;
+
+    let values: Vec<f64> = prices.iter().map(|&(_, price)| price).collect();
+    let all_time_low = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let all_time_high = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+
+    let [average_30_days, average_90_days, average_365_days] = AVERAGE_WINDOWS.map(|days| {
+        let since = now - Duration::days(days);
+        mean(prices.iter().filter(|&&(datetime, _)| datetime >= since).map(|&(_, price)| price))
+    });
+
+    let below = values.iter().filter(|&&price| price < current).count();
+    let equal = values.iter().filter(|&&price| price == current).count();
+    let percentile_rank = 100.0 * (below as f64 + equal as f64 / 2.0) / values.len() as f64;
+
+    let last_low = prices
+        .iter()
+        .rev()
+        .find(|&&(_, price)| price == all_time_low)
+        .map(|&(datetime, _)| datetime)
+        .unwrap_or(updated_at);
+    let days_since_low = if current == all_time_low { 0 } else { (now - last_low).num_days().max(0) };
+
+    Some(PriceStats {
+        asin: asin.to_string(),
+        condition: condition.to_string(),
+        current,
+        updated_at,
+        all_time_low,
+        all_time_high,
+        average_30_days,
+        average_90_days,
+        average_365_days,
+        median: median(&values),
+        percentile_rank,
+        volatility: volatility(&values),
+        days_since_low,
+        samples: values.len(),
+    })
+}
+
+pub(crate) fn mean(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let (sum, count) = values.fold((0.0, 0usize), |(sum, count), value| (sum + value, count + 1));
+    (count > 0).then_some(sum / count as f64)
+}
+
+pub(crate) fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+
+    let middle = sorted.len() / 2;
+    match sorted.len() % 2 {
+        0 => (sorted[middle - 1] + sorted[middle]) / 2.0,
+        _ => sorted[middle],
+    }
+}
+
+/// The coefficient of variation of the prices, as a percentage
+fn volatility(values: &[f64]) -> f64 {
+    let mean = match mean(values.iter().copied()) {
+        Some(mean) if mean > 0.0 => mean,
+        _ => return 0.0,
+    };
+
+    let variance = values.iter().map(|value| (value - mean).powi(2)).sum::<f64>() / values.len() as f64;
+    100.0 * variance.sqrt() / mean
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn point(condition: &str, days_ago: i64, price: f64, now: DateTime<Utc>) -> PricePoint {
+        PricePoint {
+            asin: "B07VGRJDFY".to_string(),
+            condition: Some(condition.to_string()),
+            datetime: now - Duration::days(days_ago),
+            price,
+        }
+    }
+
+    #[test]
+    pub fn summary_statistics() {
+        let now = Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap();
+        let points = vec![
+            point("New", 400, 10.0, now),
+            point("New", 100, 40.0, now),
+            point("New", 60, 20.0, now),
+            point("New", 20, 30.0, now),
+            point("New", 0, 25.0, now),
+        ];
+
+        let stats = price_stats(&points, now);
+        assert_eq!(stats.len(), 1);
+
+        let stats = &stats[0];
+        assert_eq!(stats.condition, "New");
+        assert_eq!((stats.current, stats.updated_at), (25.0, now));
+        assert_eq!((stats.all_time_low, stats.all_time_high), (10.0, 40.0));
+        assert_eq!(stats.average_30_days, Some(27.5));
+        assert_eq!(stats.average_90_days, Some(25.0));
+        assert_eq!(stats.average_365_days, Some(28.75));
+        assert_eq!(stats.median, 25.0);
+        assert_eq!(stats.percentile_rank, 50.0);
+        assert_eq!(stats.days_since_low, 400);
+        assert_eq!(stats.samples, 5);
+
+        // Prices 10, 20, 25, 30 and 40 have a mean of 25 and a standard deviation of 10
+        assert!((stats.volatility - 40.0).abs() < 1e-9);
+    }
+
+    #[test]
+    pub fn grouped_by_condition() {
+        let now = Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap();
+        let points = vec![
+            point("Used", 5, 12.0, now),
+            point("New", 3, 20.0, now),
+            point("Used", 1, 10.0, now),
+            point("New", 2, 21.0, now),
+        ];
+
+        let stats = price_stats(&points, now);
+        let conditions: Vec<_> = stats.iter().map(|stats| stats.condition.as_str()).collect();
+        assert_eq!(conditions, vec!["New", "Used"]);
+
+        // The newest used price is the lowest yet
+        assert_eq!((stats[1].current, stats[1].days_since_low), (10.0, 0));
+        assert_eq!(stats[1].percentile_rank, 25.0);
+        assert_eq!(stats[0].median, 20.5);
+        assert_eq!(stats[0].days_since_low, 3);
+        assert_eq!(stats[0].percentile_rank, 75.0);
+    }
+
+    #[test]
+    pub fn averages_need_recent_prices() {
+        let now = Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap();
+        let stats = price_stats(&[point("New", 200, 15.0, now)], now);
+
+        assert_eq!(stats[0].average_30_days, None);
+        assert_eq!(stats[0].average_90_days, None);
+        assert_eq!(stats[0].average_365_days, Some(15.0));
+        assert_eq!(stats[0].volatility, 0.0);
+        assert!(price_stats(&[], now).is_empty());
+    }
+}
@@ -0,0 +1,243 @@
+use crate::database::Connection;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use sqlx::types::Uuid;
+use sqlx::Sqlite;
+use std::fmt::Write;
+
+/// Codes are six digits long and change every 30 seconds, the defaults every authenticator app
+/// supports
+const DIGITS: u32 = 6;
+const STEP_SECS: i64 = 30;
+
+/// Codes from this many steps before or after the current one are accepted to allow for clock drift
+const ALLOWED_DRIFT: i64 = 1;
+
+/// RFC 4226 recommends secrets of 160 bits
+const SECRET_BYTES: usize = 20;
+
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// A new random secret from the operating system's secure random source
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0; SECRET_BYTES];
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+/// The URI encoded in the QR code scanned by authenticator apps
+pub fn provisioning_uri(secret: &[u8], issuer: &str, account: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        uri_encode(issuer),
+        uri_encode(account),
+        base32(secret),
+        uri_encode(issuer),
+        DIGITS,
+        STEP_SECS
+    )
+}
+
+/// The time step a code belongs to (RFC 6238)
+pub fn time_step(time: DateTime<Utc>) -> i64 {
+    time.timestamp().div_euclid(STEP_SECS)
+}
+
+/// The code shown by authenticator apps at the given time
+#[cfg(test)]
+pub fn code_at(secret: &[u8], time: DateTime<Utc>) -> String {
+    step_code(secret, time_step(time))
+}
+
+fn step_code(secret: &[u8], step: i64) -> String {
+    format!("{:0width$}", hotp(secret, step as u64, DIGITS), width = DIGITS as usize)
+}
+
+/// Check a code against the steps around the current time, returning the step it belongs to.
+/// Codes for steps up to `last_used_step` are rejected so an observed code can not be used again.
+pub fn verify(secret: &[u8], code: &str, now: DateTime<Utc>, last_used_step: Option<i64>) -> Option<i64> {
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let current = time_step(now);
+    (current - ALLOWED_DRIFT..=current + ALLOWED_DRIFT)
+        .filter(|step| !matches!(last_used_step, Some(last) if *step <= last))
+        .find(|step| crate::csrf::tokens_match(&step_code(secret, *step), &code))
+}
+
+/// Check a code from the user's authenticator app, or one of their recovery codes, using it up if
+/// it is accepted. Always fails if two-factor authentication is not enabled.
+pub async fn check_second_factor(
+    database: &mut Connection<Sqlite>,
+    user: Uuid,
+    code: &str,
+    now: DateTime<Utc>,
+) -> sqlx::Result<bool> {
+    let (secret, last_used_step) = match database.two_factor(user).await? {
+        Some((secret, true, last_used_step)) => (secret, last_used_step),
+        _ => return Ok(false),
+    };
+
+    if let Some(step) = verify(&secret, code, now, last_used_step) {
+        return database.use_two_factor_step(user, step).await;
+    }
+
+    database.use_recovery_code(user, &hash_recovery_code(code), now).await
+}
+
+/// Single-use codes for signing in without the authenticator, written as two groups of five
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut bytes = [0; 6];
+            OsRng.fill_bytes(&mut bytes);
+            let code = base32(&bytes).to_ascii_lowercase();
+            format!("{}-{}", &code[..5], &code[5..10])
+        })
+        .collect()
+}
+
+/// Recovery codes are stored hashed, ignoring case, spaces and dashes
+pub fn hash_recovery_code(code: &str) -> [u8; 32] {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    Sha256::digest(normalized.as_bytes()).into()
+}
+
+/// HMAC-based one-time password (RFC 4226). Authenticator apps only support HMAC-SHA1, which is
+/// fine for HOTP even though SHA-1 is no longer collision resistant.
+fn hotp(secret: &[u8], counter: u64, digits: u32) -> u32 {
+    let mut hmac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    hmac.update(&counter.to_be_bytes());
+    let mac = hmac.finalize().into_bytes();
+    let offset = (mac[19] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([mac[offset], mac[offset + 1], mac[offset + 2], mac[offset + 3]]) & 0x7fff_ffff;
+
+    binary % 10u32.pow(digits)
+}
+
+/// Base32 (RFC 4648) without padding, as used for secrets in provisioning URIs
+pub fn base32(bytes: &[u8]) -> String {
+    let mut output = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+
+        while bits >= 5 {
+            output.push(BASE32_ALPHABET[((buffer >> (bits - 5)) & 31) as usize] as char);
+            bits -= 5;
+        }
+    }
+
+    if bits > 0 {
+        output.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+
+    output
+}
+
+/// Percent-encode everything except unreserved characters
+fn uri_encode(text: &str) -> String {
+    text.bytes().fold(String::new(), |mut output, byte| {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            output.push(byte as char);
+        } else {
+            let _ = write!(output, "%{:02X}", byte);
+        }
+        output
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    pub fn hotp_test_vectors() {
+        // Appendix D of RFC 4226
+        let codes: Vec<_> = (0..10).map(|counter| hotp(RFC_SECRET, counter, 6)).collect();
+        assert_eq!(
+            codes,
+            vec![755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489]
+        );
+    }
+
+    #[test]
+    pub fn totp_test_vectors() {
+        // The SHA-1 cases from appendix B of RFC 6238
+        for (time, code) in [
+            (59, 94287082),
+            (1111111109, 7081804),
+            (1111111111, 14050471),
+            (1234567890, 89005924),
+            (2000000000, 69279037),
+            (20000000000, 65353130),
+        ] {
+            let time = Utc.timestamp_opt(time, 0).unwrap();
+            assert_eq!(hotp(RFC_SECRET, time_step(time) as u64, 8), code);
+        }
+    }
+
+    #[test]
+    pub fn verify_codes() {
+        let now = Utc.timestamp_opt(1111111111, 0).unwrap();
+        let step = time_step(now);
+        let code = code_at(RFC_SECRET, now);
+        assert_eq!(code, "050471");
+
+        assert_eq!(verify(RFC_SECRET, &code, now, None), Some(step));
+        assert_eq!(verify(RFC_SECRET, "050 471", now, None), Some(step));
+
+        // Codes from the neighbouring steps are accepted, but not older ones
+        assert_eq!(verify(RFC_SECRET, &code, now + chrono::Duration::seconds(30), None), Some(step));
+        assert_eq!(verify(RFC_SECRET, &code, now + chrono::Duration::seconds(90), None), None);
+
+        // A code can only be used once
+        assert_eq!(verify(RFC_SECRET, &code, now, Some(step)), None);
+
+        assert_eq!(verify(RFC_SECRET, "123456", now, None), None);
+        assert_eq!(verify(RFC_SECRET, "05047", now, None), None);
+        assert_eq!(verify(RFC_SECRET, "abcdef", now, None), None);
+    }
+
+    #[test]
+    pub fn provisioning() {
+        assert_eq!(base32(RFC_SECRET), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(base32(b"f"), "MY");
+        assert_eq!(generate_secret().len(), SECRET_BYTES);
+
+        assert_eq!(
+            provisioning_uri(RFC_SECRET, "Amazon Tracker", "a+b@example.com"),
+            "otpauth://totp/Amazon%20Tracker:a%2Bb%40example.com?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ\
+             &issuer=Amazon%20Tracker&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    pub fn recovery_codes() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert!(codes.iter().all(|code| code.len() == 11 && code.as_bytes()[5] == b'-'));
+
+        assert_eq!(hash_recovery_code("abcde-fghij"), hash_recovery_code(" ABCDE FGHIJ "));
+        assert_ne!(hash_recovery_code("abcde-fghij"), hash_recovery_code("abcde-fghik"));
+    }
+}
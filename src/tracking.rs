@@ -0,0 +1,63 @@
+use crate::database::Connection;
+use crate::error::Error;
+use crate::scraper::price::PriceUSD;
+use crate::scraper::{AmazonApi, CacheMode};
+use crate::session::UserId;
+use chrono::Utc;
+use sqlx::Sqlite;
+
+/// Add the product to the database if it is new, track it for the user, and record its current
+/// offers. Returns false if the product page could not be read.
+pub async fn track_new_product(
+    user_id: UserId,
+    database: &mut Connection<Sqlite>,
+    amazon_api: &AmazonApi,
+    asin: &str,
+    target_price: Option<PriceUSD>,
+) -> crate::Result<bool> {
+    let product = match amazon_api.get_product_info(asin, CacheMode::Default).await? {
+        Some(product) => product,
+        None => return Ok(false),
+    };
+
+    let product_id = match database.product_exists(&product.asin).await? {
+        Some(id) => id,
+        None => match database.add_product(&product).await? {
+            Some(id) => id,
+            None => return Err(Error::from("The product page does not say which department the product is in")),
+        },
+    };
+
+    database
+        .track_product(user_id, product_id, asin, target_price)
+        .await?;
+
+    // The product page fetched above will be served from the response cache if it is enabled
+    refresh_product(database, amazon_api, asin, CacheMode::Default).await?;
+    Ok(true)
+}
+
+/// Update the product metadata and record the current offers. Returns false if the product page
+/// could not be read.
+pub async fn refresh_product(
+    database: &mut Connection<Sqlite>,
+    amazon_api: &AmazonApi,
+    asin: &str,
+    cache_mode: CacheMode,
+) -> crate::Result<bool> {
+    let product = match amazon_api.get_product_info(asin, cache_mode).await? {
+        Some(product) => product,
+        None => return Ok(false),
+    };
+
+    if database.product_exists(&product.asin).await?.is_none() {
+        return Err(Error::from("Product must be added before it can be updated"));
+    };
+
+    database.update_product_metadata(&product).await?;
+
+    let offers = amazon_api.get_offers_for_asin(asin, cache_mode).await?;
+
+    database.add_listings(&product.asin, Utc::now(), &offers).await?;
+    Ok(true)
+}